@@ -17,10 +17,14 @@
  */
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::kvs::{KvsEngine, KvStore, SledKvsEngine};
+use kvs::kvs::{Codec, CodecKind, IndexMode, KvsEngine, KvStore, KvStoreOptions, SledKvsEngine};
 use rand::distributions::{Distribution, Uniform, Alphanumeric};
 use rand::{Rng, SeedableRng};
 use rand::rngs::StdRng;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn gen_random_string(n: usize) -> String {
@@ -66,6 +70,30 @@ fn kvs_benches(c: &mut Criterion) {
         });
     });
     
+    // With the sled engine, write 100 values as above and flush after every value
+    c.bench_function("sled_write_flush_each", |b| {
+        b.iter(|| {
+            let store = SledKvsEngine::open(temp_dir_sled.path()).expect("Unable to open the database");
+            for i in 0..100 {
+                let (key, value) = samples.get(i).unwrap();
+                store.set(key.to_owned(), value.to_owned()).expect("Unable to write to the database");
+                store.flush().expect("Unable to flush the database");
+            }
+        });
+    });
+    
+    // With the sled engine, write 100 values as above and flush once after the batch
+    c.bench_function("sled_write_flush_batch", |b| {
+        b.iter(|| {
+            let store = SledKvsEngine::open(temp_dir_sled.path()).expect("Unable to open the database");
+            for i in 0..100 {
+                let (key, value) = samples.get(i).unwrap();
+                store.set(key.to_owned(), value.to_owned()).expect("Unable to write to the database");
+            }
+            store.flush().expect("Unable to flush the database");
+        });
+    });
+    
     // With the kvs engine, read 1000 values from previously written keys, with keys and values of random length
     c.bench_function("kvs_read", |b| {
         b.iter(|| {
@@ -82,6 +110,154 @@ fn kvs_benches(c: &mut Criterion) {
         });
     });
     
+    // With the kvs engine, read 1000 values from previously written keys through a single opened store
+    // The database file handles are reused between reads
+    let store = KvStore::open(temp_dir_kvs.path()).expect("Unable to open the database");
+    c.bench_function("kvs_read_opened", |b| {
+        b.iter(|| {
+            for _ in 0..10 {
+                for i in 0..100 {
+                    let (key, value) = samples.get(i).unwrap();
+                    if store.get(key.to_owned())
+                            .expect("Unable to read from the database").unwrap().ne(value) {
+                        panic!("Should not be here")
+                    }
+                }
+            }
+        });
+    });
+    drop(store);
+    
+    // With the kvs engine, read 1000 values while compaction keeps rewriting the segments in background
+    // The read latency should stay close to kvs_read_opened as reads are not blocked by compaction
+    let temp_dir_compaction = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir_compaction.path()).expect("Unable to open the database");
+    for _ in 0..2 {
+        for i in 0..100 {
+            let (key, value) = samples.get(i).unwrap();
+            store.set(key.to_owned(), value.to_owned()).expect("Unable to write to the database");
+        }
+    }
+    store.inject_compaction_delay(Duration::from_millis(1));
+    let stop = Arc::new(AtomicBool::new(false));
+    let compactor = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                store.compact().expect("Unable to compact the database");
+            }
+        })
+    };
+    c.bench_function("kvs_read_during_compaction", |b| {
+        b.iter(|| {
+            for _ in 0..10 {
+                for i in 0..100 {
+                    let (key, value) = samples.get(i).unwrap();
+                    if store.get(key.to_owned())
+                            .expect("Unable to read from the database").unwrap().ne(value) {
+                        panic!("Should not be here")
+                    }
+                }
+            }
+        });
+    });
+    stop.store(true, Ordering::Relaxed);
+    compactor.join().unwrap();
+    drop(store);
+    
+    // With the kvs engine, open a database of 100000 keys with the saved index and by scanning the segment files
+    // The database spans about 16 segments of 256 KiB
+    let temp_dir_open = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { segment_size: 256 << 10, ..KvStoreOptions::default() };
+    let store = KvStore::open_with_options(temp_dir_open.path(), options).expect("Unable to open the database");
+    for i in 0..100000 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("Unable to write to the database");
+    }
+    drop(store);
+    for (name, index_mode) in [("kvs_open_persisted_index", IndexMode::Persisted), ("kvs_open_rebuilt_index", IndexMode::AlwaysRebuild)] {
+        let options = KvStoreOptions { index_mode, ..KvStoreOptions::default() };
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                KvStore::open_with_options(temp_dir_open.path(), options.clone()).expect("Unable to open the database");
+            });
+        });
+    }
+    
+    // With the kvs engine, rebuild the index of the same database by scanning the segment files serially and in parallel
+    for (name, reindex_threads) in [("kvs_reindex_serial", 1), ("kvs_reindex_parallel", 4)] {
+        let options = KvStoreOptions { index_mode: IndexMode::AlwaysRebuild, reindex_threads, ..KvStoreOptions::default() };
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                KvStore::open_with_options(temp_dir_open.path(), options.clone()).expect("Unable to open the database");
+            });
+        });
+    }
+    
+    // With the kvs engine, read 1000 random keys of the same database through file handles and memory-mapped segments
+    let keys = Uniform::from(0..100000).sample_iter(StdRng::seed_from_u64(0)).take(1000).collect::<Vec<u32>>();
+    for (name, mmap) in [("kvs_random_read_file", false), ("kvs_random_read_mmap", true)] {
+        let options = KvStoreOptions { mmap, ..KvStoreOptions::default() };
+        let store = KvStore::open_with_options(temp_dir_open.path(), options).expect("Unable to open the database");
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                for i in keys.iter() {
+                    if store.get(format!("key{}", i)).expect("Unable to read from the database") != Some(format!("value{}", i)) {
+                        panic!("Should not be here")
+                    }
+                }
+            });
+        });
+    }
+    
+    // Encode a small record with every codec, the encoded size is printed for comparison
+    let record = std::collections::HashMap::from([("key".to_owned(), "value".to_owned())]);
+    for (name, codec) in [("bson_encode", CodecKind::Bson), ("bincode_encode", CodecKind::Bincode)] {
+        println!("{}: {} bytes", name, codec.encode(&record).expect("Unable to encode the record").len());
+        c.bench_function(name, |b| {
+            b.iter(|| codec.encode(&record).expect("Unable to encode the record"));
+        });
+    }
+    
+    // With the kvs engine, write 1000 small entries with every codec, the database size is printed for comparison
+    for (name, codec) in [("kvs_write_bson", CodecKind::Bson), ("kvs_write_bincode", CodecKind::Bincode)] {
+        let temp_dir_codec = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { codec, ..KvStoreOptions::default() };
+        let store = KvStore::open_with_options(temp_dir_codec.path(), options).expect("Unable to open the database");
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                for i in 0..1000 {
+                    store.set(format!("key{}", i), format!("value{}", i)).expect("Unable to write to the database");
+                }
+            });
+        });
+        drop(store);
+        let size = std::fs::metadata(temp_dir_codec.path().join("kvs.0.db")).map_or(0, |metadata| metadata.len());
+        println!("{}: {} bytes in the active segment", name, size);
+    }
+    
+    // With the kvs engine, write 800 entries from 8 threads to a write-ahead logged database with and without group commit
+    for (name, group_commit) in [("kvs_wal_concurrent_group_commit", true), ("kvs_wal_concurrent_per_write_sync", false)] {
+        let temp_dir_wal = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder().wal(true).open(temp_dir_wal.path()).expect("Unable to open the database");
+        let store = if group_commit { store } else { store.without_group_commit() };
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let handles = (0..8).map(|i| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for j in 0..100 {
+                            store.set(format!("key{}-{}", i, j), format!("value{}", j)).expect("Unable to write to the database");
+                        }
+                    })
+                }).collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+        });
+    }
+    
     // With the sled engine, read 1000 values from previously written keys, with keys and values of random length
     c.bench_function("sled_read", |b| {
         b.iter(|| {
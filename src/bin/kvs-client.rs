@@ -15,14 +15,20 @@
  * You should have received a copy of the GNU Lesser General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-
 #[macro_use]
 extern crate clap;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use clap::App;
-use kvs::kvs::{Result, KvsError, KvsClient};
+use kvs::kvs::{KvsError, KvsClient, Result};
 
+// Exit codes of failed commands
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CONNECTION: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
 
-fn main() -> Result<()> {
+#[quit::main]
+fn main() {
     let yaml = load_yaml!("kvs_client.yaml");
     let args = App::from_yaml(yaml)
         .version(env!("CARGO_PKG_VERSION"))
@@ -30,59 +36,111 @@ fn main() -> Result<()> {
     
     let addr = args.value_of("addr").unwrap();
     
-    let mut kv = KvsClient::open(addr)?;
+    let mut kv = KvsClient::open(addr).unwrap_or_else(|err| fail(err));
     
-    match args.subcommand() {
+    let result = match args.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            if let Err(KvsError::KeyNotExist(_)) = kv.set(key.to_string(), value.to_string()) {
-                println!("Key not found");
-                quit::with_code(255);
-                // exit(255);
-            }
+            kv.set(key.to_string(), value.to_string())
         },
         
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            match kv.get(key.to_string()) {
-                Ok(result) => {
-                    if let Some(value) = result {
-                        println!("{}", value);
-                    } else {
-                        println!("Key not found");
-                    }
-                },
-                
-                Err(err) => {
-                    println!("{}", err.to_string());
-                    quit::with_code(255);
-                    // exit(255);
-                }
-            }
+            kv.get(key.to_string()).map(|result| match result {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found")
+            })
         },
         
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            if let Err(KvsError::KeyNotExist(_)) = kv.remove(key.to_string()) {
-                eprintln!("Key not found");
-                quit::with_code(255);
-                // exit(255);
-            }
+            kv.remove(key.to_string())
         },
         
-        ("terminate", _) => {
-            if kv.send_terminate_signal().is_err() {
-                quit::with_code(255);
-                // exit(255);
-            }
-        }
+        ("compact", _) => kv.compact(),
+        
+        ("clear", _) => kv.clear(),
+        
+        ("backup", Some(matches)) => {
+            let dir = matches.value_of("DIR").unwrap();
+            kv.backup(dir.to_string())
+        },
+        
+        ("terminate", _) => kv.send_terminate_signal(),
         
+        ("selftest", _) => selftest(&kv),
+        
+        _ => quit::with_code(EXIT_FAILURE)
+    };
+    
+    if let Err(err) = result {
+        fail(err);
+    }
+}
+
+/// Set, read back and remove a throwaway key, printing the time taken by each step and the outcome
+fn selftest(kv: &KvsClient) -> Result<()> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_nanos());
+    let key = format!("__kvs_selftest_{}_{:08x}", process::id(), nanos);
+    let value = format!("{:08x}", nanos.rotate_left(16));
+    let start = Instant::now();
+    let mut step = "SET";
+    let result = (|| -> Result<bool> {
+        let timer = Instant::now();
+        kv.set(key.clone(), value.clone())?;
+        println!("SET {:.3} ms", millis(timer.elapsed()));
+        step = "GET";
+        let timer = Instant::now();
+        let matched = kv.get(key.clone())? == Some(value.clone());
+        println!("GET {:.3} ms", millis(timer.elapsed()));
+        if !matched { return Ok(false) }
+        step = "RM";
+        let timer = Instant::now();
+        kv.remove(key.clone())?;
+        println!("RM {:.3} ms", millis(timer.elapsed()));
+        Ok(true)
+    })();
+    
+    if !matches!(result, Ok(true)) {
+        // The key may be stored even if the reply of SET was lost, so it is removed whichever step failed
+        let _ = kv.remove_if_exists(key);
+    }
+    match result {
+        Ok(true) => {
+            println!("PASS {:.3} ms", millis(start.elapsed()));
+            Ok(())
+        },
+        Ok(false) => {
+            println!("FAIL {:.3} ms: {} read back a different value", millis(start.elapsed()), step);
+            quit::with_code(EXIT_FAILURE)
+        },
+        Err(err) => {
+            println!("FAIL {:.3} ms: {} failed", millis(start.elapsed()), step);
+            Err(err)
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Print the error to stderr and exit with the code of its kind
+fn fail(err: KvsError) -> ! {
+    match err {
+        KvsError::KeyNotExist(_) => {
+            eprintln!("Key not found");
+            quit::with_code(EXIT_NOT_FOUND)
+        },
+        KvsError::IOError(_) | KvsError::InvalidAddress(_) | KvsError::ConnectionRefused | KvsError::ConnectionReset
+        | KvsError::Timeout | KvsError::ProtocolDesync => {
+            eprintln!("Connection error: {}", err);
+            quit::with_code(EXIT_CONNECTION)
+        },
         _ => {
-            quit::with_code(255);
-            // exit(1);
+            eprintln!("{}", err);
+            quit::with_code(EXIT_FAILURE)
         }
     }
-    
-    Ok(())
 }
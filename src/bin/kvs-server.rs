@@ -26,10 +26,12 @@ use std::path::PathBuf;
 use clap::App;
 #[cfg(target_os = "linux")]
 use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
-use kvs::kvs::{Result, KvsServer, KvsClient};
-use slog::{Duplicate, Drain, info, Logger};
+use kvs::kvs::{detect_engine, CodecKind, IpNetwork, KeyValidation, Result, KvsServer, KvsServerOptions, KvStoreOptions};
+use kvs::kvs::util::ThreadPoolKind;
+use slog::{Duplicate, Drain, info, Level, LevelFilter, Logger, Never};
 use slog_term::{FullFormat, PlainDecorator, TermDecorator};
 use slog_async::{Async};
+use slog_json::Json;
 
 fn main() -> Result<()> {
     let yaml = load_yaml!("kvs_server.yaml");
@@ -40,37 +42,36 @@ fn main() -> Result<()> {
     let addr = args.value_of("addr").unwrap();
     let engine = args.value_of("engine").unwrap();
     let path = PathBuf::from(args.value_of("basedir").unwrap()).canonicalize()?;
+    let compaction_threshold = value_t_or_exit!(args, "compaction-threshold", u64);
+    let codec = value_t_or_exit!(args, "codec", CodecKind);
+    let key_validation = value_t_or_exit!(args, "key-validation", KeyValidation);
+    let allowlist = args.is_present("allow").then(|| values_t_or_exit!(args, "allow", IpNetwork));
+    let threads = if args.is_present("threads") {
+        value_t_or_exit!(args, "threads", u32)
+    } else {
+        thread::available_parallelism().map_or(1, |threads| threads.get() as u32)
+    };
+    let thread_pool = value_t_or_exit!(args, "pool", ThreadPoolKind);
+    let denylist = if args.is_present("deny") { values_t_or_exit!(args, "deny", IpNetwork) } else { Vec::new() };
+    let log_level = value_t_or_exit!(args, "log-level", Level);
     
     let logfile = OpenOptions::new().create(true).write(true).truncate(true).open(path.join("stderr"))?;
-    let term_drain = FullFormat::new(TermDecorator::new().build()).build();
-    let file_drain = FullFormat::new(PlainDecorator::new(logfile)).build();
-    let drains = Duplicate::new(term_drain, file_drain).fuse();
+    let drains: Box<dyn Drain<Ok = (), Err = Never> + Send> = match args.value_of("log-format").unwrap() {
+        // One object per line, including the timestamp, level and message
+        "json" => Box::new(Duplicate::new(Json::default(std::io::stderr()), Json::default(logfile)).fuse()),
+        _ => {
+            let term_drain = FullFormat::new(TermDecorator::new().build()).build();
+            let file_drain = FullFormat::new(PlainDecorator::new(logfile)).build();
+            Box::new(Duplicate::new(term_drain, file_drain).fuse())
+        }
+    };
+    let drains = LevelFilter::new(drains, log_level).fuse();
     let (drain, _guard) = Async::new(drains).build_with_guard();
     let logger = Logger::root(drain.fuse(), o!());
     
     
-    // Signal handler
-    // Currently only support Linux for signal handling
-    // TODO Signal handling for Windows platform
-    #[cfg(target_os = "linux")] {
-        let _addr = addr.to_owned();
-        let _logger = logger.clone();
-        let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
-        thread::spawn(move || -> Result<()> {
-            if signals.forever().next().is_some() {
-                // Send the termination signal
-                KvsClient::open(&_addr)?.send_terminate_signal()?;
-                warn!(_logger, "Terminated by signal");
-            }
-            Ok(())
-        });
-    }
-    
     // Check previously used database engine
-    // kvs: kvs.db and kvs.dir
-    // sled: db, config and blob directory
-    if (path.join("kvs.db").exists() && engine != "kvs")
-        || (path.join("db").exists() && engine != "sled") {
+    if detect_engine(&path).is_some_and(|found| found != engine) {
         error!(logger, "Conflicted engine detected";
 			"path" => path.to_str().unwrap(), "engine" => engine);
         info!(logger, "Consider change the working directory with --base-dir options.");
@@ -82,7 +83,45 @@ fn main() -> Result<()> {
     info!(logger, "kvs-server";
 		"addr" => addr, "path" => path.to_str().unwrap(), "engine" => engine, "version" => env!("CARGO_PKG_VERSION"));
     
-    KvsServer::open(engine, path)?.start(addr)?;
+    let options = KvsServerOptions {
+        logger: logger.clone(),
+        store: KvStoreOptions {
+            // Zero disables automatic compaction
+            compaction_threshold: (compaction_threshold > 0).then_some(compaction_threshold),
+            codec,
+            key_validation,
+            ..Default::default()
+        },
+        allowlist,
+        denylist,
+        thread_pool,
+        threads,
+        persist_stats: args.is_present("persist-stats"),
+        backup_dir: args.value_of("backup-dir").map(PathBuf::from),
+        tls_cert: args.value_of("tls-cert").map(PathBuf::from),
+        tls_key: args.value_of("tls-key").map(PathBuf::from),
+        auth_token: args.value_of("token").map(str::to_owned),
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options(engine, path, options)?;
+    
+    // Signal handler
+    // Currently only support Linux for signal handling
+    // TODO Signal handling for Windows platform
+    #[cfg(target_os = "linux")] {
+        let _server = server.clone();
+        let _logger = logger.clone();
+        let mut signals = Signals::new([SIGINT, SIGTERM]).unwrap();
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                // Stop the server in process, KILL is only for remote shutdown
+                _server.shutdown();
+                warn!(_logger, "Terminated by signal");
+            }
+        });
+    }
+    
+    server.start(addr)?;
     info!(logger, "Server shutdown gratefully");
     Ok(())
 }
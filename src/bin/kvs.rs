@@ -15,58 +15,60 @@
  * You should have received a copy of the GNU Lesser General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-
 #[macro_use]
 extern crate clap;
+use std::fs;
+use std::path::PathBuf;
 use std::process::exit;
 use clap::App;
-use kvs::kvs::{KvsEngine, KvStore, Result, KvsError};
+use kvs::kvs::{dispatch, open_engine, Result, KvsError};
 
 fn main() -> Result<()> {
     let yaml = load_yaml!("kvs_cli.yaml");
     let args = App::from_yaml(yaml)
         .version(env!("CARGO_PKG_VERSION"))
         .get_matches();
-    let kv = KvStore::open("kvs.db")?;
+    let path = PathBuf::from(args.value_of("basedir").unwrap());
+    let engine = args.value_of("engine").unwrap();
     
-    match args.subcommand() {
-        ("set", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let value = matches.value_of("VALUE").unwrap();
-            if let Err(KvsError::KeyNotExist(_)) = kv.set(key.to_string(), value.to_string()) {
-                println!("Key not found");
-                exit(255);
-            }
+    // Same commands as served by kvs-server
+    let (cmd, argument) = match args.subcommand() {
+        ("set", Some(matches)) => ("SET", vec![matches.value_of("KEY").unwrap(), matches.value_of("VALUE").unwrap()]),
+        ("get", Some(matches)) => ("GET", vec![matches.value_of("KEY").unwrap()]),
+        ("rm", Some(matches)) => ("RM", vec![matches.value_of("KEY").unwrap()]),
+        _ => { exit(1); }
+    };
+    let argument = argument.into_iter().map(str::to_owned).collect::<Vec<_>>();
+    
+    fs::create_dir_all(&path)?;
+    let store = open_engine(engine, path)?;
+    let code = match dispatch(store.as_ref(), cmd, &argument) {
+        Ok(Some(value)) => {
+            println!("{}", value);
+            0
         },
         
-        ("get", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            match kv.get(key.to_string()) {
-                Ok(result) => {
-                    if let Some(value) = result {
-                        println!("{}", value);
-                    } else {
-                        println!("Key not found");
-                    }
-                },
-                
-                Err(err) => {
-                    println!("{}", err.to_string());
-                    exit(255);
-                }
+        Ok(None) => {
+            if cmd == "GET" {
+                println!("Key not found");
             }
+            0
         },
         
-        ("rm", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            if let Err(KvsError::KeyNotExist(_)) = kv.remove(key.to_string()) {
-                println!("Key not found");
-                exit(255);
-            }
+        Err(KvsError::KeyNotExist(_)) => {
+            println!("Key not found");
+            255
         },
         
-        _ => { exit(1); }
-    }
+        Err(err) => {
+            println!("{}", err);
+            255
+        }
+    };
+    // Close the database before exit, which does not run destructors
+    store.flush()?;
+    drop(store);
+    if code != 0 { exit(code); }
     
     Ok(())
 }
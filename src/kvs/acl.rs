@@ -0,0 +1,79 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use super::{KvsError, Result};
+
+/// IPv4 or IPv6 network in CIDR notation such as `127.0.0.0/8`, a bare address is a network of a single host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8
+}
+
+impl IpNetwork {
+    /// Network of the addresses sharing the first `prefix_len` bits with `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpNetwork> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(KvsError::InvalidArguments(format!("Prefix length {} exceeds {}", prefix_len, max_len)))
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+    
+    /// Check if `ip` belongs to the network, IPv4-mapped IPv6 addresses are matched as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = KvsError;
+    
+    fn from_str(network: &str) -> Result<IpNetwork> {
+        match network.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let prefix_len = prefix_len.parse::<u8>()
+                    .map_err(|_| KvsError::InvalidArguments(format!("Invalid network {}", network)))?;
+                IpNetwork::new(addr.parse()?, prefix_len)
+            },
+            None => {
+                let addr = network.parse::<IpAddr>()?;
+                IpNetwork::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
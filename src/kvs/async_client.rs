@@ -0,0 +1,131 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use super::server::read_frame;
+use super::{KvsError, KvsCmdRequest, KvsServerReply, KvsServerReplyStatus, Result};
+
+/// Asynchronous client keeping a single connection to KvsServer, requests are sent one at a time
+pub struct AsyncKvsClient {
+    stream: TcpStream,
+    next_id: u64 // Id of the next request, echoed in its reply
+}
+
+impl AsyncKvsClient {
+    /// Establish connection to KvsServer
+    pub async fn connect(addr: &str) -> Result<AsyncKvsClient> {
+        Ok(AsyncKvsClient {
+            stream: TcpStream::connect(addr).await?,
+            next_id: 0
+        })
+    }
+    
+    /// Authenticate the connection using `token`
+    pub async fn authenticate(&mut self, token: &str) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "AUTH".to_owned(),
+            argument: vec![token.to_owned()],
+            id: None
+        }).await?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            KvsServerReplyStatus::Unauthorized => Err(KvsError::Unauthorized),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Check if the connection is still served
+    pub async fn ping(&mut self) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "PING".to_owned(),
+            argument: Vec::new(),
+            id: None
+        }).await?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Set the value of a string key to a string
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "SET".to_owned(),
+            argument: vec![key.to_owned(), value],
+            id: None
+        }).await?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            KvsServerReplyStatus::KeyNotFound => Err(KvsError::KeyNotExist(key)),
+            KvsServerReplyStatus::Unauthorized => Err(KvsError::Unauthorized),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Get the string value of a given string key
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "GET".to_owned(),
+            argument: vec![key],
+            id: None
+        }).await?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(reply.result),
+            KvsServerReplyStatus::KeyNotFound => Ok(None),
+            KvsServerReplyStatus::Unauthorized => Err(KvsError::Unauthorized),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Remove a given key `key`
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "REMOVE".to_owned(),
+            argument: vec![key.to_owned()],
+            id: None
+        }).await?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            KvsServerReplyStatus::KeyNotFound => Err(KvsError::KeyNotExist(key)),
+            KvsServerReplyStatus::Unauthorized => Err(KvsError::Unauthorized),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    async fn send_and_fetch(&mut self, mut request: KvsCmdRequest) -> Result<KvsServerReply> {
+        request.id = Some(self.next_id);
+        self.next_id += 1;
+        self.stream.write_all(bson::to_vec(&request)?.as_slice()).await?;
+        self.stream.flush().await?;
+        let reply = match read_frame(&mut self.stream, i32::MAX as usize).await? {
+            Some(frame) => bson::from_slice::<KvsServerReply>(&frame)?,
+            None => return Err(KvsError::ServerError)
+        };
+        // Replies without id come from the servers predating the request ids
+        match reply.request_id {
+            Some(id) if Some(id) != request.id => Err(KvsError::ProtocolDesync),
+            _ => Ok(reply)
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::future::Future;
+use std::io;
+use super::{KvsEngine, Result};
+
+/// Asynchronous counterpart of `KvsEngine`
+///
+/// Every `KvsEngine` is an `AsyncKvsEngine`, its blocking disk I/O runs on the blocking thread pool of Tokio.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Set the value of a string key to a string
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+    /// Get the string value of a given string key
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// Remove a given key `key`
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+    /// Reclaim the space occupied by stale entries
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send;
+    /// Make all previous writes durable on the disk
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<E: KvsEngine + Clone + Sync> AsyncKvsEngine for E {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        let engine = self.clone();
+        blocking(move || engine.set(key, value)).await
+    }
+    
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        let engine = self.clone();
+        blocking(move || engine.get(key)).await
+    }
+    
+    async fn remove(&self, key: String) -> Result<()> {
+        let engine = self.clone();
+        blocking(move || engine.remove(key)).await
+    }
+    
+    async fn compact(&self) -> Result<()> {
+        let engine = self.clone();
+        blocking(move || engine.compact()).await
+    }
+    
+    async fn flush(&self) -> Result<()> {
+        let engine = self.clone();
+        blocking(move || KvsEngine::flush(&engine)).await
+    }
+}
+
+/// Run `task` on the blocking thread pool and wait for its result
+pub(super) async fn blocking<T: Send + 'static>(task: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(task).await.map_err(io::Error::other)?
+}
@@ -0,0 +1,146 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use super::Result;
+
+// Bloom filter over the keys of the store, a negative answer is always correct
+// The bits are atomic so keys can be added while the filter is being read
+#[derive(Debug)]
+pub(super) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    num_hashes: u32,
+    capacity: u64,
+    count: AtomicU64,
+    false_positive_rate: f64
+}
+
+// In-disk data format for the bloom filter file
+#[derive(Serialize, Deserialize, Debug)]
+struct BloomFilterFile {
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+    num_hashes: u32,
+    capacity: u64,
+    count: u64,
+    false_positive_rate: f64
+}
+
+impl BloomFilter {
+    const MIN_CAPACITY: u64 = 1024;
+    
+    /// Create an empty filter sized for `capacity` keys at the given false positive rate
+    pub(super) fn new(capacity: u64, false_positive_rate: f64) -> BloomFilter {
+        let capacity = capacity.max(BloomFilter::MIN_CAPACITY);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_words = num_bits.div_ceil(64).max(1);
+        let num_hashes = ((num_words * 64) as f64 / capacity as f64 * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+            num_hashes,
+            capacity,
+            count: AtomicU64::new(0),
+            false_positive_rate
+        }
+    }
+    
+    /// Create a filter holding `keys` with room for as many new keys
+    pub(super) fn build<'a>(keys: impl ExactSizeIterator<Item = &'a [u8]>, false_positive_rate: f64) -> BloomFilter {
+        let filter = BloomFilter::new(keys.len() as u64 * 2, false_positive_rate);
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+    
+    /// Add `key` to the filter
+    pub(super) fn insert(&self, key: &[u8]) {
+        for bit in self.probes(key) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Check if `key` may have been added, `false` means it was definitely not
+    pub(super) fn contains(&self, key: &[u8]) -> bool {
+        self.probes(key).all(|bit| self.bits[(bit / 64) as usize].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+    
+    /// Check if more keys were added than the filter was sized for
+    pub(super) fn is_full(&self) -> bool {
+        self.count.load(Ordering::Relaxed) > self.capacity
+    }
+    
+    /// Load the filter saved in `path`, returns `None` if missing, unreadable or created with another false positive rate
+    pub(super) fn load(path: &Path, false_positive_rate: f64) -> Option<BloomFilter> {
+        let file = bson::from_slice::<BloomFilterFile>(&fs::read(path).ok()?).ok()?;
+        if file.false_positive_rate != false_positive_rate || file.bits.is_empty() || file.bits.len() % 8 != 0 {
+            return None
+        }
+        Some(BloomFilter {
+            bits: file.bits.chunks_exact(8)
+                .map(|word| AtomicU64::new(u64::from_le_bytes(word.try_into().unwrap())))
+                .collect(),
+            num_hashes: file.num_hashes,
+            capacity: file.capacity,
+            count: AtomicU64::new(file.count),
+            false_positive_rate
+        })
+    }
+    
+    /// Save the filter into `path`
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let file = BloomFilterFile {
+            bits: self.bits.iter().flat_map(|word| word.load(Ordering::Relaxed).to_le_bytes()).collect(),
+            num_hashes: self.num_hashes,
+            capacity: self.capacity,
+            count: self.count.load(Ordering::Relaxed),
+            false_positive_rate: self.false_positive_rate
+        };
+        let mut handle = OpenOptions::new().write(true).truncate(true).create(true).open(path)?;
+        handle.write_all(bson::to_vec(&file)?.as_slice())?;
+        Ok(())
+    }
+    
+    /// Bit positions of `key` using double hashing
+    fn probes(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        // The hash must be stable across builds since the filter is persisted, so std hasher is not used
+        let h1 = BloomFilter::fnv1a(key);
+        let h2 = BloomFilter::mix(h1) | 1;
+        let num_bits = self.bits.len() as u64 * 64;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+    
+    fn fnv1a(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+    }
+    
+    // Finalizer of MurmurHash3
+    fn mix(mut hash: u64) -> u64 {
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+        hash ^ (hash >> 33)
+    }
+}
@@ -0,0 +1,78 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+
+// Bounded least-recently-used cache of values, the memory usage is measured by the summed length of the values
+// Each value is stored with the location of its entry, a value is only valid while the index still points there
+#[derive(Debug)]
+pub(super) struct LruCache<P> {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<Vec<u8>, (Vec<u8>, P, u64)>, // Value, location and last access tick
+    recency: BTreeMap<u64, Vec<u8>> // Keys ordered by last access
+}
+
+impl<P: Copy + PartialEq> LruCache<P> {
+    pub(super) fn new(capacity: u64) -> LruCache<P> {
+        LruCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new()
+        }
+    }
+    
+    /// Get the cached value of `key` if it was read from `pos`
+    pub(super) fn get(&mut self, key: &[u8], pos: P) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, pos_, last_access) = self.entries.get_mut(key)?;
+        if *pos_ != pos { return None }
+        let key = self.recency.remove(last_access).unwrap();
+        self.recency.insert(tick, key);
+        *last_access = tick;
+        Some(value.clone())
+    }
+    
+    /// Cache `value` of `key` read from `pos`, evicting the least recently used values if full
+    pub(super) fn insert(&mut self, key: Vec<u8>, pos: P, value: Vec<u8>) {
+        self.remove(&key);
+        // Value larger than the whole cache is never cached
+        if value.len() as u64 > self.capacity { return }
+        self.size += value.len() as u64;
+        while self.size > self.capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            let (value, _, _) = self.entries.remove(&oldest).unwrap();
+            self.size -= value.len() as u64;
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, pos, self.tick));
+    }
+    
+    /// Drop the cached value of `key`
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        if let Some((value, _, last_access)) = self.entries.remove(key) {
+            self.recency.remove(&last_access);
+            self.size -= value.len() as u64;
+        }
+    }
+}
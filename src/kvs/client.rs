@@ -16,27 +16,129 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
 use std::net::{SocketAddr, TcpStream};
-use super::{KvsError, KvsCmdRequest, KvsServerReply, KvsServerReplyStatus, Result};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{KvsError, KvsCmdRequest, KvsServerReply, KvsServerReplyStatus, Result, ServerInfo, ValueType};
+use rustls::{ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
 
 #[derive(Clone)]
 pub struct KvsClient {
-    addr: SocketAddr
+    endpoint: Endpoint,
+    config: ClientConfig,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    token: Option<String>, // Sent before every request once authenticated
+    namespace: Option<String>, // Selected before every request
+    next_id: Arc<AtomicU64> // Id of the next request, echoed in its reply
+}
+
+// Address of KvsServer
+#[derive(Clone, Debug)]
+enum Endpoint {
+    // Servers tried in turn, starting from the index of the one which accepted the last connection
+    Tcp(Vec<SocketAddr>, Arc<AtomicUsize>),
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+/// Connection settings of KvsClient
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Maximum time to wait for establishing the connection
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for sending the request or receiving the reply, `None` waits forever
+    pub request_timeout: Option<Duration>,
+    /// Number of reconnection attempts after the first connection failed
+    pub retries: u32,
+    /// Delay before the first reconnection, doubled after every failed attempt
+    pub backoff: Duration
+}
+
+// Plain or encrypted stream to the server
+trait Connection: Read + Write {}
+
+impl<S: Read + Write> Connection for S {}
+
+/// Time taken by a request, returned by the timed variants of the requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerTiming {
+    /// Time spent by the server executing the request, `None` if the server does not report it
+    pub server_latency: Option<Duration>,
+    /// Time from connecting to the server until the reply is received
+    pub round_trip: Duration
+}
+
+/// Command sent as part of a pipeline by `KvsClient::pipeline`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvsCommand {
+    Get(String),
+    Set(String, String),
+    Remove(String)
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Some(Duration::from_secs(30)),
+            retries: 3,
+            backoff: Duration::from_millis(100)
+        }
+    }
 }
 
 impl KvsClient {
+    // Size of the requests of a pipeline sent before reading their replies, well below the socket buffers
+    const PIPELINE_CHUNK_SIZE: usize = 64 << 10;
+    
     /// Get the string value of a given string key
     pub fn set(&self, key: String, value: String) -> Result<()> {
         let reply = self.send_and_fetch(KvsCmdRequest {
             cmd: "SET".to_owned(),
-            argument: vec![key.to_owned(), value]
+            argument: vec![key.to_owned(), value],
+            id: None
         })?;
         
         match reply.status {
             KvsServerReplyStatus::Success => Ok(()),
             KvsServerReplyStatus::KeyNotFound => Err(KvsError::KeyNotExist(key)),
-            _ => Err(KvsError::ServerError)
+            KvsServerReplyStatus::ValueTooLarge => Err(KvsClient::value_too_large(reply.result)),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Set the value of a string key only if the key does not exist, returns whether the value was set
+    pub fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.set_with_flag(key, value, "NX")
+    }
+    
+    /// Set the value of a string key only if the key already exists, returns whether the value was set
+    pub fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        self.set_with_flag(key, value, "XX")
+    }
+    
+    /// Send SET with the condition `flag`
+    fn set_with_flag(&self, key: String, value: String, flag: &str) -> Result<bool> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "SET".to_owned(),
+            argument: vec![key, value, flag.to_owned()],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(reply.result.is_some()),
+            KvsServerReplyStatus::ValueTooLarge => Err(KvsClient::value_too_large(reply.result)),
+            _ => Err(reply.into_error())
         }
     }
     
@@ -44,12 +146,65 @@ impl KvsClient {
     pub fn get(&self, key: String) -> Result<Option<String>> {
         let reply = self.send_and_fetch(KvsCmdRequest {
             cmd: "GET".to_owned(),
-            argument: vec![key]
+            argument: vec![key],
+            id: None
         })?;
         
         match reply.status {
             KvsServerReplyStatus::Success => Ok(reply.result),
-            _ => Err(KvsError::ServerError)
+            KvsServerReplyStatus::KeyNotFound => Ok(None),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Same as `get`, along with the time spent by the server and the whole round trip
+    pub fn get_timed(&self, key: String) -> Result<(Option<String>, ServerTiming)> {
+        let start = Instant::now();
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "GET".to_owned(),
+            argument: vec![key],
+            id: None
+        })?;
+        let timing = ServerTiming {
+            server_latency: reply.server_latency_us.map(Duration::from_micros),
+            round_trip: start.elapsed()
+        };
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok((reply.result, timing)),
+            KvsServerReplyStatus::KeyNotFound => Ok((None, timing)),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Write the value of `key` to `writer` as it is received, returns the number of bytes written or `None` if the
+    /// key does not exist
+    ///
+    /// The value is transferred in chunks, so large values are never held in memory as a whole by the client
+    pub fn get_to_writer(&self, key: String, writer: &mut impl Write) -> Result<Option<u64>> {
+        self.try_get_to_writer(key, writer).map_err(KvsError::into_connection_error)
+    }
+    
+    fn try_get_to_writer(&self, key: String, writer: &mut impl Write) -> Result<Option<u64>> {
+        let mut conn = self.open_connection()?;
+        self.start_session(&mut conn)?;
+        let reply = self.send_request(&mut conn, KvsCmdRequest {
+            cmd: "GETSTREAM".to_owned(),
+            argument: vec![key],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => {
+                let len = reply.result.unwrap_or_default().parse::<u64>().map_err(|_| KvsError::ServerError)?;
+                let copied = io::copy(&mut conn.take(len), writer)?;
+                // The server closed the connection before sending the whole value
+                if copied < len { return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()) }
+                Ok(Some(copied))
+            },
+            KvsServerReplyStatus::KeyNotFound => Ok(None),
+            KvsServerReplyStatus::Unauthorized => Err(KvsError::Unauthorized),
+            _ => Err(reply.into_error())
         }
     }
     
@@ -57,42 +212,509 @@ impl KvsClient {
     pub fn remove(&self, key: String) -> Result<()> {
         let reply = self.send_and_fetch(KvsCmdRequest {
             cmd: "REMOVE".to_owned(),
-            argument: vec![key.to_owned()]
+            argument: vec![key.to_owned()],
+            id: None
         })?;
         
         match reply.status {
             KvsServerReplyStatus::Success => Ok(()),
             KvsServerReplyStatus::KeyNotFound => Err(KvsError::KeyNotExist(key)),
-            _ => Err(KvsError::ServerError)
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Remove a given key `key` if it exists, returns whether the key was removed instead of failing for a missing key
+    pub fn remove_if_exists(&self, key: String) -> Result<bool> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "REMOVEIFEXISTS".to_owned(),
+            argument: vec![key],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(reply.result.is_some()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Append `value` to the value of `key`, a missing key starts from empty, returns the new length in bytes
+    pub fn append(&self, key: String, value: String) -> Result<usize> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "APPEND".to_owned(),
+            argument: vec![key, value],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => reply.result.unwrap_or_default().parse::<usize>().map_err(|_| KvsError::ServerError),
+            KvsServerReplyStatus::ValueTooLarge => Err(KvsClient::value_too_large(reply.result)),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Get the bytes from `start` to `end` inclusive of the value of `key`, only the substring is transferred
+    ///
+    /// Negative index counts from the end of the value, indices out of the value are clamped to it
+    pub fn get_range(&self, key: String, start: i64, end: i64) -> Result<Option<String>> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "GETRANGE".to_owned(),
+            argument: vec![key, start.to_string(), end.to_string()],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(Some(reply.result.unwrap_or_default())),
+            KvsServerReplyStatus::KeyNotFound => Ok(None),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Add `delta` to the integer value of `key`, a missing key starts from zero, returns the new value
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "INCR".to_owned(),
+            argument: vec![key, delta.to_string()],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => reply.result.unwrap_or_default().parse::<i64>().map_err(|_| KvsError::ServerError),
+            KvsServerReplyStatus::ValueTooLarge => Err(KvsClient::value_too_large(reply.result)),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Get the logical type of the value of a given string key
+    pub fn type_of(&self, key: String) -> Result<Option<ValueType>> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "TYPE".to_owned(),
+            argument: vec![key],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(Some(reply.result.unwrap_or_default().parse::<ValueType>()?)),
+            KvsServerReplyStatus::KeyNotFound => Ok(None),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Fetch up to `count` keys following `cursor` and the cursor of the next page
+    ///
+    /// Start with the cursor `0` and repeat with the returned cursor until it is `0` again.
+    pub fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "SCAN".to_owned(),
+            argument: vec![cursor.to_owned(), count.to_string()],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => {
+                serde_json::from_str(&reply.result.unwrap_or_default()).map_err(|_| KvsError::ServerError)
+            },
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Number of keys in the namespace of the client
+    pub fn len(&self) -> Result<usize> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "DBSIZE".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => reply.result.unwrap_or_default().parse::<usize>().map_err(|_| KvsError::ServerError),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Whether the namespace of the client holds no key
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    
+    /// Send all `commands` in one round trip and return their results in the same order
+    ///
+    /// `Get` results in the value, `Set` and `Remove` result in `None`. A failed command does not stop the others.
+    /// Large batches are sent in chunks, each waiting for the replies of the previous one.
+    pub fn pipeline(&self, commands: Vec<KvsCommand>) -> Result<Vec<Result<Option<String>>>> {
+        if commands.is_empty() { return Ok(Vec::new()) }
+        let requests = commands.iter().map(|command| match command {
+            KvsCommand::Get(key) => KvsCmdRequest { cmd: "GET".to_owned(), argument: vec![key.to_owned()], id: None },
+            KvsCommand::Set(key, value) => KvsCmdRequest { cmd: "SET".to_owned(), argument: vec![key.to_owned(), value.to_owned()], id: None },
+            KvsCommand::Remove(key) => KvsCmdRequest { cmd: "REMOVE".to_owned(), argument: vec![key.to_owned()], id: None }
+        }).collect();
+        let replies = self.send_batch(requests)?;
+        
+        Ok(commands.into_iter().zip(replies).map(|(command, reply)| match reply.status {
+            KvsServerReplyStatus::Success => match command {
+                KvsCommand::Get(_) => Ok(reply.result),
+                _ => Ok(None)
+            },
+            KvsServerReplyStatus::KeyNotFound => match command {
+                KvsCommand::Get(_) => Ok(None),
+                KvsCommand::Set(key, _) | KvsCommand::Remove(key) => Err(KvsError::KeyNotExist(key))
+            },
+            KvsServerReplyStatus::ValueTooLarge => Err(KvsClient::value_too_large(reply.result)),
+            _ => Err(reply.into_error())
+        }).collect())
+    }
+    
+    /// Rebuild the error from the size and the limit in the reply
+    fn value_too_large(result: Option<String>) -> KvsError {
+        let numbers = result.unwrap_or_default().split(' ').map(|number| number.parse::<u64>()).collect::<Vec<_>>();
+        match numbers.as_slice() {
+            [Ok(size), Ok(limit)] => KvsError::ValueTooLarge { size: *size, limit: *limit },
+            _ => KvsError::ServerError
+        }
+    }
+    
+    /// Check if the server is able to serve requests
+    pub fn ping(&self) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "PING".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Fetch the engine, version and uptime of the server
+    pub fn info(&self) -> Result<ServerInfo> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "INFO".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => {
+                serde_json::from_str(&reply.result.unwrap_or_default()).map_err(|_| KvsError::ServerError)
+            },
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Request the server to compact its database immediately
+    pub fn compact(&self) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "COMPACT".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Request the server to remove all keys of every namespace
+    pub fn clear(&self) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "FLUSH".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Request the server to copy a snapshot of its database into the directory `dir` on the server
+    ///
+    /// `dir` is relative to the backup directory of the server, absolute paths and `..` are rejected.
+    pub fn backup(&self, dir: String) -> Result<()> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "BACKUP".to_owned(),
+            argument: vec![dir],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(()),
+            _ => Err(reply.into_error())
+        }
+    }
+    
+    /// Fetch the server metrics in Prometheus text format
+    pub fn metrics(&self) -> Result<String> {
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "METRICS".to_owned(),
+            argument: Vec::new(),
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => Ok(reply.result.unwrap_or_default()),
+            _ => Err(reply.into_error())
         }
     }
     
     /// Establish connection to KvsServer
     pub fn open(addr: &str) -> Result<KvsClient> {
+        KvsClient::open_with_config(addr, ClientConfig::default())
+    }
+    
+    /// Establish connection to KvsServer with the given connection settings
+    pub fn open_with_config(addr: &str, config: ClientConfig) -> Result<KvsClient> {
+        KvsClient::open_many_with_config(&[addr], config)
+    }
+    
+    /// Establish connection to any of the KvsServer in `addrs`, failing over to the next one if it is not reachable
+    ///
+    /// Only the connection failures fail over, the requests are sent once to the server which accepted the
+    /// connection. The following connections start from that server.
+    pub fn open_many(addrs: &[&str]) -> Result<KvsClient> {
+        KvsClient::open_many_with_config(addrs, ClientConfig::default())
+    }
+    
+    /// Establish connection to any of the KvsServer in `addrs` with the given connection settings
+    pub fn open_many_with_config(addrs: &[&str], config: ClientConfig) -> Result<KvsClient> {
+        if addrs.is_empty() {
+            return Err(KvsError::InvalidArguments("No server address".to_owned()))
+        }
+        let addrs = addrs.iter().map(|addr| addr.parse()).collect::<std::result::Result<Vec<SocketAddr>, _>>()?;
         Ok(KvsClient {
-            addr: addr.parse()?
+            endpoint: Endpoint::Tcp(addrs, Arc::new(AtomicUsize::new(0))),
+            config,
+            tls: None,
+            token: None,
+            namespace: None,
+            next_id: Arc::new(AtomicU64::new(0))
         })
     }
     
+    /// Establish connection to KvsServer listening on the Unix domain socket at `path`
+    #[cfg(unix)]
+    pub fn open_uds(path: impl AsRef<Path>) -> Result<KvsClient> {
+        Ok(KvsClient {
+            endpoint: Endpoint::Unix(path.as_ref().to_owned()),
+            config: ClientConfig::default(),
+            tls: None,
+            token: None,
+            namespace: None,
+            next_id: Arc::new(AtomicU64::new(0))
+        })
+    }
+    
+    /// Establish TLS connection to KvsServer, trusting only the certificates in the PEM file `ca`
+    pub fn open_tls(addr: &str, ca: impl AsRef<Path>) -> Result<KvsClient> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?)) {
+            roots.add(cert?)?;
+        }
+        let tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        
+        let mut client = KvsClient::open(addr)?;
+        client.tls = Some(Arc::new(tls));
+        Ok(client)
+    }
+    
+    /// Client whose requests only see the keys of namespace `name`, the empty name selects the default namespace
+    pub fn with_namespace(&self, name: &str) -> KvsClient {
+        let mut client = self.clone();
+        client.namespace = Some(name.to_owned());
+        client
+    }
+    
+    /// Authenticate with the server using `token`, which is then sent automatically on every connection
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.token = None;
+        let reply = self.send_and_fetch(KvsCmdRequest {
+            cmd: "AUTH".to_owned(),
+            argument: vec![token.to_owned()],
+            id: None
+        })?;
+        
+        match reply.status {
+            KvsServerReplyStatus::Success => {
+                self.token = Some(token.to_owned());
+                Ok(())
+            },
+            _ => Err(reply.into_error())
+        }
+    }
+    
     pub fn send_terminate_signal(&mut self) -> Result<()> {
         let reply = self.send_and_fetch(KvsCmdRequest {
             cmd: "KILL".to_owned(),
-            argument: Vec::new()
+            argument: Vec::new(),
+            id: None
         })?;
         
         match reply.status {
             KvsServerReplyStatus::Success => Ok(()),
-            _ => Err(KvsError::ServerError)
+            _ => Err(reply.into_error())
         }
     }
     
     fn send_and_fetch(&self, request: KvsCmdRequest) -> Result<KvsServerReply> {
+        Ok(self.send_batch(vec![request])?.pop().unwrap())
+    }
+    
+    /// Send the requests over a single connection and wait for all their replies
+    fn send_batch(&self, requests: Vec<KvsCmdRequest>) -> Result<Vec<KvsServerReply>> {
+        self.try_send_batch(requests).map_err(KvsError::into_connection_error)
+    }
+    
+    fn try_send_batch(&self, requests: Vec<KvsCmdRequest>) -> Result<Vec<KvsServerReply>> {
+        let conn = self.open_connection()?;
+        self.exchange(conn, requests)
+    }
+    
+    /// Connect to the server over the plain or encrypted stream
+    fn open_connection(&self) -> Result<Box<dyn Connection>> {
+        match &self.endpoint {
+            Endpoint::Tcp(addrs, preferred) => {
+                let (conn, addr) = self.connect(|| self.connect_any(addrs, preferred))?;
+                conn.set_read_timeout(self.config.request_timeout)?;
+                conn.set_write_timeout(self.config.request_timeout)?;
+                match &self.tls {
+                    Some(config) => {
+                        let server_name = ServerName::IpAddress(addr.ip().into());
+                        Ok(Box::new(StreamOwned::new(ClientConnection::new(config.clone(), server_name)?, conn)))
+                    },
+                    None => Ok(Box::new(conn))
+                }
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let conn = self.connect(|| Ok(UnixStream::connect(path)?))?;
+                conn.set_read_timeout(self.config.request_timeout)?;
+                conn.set_write_timeout(self.config.request_timeout)?;
+                Ok(Box::new(conn))
+            }
+        }
+    }
+    
+    /// Send the requests and wait for the replies over the plain or encrypted stream
+    fn exchange<S: Read + Write>(&self, mut conn: S, mut requests: Vec<KvsCmdRequest>) -> Result<Vec<KvsServerReply>> {
+        self.start_session(&mut conn)?;
+        // Requests are sent in chunks, the server may reply to them while the rest of the chunk is being sent, so the
+        // replies of a chunk are read before the next chunk fills up the socket buffers
+        let mut replies = Vec::with_capacity(requests.len());
+        let mut start = 0;
+        while start < requests.len() {
+            let mut batch = Vec::new();
+            let mut end = start;
+            while end < requests.len() && batch.len() < KvsClient::PIPELINE_CHUNK_SIZE {
+                requests[end].id = Some(self.next_id.fetch_add(1, Ordering::Relaxed));
+                batch.extend_from_slice(bson::to_vec(&requests[end])?.as_slice());
+                end += 1;
+            }
+            conn.write_all(batch.as_slice())?;
+            conn.flush()?;
+            for request in requests[start..end].iter() {
+                let reply = bson::from_reader::<_, KvsServerReply>(&mut conn)?;
+                if let KvsServerReplyStatus::Unauthorized = reply.status { return Err(KvsError::Unauthorized) }
+                KvsClient::check_reply(request, &reply)?;
+                replies.push(reply);
+            }
+            start = end;
+        }
+        Ok(replies)
+    }
+    
+    /// Authenticate and select the namespace on a new connection
+    fn start_session<S: Read + Write>(&self, conn: &mut S) -> Result<()> {
+        if let Some(token) = &self.token {
+            let reply = self.send_request(conn, KvsCmdRequest {
+                cmd: "AUTH".to_owned(),
+                argument: vec![token.to_owned()],
+                id: None
+            })?;
+            // The server closes the connection after rejecting the token
+            if let KvsServerReplyStatus::Unauthorized = reply.status { return Err(KvsError::Unauthorized) }
+        }
+        if let Some(namespace) = &self.namespace {
+            let reply = self.send_request(conn, KvsCmdRequest {
+                cmd: "NAMESPACE".to_owned(),
+                argument: vec![namespace.to_owned()],
+                id: None
+            })?;
+            if !matches!(reply.status, KvsServerReplyStatus::Success) { return Err(reply.into_error()) }
+        }
+        Ok(())
+    }
+    
+    fn send_request<S: Read + Write>(&self, conn: &mut S, mut request: KvsCmdRequest) -> Result<KvsServerReply> {
+        request.id = Some(self.next_id.fetch_add(1, Ordering::Relaxed));
         // Send request
-        let mut conn = TcpStream::connect(self.addr)?;
         conn.write_all(bson::to_vec(&request)?.as_slice())?;
-        let mut buf = [0; 1024];
+        conn.flush()?;
         // Wait for server reply
-        let len = conn.read(&mut buf)?;
-        Ok(bson::from_slice::<KvsServerReply>(&buf[..len])?)
+        let reply = bson::from_reader::<_, KvsServerReply>(conn)?;
+        KvsClient::check_reply(&request, &reply)?;
+        Ok(reply)
+    }
+    
+    /// Fail if the reply carries the id of another request, which means the replies on the connection are out of step
+    ///
+    /// Replies without id are accepted, they are sent by the servers predating the request ids and for the requests
+    /// rejected before being read, such as when the server is busy.
+    fn check_reply(request: &KvsCmdRequest, reply: &KvsServerReply) -> Result<()> {
+        match reply.request_id {
+            Some(id) if Some(id) != request.id => Err(KvsError::ProtocolDesync),
+            _ => Ok(())
+        }
+    }
+    
+    /// Connect to the server, retrying with exponential backoff if the server is not available yet
+    fn connect<C>(&self, connect: impl Fn() -> Result<C>) -> Result<C> {
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            match connect().map_err(KvsError::into_connection_error) {
+                Ok(conn) => return Ok(conn),
+                Err(err) if attempt < self.config.retries && KvsClient::is_transient(&err) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                Err(err) => return Err(err)
+            }
+        }
+    }
+    
+    /// Connect to the first reachable server in `addrs`, starting from the `preferred` one
+    ///
+    /// Servers refusing or timing out the connection are skipped, other failures are returned immediately
+    fn connect_any(&self, addrs: &[SocketAddr], preferred: &AtomicUsize) -> Result<(TcpStream, SocketAddr)> {
+        let first = preferred.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for index in (first..addrs.len()).chain(0..first) {
+            match TcpStream::connect_timeout(&addrs[index], self.config.connect_timeout).map_err(|err| KvsError::from(err).into_connection_error()) {
+                Ok(conn) => {
+                    // Stay on the standby once failed over, instead of waiting for the dead server every time
+                    preferred.store(index, Ordering::Relaxed);
+                    return Ok((conn, addrs[index]))
+                },
+                Err(err) if KvsClient::is_transient(&err) => last_err = Some(err),
+                Err(err) => return Err(err)
+            }
+        }
+        Err(last_err.unwrap())
+    }
+    
+    /// Connection errors which may disappear by trying again later
+    fn is_transient(err: &KvsError) -> bool {
+        match err {
+            KvsError::ConnectionRefused | KvsError::ConnectionReset | KvsError::Timeout => true,
+            KvsError::IOError(err) => err.kind() == io::ErrorKind::Interrupted,
+            _ => false
+        }
     }
 }
@@ -0,0 +1,127 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::Read;
+use std::str::FromStr;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use super::{KvsError, Result};
+
+/// Serialization format of the entries in the segment files
+///
+/// Every record starts with its total length as a 4-byte little-endian integer, as BSON documents do,
+/// so records can be skipped and copied without being decoded.
+pub trait Codec {
+    /// Serialize `value` into a single record
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    /// Deserialize the record at the current position of `reader`, consuming exactly the record
+    fn decode<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T>;
+}
+
+/// BSON documents, the format of databases created before the codec was selectable
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BsonCodec;
+
+impl Codec for BsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bson::to_vec(value)?)
+    }
+    
+    fn decode<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T> {
+        Ok(bson::from_reader(reader)?)
+    }
+}
+
+/// Bincode prefixed with the record length, without field names and type tags
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let len = bincode::serialized_size(value)? + 4;
+        let len = i32::try_from(len).map_err(|_| KvsError::InvalidDataEntry)?;
+        let mut buf = Vec::with_capacity(len as usize);
+        buf.extend_from_slice(&len.to_le_bytes());
+        bincode::serialize_into(&mut buf, value)?;
+        Ok(buf)
+    }
+    
+    fn decode<T: DeserializeOwned, R: Read>(&self, mut reader: R) -> Result<T> {
+        let mut len_bytes = [0; 4];
+        reader.read_exact(&mut len_bytes)?;
+        let len = i32::from_le_bytes(len_bytes);
+        if len < 4 { return Err(KvsError::InvalidDataEntry) }
+        let mut buf = vec![0; len as usize - 4];
+        reader.read_exact(&mut buf)?;
+        Ok(bincode::deserialize(&buf)?)
+    }
+}
+
+/// Codec selected for a database, its id is saved in the database header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecKind {
+    Bson,
+    Bincode
+}
+
+impl CodecKind {
+    /// Id saved in the database header
+    pub(super) fn id(&self) -> u8 {
+        match self {
+            CodecKind::Bson => 0,
+            CodecKind::Bincode => 1
+        }
+    }
+    
+    /// Codec of the database header holding `id`
+    pub(super) fn from_id(id: u8) -> Result<CodecKind> {
+        match id {
+            0 => Ok(CodecKind::Bson),
+            1 => Ok(CodecKind::Bincode),
+            _ => Err(KvsError::InvalidDatabaseFormat)
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Bson => BsonCodec.encode(value),
+            CodecKind::Bincode => BincodeCodec.encode(value)
+        }
+    }
+    
+    fn decode<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T> {
+        match self {
+            CodecKind::Bson => BsonCodec.decode(reader),
+            CodecKind::Bincode => BincodeCodec.decode(reader)
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = KvsError;
+    
+    fn from_str(name: &str) -> Result<CodecKind> {
+        match name {
+            "bson" => Ok(CodecKind::Bson),
+            "bincode" => Ok(CodecKind::Bincode),
+            _ => Err(KvsError::InvalidArguments(format!("Unknown codec {}", name)))
+        }
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::{Path, PathBuf};
+use super::{KvsEngine, KvsError, KvStore, KvStoreOptions, Result, SledKvsEngine};
+
+/// Open the database in `path` with the engine named `engine_type`, either `kvs` or `sled`
+pub fn open_engine(engine_type: &str, path: impl Into<PathBuf>) -> Result<Box<dyn KvsEngine + Sync>> {
+    open_engine_with_options(engine_type, path, KvStoreOptions::default())
+}
+
+/// Open the database in `path` with the engine named `engine_type`, only `utf8` of `options` applies to the `sled` engine
+pub fn open_engine_with_options(engine_type: &str, path: impl Into<PathBuf>,
+                                options: KvStoreOptions) -> Result<Box<dyn KvsEngine + Sync>> {
+    let path = path.into();
+    check_engine(engine_type, &path)?;
+    match engine_type.to_lowercase().as_ref() {
+        "kvs" => Ok(Box::new(KvStore::open_with_options(path, options)?)),
+        "sled" => Ok(Box::new(SledKvsEngine::open_with_options(path, options)?)),
+        _ => Err(KvsError::UnsupportedEngine)
+    }
+}
+
+/// Engine of the database previously created in `path`, `None` if there is no database
+pub fn detect_engine(path: &Path) -> Option<&'static str> {
+    // kvs: {name}.db and {name}.dir, named kvs by default
+    // sled: db, config and blob directory
+    if path.join(format!("{}.db", KvStore::DEFAULT_NAME)).exists() {
+        Some("kvs")
+    } else if path.join("db").exists() {
+        Some("sled")
+    } else {
+        None
+    }
+}
+
+/// Fail with `KvsError::ConflictingEngine` if `path` holds a database of an engine other than `engine_type`
+pub(super) fn check_engine(engine_type: &str, path: &Path) -> Result<()> {
+    let requested = engine_type.to_lowercase();
+    match detect_engine(path) {
+        Some(found) if found != requested => Err(KvsError::ConflictingEngine { found: found.to_owned(), requested }),
+        _ => Ok(())
+    }
+}
+
+/// Execute the data command `cmd` on `store`, shared by the server and the embedded `kvs` binary
+///
+/// Supported commands are GET, SET, RM, REMOVE and DELETE. Returns the value for GET and `None` otherwise.
+pub fn dispatch(store: &dyn KvsEngine, cmd: &str, argument: &[String]) -> Result<Option<String>> {
+    let expected = match cmd {
+        "GET" | "RM" | "REMOVE" | "DELETE" => 1,
+        "SET" => 2,
+        _ => return Err(KvsError::UnknownCommand(cmd.to_owned()))
+    };
+    if argument.len() != expected {
+        return Err(KvsError::WrongArgumentCount {
+            command: cmd.to_owned(),
+            expected: expected..=expected,
+            provided: argument.len()
+        })
+    }
+    
+    match cmd {
+        "GET" => store.get(argument[0].to_owned()),
+        "SET" => store.set(argument[0].to_owned(), argument[1].to_owned()).map(|_| None),
+        _ => store.remove(argument[0].to_owned()).map(|_| None)
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use super::{KvsEngine, KvsError, Result};
+
+const DUMP_FORMAT: &str = "kvs-dump";
+const DUMP_VERSION: u32 = 1;
+
+// Engine independent dump file records, each record is a BSON document prefixed with its length
+// A dump is a Header, followed by any number of Pair, and terminated by End
+#[derive(Serialize, Deserialize, Debug)]
+enum DumpRecord {
+    Header { format: String, version: u32 },
+    Pair { key: String, value: String },
+    End { count: u64 }
+}
+
+/// Write all `pairs` into `writer` in dump format
+pub(super) fn export<W: Write>(pairs: impl Iterator<Item = Result<(String, String)>>, mut writer: W) -> Result<()> {
+    let header = DumpRecord::Header { format: DUMP_FORMAT.to_owned(), version: DUMP_VERSION };
+    writer.write_all(bson::to_vec(&header)?.as_slice())?;
+    let mut count = 0;
+    for pair in pairs {
+        let (key, value) = pair?;
+        writer.write_all(bson::to_vec(&DumpRecord::Pair { key, value })?.as_slice())?;
+        count += 1;
+    }
+    writer.write_all(bson::to_vec(&DumpRecord::End { count })?.as_slice())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Load all pairs from `reader` in dump format into `store`, returns the number of imported pairs
+pub(super) fn import<R: Read>(store: &dyn KvsEngine, mut reader: R) -> Result<usize> {
+    match bson::from_reader::<_, DumpRecord>(&mut reader) {
+        Ok(DumpRecord::Header { format, version }) if format == DUMP_FORMAT && version == DUMP_VERSION => {},
+        _ => return Err(KvsError::InvalidDatabaseFormat)
+    }
+    let mut count = 0;
+    loop {
+        match bson::from_reader::<_, DumpRecord>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)? {
+            DumpRecord::Pair { key, value } => {
+                store.set(key, value)?;
+                count += 1;
+            },
+            // Truncated dump is detected by the missing or mismatched trailer
+            DumpRecord::End { count: expected } if expected == count as u64 => {
+                // Imported pairs are made durable at once
+                store.flush()?;
+                return Ok(count)
+            },
+            _ => return Err(KvsError::InvalidDatabaseFormat)
+        }
+    }
+}
@@ -16,8 +16,11 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::PathBuf;
-use super::Result;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use super::{CompactionInfo, KvsError, Result};
 use dyn_clone::DynClone;
 
 pub trait KvsEngine: DynClone + Send + 'static {
@@ -25,10 +28,267 @@ pub trait KvsEngine: DynClone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
     /// Get the string value of a given string key
     fn get(&self, key: String) -> Result<Option<String>>;
+    /// Set the value of a string key only if the key does not exist, returns whether the value was set
+    fn set_nx(&self, key: String, value: String) -> Result<bool>;
+    /// Set the value of a string key only if the key already exists, returns whether the value was set
+    fn set_xx(&self, key: String, value: String) -> Result<bool>;
     /// Remove a given key `key`
     fn remove(&self, key: String) -> Result<()>;
+    /// Remove a given key `key` if it exists, returns whether the key was removed
+    fn remove_optional(&self, key: String) -> Result<bool>;
+    /// Remove all keys starting with `prefix`, returns the number of removed keys
+    fn remove_prefix(&self, prefix: &str) -> Result<usize>;
+    /// Add `delta` to the integer value of `key`, a missing key starts from zero, returns the new value
+    ///
+    /// Engines without type tags accept any value holding a decimal integer
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get(key.clone())? {
+            Some(value) => value.parse::<i64>().map_err(|_| KvsError::WrongType)?,
+            None => 0
+        };
+        let value = current.checked_add(delta).ok_or_else(|| KvsError::InvalidArguments("Increment would overflow".to_owned()))?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+    /// Append `value` to the value of `key`, a missing key starts from empty, returns the new length in bytes
+    ///
+    /// Concurrent appends to the same key are applied one after another, so none of them is lost
+    fn append(&self, key: String, value: String) -> Result<usize>;
+    /// Get the bytes from `start` to `end` inclusive of the value of a given string key
+    ///
+    /// Negative index counts from the end of the value, indices out of the value are clamped to it. Range cutting a
+    /// multi-byte character decodes it with the replacement character.
+    fn get_range(&self, key: String, start: i64, end: i64) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.into_bytes())?.map(|value| {
+            String::from_utf8_lossy(&value[byte_range(value.len(), start, end)]).into_owned()
+        }))
+    }
+    /// Get the logical type of the value of a given string key
+    ///
+    /// Engines without type tags report every value as `ValueType::String`
+    fn type_of(&self, key: String) -> Result<Option<ValueType>> {
+        Ok(self.get_bytes(key.into_bytes())?.map(|_| ValueType::String))
+    }
+    /// Return up to `count` keys following `cursor` in ascending byte order, and the cursor of the next page
+    ///
+    /// The first page is requested with the cursor `0`, which is also returned once the last key is reached.
+    /// Every key present during the whole iteration is returned exactly once.
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)>;
+    /// Number of keys in the namespace
+    fn len(&self) -> Result<usize>;
+    /// Whether the namespace holds no key
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Set the value of a binary key to a binary value
+    ///
+    /// Engines storing strings only reject key or value which is not valid UTF-8
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set(String::from_utf8(key)?, String::from_utf8(value)?)
+    }
+    /// Get the binary value of a given binary key
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(String::from_utf8(key)?)?.map(String::into_bytes))
+    }
+    /// Reader of the binary value of a given binary key, along with its length
+    ///
+    /// Engines unable to read a value in pieces hold the whole value in memory until the reader is dropped
+    fn get_reader(&self, key: Vec<u8>) -> Result<Option<ValueReader>> {
+        Ok(self.get_bytes(key)?.map(|value| ValueReader::new(value.len() as u64, io::Cursor::new(value))))
+    }
+    /// Reclaim the space occupied by stale entries
+    fn compact(&self) -> Result<()>;
+    /// Number of compactions completed since opened, engines reclaiming the space by themselves report none
+    fn compactions(&self) -> u64 {
+        0
+    }
+    /// Sizes deciding the automatic compaction, engines reclaiming the space by themselves report none
+    fn compaction_info(&self) -> Option<CompactionInfo> {
+        None
+    }
+    /// Make all previous writes durable on the disk
+    fn flush(&self) -> Result<()>;
+    /// Remove all keys of every namespace
+    fn clear(&self) -> Result<()>;
+    /// Copy the current content into a new database in the directory `dest` without blocking writes
+    fn backup(&self, dest: &Path) -> Result<()>;
+    /// Handle of the same database whose operations only see the keys of namespace `name`
+    ///
+    /// The empty name refers to the default namespace holding the keys stored without namespace
+    fn namespace(&self, name: &str) -> Result<Box<dyn KvsEngine + Sync>>;
     /// Create or open KvStore instance
     fn open(path: impl Into<PathBuf>) -> Result<Self> where Self: Sized;
 }
 
 dyn_clone::clone_trait_object!(KvsEngine);
+
+/// Handling of values which are not valid UTF-8 when read through the string API
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Mode {
+    /// Fail with `KvsError::InvalidUtf8`, so corrupt values are never hidden
+    #[default]
+    Strict,
+    /// Replace invalid sequences with U+FFFD
+    Lossy
+}
+
+impl Utf8Mode {
+    /// Convert the stored bytes into a string according to the mode
+    pub(super) fn decode(&self, value: Vec<u8>) -> Result<String> {
+        match self {
+            Utf8Mode::Strict => Ok(String::from_utf8(value)?),
+            Utf8Mode::Lossy => Ok(String::from_utf8(value).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned()))
+        }
+    }
+}
+
+/// Binary value of known length read in pieces, returned by `KvsEngine::get_reader`
+pub struct ValueReader {
+    len: u64,
+    reader: Box<dyn Read + Send>
+}
+
+impl ValueReader {
+    pub(super) fn new(len: u64, reader: impl Read + Send + 'static) -> ValueReader {
+        ValueReader { len, reader: Box::new(reader.take(len)) }
+    }
+    
+    /// Length of the value in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    
+    /// Whether the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl fmt::Debug for ValueReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueReader").field("len", &self.len).finish_non_exhaustive()
+    }
+}
+
+/// Keys accepted when a value is written, reading and removing accept any key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyValidation {
+    /// Accept every key
+    #[default]
+    Permissive,
+    /// Reject the empty key
+    NonEmpty,
+    /// Reject the empty key and keys containing control characters such as NUL or newline
+    Strict
+}
+
+impl KeyValidation {
+    /// Fail with `KvsError::InvalidKey` if `key` is not accepted by the mode
+    pub(super) fn check(&self, key: &[u8]) -> Result<()> {
+        if *self == KeyValidation::Permissive { return Ok(()) }
+        if key.is_empty() {
+            return Err(KvsError::InvalidKey("Key must not be empty".to_owned()))
+        }
+        if *self == KeyValidation::Strict && String::from_utf8_lossy(key).chars().any(char::is_control) {
+            return Err(KvsError::InvalidKey("Key must not contain control characters".to_owned()))
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyValidation {
+    type Err = KvsError;
+    
+    fn from_str(name: &str) -> Result<KeyValidation> {
+        match name {
+            "permissive" => Ok(KeyValidation::Permissive),
+            "non-empty" => Ok(KeyValidation::NonEmpty),
+            "strict" => Ok(KeyValidation::Strict),
+            _ => Err(KvsError::InvalidArguments(format!("Unknown key validation {}", name)))
+        }
+    }
+}
+
+/// Logical type of a stored value, values set by `set` are strings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    /// Decimal integer maintained by `incr`
+    Int,
+    List
+}
+
+impl ValueType {
+    /// Tag saved along with the value
+    pub(super) fn id(&self) -> u8 {
+        match self {
+            ValueType::String => 0,
+            ValueType::Int => 1,
+            ValueType::List => 2
+        }
+    }
+    
+    /// Type of the value saved with the tag `id`
+    pub(super) fn from_id(id: u8) -> Result<ValueType> {
+        match id {
+            0 => Ok(ValueType::String),
+            1 => Ok(ValueType::Int),
+            2 => Ok(ValueType::List),
+            _ => Err(KvsError::InvalidDataEntry)
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueType::String => "string",
+            ValueType::Int => "int",
+            ValueType::List => "list"
+        })
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = KvsError;
+    
+    fn from_str(name: &str) -> Result<ValueType> {
+        match name {
+            "string" => Ok(ValueType::String),
+            "int" => Ok(ValueType::Int),
+            "list" => Ok(ValueType::List),
+            _ => Err(KvsError::InvalidArguments(format!("Unknown value type {}", name)))
+        }
+    }
+}
+
+/// Bytes of a value of `len` bytes selected by the inclusive indices of `KvsEngine::get_range`
+fn byte_range(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+    let clamp = |index: i64| if index < 0 { (len as i64 + index).max(0) } else { index.min(len as i64) };
+    let (start, end) = (clamp(start), clamp(end).saturating_add(1).min(len as i64));
+    if start >= end { return 0..0 }
+    start as usize..end as usize
+}
+
+/// Cursor of `KvsEngine::scan` continuing after `key`, the key in hex so it never equals the initial cursor `0`
+pub(super) fn encode_cursor(key: &[u8]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Key after which the scan continues, `None` for the initial cursor
+pub(super) fn decode_cursor(cursor: &str, count: usize) -> Result<Option<Vec<u8>>> {
+    if count == 0 { return Err(KvsError::InvalidArguments("`SCAN` count must be positive".to_owned())) }
+    if cursor == "0" { return Ok(None) }
+    let invalid = || KvsError::InvalidArguments(format!("Invalid cursor {}", cursor));
+    if cursor.is_empty() || !cursor.len().is_multiple_of(2) { return Err(invalid()) }
+    (0..cursor.len()).step_by(2)
+        .map(|i| cursor.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()).ok_or_else(invalid))
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
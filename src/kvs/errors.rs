@@ -16,6 +16,8 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::ops::RangeInclusive;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 pub type Result<T> = std::result::Result<T, KvsError>;
 
@@ -26,26 +28,196 @@ pub enum KvsError {
     IOError(#[from] std::io::Error),
     #[error(r#"Key "{0}" does not exist"#)]
     KeyNotExist(String),
+    #[error("Size {size} exceeds the limit {limit}")]
+    ValueTooLarge { size: u64, limit: u64 },
     #[error("Invalid data entry")]
     InvalidDataEntry,
+    #[error("Database is opened read-only")]
+    ReadOnly,
+    #[error("Database is already opened for writing, {} is locked", .0.display())]
+    AlreadyLocked(std::path::PathBuf),
+    #[error("Operation against a value of the wrong type")]
+    WrongType,
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
     #[error(transparent)]
     SerializationError(#[from] bson::ser::Error),
     #[error(transparent)]
     DeserializationError(#[from] bson::de::Error),
+    #[error(transparent)]
+    BincodeError(#[from] bincode::Error),
     #[error("Unsupported engine type")]
     UnsupportedEngine,
+    #[error("Found database of the {found} engine, requested {requested}")]
+    ConflictingEngine { found: String, requested: String },
     #[error("Invalid database file format")]
     InvalidDatabaseFormat,
+    #[error("Database files do not match the checksum saved on last exit")]
+    FileChecksumMismatch,
     #[error("Found incompatible database version {0}, current version {1}")]
     IncompatibleDatabaseVersion(u64, u64),
     #[error(transparent)]
     SystemTimeError(#[from] std::time::SystemTimeError),
     #[error("Unknown protocol")]
     UnknownProtocol,
+    #[error("Unknown command {0}")]
+    UnknownCommand(String),
+    #[error("{0}")]
+    InvalidArguments(String),
+    #[error("{0}")]
+    InvalidKey(String),
+    #[error("`{command}` command required {} argument, provided {provided}", argument_count(.expected))]
+    WrongArgumentCount { command: String, expected: RangeInclusive<usize>, provided: usize },
     #[error("Server internal error")]
     ServerError,
+    #[error("Server is busy")]
+    ServerBusy,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Connection refused by the server")]
+    ConnectionRefused,
+    #[error("Connection closed by the server before the reply")]
+    ConnectionReset,
+    #[error("Timed out waiting for the server")]
+    Timeout,
+    #[error("Reply of the server does not match the request")]
+    ProtocolDesync,
+    #[error("Unable to listen on {addr}: {source}")]
+    BindFailed { addr: String, source: std::io::Error },
     #[error(transparent)]
     InvalidAddress(#[from] std::net::AddrParseError),
     #[error(transparent)]
-    SledError(#[from] sled::Error)
+    SledError(#[from] sled::Error),
+    #[error(transparent)]
+    ThreadPoolError(#[from] rayon::ThreadPoolBuildError),
+    #[error(transparent)]
+    TlsError(#[from] rustls::Error),
+    #[error("Invalid certificate or private key")]
+    InvalidCertificate
+}
+
+impl KvsError {
+    /// Name of the variant, used to send the error over the protocol
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KvsError::IOError(_) => "IOError",
+            KvsError::KeyNotExist(_) => "KeyNotExist",
+            KvsError::ValueTooLarge { .. } => "ValueTooLarge",
+            KvsError::InvalidDataEntry => "InvalidDataEntry",
+            KvsError::ReadOnly => "ReadOnly",
+            KvsError::AlreadyLocked(_) => "AlreadyLocked",
+            KvsError::WrongType => "WrongType",
+            KvsError::InvalidUtf8(_) => "InvalidUtf8",
+            KvsError::SerializationError(_) => "SerializationError",
+            KvsError::DeserializationError(_) => "DeserializationError",
+            KvsError::BincodeError(_) => "BincodeError",
+            KvsError::UnsupportedEngine => "UnsupportedEngine",
+            KvsError::ConflictingEngine { .. } => "ConflictingEngine",
+            KvsError::InvalidDatabaseFormat => "InvalidDatabaseFormat",
+            KvsError::FileChecksumMismatch => "FileChecksumMismatch",
+            KvsError::IncompatibleDatabaseVersion(_, _) => "IncompatibleDatabaseVersion",
+            KvsError::SystemTimeError(_) => "SystemTimeError",
+            KvsError::UnknownProtocol => "UnknownProtocol",
+            KvsError::UnknownCommand(_) => "UnknownCommand",
+            KvsError::InvalidArguments(_) => "InvalidArguments",
+            KvsError::InvalidKey(_) => "InvalidKey",
+            KvsError::WrongArgumentCount { .. } => "WrongArgumentCount",
+            KvsError::ServerError => "ServerError",
+            KvsError::ServerBusy => "ServerBusy",
+            KvsError::Unauthorized => "Unauthorized",
+            KvsError::ConnectionRefused => "ConnectionRefused",
+            KvsError::ConnectionReset => "ConnectionReset",
+            KvsError::Timeout => "Timeout",
+            KvsError::ProtocolDesync => "ProtocolDesync",
+            KvsError::BindFailed { .. } => "BindFailed",
+            KvsError::InvalidAddress(_) => "InvalidAddress",
+            KvsError::SledError(_) => "SledError",
+            KvsError::ThreadPoolError(_) => "ThreadPoolError",
+            KvsError::TlsError(_) => "TlsError",
+            KvsError::InvalidCertificate => "InvalidCertificate"
+        }
+    }
+    
+    /// Message sent along with the kind of the error, the fields of `WrongArgumentCount` are sent as JSON
+    pub(super) fn to_remote(&self) -> String {
+        match self {
+            KvsError::WrongArgumentCount { command, expected, provided } => serde_json::to_string(&ArgumentCount {
+                command: command.clone(),
+                min_expected: *expected.start(),
+                max_expected: *expected.end(),
+                provided: *provided
+            }).unwrap(),
+            _ => self.to_string()
+        }
+    }
+    
+    /// Failure of listening on `addr`, which is not known if the address resolved to nothing
+    pub(super) fn bind_failed(addr: Option<std::net::SocketAddr>, source: std::io::Error) -> KvsError {
+        let addr = addr.map_or_else(|| "unresolved address".to_owned(), |addr| addr.to_string());
+        KvsError::BindFailed { addr, source }
+    }
+    
+    /// Tell the socket failures of a client connection apart, other errors are returned as is
+    pub(super) fn into_connection_error(self) -> KvsError {
+        let kind = match &self {
+            KvsError::IOError(err) => err.kind(),
+            // Replies are read by the decoder, which wraps the failure of the socket
+            KvsError::DeserializationError(bson::de::Error::Io(err)) => err.kind(),
+            _ => return self
+        };
+        match kind {
+            std::io::ErrorKind::ConnectionRefused => KvsError::ConnectionRefused,
+            std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof => KvsError::ConnectionReset,
+            // Read timeout is reported as WouldBlock on Unix
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => KvsError::Timeout,
+            _ => self
+        }
+    }
+    
+    /// Rebuild the error of `kind` received from the server, `ServerError` if it cannot be rebuilt on the client
+    pub(super) fn from_remote(kind: &str, message: String) -> KvsError {
+        match kind {
+            "WrongArgumentCount" => match serde_json::from_str::<ArgumentCount>(&message) {
+                Ok(fields) => KvsError::WrongArgumentCount {
+                    command: fields.command,
+                    expected: fields.min_expected..=fields.max_expected,
+                    provided: fields.provided
+                },
+                Err(_) => KvsError::ServerError
+            },
+            "IOError" => KvsError::IOError(std::io::Error::other(message)),
+            "InvalidDataEntry" => KvsError::InvalidDataEntry,
+            "ReadOnly" => KvsError::ReadOnly,
+            "WrongType" => KvsError::WrongType,
+            "UnsupportedEngine" => KvsError::UnsupportedEngine,
+            "InvalidDatabaseFormat" => KvsError::InvalidDatabaseFormat,
+            "FileChecksumMismatch" => KvsError::FileChecksumMismatch,
+            "UnknownProtocol" => KvsError::UnknownProtocol,
+            "InvalidArguments" => KvsError::InvalidArguments(message),
+            "InvalidKey" => KvsError::InvalidKey(message),
+            "Unauthorized" => KvsError::Unauthorized,
+            "ServerBusy" => KvsError::ServerBusy,
+            "InvalidCertificate" => KvsError::InvalidCertificate,
+            _ => KvsError::ServerError
+        }
+    }
+}
+
+// Fields of `WrongArgumentCount` in the reply, so clients can render their own message
+#[derive(Serialize, Deserialize)]
+struct ArgumentCount {
+    command: String,
+    min_expected: usize,
+    max_expected: usize,
+    provided: usize
+}
+
+// Accepted number of arguments in the message of `WrongArgumentCount`
+fn argument_count(expected: &RangeInclusive<usize>) -> String {
+    match expected.end() - expected.start() {
+        0 => expected.start().to_string(),
+        1 => format!("{} or {}", expected.start(), expected.end()),
+        _ => format!("{} to {}", expected.start(), expected.end())
+    }
 }
@@ -0,0 +1,111 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use super::{KvsClient, KvsEngine, Result};
+
+/// Operations available both over the network with `KvsClient` and in process with `KvsHandle`
+pub trait KvsApi {
+    /// Get the string value of a given string key
+    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Set the value of a string key to a string
+    fn set(&self, key: String, value: String) -> Result<()>;
+    /// Remove a given key `key`, fails with `KvsError::KeyNotExist` if the key does not exist
+    fn remove(&self, key: String) -> Result<()>;
+    /// Fetch up to `count` keys following `cursor` and the cursor of the next page, starting with the cursor `0`
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)>;
+}
+
+/// In-process access to a kvs engine with the same methods as `KvsClient`, without a server in between
+#[derive(Clone)]
+pub struct KvsHandle {
+    engine: Box<dyn KvsEngine + Sync>
+}
+
+impl KvsHandle {
+    /// Wrap `engine`, clones of the handle share the same database
+    pub fn new(engine: impl KvsEngine + Sync) -> KvsHandle {
+        KvsHandle::from_boxed(Box::new(engine))
+    }
+    
+    /// Wrap the engine returned by `open_engine`
+    pub fn from_boxed(engine: Box<dyn KvsEngine + Sync>) -> KvsHandle {
+        KvsHandle { engine }
+    }
+    
+    /// The wrapped engine, for the operations not offered by the handle
+    pub fn engine(&self) -> &(dyn KvsEngine + Sync) {
+        self.engine.as_ref()
+    }
+    
+    /// Get the string value of a given string key
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+    
+    /// Set the value of a string key to a string
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(key, value)
+    }
+    
+    /// Remove a given key `key`
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+    
+    /// Fetch up to `count` keys following `cursor` and the cursor of the next page, starting with the cursor `0`
+    pub fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        self.engine.scan(cursor, count)
+    }
+}
+
+impl KvsApi for KvsHandle {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsHandle::get(self, key)
+    }
+    
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsHandle::set(self, key, value)
+    }
+    
+    fn remove(&self, key: String) -> Result<()> {
+        KvsHandle::remove(self, key)
+    }
+    
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        KvsHandle::scan(self, cursor, count)
+    }
+}
+
+impl KvsApi for KvsClient {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+    
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+    
+    fn remove(&self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+    
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        KvsClient::scan(self, cursor, count)
+    }
+}
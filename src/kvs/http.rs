@@ -0,0 +1,127 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{BufRead, Write};
+use serde_json::{json, Value};
+use super::{KvsEngine, KvsError, Result};
+
+// Parsed HTTP/1.1 request
+#[derive(Debug)]
+pub(super) struct HttpRequest {
+    pub(super) method: String,
+    pub(super) path: String,
+    pub(super) authorization: Option<String>,
+    pub(super) body: Vec<u8>
+}
+
+// Reject request body larger than 16 MiB
+const MAX_BODY_SIZE: usize = 16 << 20;
+
+/// Read a request with optional body given by `Content-Length`, returns `None` if the connection is closed
+pub(super) fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 { return Ok(None) }
+    // Request line: <method> <path> HTTP/1.x
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => (method.to_owned(), path.to_owned()),
+        _ => return Err(KvsError::UnknownProtocol)
+    };
+    
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 { return Err(KvsError::UnknownProtocol) }
+        let header = line.trim_end();
+        if header.is_empty() { break; }
+        let (name, value) = header.split_once(':').ok_or(KvsError::UnknownProtocol)?;
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse().map_err(|_| KvsError::UnknownProtocol)?,
+            "authorization" => authorization = Some(value.trim().to_owned()),
+            // Chunked body is not supported
+            "transfer-encoding" => return Err(KvsError::UnknownProtocol),
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_SIZE { return Err(KvsError::UnknownProtocol) }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(HttpRequest { method, path, authorization, body }))
+}
+
+/// Execute the request on `store`, returning the status code and the JSON body
+/// Supported endpoint: GET, PUT and DELETE on /kv/{key}
+pub(super) fn execute(store: &dyn KvsEngine, request: HttpRequest) -> (u16, Value) {
+    let key = match request.path.strip_prefix("/kv/").map(percent_decode) {
+        Some(Some(key)) if !key.is_empty() => key,
+        Some(_) => return (400, json!({ "error": "Invalid key" })),
+        None => return (404, json!({ "error": "Not found" }))
+    };
+    let result = match request.method.as_str() {
+        "GET" => store.get(key.clone()).map(|value| match value {
+            Some(value) => (200, json!({ "key": key, "value": value })),
+            None => (404, json!({ "error": "Key not found" }))
+        }),
+        "PUT" => match String::from_utf8(request.body) {
+            Ok(value) => store.set(key.clone(), value).map(|_| (200, json!({ "key": key }))),
+            Err(_) => Ok((400, json!({ "error": "Value is not valid UTF-8" })))
+        },
+        "DELETE" => match store.remove(key.clone()) {
+            Err(KvsError::KeyNotExist(_)) => Ok((404, json!({ "error": "Key not found" }))),
+            result => result.map(|_| (200, json!({ "key": key })))
+        },
+        _ => Ok((405, json!({ "error": "Method not allowed" })))
+    };
+    result.unwrap_or_else(|err| (500, json!({ "error": err.to_string() })))
+}
+
+/// Write the response with JSON body, the connection is closed afterward
+pub(super) fn write_response<W: Write>(writer: &mut W, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error"
+    };
+    let body = body.to_string();
+    write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, reason, body.len(), body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decode %XX escapes in the path segment, returns `None` if the result is not valid UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,427 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::cmp;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use memmap2::MmapMut;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Number of records of the spill file between two records whose offset is kept in memory
+const FENCE_INTERVAL: usize = 64;
+
+// Distinguish the spill files of the indexes of the process, such as a read-only and a writable open of a database
+static NEXT_SPILL_ID: AtomicU64 = AtomicU64::new(0);
+
+// Index of the keys keeping the recently used keys in memory up to a limit, the other keys are spilled to a file sorted
+// by key, which is searched from the offset of every `FENCE_INTERVAL`-th record
+// The memory usage is estimated from the length of the keys plus the size of an entry of the hash table
+#[derive(Debug)]
+pub(super) struct BoundedIndex<P> {
+    limit: Option<u64>, // Estimated memory usage before the least recently used keys are spilled, unbounded if None
+    size: u64, // Estimated memory usage of the keys in memory and of the removed keys
+    len: usize, // Number of live keys, either in memory or in the spill file
+    tick: AtomicU64,
+    entries: HashMap<Vec<u8>, (P, AtomicU64)>, // Position and last access tick, shadowing the spill file
+    removed: HashSet<Vec<u8>>, // Keys of the spill file removed since it was written
+    spill_path: PathBuf,
+    spill: Option<Spill>
+}
+
+// Records of the spill file are the length of the key as u32, the key and the value encoded with bincode,
+// which has the same length for all values
+#[derive(Debug)]
+struct Spill {
+    map: MmapMut,
+    fences: Vec<usize>, // Offset of every `FENCE_INTERVAL`-th record
+    value_len: usize
+}
+
+impl Spill {
+    /// Key of the record at `offset` and the offset of its value
+    fn record(&self, offset: usize) -> (&[u8], usize) {
+        let key_len = u32::from_le_bytes(self.map[offset..offset + 4].try_into().unwrap()) as usize;
+        (&self.map[offset + 4..offset + 4 + key_len], offset + 4 + key_len)
+    }
+    
+    /// Offset of the value of `key` if it has a record
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        // Records from the last fence not after the key until the next fence
+        let fence = self.fences.partition_point(|offset| self.record(*offset).0 <= key).checked_sub(1)?;
+        let end = self.fences.get(fence + 1).map_or(self.map.len(), |offset| *offset);
+        let mut offset = self.fences[fence];
+        while offset < end {
+            let (key_, value) = self.record(offset);
+            match key_.cmp(key) {
+                cmp::Ordering::Less => offset = value + self.value_len,
+                cmp::Ordering::Equal => return Some(value),
+                cmp::Ordering::Greater => return None
+            }
+        }
+        None
+    }
+    
+    /// Value at `offset`, records are written by this process, so they always decode
+    fn value<P: DeserializeOwned>(&self, offset: usize) -> Option<P> {
+        bincode::deserialize(&self.map[offset..offset + self.value_len]).ok()
+    }
+}
+
+impl<P: Copy + PartialEq + Serialize + DeserializeOwned> BoundedIndex<P> {
+    /// Index holding `entries`, spilling the keys beyond `limit` to a file next to `base_path`
+    pub(super) fn new(entries: HashMap<Vec<u8>, P>, limit: Option<u64>, base_path: &Path) -> io::Result<BoundedIndex<P>> {
+        let spill_id = NEXT_SPILL_ID.fetch_add(1, Ordering::Relaxed);
+        let mut index = BoundedIndex {
+            limit,
+            size: entries.keys().map(|key| BoundedIndex::<P>::cost(key)).sum(),
+            len: entries.len(),
+            tick: AtomicU64::new(0),
+            entries: entries.into_iter().map(|(key, pos)| (key, (pos, AtomicU64::new(0)))).collect(),
+            removed: HashSet::new(),
+            spill_path: base_path.with_extension(format!("{}.{}.spill", process::id(), spill_id)),
+            spill: None
+        };
+        index.evict()?;
+        Ok(index)
+    }
+    
+    /// Estimated memory usage of `key` in memory
+    fn cost(key: &[u8]) -> u64 {
+        (key.len() + mem::size_of::<(Vec<u8>, (P, AtomicU64))>()) as u64
+    }
+    
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    
+    /// Number of live keys
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+    
+    /// Number of live keys only found in the spill file
+    pub(super) fn spilled(&self) -> usize {
+        self.len - self.entries.len()
+    }
+    
+    /// Whether some keys may only be found in the spill file
+    pub(super) fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+    
+    /// Position of `key` if it is in memory, the spill file is not read
+    pub(super) fn get_in_memory(&self, key: &[u8]) -> Option<P> {
+        let (pos, last_access) = self.entries.get(key)?;
+        last_access.store(self.next_tick(), Ordering::Relaxed);
+        Some(*pos)
+    }
+    
+    /// Position of `key`, read from the spill file if it is not in memory
+    pub(super) fn get(&self, key: &[u8]) -> Option<P> {
+        if !self.entries.contains_key(key) {
+            return self.get_spilled(key)
+        }
+        self.get_in_memory(key)
+    }
+    
+    fn get_spilled(&self, key: &[u8]) -> Option<P> {
+        if self.removed.contains(key) { return None }
+        let spill = self.spill.as_ref()?;
+        spill.value(spill.find(key)?)
+    }
+    
+    pub(super) fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+    
+    /// Position of `key`, which is moved back to memory if it is only in the spill file
+    ///
+    /// The memory usage may exceed the limit until `evict` is called.
+    pub(super) fn promote(&mut self, key: &[u8]) -> Option<P> {
+        if let Some(pos) = self.get_in_memory(key) {
+            return Some(pos)
+        }
+        let pos = self.get_spilled(key)?;
+        self.size += BoundedIndex::<P>::cost(key);
+        self.entries.insert(key.to_vec(), (pos, AtomicU64::new(self.next_tick())));
+        Some(pos)
+    }
+    
+    /// Set the position of `key` in memory, the memory usage may exceed the limit until `evict` is called
+    pub(super) fn insert(&mut self, key: Vec<u8>, pos: P) {
+        let tick = self.next_tick();
+        if let Some((pos_, last_access)) = self.entries.get_mut(&key) {
+            *pos_ = pos;
+            *last_access.get_mut() = tick;
+            return
+        }
+        let is_new = if self.removed.remove(&key) {
+            self.size -= BoundedIndex::<P>::cost(&key);
+            true
+        } else {
+            self.get_spilled(&key).is_none()
+        };
+        if is_new {
+            self.len += 1;
+        }
+        self.size += BoundedIndex::<P>::cost(&key);
+        self.entries.insert(key, (pos, AtomicU64::new(tick)));
+    }
+    
+    /// Remove `key`, a key of the spill file is remembered as removed until the file is written again
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        let in_memory = self.entries.remove(key).is_some();
+        if in_memory {
+            self.size -= BoundedIndex::<P>::cost(key);
+        }
+        if self.get_spilled(key).is_some() {
+            self.removed.insert(key.to_vec());
+            self.size += BoundedIndex::<P>::cost(key);
+            self.len -= 1;
+        } else if in_memory {
+            self.len -= 1;
+        }
+    }
+    
+    /// Replace the position of `key` by `pos` if it is still `current`, the spill file is updated in place
+    pub(super) fn replace(&mut self, key: &[u8], current: P, pos: P) {
+        if let Some((pos_, _)) = self.entries.get_mut(key) {
+            if *pos_ == current {
+                *pos_ = pos;
+            }
+            return
+        }
+        if self.get_spilled(key) != Some(current) { return }
+        let spill = self.spill.as_mut().unwrap();
+        let offset = spill.find(key).unwrap();
+        if let Ok(value) = bincode::serialize(&pos) {
+            spill.map[offset..offset + spill.value_len].copy_from_slice(&value);
+        }
+    }
+    
+    /// Remove all keys
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.removed.clear();
+        self.spill = None;
+        self.size = 0;
+        self.len = 0;
+        let _ = fs::remove_file(&self.spill_path);
+    }
+    
+    /// Iterate over the keys in memory, then the other keys of the spill file in order
+    pub(super) fn iter(&self) -> Iter<'_, P> {
+        Iter {
+            index: self,
+            entries: self.entries.iter(),
+            offset: 0,
+            remaining: self.len
+        }
+    }
+    
+    pub(super) fn keys(&self) -> impl ExactSizeIterator<Item = &[u8]> {
+        self.iter().map(|(key, _)| key)
+    }
+    
+    pub(super) fn values(&self) -> impl ExactSizeIterator<Item = P> + '_ {
+        self.iter().map(|(_, pos)| pos)
+    }
+    
+    /// Spill the least recently used keys once the estimated memory usage exceeds the limit
+    ///
+    /// Keys are spilled until at most half of the limit is used, so the spill file is not written again on every
+    /// new key.
+    pub(super) fn evict(&mut self) -> io::Result<()> {
+        let limit = match self.limit {
+            Some(limit) if self.size > limit => limit,
+            _ => return Ok(())
+        };
+        // Removed keys are dropped from the spill file written next
+        let mut size = self.size - self.removed.iter().map(|key| BoundedIndex::<P>::cost(key)).sum::<u64>();
+        let mut by_access = self.entries.iter()
+            .map(|(key, (_, last_access))| (last_access.load(Ordering::Relaxed), key))
+            .collect::<Vec<_>>();
+        by_access.sort_unstable();
+        let mut evicted = Vec::new();
+        for (_, key) in by_access.into_iter() {
+            if size <= limit / 2 { break }
+            size -= BoundedIndex::<P>::cost(key);
+            evicted.push(key.clone());
+        }
+        let mut evicted = evicted.into_iter()
+            .map(|key| {
+                let (pos, _) = self.entries.remove(&key).unwrap();
+                (key, pos)
+            })
+            .collect::<Vec<_>>();
+        evicted.sort_unstable_by(|(key1, _), (key2, _)| key1.cmp(key2));
+        self.write_spill(evicted)?;
+        self.size = size;
+        Ok(())
+    }
+    
+    /// Write the spill file again with the sorted `evicted` keys, dropping the removed keys and the keys in memory
+    fn write_spill(&mut self, evicted: Vec<(Vec<u8>, P)>) -> io::Result<()> {
+        let value_len = match (&self.spill, evicted.first()) {
+            (Some(spill), _) => spill.value_len,
+            (None, Some((_, pos))) => bincode::serialized_size(pos).map_err(io::Error::other)? as usize,
+            (None, None) => return Ok(())
+        };
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&self.spill_path)?;
+        let mut writer = SpillWriter { writer: BufWriter::new(&file), fences: Vec::new(), offset: 0, count: 0 };
+        let mut evicted = evicted.into_iter().peekable();
+        if let Some(spill) = &self.spill {
+            let mut offset = 0;
+            while offset < spill.map.len() {
+                let (key, value) = spill.record(offset);
+                offset = value + value_len;
+                while let Some((key_, pos)) = evicted.next_if(|(key_, _)| key_.as_slice() < key) {
+                    writer.push(&key_, &bincode::serialize(&pos).map_err(io::Error::other)?)?;
+                }
+                // The evicted position is newer than the record
+                if evicted.peek().is_some_and(|(key_, _)| key_ == key) { continue }
+                if self.entries.contains_key(key) || self.removed.contains(key) { continue }
+                writer.push(key, &spill.map[value..value + value_len])?;
+            }
+        }
+        for (key, pos) in evicted {
+            writer.push(&key, &bincode::serialize(&pos).map_err(io::Error::other)?)?;
+        }
+        writer.writer.flush()?;
+        let SpillWriter { writer, fences, count, .. } = writer;
+        drop(writer);
+        self.removed.clear();
+        self.spill = if count > 0 {
+            // Safety: the file is private to the index and only modified through the map
+            let map = unsafe { MmapMut::map_mut(&file)? };
+            Some(Spill { map, fences, value_len })
+        } else { None };
+        // The mapping stays valid after the file is removed, so nothing is left behind by a crash
+        let _ = fs::remove_file(&self.spill_path);
+        Ok(())
+    }
+}
+
+impl<P> Drop for BoundedIndex<P> {
+    fn drop(&mut self) {
+        // Platforms which cannot remove a mapped file keep it until the index is dropped
+        if self.spill.is_some() && self.spill_path.exists() {
+            let _ = fs::remove_file(&self.spill_path);
+        }
+    }
+}
+
+// Writer of the records of a new spill file, remembering the offset of every `FENCE_INTERVAL`-th record
+struct SpillWriter<W: Write> {
+    writer: W,
+    fences: Vec<usize>,
+    offset: usize,
+    count: usize
+}
+
+impl<W: Write> SpillWriter<W> {
+    fn push(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if self.count.is_multiple_of(FENCE_INTERVAL) {
+            self.fences.push(self.offset);
+        }
+        self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+        self.writer.write_all(key)?;
+        self.writer.write_all(value)?;
+        self.offset += 4 + key.len() + value.len();
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Iterator over the keys and positions of `BoundedIndex`
+pub(super) struct Iter<'a, P> {
+    index: &'a BoundedIndex<P>,
+    entries: hash_map::Iter<'a, Vec<u8>, (P, AtomicU64)>,
+    offset: usize, // Next record of the spill file
+    remaining: usize
+}
+
+impl<'a, P: DeserializeOwned + Copy> Iterator for Iter<'a, P> {
+    type Item = (&'a [u8], P);
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((key, (pos, _))) = self.entries.next() {
+            self.remaining = self.remaining.saturating_sub(1);
+            return Some((key, *pos))
+        }
+        let spill = self.index.spill.as_ref()?;
+        while self.offset < spill.map.len() {
+            let (key, value) = spill.record(self.offset);
+            self.offset = value + spill.value_len;
+            // Shadowed by the position in memory or removed
+            if self.index.entries.contains_key(key) || self.index.removed.contains(key) { continue }
+            if let Some(pos) = spill.value(value) {
+                self.remaining = self.remaining.saturating_sub(1);
+                return Some((key, pos))
+            }
+        }
+        None
+    }
+    
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<P: DeserializeOwned + Copy> ExactSizeIterator for Iter<'_, P> {}
+
+// Maps from keys to the positions of their entries, updated by `KvStore::apply_entry`
+pub(super) trait PositionMap<P> {
+    fn position(&self, key: &[u8]) -> Option<P>;
+    fn set_position(&mut self, key: Vec<u8>, pos: P);
+    fn remove_position(&mut self, key: &[u8]);
+}
+
+impl<P: Copy> PositionMap<P> for HashMap<Vec<u8>, P> {
+    fn position(&self, key: &[u8]) -> Option<P> {
+        self.get(key).cloned()
+    }
+    
+    fn set_position(&mut self, key: Vec<u8>, pos: P) {
+        self.insert(key, pos);
+    }
+    
+    fn remove_position(&mut self, key: &[u8]) {
+        self.remove(key);
+    }
+}
+
+impl<P: Copy + PartialEq + Serialize + DeserializeOwned> PositionMap<P> for BoundedIndex<P> {
+    fn position(&self, key: &[u8]) -> Option<P> {
+        self.get(key)
+    }
+    
+    fn set_position(&mut self, key: Vec<u8>, pos: P) {
+        self.insert(key, pos);
+    }
+    
+    fn remove_position(&mut self, key: &[u8]) {
+        self.remove(key);
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use super::KvsServerReplyStatus;
+
+// Upper bounds of the latency histogram buckets in microsecond
+const LATENCY_BUCKETS: [u64; 8] = [100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 1_000_000];
+
+/// Request counters and latency histogram of KvsServer
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    sets: AtomicU64,
+    gets: AtomicU64,
+    removes: AtomicU64,
+    errors: AtomicU64, // Requests not replied with success
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum: AtomicU64, // in microsecond
+    latency_count: AtomicU64,
+    requests: Mutex<BTreeMap<String, u64>> // Requests of every command, including the unknown ones
+}
+
+impl Metrics {
+    /// Record a completed request, only GET, SET and REMOVE are exported
+    pub(super) fn record(&self, cmd: &str, status: &KvsServerReplyStatus, latency: Duration) {
+        *self.requests.lock().unwrap().entry(cmd.to_owned()).or_default() += 1;
+        let counter = match cmd {
+            "GET" => &self.gets,
+            "SET" => &self.sets,
+            "RM" | "REMOVE" | "DELETE" => &self.removes,
+            _ => return
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        // Missing key is a regular result of GET
+        let is_miss = cmd == "GET" && matches!(status, KvsServerReplyStatus::KeyNotFound);
+        if !matches!(status, KvsServerReplyStatus::Success) && !is_miss {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        
+        let latency = latency.as_micros() as u64;
+        // Buckets are cumulative as required by the exposition format
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if latency <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_sum.fetch_add(latency, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Number of requests recorded so far by command
+    pub(super) fn requests(&self) -> BTreeMap<String, u64> {
+        self.requests.lock().unwrap().clone()
+    }
+    
+    /// Render all metrics in Prometheus text exposition format
+    pub(super) fn render(&self) -> String {
+        let mut output = String::new();
+        output.push_str("# HELP kvs_requests_total Number of requests by command.\n");
+        output.push_str("# TYPE kvs_requests_total counter\n");
+        for (cmd, counter) in [("get", &self.gets), ("set", &self.sets), ("remove", &self.removes)] {
+            writeln!(output, "kvs_requests_total{{cmd=\"{}\"}} {}", cmd, counter.load(Ordering::Relaxed)).unwrap();
+        }
+        output.push_str("# HELP kvs_errors_total Number of requests not replied with success.\n");
+        output.push_str("# TYPE kvs_errors_total counter\n");
+        writeln!(output, "kvs_errors_total {}", self.errors.load(Ordering::Relaxed)).unwrap();
+        
+        output.push_str("# HELP kvs_request_duration_seconds Latency of requests.\n");
+        output.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            writeln!(output, "kvs_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                     bound as f64 / 1e6, bucket.load(Ordering::Relaxed)).unwrap();
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        writeln!(output, "kvs_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).unwrap();
+        writeln!(output, "kvs_request_duration_seconds_sum {}", self.latency_sum.load(Ordering::Relaxed) as f64 / 1e6).unwrap();
+        writeln!(output, "kvs_request_duration_seconds_count {}", count).unwrap();
+        output
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use super::{KvsError, KvStore, Result};
+use super::store::KvHeader;
+
+// In-disk data format for database file header of build 1001
+#[derive(Deserialize, Debug)]
+struct KvHeaderV1001 {
+    last_open: u64
+}
+
+// In-disk data format for database file entries of build 1001
+// Removal is recorded as an entry without value
+#[derive(Deserialize, Debug)]
+struct KvsEntriesV1001 {
+    key: String,
+    value: Option<String>
+}
+
+// In-disk data format for database file entries of build 1200 and 1300, keys and values are strings
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug)]
+enum KvsEntriesV1300 {
+    SET(String, String),
+    DELETE(String)
+}
+
+// In-disk data format for segment file entries of build 1400, values are never compressed
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug)]
+enum KvsEntriesV1400 {
+    SET(#[serde(with = "serde_bytes")] Vec<u8>, #[serde(with = "serde_bytes")] Vec<u8>),
+    DELETE(#[serde(with = "serde_bytes")] Vec<u8>)
+}
+
+/// Upgrade the database file to the current build, step by step, if it was created by an older build
+pub(super) fn upgrade(db_path: &Path) -> Result<()> {
+    if !db_path.exists() || db_path.metadata()?.len() == 0 { return Ok(()) }
+    let mut build_number = read_build_number(db_path)?;
+    while build_number != KvStore::BUILD_NUMBER {
+        build_number = match build_number {
+            1001 => migrate_from_1001(db_path)?,
+            1200 => migrate_from_1200(db_path)?,
+            1300 => migrate_from_1300(db_path)?,
+            1400 => migrate_from_1400(db_path)?,
+            1500 => migrate_from_1500(db_path)?,
+            found => return Err(KvsError::IncompatibleDatabaseVersion(found, KvStore::BUILD_NUMBER))
+        };
+    }
+    Ok(())
+}
+
+/// Read the build number from the database file header without assuming its layout
+fn read_build_number(db_path: &Path) -> Result<u64> {
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let header = Document::from_reader(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    match header.get("build_number") {
+        Some(Bson::Int64(build_number)) => Ok(*build_number as u64),
+        Some(Bson::Int32(build_number)) => Ok(*build_number as u64),
+        _ => Err(KvsError::InvalidDatabaseFormat)
+    }
+}
+
+/// Rewrite entries of build 1001 into the layout of build 1200
+fn migrate_from_1001(db_path: &Path) -> Result<u64> {
+    const TARGET_BUILD: u64 = 1200;
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let old_header = bson::from_reader::<_, KvHeaderV1001>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    
+    let tmp_path = db_path.with_extension("db.tmp");
+    let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+    // Clear is_last_graceful_exit bit so the index is rebuilt with the new offsets
+    let header = KvHeader {
+        build_number: TARGET_BUILD,
+        last_open: old_header.last_open,
+        next_compaction_size: KvStore::MIN_COMPACTION_THRESHOLD,
+        dead_bytes: 0,
+        total_written: 0,
+        generation: 0,
+        codec: 0,
+        checksum: None,
+        flags: 0x1
+    };
+    writer.write_all(bson::to_vec(&header)?.as_slice())?;
+    while let Ok(old_entry) = bson::from_reader::<_, KvsEntriesV1001>(&mut reader) {
+        let entry = match old_entry.value {
+            Some(value) => KvsEntriesV1300::SET(old_entry.key, value),
+            None => KvsEntriesV1300::DELETE(old_entry.key)
+        };
+        writer.write_all(bson::to_vec(&entry)?.as_slice())?;
+    }
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    drop(reader);
+    
+    fs::rename(&tmp_path, db_path)?;
+    Ok(TARGET_BUILD)
+}
+
+/// Move entries of build 1200 into the first segment file, leaving only the header in the database file
+fn migrate_from_1200(db_path: &Path) -> Result<u64> {
+    const TARGET_BUILD: u64 = 1300;
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let mut header = bson::from_reader::<_, KvHeader>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    
+    let tmp_path = db_path.with_extension("db.tmp");
+    let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+    io::copy(&mut reader, &mut writer)?;
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    drop(reader);
+    fs::rename(&tmp_path, KvStore::segment_path(db_path, 0))?;
+    
+    // Clear is_last_graceful_exit bit so the index is rebuilt in the new layout
+    header.build_number = TARGET_BUILD;
+    header.flags = 0x1;
+    let mut writer = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    KvStore::write_header(&header, &mut writer)?;
+    writer.sync_all()?;
+    fs::rename(&tmp_path, db_path)?;
+    Ok(TARGET_BUILD)
+}
+
+/// Rewrite string entries of build 1300 into binary entries, segment by segment
+fn migrate_from_1300(db_path: &Path) -> Result<u64> {
+    const TARGET_BUILD: u64 = 1400;
+    let tmp_path = db_path.with_extension("db.tmp");
+    let mut buf = Vec::new();
+    for segment in KvStore::list_segments(db_path)? {
+        let segment_path = KvStore::segment_path(db_path, segment);
+        let segment_size = segment_path.metadata()?.len();
+        let mut reader = BufReader::new(OpenOptions::new().read(true).open(&segment_path)?);
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+        let mut offset = 0;
+        while offset < segment_size {
+            KvStore::read_raw_entry(&mut reader, &mut buf)?;
+            offset += buf.len() as u64;
+            // Segments already converted by an interrupted migration are kept as is
+            let entry = match bson::from_slice::<KvsEntriesV1300>(&buf) {
+                Ok(KvsEntriesV1300::SET(key, value)) => KvsEntriesV1400::SET(key.into_bytes(), value.into_bytes()),
+                Ok(KvsEntriesV1300::DELETE(key)) => KvsEntriesV1400::DELETE(key.into_bytes()),
+                Err(_) => bson::from_slice::<KvsEntriesV1400>(&buf).map_err(|_| KvsError::InvalidDatabaseFormat)?
+            };
+            writer.write_all(bson::to_vec(&entry)?.as_slice())?;
+        }
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        drop(reader);
+        fs::rename(&tmp_path, segment_path)?;
+    }
+    
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let mut header = bson::from_reader::<_, KvHeader>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    drop(reader);
+    // Clear is_last_graceful_exit bit so the index is rebuilt with the new offsets
+    header.build_number = TARGET_BUILD;
+    header.flags = 0x1;
+    let mut writer = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    KvStore::write_header(&header, &mut writer)?;
+    writer.sync_all()?;
+    fs::rename(&tmp_path, db_path)?;
+    Ok(TARGET_BUILD)
+}
+
+/// Entries of build 1400 are read as uncompressed entries, only the build number in the header is updated
+fn migrate_from_1400(db_path: &Path) -> Result<u64> {
+    const TARGET_BUILD: u64 = 1500;
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let mut header = bson::from_reader::<_, KvHeader>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    drop(reader);
+    
+    let tmp_path = db_path.with_extension("db.tmp");
+    header.build_number = TARGET_BUILD;
+    let mut writer = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    KvStore::write_header(&header, &mut writer)?;
+    writer.sync_all()?;
+    fs::rename(&tmp_path, db_path)?;
+    Ok(TARGET_BUILD)
+}
+
+/// Headers of build 1500 have no written counter, all data in the segment files is counted as written since the last
+/// compaction
+fn migrate_from_1500(db_path: &Path) -> Result<u64> {
+    const TARGET_BUILD: u64 = 1600;
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(db_path)?);
+    let mut header = bson::from_reader::<_, KvHeader>(&mut reader).map_err(|_| KvsError::InvalidDatabaseFormat)?;
+    drop(reader);
+    
+    let mut total_size = 0;
+    for segment in KvStore::list_segments(db_path)? {
+        total_size += KvStore::segment_path(db_path, segment).metadata()?.len();
+    }
+    let tmp_path = db_path.with_extension("db.tmp");
+    header.build_number = TARGET_BUILD;
+    header.total_written = total_size;
+    let mut writer = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    KvStore::write_header(&header, &mut writer)?;
+    writer.sync_all()?;
+    fs::rename(&tmp_path, db_path)?;
+    Ok(TARGET_BUILD)
+}
@@ -22,15 +22,35 @@ mod server;
 mod client;
 mod sled;
 mod errors;
+mod migration;
+mod resp;
+mod http;
+mod metrics;
+mod dump;
+mod cache;
+mod bloom;
+mod index;
+mod async_engine;
+mod async_client;
+mod command;
+mod codec;
+mod acl;
+mod handle;
 
 // Public export symbol
 pub mod util;
-pub use self::store::KvStore;
-pub use self::engine::KvsEngine;
-pub use self::server::KvsServer;
-pub use self::client::KvsClient;
+pub use self::store::{CompactionInfo, CompactionPolicy, CompactionProgress, Compression, IndexMode, KvStore, KvStoreBuilder, KvStoreIter, KvStoreMetrics, KvStoreOptions, RepairReport, ValidationIssue, ValidationReport};
+pub use self::engine::{KeyValidation, KvsEngine, Utf8Mode, ValueReader, ValueType};
+pub use self::command::{detect_engine, dispatch, open_engine, open_engine_with_options};
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::codec::{BincodeCodec, BsonCodec, Codec, CodecKind};
+pub use self::acl::IpNetwork;
+pub use self::server::{Acceptor, ConnectionLimitPolicy, KvsServer, KvsServerOptions, Peer, ServerInfo, ServerStats};
+pub use self::client::{ClientConfig, KvsClient, KvsCommand, ServerTiming};
+pub use self::async_client::AsyncKvsClient;
+pub use self::handle::{KvsApi, KvsHandle};
 pub use self::errors::{KvsError, Result};
-pub use self::sled::SledKvsEngine;
+pub use self::sled::{SledKvsEngine, SledKvsIter};
 
 // Internal use
 use self::server::{KvsCmdRequest, KvsServerReply, KvsServerReplyStatus};
@@ -0,0 +1,144 @@
+/*
+ * This file is part of kvs.
+ * Copyright (c) 2022-2023 Joe Ma <rikkaneko23@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{BufRead, Read, Write};
+use super::{KvsEngine, KvsError, Result};
+
+// Reply value of RESP2 protocol
+#[derive(Debug, PartialEq)]
+pub(super) enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Option<Vec<u8>>)
+}
+
+impl RespValue {
+    /// Encode the value in RESP2 wire format
+    pub(super) fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            RespValue::SimpleString(value) => write!(writer, "+{}\r\n", value)?,
+            RespValue::Error(message) => write!(writer, "-{}\r\n", message)?,
+            RespValue::Integer(value) => write!(writer, ":{}\r\n", value)?,
+            RespValue::BulkString(None) => writer.write_all(b"$-1\r\n")?,
+            RespValue::BulkString(Some(value)) => {
+                write!(writer, "${}\r\n", value.len())?;
+                writer.write_all(value)?;
+                writer.write_all(b"\r\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a command in either multi-bulk or inline format, returns `None` if the connection is closed
+///
+/// Commands whose arguments take more than `max_size` bytes in total, or more arguments than that, are rejected
+/// with `KvsError::UnknownProtocol` before anything is allocated for them.
+pub(super) fn read_command<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None)
+    };
+    if let Some(count) = line.strip_prefix(b"*") {
+        // Multi-bulk: *<count>\r\n followed by <count> bulk strings $<len>\r\n<data>\r\n
+        let count = parse_number(count)?;
+        if count > max_size as i64 { return Err(KvsError::UnknownProtocol) }
+        // Every argument counts as one byte more than its length, so the argument count is bounded as well
+        let mut remaining = max_size as i64 - count.max(0);
+        let mut args = Vec::with_capacity(count.clamp(0, 64) as usize);
+        for _ in 0..count {
+            let line = read_line(reader, max_size)?.ok_or(KvsError::UnknownProtocol)?;
+            let len = parse_number(line.strip_prefix(b"$").ok_or(KvsError::UnknownProtocol)?)?;
+            if len < 0 || len > remaining { return Err(KvsError::UnknownProtocol) }
+            remaining -= len;
+            let mut arg = vec![0; len as usize + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") { return Err(KvsError::UnknownProtocol) }
+            arg.truncate(len as usize);
+            args.push(arg);
+        }
+        Ok(Some(args))
+    } else {
+        // Inline: arguments separated by spaces
+        Ok(Some(line.split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect()))
+    }
+}
+
+/// Execute a single command on `store`
+/// Supported command: PING, GET, SET, DEL, EXISTS
+pub(super) fn execute(store: &dyn KvsEngine, args: Vec<Vec<u8>>) -> RespValue {
+    let mut args = args.into_iter();
+    let cmd = match args.next() {
+        Some(cmd) => String::from_utf8_lossy(&cmd).to_uppercase(),
+        None => return RespValue::Error("ERR empty command".to_owned())
+    };
+    let args = args.collect::<Vec<_>>();
+    let result = match (cmd.as_str(), args.len()) {
+        ("PING", 0) => Ok(RespValue::SimpleString("PONG".to_owned())),
+        ("GET", 1) => store.get_bytes(args[0].clone()).map(RespValue::BulkString),
+        ("SET", 2) => store.set_bytes(args[0].clone(), args[1].clone())
+            .map(|_| RespValue::SimpleString("OK".to_owned())),
+        ("DEL", 1..) => {
+            let mut removed = 0;
+            for key in args {
+                match String::from_utf8(key).map_err(KvsError::from).and_then(|key| store.remove(key)) {
+                    Ok(_) => removed += 1,
+                    Err(KvsError::KeyNotExist(_)) => {},
+                    Err(err) => return RespValue::Error(format!("ERR {}", err))
+                }
+            }
+            Ok(RespValue::Integer(removed))
+        },
+        ("EXISTS", 1..) => {
+            let mut found = 0;
+            for key in args {
+                match store.get_bytes(key) {
+                    Ok(Some(_)) => found += 1,
+                    Ok(None) => {},
+                    Err(err) => return RespValue::Error(format!("ERR {}", err))
+                }
+            }
+            Ok(RespValue::Integer(found))
+        },
+        ("PING" | "GET" | "SET" | "DEL" | "EXISTS", _) => {
+            return RespValue::Error(format!("ERR wrong number of arguments for '{}' command", cmd.to_lowercase()))
+        },
+        _ => return RespValue::Error(format!("ERR unknown command '{}'", cmd.to_lowercase()))
+    };
+    result.unwrap_or_else(|err| RespValue::Error(format!("ERR {}", err)))
+}
+
+/// Read a line terminated by CRLF or LF without the terminator, lines longer than `max_len` are rejected
+fn read_line<R: BufRead>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // The terminator is missing from the line cut at the limit
+    if reader.take(max_len as u64 + 2).read_until(b'\n', &mut line)? == 0 { return Ok(None) }
+    if line.pop() != Some(b'\n') { return Err(KvsError::UnknownProtocol) }
+    if line.last() == Some(&b'\r') { line.pop(); }
+    Ok(Some(line))
+}
+
+fn parse_number(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes).ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or(KvsError::UnknownProtocol)
+}
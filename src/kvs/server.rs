@@ -16,35 +16,358 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hint;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use super::{KvsEngine, KvsError, KvStore, Result};
-use super::util::{NaiveThreadPool, ThreadPool};
-use super::SledKvsEngine;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{async_engine, command, http, resp, CompactionInfo, IpNetwork, KvsEngine, KvsError, KvStore, KvStoreOptions, Result, ValueReader};
+use super::metrics::Metrics;
+use super::util::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolKind};
 use serde::{Deserialize, Serialize};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use slog::{info, o, Discard, Logger};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::JoinSet;
+use mio::{Events, Interest, Poll, Token, Waker};
 
 #[derive(Clone)]
 pub struct KvsServer {
     // TODO Alternative way to hold KvsEngine objects
     store: Box<dyn KvsEngine + Sync>,
-    need_termination: Arc<AtomicBool>
+    need_termination: Arc<AtomicBool>,
+    options: KvsServerOptions,
+    tls: Option<Arc<ServerConfig>>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<Shutdown>,
+    connections: Option<Arc<Semaphore>>, // Slots of the connections being served, `None` if unlimited
+    engine_type: String,
+    path: PathBuf, // Base directory of the database
+    opened: Instant
+}
+
+/// Description of a running server, replied to the `INFO` command
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Name of the storage engine, either `kvs` or `sled`
+    pub engine: String,
+    /// Version of the server crate
+    pub version: String,
+    /// Build number of the database format, only for the `kvs` engine
+    pub build_number: Option<u64>,
+    /// Seconds since the server was opened
+    pub uptime_secs: u64,
+    /// Sizes deciding whether the database is worth compacting, only for the `kvs` engine
+    #[serde(default)]
+    pub compaction: Option<CompactionInfo>
+}
+
+// Client of a connection, as shown in the log records
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix // Clients of Unix domain socket are unnamed
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(peer_addr) => write!(f, "{}", peer_addr),
+            Peer::Unix => write!(f, "unix")
+        }
+    }
+}
+
+// Listener of the blocking server, polled in non-blocking mode
+#[doc(hidden)]
+pub trait Acceptor: mio::event::Source {
+    type Stream: Send + 'static;
+    
+    /// Accept a pending connection as a blocking stream
+    fn accept_stream(&self) -> io::Result<(Self::Stream, Peer)>;
+}
+
+impl Acceptor for mio::net::TcpListener {
+    type Stream = TcpStream;
+    
+    fn accept_stream(&self) -> io::Result<(TcpStream, Peer)> {
+        let (stream, peer_addr) = self.accept()?;
+        let stream = TcpStream::from(stream);
+        stream.set_nonblocking(false)?;
+        Ok((stream, Peer::Tcp(peer_addr)))
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for mio::net::UnixListener {
+    type Stream = UnixStream;
+    
+    fn accept_stream(&self) -> io::Result<(UnixStream, Peer)> {
+        let (stream, _) = self.accept()?;
+        let stream = UnixStream::from(stream);
+        stream.set_nonblocking(false)?;
+        Ok((stream, Peer::Unix))
+    }
+}
+
+// Handler of a connection accepted by the blocking server
+type StreamHandler<S> = fn(&KvsServer, S, Peer) -> Result<()>;
+// Handler of a connection over the limit
+type StreamRejecter<S> = fn(&KvsServer, S) -> Result<()>;
+
+/// Statistics of the sessions of KvsServer, accumulated in the base directory when `persist_stats` is set
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Number of sessions shut down gracefully
+    pub sessions: u64,
+    /// Total seconds the server was running
+    pub uptime_secs: u64,
+    /// Number of requests served by command, only the KvsClient protocol is counted
+    pub requests: BTreeMap<String, u64>,
+    /// Number of compactions completed
+    pub compactions: u64
+}
+
+impl ServerStats {
+    /// Read the accumulated statistics of the server in directory `path`, empty if nothing is persisted yet
+    pub fn load(path: impl AsRef<Path>) -> Result<ServerStats> {
+        match fs::read(path.as_ref().join(KvsServer::STATS_FILE)) {
+            Ok(content) => serde_json::from_slice(&content).map_err(|_| KvsError::InvalidDatabaseFormat),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(ServerStats::default()),
+            Err(err) => Err(err.into())
+        }
+    }
+    
+    /// Add the statistics of `other` to this one
+    fn accumulate(&mut self, other: &ServerStats) {
+        self.sessions += other.sessions;
+        self.uptime_secs += other.uptime_secs;
+        for (cmd, count) in other.requests.iter() {
+            *self.requests.entry(cmd.clone()).or_default() += count;
+        }
+        self.compactions += other.compactions;
+    }
+}
+
+// Wake up the accept loop of the running server on termination, or once a connection slot is released
+#[derive(Default)]
+struct Shutdown {
+    waker: Mutex<Option<Waker>>, // Registered by the blocking server
+    notify: Notify // Awaited by the async server
+}
+
+// Number of connections being handled by the thread pool of the blocking server
+#[derive(Default)]
+struct Handling {
+    count: Mutex<usize>,
+    idle: Condvar
+}
+
+impl Handling {
+    /// Count a connection until the returned guard is dropped
+    fn enter(self: &Arc<Handling>) -> HandlingGuard {
+        *self.count.lock().unwrap() += 1;
+        HandlingGuard(self.clone())
+    }
+    
+    /// Block until all connections are handled, as not every thread pool waits for its jobs when dropped
+    fn wait_idle(&self) {
+        let count = self.count.lock().unwrap();
+        drop(self.idle.wait_while(count, |count| *count > 0).unwrap());
+    }
+}
+
+struct HandlingGuard(Arc<Handling>);
+
+impl Drop for HandlingGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 { self.0.idle.notify_all(); }
+    }
+}
+
+/// Options for opening KvsServer
+#[derive(Clone, Debug)]
+pub struct KvsServerOptions {
+    /// Drop the connection if the client sends nothing within the duration, `None` waits forever
+    pub read_timeout: Option<Duration>,
+    /// Drop the connection if the reply cannot be sent within the duration, `None` waits forever
+    pub write_timeout: Option<Duration>,
+    /// Logger receiving a record for every request
+    pub logger: Logger,
+    /// Options for opening the database with the `kvs` engine
+    pub store: KvStoreOptions,
+    /// Maximum number of connections served at the same time, `None` is unlimited
+    pub max_connections: Option<usize>,
+    /// What to do with new connections once `max_connections` is reached
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    /// Only accept connections from these networks, `None` accepts every peer not in `denylist`
+    pub allowlist: Option<Vec<IpNetwork>>,
+    /// Close connections from these networks before reading any request, even if they are in `allowlist`
+    pub denylist: Vec<IpNetwork>,
+    /// Thread pool handling the connections of the blocking server
+    pub thread_pool: ThreadPoolKind,
+    /// Number of threads in the thread pool, at least 1
+    pub threads: u32,
+    /// Accumulate the statistics of every session into `stats.json` of the base directory on graceful shutdown
+    pub persist_stats: bool,
+    /// Directory holding the destinations of BACKUP, `None` is the `backups` directory of the base directory
+    pub backup_dir: Option<PathBuf>,
+    /// PEM file of the certificate chain, clients are served over TLS if set along with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Token the clients must send with `AUTH` before any other request, `None` serves every client
+    pub auth_token: Option<String>
+}
+
+/// Handling of new connections when the connection limit of KvsServer is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimitPolicy {
+    /// Reply `ServerBusy` to the first request then close the connection, RESP and HTTP connections are closed
+    Reject,
+    /// Leave the connection in the backlog of the listener until a served connection is closed
+    Queue
+}
+
+// Slot of a served connection of the blocking server, the accept loop is woken up once it is released
+struct ConnectionPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    shutdown: Arc<Shutdown>
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        // The slot must be free before the accept loop checks it again
+        drop(self.permit.take());
+        if let Some(waker) = self.shutdown.waker.lock().unwrap().as_ref() {
+            let _ = waker.wake();
+        }
+    }
+}
+
+// Delay of the accept loops before retrying a failed accept, doubled on every consecutive failure
+struct AcceptBackoff {
+    delay: Duration
+}
+
+// Handling of a failed accept by the accept loops
+enum AcceptFailure {
+    // The connection is closed by the client before being accepted, or the call is interrupted
+    Retry,
+    // Running out of file descriptors or memory, the connection is left in the backlog until some are released
+    Wait(Duration),
+    // The listener itself is unusable
+    Fatal
+}
+
+impl AcceptBackoff {
+    const MIN_DELAY: Duration = Duration::from_millis(5);
+    const MAX_DELAY: Duration = Duration::from_secs(1);
+    
+    fn new() -> AcceptBackoff {
+        AcceptBackoff { delay: AcceptBackoff::MIN_DELAY }
+    }
+    
+    // Called on every accepted connection
+    fn reset(&mut self) {
+        self.delay = AcceptBackoff::MIN_DELAY;
+    }
+    
+    // Classify the failed accept, the waits are logged and lengthened
+    fn on_error(&mut self, err: &io::Error, logger: &Logger) -> AcceptFailure {
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted => {
+                AcceptFailure::Retry
+            },
+            io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported => AcceptFailure::Fatal,
+            _ => {
+                let delay = self.delay;
+                info!(logger, "Accept failed"; "error" => %err, "backoff_ms" => delay.as_millis() as u64);
+                self.delay = (delay * 2).min(AcceptBackoff::MAX_DELAY);
+                AcceptFailure::Wait(delay)
+            }
+        }
+    }
 }
 
 // Communication protocol for Client-Server request (in bson)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KvsCmdRequest {
     pub(super) cmd: String,
-    pub(super) argument: Vec<String>
+    pub(super) argument: Vec<String>,
+    // Echoed in the reply, so the client can tell the reply belongs to the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) id: Option<u64>
 }
 
 // Communication protocol for Server-Client reply (in bson)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KvsServerReply {
     pub(super) result: Option<String>,
-    pub(super) status: KvsServerReplyStatus
+    pub(super) status: KvsServerReplyStatus,
+    // Name of the KvsError variant of the failure, the result holds its message
+    #[serde(default)]
+    pub(super) error_kind: Option<String>,
+    // Time spent by the server executing the request, omitted for replies not produced by a request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) server_latency_us: Option<u64>,
+    // Id of the request replied to, omitted if the request has no id or the reply is not produced by a request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) request_id: Option<u64>
+}
+
+impl KvsServerReply {
+    /// Reply with `status` and `result`, the latency and the request id are filled in by the server
+    pub(super) fn new(status: KvsServerReplyStatus, result: Option<String>) -> KvsServerReply {
+        KvsServerReply {
+            result,
+            status,
+            error_kind: None,
+            server_latency_us: None,
+            request_id: None
+        }
+    }
+    
+    /// Reply to a successful request
+    pub(super) fn ok(result: Option<String>) -> KvsServerReply {
+        KvsServerReply::new(KvsServerReplyStatus::Success, result)
+    }
+    
+    /// Reply to a failed request, with the error kind for the client to rebuild `err`
+    pub(super) fn error(status: KvsServerReplyStatus, err: KvsError) -> KvsServerReply {
+        KvsServerReply {
+            error_kind: Some(err.kind().to_owned()),
+            ..KvsServerReply::new(status, Some(err.to_remote()))
+        }
+    }
+    
+    /// Error of a failed request, rebuilt from the error kind if the server sent one
+    pub(super) fn into_error(self) -> KvsError {
+        match self.error_kind {
+            Some(kind) => KvsError::from_remote(&kind, self.result.unwrap_or_default()),
+            None => KvsError::ServerError
+        }
+    }
+}
+
+// State of a client connection
+struct Session {
+    authenticated: bool,
+    store: Box<dyn KvsEngine + Sync>, // Scoped to the namespace selected by the client
+    stream: Option<ValueReader> // Value of `GETSTREAM` to be sent after its reply
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,149 +376,1168 @@ pub enum KvsServerReplyStatus {
     InvalidArguments,
     InvalidCommand,
     KeyNotFound,
-    ServerInternalError
+    ServerInternalError,
+    Unauthorized,
+    // The result holds the size and the limit separated by a space
+    ValueTooLarge,
+    // The result describes why the request cannot be decoded
+    MalformedRequest,
+    // The connection limit is reached, the connection is closed after the reply
+    ServerBusy
+}
+
+impl Default for KvsServerOptions {
+    fn default() -> Self {
+        KvsServerOptions {
+            read_timeout: Some(Duration::from_secs(5)),
+            write_timeout: Some(Duration::from_secs(5)),
+            logger: Logger::root(Discard, o!()),
+            store: KvStoreOptions::default(),
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::Reject,
+            allowlist: None,
+            denylist: Vec::new(),
+            thread_pool: ThreadPoolKind::SharedQueue,
+            threads: 8,
+            persist_stats: false,
+            backup_dir: None,
+            tls_cert: None,
+            tls_key: None,
+            auth_token: None
+        }
+    }
 }
 
 impl KvsServer {
+    /// File name of the persisted statistics in the base directory
+    pub const STATS_FILE: &'static str = "stats.json";
+    /// Default directory of the BACKUP destinations in the base directory
+    pub const BACKUP_DIR: &'static str = "backups";
+    
+    /// Size of the chunks a streamed value is written in after the `GETSTREAM` reply
+    pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+    
     /// Open the database file with specified engine
     pub fn open(engine_type: &str, path: impl Into<PathBuf>) -> Result<KvsServer> {
-        // Supported database engine: kvs, sled
-        let store: Box<dyn KvsEngine + Sync> = match engine_type.to_lowercase().as_ref() {
-            "kvs" => Box::new(KvStore::open(path)?),
-            "sled" => Box::new(SledKvsEngine::open(path)?),
-            _ => { return Err(KvsError::UnsupportedEngine) }
+        KvsServer::open_with_options(engine_type, path, KvsServerOptions::default())
+    }
+    
+    /// Open the database file with specified engine and server options
+    pub fn open_with_options(engine_type: &str, path: impl Into<PathBuf>, options: KvsServerOptions) -> Result<KvsServer> {
+        if options.threads == 0 {
+            return Err(KvsError::InvalidArguments("Thread pool requires at least 1 thread".to_owned()))
+        }
+        let tls = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => Some(Arc::new(KvsServer::load_tls(cert, key)?)),
+            (None, None) => None,
+            _ => return Err(KvsError::InvalidArguments("TLS requires both the certificate and the private key".to_owned()))
         };
+        // Supported database engine: kvs, sled
+        let path = path.into();
+        command::check_engine(engine_type, &path)?;
+        let store = command::open_engine_with_options(engine_type, path.clone(), options.store.clone())?;
         
         Ok(KvsServer {
             store,
-            need_termination: Arc::new(AtomicBool::new(false))
+            engine_type: engine_type.to_lowercase(),
+            path,
+            opened: Instant::now(),
+            need_termination: Arc::new(AtomicBool::new(false)),
+            connections: options.max_connections.map(|limit| Arc::new(Semaphore::new(limit))),
+            options,
+            tls,
+            metrics: Arc::new(Metrics::default()),
+            shutdown: Arc::new(Shutdown::default())
+        })
+    }
+    
+    /// Open the database file with specified engine, only serving clients authenticated with `token`
+    ///
+    /// The first request on every connection must be `AUTH` with the token, otherwise the connection is closed
+    pub fn open_with_auth(engine_type: &str, path: impl Into<PathBuf>, token: &str) -> Result<KvsServer> {
+        KvsServer::open_with_options(engine_type, path, KvsServerOptions {
+            auth_token: Some(token.to_owned()),
+            ..Default::default()
+        })
+    }
+    
+    /// Open the database file with specified engine, serving clients over TLS
+    ///
+    /// `cert` is the PEM file of the certificate chain and `key` is the PEM file of its private key
+    pub fn open_with_tls(engine_type: &str, path: impl Into<PathBuf>,
+                         cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<KvsServer> {
+        KvsServer::open_with_options(engine_type, path, KvsServerOptions {
+            tls_cert: Some(cert.as_ref().to_owned()),
+            tls_key: Some(key.as_ref().to_owned()),
+            ..Default::default()
         })
     }
     
+    /// TLS configuration serving the certificate chain in the PEM file `cert` with the private key in `key`
+    fn load_tls(cert: &Path, key: &Path) -> Result<ServerConfig> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?)).collect::<io::Result<Vec<_>>>()?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+            .ok_or(KvsError::InvalidCertificate)?;
+        Ok(ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?)
+    }
+    
     /// Start server listening on `addr`
     ///
     /// This method would not return util received termination signal or error
+    /// On termination, no more connection is accepted and the requests in progress are completed before returning
     pub fn start(&self, addr: impl ToSocketAddrs) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        let thread_pool = NaiveThreadPool::new(8)?;
-        for stream in listener.incoming().flatten() {
-            let handle = self.clone();
-            thread_pool.spawn(move || {
-                handle.handle_stream(stream).unwrap();
-            });
-            if self.need_termination.load(Ordering::Relaxed) { break; }
+        self.start_with_listener(KvsServer::bind(addr)?)
+    }
+    
+    /// Start server accepting connections from `listener`, with the same protocol as `start`
+    #[doc(hidden)]
+    pub fn start_with_listener(&self, listener: impl Acceptor<Stream = TcpStream>) -> Result<()> {
+        self.serve(listener, KvsServer::handle_stream, Some(KvsServer::reject_stream))?;
+        self.finish_session()
+    }
+    
+    /// Start server listening on the Unix domain socket at `path`, with the same protocol as `start`
+    ///
+    /// The socket file must not exist and is removed on termination. TLS is not supported and the allowlist and
+    /// denylist do not apply, access is controlled by the permissions of the socket file.
+    /// This method would not return util received termination signal or error
+    #[cfg(unix)]
+    pub fn start_uds(&self, path: impl AsRef<Path>) -> Result<()> {
+        if self.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "TLS is not supported over Unix domain socket").into())
         }
+        let listener = mio::net::UnixListener::bind(path.as_ref()).map_err(|source| {
+            KvsError::BindFailed { addr: path.as_ref().display().to_string(), source }
+        })?;
+        let served = self.serve(listener, KvsServer::handle_unix_stream, Some(KvsServer::reject_unix_stream));
+        // Binding the path again fails while the socket file exists
+        let _ = fs::remove_file(path.as_ref());
+        served?;
+        self.finish_session()
+    }
+    
+    /// Stop the server running on any clone of this instance, same as receiving `KILL`
+    ///
+    /// No more connection is accepted and the start method returns once the requests in progress are completed
+    pub fn shutdown(&self) {
+        self.need_termination.store(true, Ordering::Relaxed);
+        if let Some(waker) = self.shutdown.waker.lock().unwrap().as_ref() {
+            let _ = waker.wake();
+        }
+        // The permit is kept if the async server is not waiting at the moment
+        self.shutdown.notify.notify_one();
+    }
+    
+    /// Start server speaking Redis RESP2 protocol on `addr`, supporting GET, SET, DEL and EXISTS
+    ///
+    /// This method would not return util received termination signal or error
+    pub fn start_resp(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(KvsServer::bind(addr)?, KvsServer::handle_resp_stream, None)
+    }
+    
+    /// Start HTTP gateway on `addr`, exposing GET, PUT and DELETE on `/kv/{key}` with JSON response
+    ///
+    /// This method would not return util received termination signal or error
+    pub fn start_http(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(KvsServer::bind(addr)?, KvsServer::handle_http_stream, None)
+    }
+    
+    /// Start server listening on `addr` with Tokio, every connection is a task instead of a thread
+    ///
+    /// The network I/O is asynchronous while the requests are executed on the blocking thread pool of Tokio.
+    /// TLS is not supported. This method would not return util received termination signal or error
+    pub fn start_async(&self, addr: impl tokio::net::ToSocketAddrs) -> Result<()> {
+        if self.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "TLS is not supported by the async server").into())
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        runtime.block_on(self.serve_async(addr))?;
+        self.finish_session()
+    }
+    
+    /// Log the statistics of the session, and add them to the persisted ones if enabled
+    fn finish_session(&self) -> Result<()> {
+        let session = ServerStats {
+            sessions: 1,
+            uptime_secs: self.opened.elapsed().as_secs(),
+            requests: self.metrics.requests(),
+            compactions: self.store.compactions()
+        };
+        info!(self.options.logger, "Session summary";
+            "uptime_secs" => session.uptime_secs, "requests" => session.requests.values().sum::<u64>(),
+            "compactions" => session.compactions);
+        if !self.options.persist_stats { return Ok(()) }
+        
+        let mut stats = ServerStats::load(&self.path)?;
+        stats.accumulate(&session);
+        // Replace the file at once, so an interrupted write does not lose the previous sessions
+        let tmp_path = self.path.join(KvsServer::STATS_FILE).with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&stats).unwrap())?;
+        fs::rename(&tmp_path, self.path.join(KvsServer::STATS_FILE))?;
         Ok(())
     }
     
-    /// Handle request from client
-    /// KvsServer currently support six command: GET, SET, RM, REMOVE, DELETE, KILL
-    fn handle_stream(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buf = [0; 1024];
-        let len = stream.read(&mut buf)?;
-        if let Ok(request) = bson::from_slice::<KvsCmdRequest>(&buf[..len]) {
-            let reply = match request.cmd.as_ref() {
-                "GET" => {
-                    if request.argument.len() == 1 {
-                        match self.store.get(request.argument.get(0).unwrap().to_owned())? {
-                            Some(result) => KvsServerReply {
-                                result: Some(result),
-                                status: KvsServerReplyStatus::Success
-                            },
-                            
-                            None => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::Success
+    /// Accept connections on `addr` and handle each of them in a separate task
+    async fn serve_async(&self, addr: impl tokio::net::ToSocketAddrs) -> Result<()> {
+        let mut listener = Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"));
+        let mut last_addr = None;
+        for addr in tokio::net::lookup_host(addr).await? {
+            listener = tokio::net::TcpListener::bind(addr).await;
+            last_addr = Some(addr);
+            if listener.is_ok() { break }
+        }
+        let listener = listener.map_err(|source| KvsError::bind_failed(last_addr, source))?;
+        let mut tasks = JoinSet::new();
+        let mut backoff = AcceptBackoff::new();
+        let mut served = Ok(());
+        while !self.need_termination.load(Ordering::Relaxed) {
+            // Leave the connections in the backlog until a connection slot is released
+            let queued = match &self.connections {
+                Some(connections) if self.options.connection_limit_policy == ConnectionLimitPolicy::Queue => {
+                    tokio::select! {
+                        permit = connections.clone().acquire_owned() => Some(permit.unwrap()),
+                        _ = self.shutdown.notify.notified() => continue
+                    }
+                },
+                _ => None
+            };
+            let (stream, peer_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => {
+                        backoff.reset();
+                        accepted
+                    },
+                    Err(err) => match backoff.on_error(&err, &self.options.logger) {
+                        AcceptFailure::Retry => continue,
+                        AcceptFailure::Wait(delay) => {
+                            tokio::select! {
+                                _ = tokio::time::sleep(delay) => {},
+                                _ = self.shutdown.notify.notified() => {}
                             }
+                            continue
+                        },
+                        AcceptFailure::Fatal => {
+                            served = Err(err.into());
+                            break
                         }
-                    } else {
-                        KvsServerReply {
-                            result: Some(format!("`GET` command required 1 argument, provided {}", request.argument.len())),
-                            status: KvsServerReplyStatus::InvalidArguments
+                    }
+                },
+                _ = self.shutdown.notify.notified() => continue
+            };
+            // The queued slot is released along with the connection
+            if !self.is_peer_allowed(Peer::Tcp(peer_addr)) { continue }
+            let permit = match (queued, &self.connections) {
+                (Some(permit), _) => Ok(Some(permit)),
+                (None, Some(connections)) => connections.clone().try_acquire_owned().map(Some),
+                (None, None) => Ok(None)
+            };
+            let handle = self.clone();
+            tasks.spawn(async move {
+                match permit {
+                    Ok(_permit) => { let _ = handle.handle_async_stream(stream, peer_addr).await; },
+                    Err(_) => { let _ = handle.reject_async_stream(stream).await; }
+                }
+            });
+            // Reap finished connections
+            while tasks.try_join_next().is_some() {}
+        }
+        drop(listener);
+        // Wait for the pending requests
+        while tasks.join_next().await.is_some() {}
+        let store = self.store.clone();
+        async_engine::blocking(move || store.flush()).await?;
+        served
+    }
+    
+    /// Handle connection from client without blocking the runtime
+    async fn handle_async_stream(&self, stream: tokio::net::TcpStream, peer_addr: SocketAddr) -> Result<()> {
+        let mut session = self.new_session();
+        let mut reader = tokio::io::BufReader::new(stream);
+        let mut replies = Vec::new();
+        'serve: loop {
+            // Timed out or closed connection is dropped silently
+            let read = read_frame(&mut reader, self.max_request_size());
+            let frame = match self.options.read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read).await.unwrap_or(Ok(None)),
+                None => read.await
+            };
+            let (reply, is_framed) = match frame {
+                Ok(Some(frame)) => match bson::from_slice::<KvsCmdRequest>(&frame) {
+                    Ok(request) => {
+                        let handle = self.clone();
+                        let (reply, session_) = async_engine::blocking(move || {
+                            let reply = handle.process(&request, Peer::Tcp(peer_addr), &mut session)?;
+                            Ok((reply, session))
+                        }).await?;
+                        session = session_;
+                        (reply, true)
+                    },
+                    Err(err) => (KvsServer::malformed_request(err.into()), true)
+                },
+                Ok(None) | Err(KvsError::IOError(_)) => break,
+                Err(err) => (KvsServer::malformed_request(err), false)
+            };
+            replies.extend_from_slice(bson::to_vec(&reply)?.as_slice());
+            let value = session.stream.take();
+            // Close the connection of unauthenticated client, or once the request boundary is lost
+            let is_last = !is_framed || !session.authenticated || self.need_termination.load(Ordering::Relaxed);
+            // Same batching as `handle_request`
+            if is_last || value.is_some() || reader.buffer().is_empty() {
+                let handle = self.clone();
+                async_engine::blocking(move || handle.end_batch()).await?;
+                if !self.write_async(reader.get_mut(), &replies).await? { break 'serve }
+                replies.clear();
+                // The value is read in a blocking task one chunk at a time, and the timeout applies to each chunk
+                // instead of the whole transfer
+                if let Some(mut value) = value {
+                    let mut sent = 0;
+                    loop {
+                        let (value_, chunk) = async_engine::blocking(move || {
+                            let mut chunk = vec![0; KvsServer::STREAM_CHUNK_SIZE];
+                            let read = value.read(&mut chunk)?;
+                            chunk.truncate(read);
+                            Ok((value, chunk))
+                        }).await?;
+                        value = value_;
+                        if chunk.is_empty() { break }
+                        sent += chunk.len() as u64;
+                        if !self.write_async(reader.get_mut(), &chunk).await? { break 'serve }
+                    }
+                    // The client expects exactly the announced length
+                    if sent < value.len() { return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()) }
+                }
+            }
+            if is_last { break; }
+        }
+        Ok(())
+    }
+    
+    /// Answer the first request of a connection over the limit with `ServerBusy`
+    async fn reject_async_stream(&self, mut stream: tokio::net::TcpStream) -> Result<()> {
+        // The request is read first, so the client is not reset before reading the reply
+        let read = read_frame(&mut stream, self.max_request_size());
+        let frame = match self.options.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read).await.unwrap_or(Ok(None)),
+            None => read.await
+        };
+        if let Ok(Some(_)) = frame {
+            stream.write_all(bson::to_vec(&KvsServer::server_busy())?.as_slice()).await?;
+        }
+        Ok(())
+    }
+    
+    /// Bind a non-blocking TCP listener with `SO_REUSEADDR` on the first usable address of `addr` for the blocking
+    /// server, so it can be restarted while connections of the previous run are still in `TIME_WAIT`
+    fn bind(addr: impl ToSocketAddrs) -> Result<mio::net::TcpListener> {
+        let mut listener = Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"));
+        let mut last_addr = None;
+        for addr in addr.to_socket_addrs()? {
+            listener = mio::net::TcpListener::bind(addr);
+            last_addr = Some(addr);
+            if listener.is_ok() { break }
+        }
+        listener.map_err(|source| KvsError::bind_failed(last_addr, source))
+    }
+    
+    /// Accept connections from `listener` and handle each of them with `handler` in the configured thread pool
+    ///
+    /// Connections over the limit are handled with `reject` if the policy is `Reject`, or closed if it is `None`
+    fn serve<A: Acceptor>(&self, listener: A, handler: StreamHandler<A::Stream>,
+                          reject: Option<StreamRejecter<A::Stream>>) -> Result<()> {
+        match self.options.thread_pool {
+            ThreadPoolKind::Naive => self.serve_with::<NaiveThreadPool, A>(listener, handler, reject),
+            ThreadPoolKind::SharedQueue => self.serve_with::<SharedQueueThreadPool, A>(listener, handler, reject),
+            ThreadPoolKind::Rayon => self.serve_with::<RayonThreadPool, A>(listener, handler, reject)
+        }
+    }
+    
+    /// Accept connections from `listener` and handle each of them with `handler` in the thread pool `P`
+    fn serve_with<P: ThreadPool, A: Acceptor>(&self, mut listener: A, handler: StreamHandler<A::Stream>,
+                                              reject: Option<StreamRejecter<A::Stream>>) -> Result<()> {
+        const LISTENER: Token = Token(0);
+        const WAKER: Token = Token(1);
+        // The listener is polled together with a waker, so termination does not need a connection to wake it up
+        let mut poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        *self.shutdown.waker.lock().unwrap() = Some(Waker::new(poll.registry(), WAKER)?);
+        let mut events = Events::with_capacity(16);
+        let thread_pool = P::new(self.options.threads)?;
+        let handling = Arc::new(Handling::default());
+        let mut backoff = AcceptBackoff::new();
+        let mut served = Ok(());
+        'serve: while !self.need_termination.load(Ordering::Relaxed) {
+            match poll.poll(&mut events, None) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    served = Err(err.into());
+                    break
+                },
+                Ok(_) => {}
+            }
+            // Accept all pending connections until the listener would block
+            while !self.need_termination.load(Ordering::Relaxed) {
+                // Leave the connections in the backlog, the loop is woken up once a connection slot is released
+                // Slots are only taken by this loop, so a free slot is still available after accepting
+                let is_full = self.connections.as_ref().is_some_and(|connections| connections.available_permits() == 0);
+                if is_full && self.options.connection_limit_policy == ConnectionLimitPolicy::Queue { break; }
+                let (stream, peer) = match listener.accept_stream() {
+                    Ok(accepted) => {
+                        backoff.reset();
+                        accepted
+                    },
+                    // The remaining connections are accepted on next readiness
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => match backoff.on_error(&err, &self.options.logger) {
+                        AcceptFailure::Retry => continue,
+                        AcceptFailure::Wait(delay) => {
+                            thread::sleep(delay);
+                            continue
+                        },
+                        AcceptFailure::Fatal => {
+                            served = Err(err.into());
+                            break 'serve
                         }
                     }
+                };
+                if !self.is_peer_allowed(peer) { continue }
+                let permit = self.try_acquire_connection();
+                let handle = self.clone();
+                let guard = handling.enter();
+                // Errors are not propagated, a panicking job aborts the process with some thread pools
+                match (permit, reject) {
+                    (Ok(permit), _) => thread_pool.spawn(move || {
+                        let _ = handler(&handle, stream, peer);
+                        drop(permit);
+                        drop(guard);
+                    }),
+                    (Err(_), Some(reject)) => thread_pool.spawn(move || {
+                        let _ = reject(&handle, stream);
+                        drop(guard);
+                    }),
+                    (Err(_), None) => drop(stream)
+                }
+            }
+        }
+        self.shutdown.waker.lock().unwrap().take();
+        drop(listener);
+        // Wait for the pending requests
+        handling.wait_idle();
+        drop(thread_pool);
+        self.store.flush()?;
+        served
+    }
+    
+    /// Check the peer against the allowlist and the denylist, the rejected peer is logged
+    fn is_peer_allowed(&self, peer: Peer) -> bool {
+        let ip = match peer {
+            Peer::Tcp(peer_addr) => peer_addr.ip(),
+            // Only reachable from the same host, guarded by the permissions of the socket file
+            Peer::Unix => return true
+        };
+        let contains = |networks: &[IpNetwork], ip: IpAddr| networks.iter().any(|network| network.contains(ip));
+        let is_allowed = !contains(&self.options.denylist, ip)
+            && self.options.allowlist.as_ref().is_none_or(|allowlist| contains(allowlist, ip));
+        if !is_allowed {
+            info!(self.options.logger, "Connection rejected"; "peer" => %peer);
+        }
+        is_allowed
+    }
+    
+    /// Handle connection from client
+    fn handle_stream(&self, stream: TcpStream, peer: Peer) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        match &self.tls {
+            Some(config) => self.handle_request(StreamOwned::new(ServerConnection::new(config.clone())?, stream), peer),
+            None => self.handle_request(stream, peer)
+        }
+    }
+    
+    /// Handle connection from client over Unix domain socket
+    #[cfg(unix)]
+    fn handle_unix_stream(&self, stream: UnixStream, peer: Peer) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        self.handle_request(stream, peer)
+    }
+    
+    /// Take a connection slot of the blocking server, `Ok(None)` if the connections are unlimited
+    fn try_acquire_connection(&self) -> std::result::Result<Option<ConnectionPermit>, TryAcquireError> {
+        match &self.connections {
+            Some(connections) => Ok(Some(ConnectionPermit {
+                permit: Some(connections.clone().try_acquire_owned()?),
+                shutdown: self.shutdown.clone()
+            })),
+            None => Ok(None)
+        }
+    }
+    
+    /// Answer the first request of a connection over the limit with `ServerBusy`
+    fn reject_stream(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        match &self.tls {
+            Some(config) => self.reject_request(StreamOwned::new(ServerConnection::new(config.clone())?, stream)),
+            None => self.reject_request(stream)
+        }
+    }
+    
+    /// Answer the first request of a Unix domain socket connection over the limit with `ServerBusy`
+    #[cfg(unix)]
+    fn reject_unix_stream(&self, stream: UnixStream) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        self.reject_request(stream)
+    }
+    
+    /// Read the first request from the plain or encrypted stream and reply `ServerBusy`
+    fn reject_request<S: Read + Write>(&self, mut stream: S) -> Result<()> {
+        // The request is read first, so the client is not reset before reading the reply
+        if let Ok(Some(_)) = read_frame_blocking(&mut stream, self.max_request_size()) {
+            stream.write_all(bson::to_vec(&KvsServer::server_busy())?.as_slice())?;
+            stream.flush()?;
+        }
+        Ok(())
+    }
+    
+    /// Handle connection from Redis client
+    fn handle_resp_stream(&self, stream: TcpStream, peer: Peer) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut authenticated = self.options.auth_token.is_none();
+        loop {
+            let args = match resp::read_command(&mut reader, self.max_request_size()) {
+                Ok(Some(args)) => args,
+                // Closed or timed out connection
+                Ok(None) | Err(KvsError::IOError(_)) => break,
+                Err(_) => {
+                    resp::RespValue::Error("ERR Protocol error".to_owned()).write_to(&mut writer)?;
+                    writer.flush()?;
+                    break;
+                }
+            };
+            // Empty inline command is ignored
+            if args.is_empty() { continue; }
+            let start = Instant::now();
+            let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+            let argument_count = args.len() - 1;
+            let value_len = if cmd == "SET" { args.get(2).map_or(0, |value| value.len()) } else { 0 };
+            
+            let reply = if args[0].eq_ignore_ascii_case(b"AUTH") {
+                if args.len() == 2 && self.options.auth_token.is_some() && self.is_token_valid(&args[1]) {
+                    authenticated = true;
+                    resp::RespValue::SimpleString("OK".to_owned())
+                } else {
+                    resp::RespValue::Error("ERR invalid password".to_owned())
+                }
+            } else if authenticated {
+                resp::execute(self.store.as_ref(), args)
+            } else {
+                resp::RespValue::Error("NOAUTH Authentication required.".to_owned())
+            };
+            let status = if let resp::RespValue::Error(_) = reply { "Error" } else { "OK" };
+            self.log_request(peer, &cmd, argument_count, value_len, status, start);
+            reply.write_to(&mut writer)?;
+            // Close the connection of unauthenticated client, so the token cannot be guessed on a single connection
+            let is_last = !authenticated || self.need_termination.load(Ordering::Relaxed);
+            // Replies of pipelined commands are sent together, once the writes before them are durable
+            if is_last || reader.buffer().is_empty() {
+                self.end_batch()?;
+                writer.flush()?;
+            }
+            if is_last { break; }
+        }
+        Ok(())
+    }
+    
+    /// Handle a single request from HTTP client
+    fn handle_http_stream(&self, stream: TcpStream, peer: Peer) -> Result<()> {
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let start = Instant::now();
+        let mut cmd = String::new();
+        let mut value_len = 0;
+        let (status, body) = match http::read_request(&mut reader) {
+            Ok(Some(request)) => {
+                cmd = request.method.clone();
+                value_len = request.body.len();
+                let authorized = self.options.auth_token.is_none() || request.authorization.as_deref()
+                    .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                    .is_some_and(|token| self.is_token_valid(token.as_bytes()));
+                if authorized {
+                    let response = http::execute(self.store.as_ref(), request);
+                    self.end_batch()?;
+                    response
+                } else {
+                    (401, serde_json::json!({ "error": "Unauthorized" }))
+                }
+            },
+            // Closed or timed out connection
+            Ok(None) | Err(KvsError::IOError(_)) => return Ok(()),
+            Err(_) => (400, serde_json::json!({ "error": "Bad request" }))
+        };
+        self.log_request(peer, &cmd, 1, value_len, &status.to_string(), start);
+        http::write_response(&mut writer, status, &body)
+    }
+    
+    /// Make the writes of the requests read so far durable before their replies are sent
+    ///
+    /// Sled buffers writes in the process, so it is flushed once at the end of each batch of pipelined requests
+    /// instead of after every write. The kvs engine writes the segment files directly.
+    fn end_batch(&self) -> Result<()> {
+        if self.engine_type == "sled" {
+            self.store.flush()?;
+        }
+        Ok(())
+    }
+    
+    /// Serve requests from the plain or encrypted stream until the client closes the connection
+    ///
+    /// Replies are sent once every request already received is executed, so a pipelined batch is answered at once.
+    /// Write `buf` to `stream` within the write timeout, returns whether it was written in time
+    async fn write_async(&self, stream: &mut tokio::net::TcpStream, buf: &[u8]) -> Result<bool> {
+        let write = stream.write_all(buf);
+        match self.options.write_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, write).await {
+                Ok(result) => result.map(|_| true).map_err(KvsError::from),
+                Err(_) => Ok(false)
+            },
+            None => write.await.map(|_| true).map_err(KvsError::from)
+        }
+    }
+    
+    /// Send the streamed value in chunks of `STREAM_CHUNK_SIZE`, only one chunk is held in memory
+    fn send_value<W: Write>(value: &mut ValueReader, writer: &mut W) -> io::Result<()> {
+        let mut chunk = vec![0; KvsServer::STREAM_CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let read = match value.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            };
+            writer.write_all(&chunk[..read])?;
+            sent += read as u64;
+        }
+        // The client expects exactly the announced length
+        if sent < value.len() { return Err(io::ErrorKind::UnexpectedEof.into()) }
+        Ok(())
+    }
+    
+    fn handle_request<S: Read + Write>(&self, stream: S, peer: Peer) -> Result<()> {
+        let mut session = self.new_session();
+        let mut reader = BufReader::new(stream);
+        let mut replies = Vec::new();
+        loop {
+            // Each request is a single BSON document, read exactly its length
+            let (reply, is_framed) = match read_frame_blocking(&mut reader, self.max_request_size()) {
+                Ok(Some(frame)) => match bson::from_slice::<KvsCmdRequest>(&frame) {
+                    Ok(request) => (self.process(&request, peer, &mut session)?, true),
+                    Err(err) => (KvsServer::malformed_request(err.into()), true)
                 },
-                
-                "SET" => {
-                    if request.argument.len() == 2 {
-                        match self.store.set(request.argument.get(0).unwrap().to_owned(),
-                                             request.argument.get(1).unwrap().to_owned()) {
-                            Ok(_) => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::Success
-                            },
+                // Timed out or closed connection is dropped silently
+                Ok(None) | Err(KvsError::IOError(_)) => break,
+                Err(err) => (KvsServer::malformed_request(err), false)
+            };
+            replies.extend_from_slice(bson::to_vec(&reply)?.as_slice());
+            let mut value = session.stream.take();
+            // Close the connection of unauthenticated client, or once the request boundary is lost
+            let is_last = !is_framed || !session.authenticated || self.need_termination.load(Ordering::Relaxed);
+            // Send the queued replies, followed by the streamed value if any, which is never queued
+            if is_last || value.is_some() || reader.buffer().is_empty() {
+                self.end_batch()?;
+                let stream = reader.get_mut();
+                let sent = stream.write_all(replies.as_slice())
+                    .and_then(|_| value.as_mut().map_or(Ok(()), |value| KvsServer::send_value(value, stream)))
+                    .and_then(|_| stream.flush());
+                match sent {
+                    Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()),
+                    result => result?
+                }
+                replies.clear();
+            }
+            if is_last { break; }
+        }
+        Ok(())
+    }
+    
+    /// Largest request accepted, enough for a SET command with the longest key and value
+    fn max_request_size(&self) -> usize {
+        (self.options.store.max_key_size + self.options.store.max_value_size) as usize + 1024
+    }
+    
+    /// Reply to a request which cannot be decoded
+    fn malformed_request(err: KvsError) -> KvsServerReply {
+        KvsServerReply::new(KvsServerReplyStatus::MalformedRequest, Some(format!("Malformed request: {}", err)))
+    }
+    
+    /// Reply to a request whose value exceeds the limit, the result holds the size and the limit
+    fn value_too_large(size: u64, limit: u64) -> KvsServerReply {
+        KvsServerReply::new(KvsServerReplyStatus::ValueTooLarge, Some(format!("{} {}", size, limit)))
+    }
+    
+    /// Reply to a request on a connection over the limit
+    fn server_busy() -> KvsServerReply {
+        KvsServerReply::error(KvsServerReplyStatus::ServerBusy, KvsError::ServerBusy)
+    }
+    
+    /// Reply to a request failed in the engine, with the error kind for the client to rebuild the error
+    fn internal_error(err: KvsError) -> KvsServerReply {
+        KvsServerReply::error(KvsServerReplyStatus::ServerInternalError, err)
+    }
+    
+    /// Reply to a request with the wrong number of arguments, the result holds the counts as JSON
+    fn wrong_argument_count(command: &str, expected: RangeInclusive<usize>, provided: usize) -> KvsServerReply {
+        KvsServer::invalid_arguments(KvsError::WrongArgumentCount { command: command.to_owned(), expected, provided })
+    }
+    
+    /// Reply to a request with invalid arguments, with the error kind for the client to rebuild the error
+    fn invalid_arguments(err: KvsError) -> KvsServerReply {
+        KvsServerReply::error(KvsServerReplyStatus::InvalidArguments, err)
+    }
+    
+    /// Resolve the BACKUP destination `dir` under the backup directory, only plain relative paths are accepted
+    fn backup_path(&self, dir: &str) -> Result<PathBuf> {
+        let dir = Path::new(dir);
+        if dir.as_os_str().is_empty() || !dir.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(KvsError::InvalidArguments("Backup destination must be a relative path without `..`".to_owned()))
+        }
+        let root = self.options.backup_dir.clone().unwrap_or_else(|| self.path.join(KvsServer::BACKUP_DIR));
+        Ok(root.join(dir))
+    }
+    
+    /// Check `token` against the token of the server in constant time, any token is valid if none is required
+    ///
+    /// Only the length of the token may be told from the time taken.
+    fn is_token_valid(&self, token: &[u8]) -> bool {
+        match &self.options.auth_token {
+            Some(expected) => {
+                let expected = expected.as_bytes();
+                expected.len() == token.len()
+                    && hint::black_box(expected.iter().zip(token).fold(0, |diff, (lhs, rhs)| diff | (lhs ^ rhs))) == 0
+            },
+            None => true
+        }
+    }
+    
+    /// State of a new connection, in the default namespace and authenticated only if no token is required
+    fn new_session(&self) -> Session {
+        Session {
+            authenticated: self.options.auth_token.is_none(),
+            store: self.store.clone(),
+            stream: None
+        }
+    }
+    
+    /// Execute the request if the client is authenticated, then log and record it
+    fn process(&self, request: &KvsCmdRequest, peer: Peer, session: &mut Session) -> Result<KvsServerReply> {
+        let start = Instant::now();
+        // Health checks do not need the token
+        let mut reply = if session.authenticated || request.cmd == "AUTH" || request.cmd == "PING" {
+            self.execute(request, session)?
+        } else {
+            KvsServerReply::new(KvsServerReplyStatus::Unauthorized, None)
+        };
+        reply.server_latency_us = Some(start.elapsed().as_micros() as u64);
+        reply.request_id = request.id;
+        // Never log the value itself, it may contain secret
+        let value_len = if request.cmd == "SET" { request.argument.get(1).map_or(0, |value| value.len()) } else { 0 };
+        self.log_request(peer, &request.cmd, request.argument.len(), value_len, &format!("{:?}", reply.status), start);
+        self.metrics.record(&request.cmd, &reply.status, start.elapsed());
+        Ok(reply)
+    }
+    
+    /// Emit a log record for a completed request
+    fn log_request(&self, peer: Peer, cmd: &str, argument_count: usize, value_len: usize, status: &str, start: Instant) {
+        info!(self.options.logger, "Request";
+            "cmd" => cmd, "args" => argument_count, "value_len" => value_len, "status" => status,
+            "peer" => %peer, "latency_us" => start.elapsed().as_micros() as u64);
+    }
+    
+    /// Execute a single request
+    /// KvsServer currently support twenty-two command:
+    /// PING, INFO, GET, GETSTREAM, GETRANGE, SET, APPEND, RM, REMOVE, DELETE, REMOVEIFEXISTS, INCR, TYPE, SCAN, DBSIZE,
+    /// NAMESPACE, COMPACT, FLUSH, BACKUP, METRICS, AUTH, KILL
+    fn execute(&self, request: &KvsCmdRequest, session: &mut Session) -> Result<KvsServerReply> {
+        let reply = match request.cmd.as_ref() {
+            // Health check of the connection, nothing is read or modified
+            "PING" => {
+                if request.argument.is_empty() {
+                    KvsServerReply::ok(Some("PONG".to_owned()))
+                } else {
+                    KvsServer::wrong_argument_count("PING", 0..=0, request.argument.len())
+                }
+            },
+            
+            // Engine and version of the server, replied as JSON
+            "INFO" => {
+                if request.argument.is_empty() {
+                    let info = ServerInfo {
+                        engine: self.engine_type.clone(),
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                        build_number: (self.engine_type == "kvs").then_some(KvStore::BUILD_NUMBER),
+                        uptime_secs: self.opened.elapsed().as_secs(),
+                        compaction: self.store.compaction_info()
+                    };
+                    KvsServerReply::ok(Some(serde_json::to_string(&info).unwrap()))
+                } else {
+                    KvsServer::wrong_argument_count("INFO", 0..=0, request.argument.len())
+                }
+            },
+            
+            // Conditional SET with the flag NX or XX, the result is `OK` if the value was set and empty otherwise
+            "SET" if request.argument.len() == 3 => {
+                let [key, value, flag] = <&[String; 3]>::try_from(request.argument.as_slice()).unwrap();
+                let result = KvStore::check_size(key.len(), self.options.store.max_key_size)
+                    .and_then(|_| KvStore::check_size(value.len(), self.options.store.max_value_size))
+                    .and_then(|_| self.options.store.key_validation.check(key.as_bytes()))
+                    .and_then(|_| match flag.to_uppercase().as_str() {
+                        "NX" => session.store.set_nx(key.to_owned(), value.to_owned()),
+                        "XX" => session.store.set_xx(key.to_owned(), value.to_owned()),
+                        _ => Err(KvsError::InvalidArguments(format!("Unknown `SET` flag {}", flag)))
+                    });
+                match result {
+                    Ok(written) => KvsServerReply::ok(written.then(|| "OK".to_owned())),
+                    
+                    Err(KvsError::InvalidArguments(message)) => {
+                        KvsServerReply::new(KvsServerReplyStatus::InvalidArguments, Some(message))
+                    },
+                    
+                    Err(err @ KvsError::InvalidKey(_)) => KvsServer::invalid_arguments(err),
+                    
+                    Err(KvsError::ValueTooLarge { size, limit }) => KvsServer::value_too_large(size, limit),
+                    
+                    Err(err) => KvsServer::internal_error(err)
+                }
+            },
+            
+            // Plain SET takes 2 arguments, conditional SET takes 3
+            "SET" if request.argument.len() != 2 => KvsServer::wrong_argument_count("SET", 2..=3, request.argument.len()),
+            
+            "GET" | "SET" | "RM" | "REMOVE" | "DELETE" => {
+                // The limits are checked before reaching the engine, so they also apply to the sled engine
+                let result = match request.argument.as_slice() {
+                    [key, value] if request.cmd == "SET" => {
+                        KvStore::check_size(key.len(), self.options.store.max_key_size)
+                            .and_then(|_| KvStore::check_size(value.len(), self.options.store.max_value_size))
+                            .and_then(|_| self.options.store.key_validation.check(key.as_bytes()))
+                    },
+                    _ => Ok(())
+                };
+                match result.and_then(|_| command::dispatch(session.store.as_ref(), &request.cmd, &request.argument)) {
+                    // Absent key is told apart from the empty value by the status
+                    Ok(None) if request.cmd == "GET" => KvsServerReply::new(KvsServerReplyStatus::KeyNotFound, None),
+                    
+                    Ok(result) => KvsServerReply::ok(result),
+                    
+                    Err(KvsError::KeyNotExist(_)) => KvsServerReply::new(KvsServerReplyStatus::KeyNotFound, None),
+                    
+                    Err(KvsError::InvalidArguments(message)) => {
+                        KvsServerReply::new(KvsServerReplyStatus::InvalidArguments, Some(message))
+                    },
+                    
+                    Err(err @ (KvsError::WrongArgumentCount { .. } | KvsError::InvalidKey(_))) => KvsServer::invalid_arguments(err),
+                    
+                    Err(KvsError::ValueTooLarge { size, limit }) => KvsServer::value_too_large(size, limit),
+                    
+                    Err(err) => KvsServer::internal_error(err)
+                }
+            },
+            
+            // Append to the value and reply the new length
+            "APPEND" => {
+                match request.argument.as_slice() {
+                    [key, value] => {
+                        // The total length is only checked by the kvs engine
+                        let result = KvStore::check_size(key.len(), self.options.store.max_key_size)
+                            .and_then(|_| KvStore::check_size(value.len(), self.options.store.max_value_size))
+                            .and_then(|_| self.options.store.key_validation.check(key.as_bytes()))
+                            .and_then(|_| session.store.append(key.to_owned(), value.to_owned()));
+                        match result {
+                            Ok(len) => KvsServerReply::ok(Some(len.to_string())),
                             
-                            Err(KvsError::KeyNotExist(_)) => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::KeyNotFound
-                            },
+                            Err(KvsError::ValueTooLarge { size, limit }) => KvsServer::value_too_large(size, limit),
                             
-                            _ => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::ServerInternalError
-                            }
-                        }
-                    } else {
-                        KvsServerReply {
-                            result: Some(format!("`GET` command required 2 argument, provided {}", request.argument.len())),
-                            status: KvsServerReplyStatus::InvalidArguments
+                            Err(err @ KvsError::InvalidKey(_)) => KvsServer::invalid_arguments(err),
+                            
+                            Err(err) => KvsServer::internal_error(err)
                         }
+                    },
+                    _ => KvsServer::wrong_argument_count("APPEND", 2..=2, request.argument.len())
+                }
+            },
+            
+            // Idempotent removal, the result is `OK` if the key was removed and empty if it did not exist
+            "REMOVEIFEXISTS" => {
+                if request.argument.len() == 1 {
+                    match session.store.remove_optional(request.argument[0].to_owned()) {
+                        Ok(removed) => KvsServerReply::ok(removed.then(|| "OK".to_owned())),
+                        
+                        Err(err) => KvsServer::internal_error(err)
                     }
-                },
-                
-                x @ ("RM" | "REMOVE" | "DELETE") => {
-                    if request.argument.len() == 1 {
-                        match self.store.remove(request.argument.get(0).unwrap().to_owned()) {
-                            Ok(_) => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::Success
-                            },
+                } else {
+                    KvsServer::wrong_argument_count("REMOVEIFEXISTS", 1..=1, request.argument.len())
+                }
+            },
+            
+            // Value read from the engine and sent in chunks after the reply, the result holds its length in bytes
+            "GETSTREAM" => {
+                if request.argument.len() == 1 {
+                    match session.store.get_reader(request.argument[0].as_bytes().to_vec()) {
+                        Ok(Some(value)) => {
+                            let len = value.len();
+                            session.stream = Some(value);
+                            KvsServerReply::ok(Some(len.to_string()))
+                        },
+                        
+                        Ok(None) => KvsServerReply::new(KvsServerReplyStatus::KeyNotFound, None),
+                        
+                        Err(err) => KvsServer::internal_error(err)
+                    }
+                } else {
+                    KvsServer::wrong_argument_count("GETSTREAM", 1..=1, request.argument.len())
+                }
+            },
+            
+            // Substring of the value between the inclusive byte indices, only the substring is sent
+            "GETRANGE" => {
+                match request.argument.as_slice() {
+                    [key, start, end] => {
+                        let parse = |index: &String| index.parse::<i64>()
+                            .map_err(|_| KvsError::InvalidArguments(format!("Invalid index {}", index)));
+                        let result = parse(start)
+                            .and_then(|start| Ok((start, parse(end)?)))
+                            .and_then(|(start, end)| session.store.get_range(key.to_owned(), start, end));
+                        match result {
+                            Ok(Some(value)) => KvsServerReply::ok(Some(value)),
                             
-                            Err(KvsError::KeyNotExist(_)) => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::KeyNotFound
-                            },
+                            Ok(None) => KvsServerReply::new(KvsServerReplyStatus::KeyNotFound, None),
                             
-                            _ => KvsServerReply {
-                                result: None,
-                                status: KvsServerReplyStatus::ServerInternalError
-                            }
+                            Err(err @ KvsError::InvalidArguments(_)) => KvsServer::invalid_arguments(err),
+                            
+                            Err(err) => KvsServer::internal_error(err)
                         }
-                    } else {
-                        KvsServerReply {
-                            result: Some(format!("`{}` command required 1 argument, provided {}",
-                                                 x, request.argument.len())),
-                            status: KvsServerReplyStatus::InvalidArguments
+                    },
+                    _ => KvsServer::wrong_argument_count("GETRANGE", 3..=3, request.argument.len())
+                }
+            },
+            
+            // Add the optional delta, one by default, to the integer value and reply the new value
+            "INCR" => {
+                match request.argument.as_slice() {
+                    [key] | [key, _] => {
+                        let delta = match request.argument.get(1) {
+                            Some(delta) => delta.parse::<i64>().map_err(|_| KvsError::InvalidArguments(format!("Invalid delta {}", delta))),
+                            None => Ok(1)
+                        };
+                        let result = KvStore::check_size(key.len(), self.options.store.max_key_size)
+                            .and_then(|_| self.options.store.key_validation.check(key.as_bytes()))
+                            .and(delta)
+                            .and_then(|delta| session.store.incr(key.to_owned(), delta));
+                        match result {
+                            Ok(value) => KvsServerReply::ok(Some(value.to_string())),
+                            
+                            Err(KvsError::InvalidArguments(message)) => {
+                                KvsServerReply::new(KvsServerReplyStatus::InvalidArguments, Some(message))
+                            },
+                            
+                            Err(err @ KvsError::InvalidKey(_)) => KvsServer::invalid_arguments(err),
+                            
+                            Err(KvsError::ValueTooLarge { size, limit }) => KvsServer::value_too_large(size, limit),
+                            
+                            Err(err) => KvsServer::internal_error(err)
                         }
+                    },
+                    _ => KvsServer::wrong_argument_count("INCR", 1..=2, request.argument.len())
+                }
+            },
+            
+            // Name of the logical type of the value
+            "TYPE" => {
+                if request.argument.len() == 1 {
+                    match session.store.type_of(request.argument[0].to_owned()) {
+                        Ok(Some(value_type)) => KvsServerReply::ok(Some(value_type.to_string())),
+                        
+                        Ok(None) => KvsServerReply::new(KvsServerReplyStatus::KeyNotFound, None),
+                        
+                        Err(err) => KvsServer::internal_error(err)
                     }
-                },
-                
-                // Termination
-                "KILL" => {
-                    if request.argument.is_empty() {
-                        self.need_termination.store(true, Ordering::Relaxed);
-                        KvsServerReply {
-                            result: None,
-                            status: KvsServerReplyStatus::Success
-                        }
-                    } else {
-                        KvsServerReply {
-                            result: Some(format!("`KILL` command required 0 argument, provided {}", request.argument.len())),
-                            status: KvsServerReplyStatus::InvalidArguments
+                } else {
+                    KvsServer::wrong_argument_count("TYPE", 1..=1, request.argument.len())
+                }
+            },
+            
+            // Page of the keys in the namespace, replied as the JSON array `[next_cursor, [keys...]]`
+            "SCAN" => {
+                match request.argument.as_slice() {
+                    [cursor, count] => {
+                        let result = count.parse::<usize>()
+                            .map_err(|_| KvsError::InvalidArguments(format!("Invalid count {}", count)))
+                            .and_then(|count| session.store.scan(cursor, count));
+                        match result {
+                            Ok(page) => KvsServerReply::ok(Some(serde_json::to_string(&page).unwrap())),
+                            
+                            Err(KvsError::InvalidArguments(message)) => {
+                                KvsServerReply::new(KvsServerReplyStatus::InvalidArguments, Some(message))
+                            },
+                            
+                            Err(err) => KvsServer::internal_error(err)
                         }
+                    },
+                    _ => KvsServer::wrong_argument_count("SCAN", 2..=2, request.argument.len())
+                }
+            },
+            
+            // Number of keys in the namespace of the session
+            "DBSIZE" => {
+                if request.argument.is_empty() {
+                    match session.store.len() {
+                        Ok(len) => KvsServerReply::ok(Some(len.to_string())),
+                        
+                        Err(err) => KvsServer::internal_error(err)
                     }
+                } else {
+                    KvsServer::wrong_argument_count("DBSIZE", 0..=0, request.argument.len())
                 }
-                
-                _ => {
-                    KvsServerReply {
-                        result: None,
-                        status: KvsServerReplyStatus::InvalidCommand
+            },
+            
+            // Scope the following requests on the connection to a namespace, the empty name selects the default one
+            "NAMESPACE" => {
+                if request.argument.len() == 1 {
+                    match self.store.namespace(request.argument.first().unwrap()) {
+                        Ok(store) => {
+                            session.store = store;
+                            KvsServerReply::ok(None)
+                        },
+                        
+                        Err(err) => KvsServer::internal_error(err)
                     }
+                } else {
+                    KvsServer::wrong_argument_count("NAMESPACE", 1..=1, request.argument.len())
                 }
-            };
-            // Send reply
-            stream.write_all(bson::to_vec(&reply)?.as_slice())?;
-        }
-        Ok(())
+            },
+            
+            "COMPACT" => {
+                if request.argument.is_empty() {
+                    match self.store.compact() {
+                        Ok(_) => KvsServerReply::ok(None),
+                        
+                        Err(err) => KvsServer::internal_error(err)
+                    }
+                } else {
+                    KvsServer::wrong_argument_count("COMPACT", 0..=0, request.argument.len())
+                }
+            },
+            
+            // Remove all keys of every namespace
+            "FLUSH" => {
+                if request.argument.is_empty() {
+                    match self.store.clear() {
+                        Ok(_) => KvsServerReply::ok(None),
+                        
+                        Err(err) => KvsServer::internal_error(err)
+                    }
+                } else {
+                    KvsServer::wrong_argument_count("FLUSH", 0..=0, request.argument.len())
+                }
+            },
+            
+            // Copy a snapshot of the database into the given directory under the backup directory
+            "BACKUP" => {
+                if request.argument.len() == 1 {
+                    match self.backup_path(request.argument.first().unwrap()) {
+                        Ok(dest) => match self.store.backup(&dest) {
+                            Ok(_) => KvsServerReply::ok(None),
+                            
+                            Err(err) => KvsServer::internal_error(err)
+                        },
+                        
+                        Err(err) => KvsServer::invalid_arguments(err)
+                    }
+                } else {
+                    KvsServer::wrong_argument_count("BACKUP", 1..=1, request.argument.len())
+                }
+            },
+            
+            // Counters and latency histogram in Prometheus text format
+            "METRICS" => {
+                if request.argument.is_empty() {
+                    KvsServerReply::ok(Some(self.metrics.render()))
+                } else {
+                    KvsServer::wrong_argument_count("METRICS", 0..=0, request.argument.len())
+                }
+            },
+            
+            // Authentication, only required if the server is opened with a token
+            "AUTH" => {
+                if request.argument.len() == 1 {
+                    if self.is_token_valid(request.argument.first().unwrap().as_bytes()) {
+                        session.authenticated = true;
+                        KvsServerReply::ok(None)
+                    } else {
+                        KvsServerReply::new(KvsServerReplyStatus::Unauthorized, None)
+                    }
+                } else {
+                    KvsServer::wrong_argument_count("AUTH", 1..=1, request.argument.len())
+                }
+            },
+            
+            // Termination
+            "KILL" => {
+                if request.argument.is_empty() {
+                    self.shutdown();
+                    KvsServerReply::ok(None)
+                } else {
+                    KvsServer::wrong_argument_count("KILL", 0..=0, request.argument.len())
+                }
+            }
+            
+            _ => {
+                KvsServerReply::new(KvsServerReplyStatus::InvalidCommand, None)
+            }
+        };
+        Ok(reply)
+    }
+}
+
+/// Read a single BSON document of at most `max_len` bytes from `reader`, returns `None` if the connection is closed
+/// before it starts
+pub(super) async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    // Each document is prefixed with its total length in little-endian
+    let mut len_bytes = [0; 4];
+    match reader.read_exact(&mut len_bytes).await {
+        Ok(_) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
+    }
+    let len = i32::from_le_bytes(len_bytes);
+    if len < 5 || len as usize > max_len { return Err(KvsError::UnknownProtocol) }
+    let mut frame = vec![0; len as usize];
+    frame[..4].copy_from_slice(&len_bytes);
+    reader.read_exact(&mut frame[4..]).await?;
+    Ok(Some(frame))
+}
+
+/// Blocking version of `read_frame`
+fn read_frame_blocking<R: Read>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(_) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
     }
+    let len = i32::from_le_bytes(len_bytes);
+    if len < 5 || len as usize > max_len { return Err(KvsError::UnknownProtocol) }
+    let mut frame = vec![0; len as usize];
+    frame[..4].copy_from_slice(&len_bytes);
+    reader.read_exact(&mut frame[4..])?;
+    Ok(Some(frame))
 }
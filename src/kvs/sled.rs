@@ -16,38 +16,290 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::PathBuf;
-use super::{KvsEngine, KvsError, Result};
+use std::fs::{File, TryLockError};
+use std::io::{self, Read, Write};
+use std::ops::{Bound, Deref};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{dump, engine, KvStoreOptions, KvsEngine, KvsError, Result, Utf8Mode, ValueReader};
 
 /// Sled storage engine
+///
+/// Writes are buffered by sled until `flush` or the periodic flush of sled, so callers can flush once after a batch
+/// of writes. `with_flush_on_write` flushes after every write instead.
 #[derive(Clone, Debug)]
 pub struct SledKvsEngine {
-    db: sled::Db
+    // Dropped before the database, so the last handle releases every reference to it
+    tree: sled::Tree, // Keyspace of the selected namespace, each namespace is a separate sled tree
+    db: Arc<SledDb>, // Shared by all handles of the database
+    utf8: Utf8Mode,
+    flush_on_write: bool
+}
+
+// Database shared by the handles of SledKvsEngine
+//
+// Dropping the last `sled::Db` hands the writes still in flight to the threads of sled, which keep the lock of the
+// database until they finish. The drop waits for the lock to be released, so the database can be opened again as soon
+// as the last handle is gone.
+#[derive(Debug)]
+struct SledDb {
+    db: Option<sled::Db>, // Only taken by drop
+    lock_path: PathBuf
+}
+
+impl Deref for SledDb {
+    type Target = sled::Db;
+    
+    fn deref(&self) -> &sled::Db {
+        self.db.as_ref().unwrap()
+    }
+}
+
+impl Drop for SledDb {
+    fn drop(&mut self) {
+        drop(self.db.take());
+        // Another process taking the lock right after the release is not waited for
+        if let Ok(file) = File::open(&self.lock_path) {
+            let deadline = Instant::now() + SledKvsEngine::RELEASE_TIMEOUT;
+            while matches!(file.try_lock(), Err(TryLockError::WouldBlock)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+/// Iterator over the key/value pairs of SledKvsEngine, created by `SledKvsEngine::iter`
+pub struct SledKvsIter {
+    iter: sled::Iter,
+    _db: Arc<SledDb> // Keep the database open while iterating, dropped after the iterator
+}
+
+impl SledKvsEngine {
+    // Longest wait for the threads of sled to release the lock of the database once the last handle is dropped
+    const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+    // Prefix of the names of the trees used by sled itself, `__sled__default` is the default namespace
+    const RESERVED_PREFIX: &'static str = "__sled__";
+    
+    /// Handle of the same database whose operations only see the keys of namespace `name`
+    ///
+    /// The empty name refers to the default namespace holding the keys stored without namespace. Each other namespace
+    /// is a tree of the database, created on first use and kept until the database is removed even if it holds no key,
+    /// so a server creates a tree for every name its clients select. Names starting with `__sled__` are reserved.
+    pub fn with_namespace(&self, name: &str) -> Result<SledKvsEngine> {
+        if name.starts_with(SledKvsEngine::RESERVED_PREFIX) {
+            return Err(KvsError::InvalidArguments(format!("Reserved namespace {}", name)))
+        }
+        let tree = if name.is_empty() { sled::Tree::clone(&self.db) } else { self.db.open_tree(name)? };
+        Ok(SledKvsEngine {
+            tree,
+            db: self.db.clone(),
+            utf8: self.utf8,
+            flush_on_write: self.flush_on_write
+        })
+    }
+    
+    /// Flush after every write, so each completed write survives a crash of the process
+    pub fn with_flush_on_write(mut self) -> SledKvsEngine {
+        self.flush_on_write = true;
+        self
+    }
+    
+    /// Flush the write just applied if every write must be flushed
+    fn written(&self) -> Result<()> {
+        if self.flush_on_write {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+    
+    /// Open the database in `path`, only `utf8` of the options applies to the sled engine
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<SledKvsEngine> {
+        let path = path.into();
+        let db = sled::open(&path)?;
+        Ok(SledKvsEngine {
+            tree: sled::Tree::clone(&db),
+            db: Arc::new(SledDb { db: Some(db), lock_path: path.join("db") }),
+            utf8: options.utf8,
+            flush_on_write: false
+        })
+    }
+    
+    /// Iterate over all key/value pairs of the namespace in key order
+    pub fn iter(&self) -> Result<SledKvsIter> {
+        Ok(SledKvsIter {
+            iter: self.tree.iter(),
+            _db: self.db.clone()
+        })
+    }
+    
+    /// Write all key/value pairs into `writer` in the engine independent dump format
+    pub fn export<W: Write>(&self, writer: W) -> Result<()> {
+        dump::export(self.iter()?, writer)
+    }
+    
+    /// Load key/value pairs from a dump created by `export`, returns the number of imported pairs
+    pub fn import<R: Read>(&self, reader: R) -> Result<usize> {
+        dump::import(self, reader)
+    }
+}
+
+impl Iterator for SledKvsIter {
+    type Item = Result<(String, String)>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|entry| {
+            let (key, value) = entry?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+        })
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
-        // Add flush
-        self.db.flush()?;
-        Ok(())
+        self.set_bytes(key.into_bytes(), value.into_bytes())
     }
     
     fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self.db.get(key.as_bytes())?.map(|result| String::from_utf8_lossy(result.as_ref()).to_string()))
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(self.utf8.decode(value)?)),
+            None => Ok(None)
+        }
+    }
+    
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let written = self.tree.compare_and_swap(key.as_bytes(), None as Option<&[u8]>, Some(value.as_bytes()))?.is_ok();
+        if written {
+            self.written()?;
+        }
+        Ok(written)
+    }
+    
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        // Retry until the value is replaced without being modified concurrently
+        loop {
+            let current = match self.tree.get(key.as_bytes())? {
+                Some(current) => current,
+                None => return Ok(false)
+            };
+            if self.tree.compare_and_swap(key.as_bytes(), Some(current), Some(value.as_bytes()))?.is_ok() {
+                self.written()?;
+                return Ok(true)
+            }
+        }
+    }
+    
+    fn append(&self, key: String, value: String) -> Result<usize> {
+        // Sled retries the closure until the value is replaced without being modified concurrently
+        let appended = self.tree.update_and_fetch(key.as_bytes(), |current| {
+            let mut appended = current.map(<[u8]>::to_vec).unwrap_or_default();
+            appended.extend_from_slice(value.as_bytes());
+            Some(appended)
+        })?;
+        self.written()?;
+        Ok(appended.map_or(0, |appended| appended.len()))
     }
     
     fn remove(&self, key: String) -> Result<()> {
-        if self.db.remove(key.as_bytes())?.is_some() {
-            // Add flush
-            self.db.flush()?;
+        if self.tree.remove(key.as_bytes())?.is_some() {
+            self.written()?;
             Ok(())
         } else { Err(KvsError::KeyNotExist(key)) }
     }
     
+    fn remove_optional(&self, key: String) -> Result<bool> {
+        let removed = self.tree.remove(key.as_bytes())?.is_some();
+        if removed {
+            self.written()?;
+        }
+        Ok(removed)
+    }
+    
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut count = 0;
+        for entry in self.tree.scan_prefix(prefix.as_bytes()) {
+            let (key, _) = entry?;
+            if self.tree.remove(key)?.is_some() {
+                count += 1;
+            }
+        }
+        self.written()?;
+        Ok(count)
+    }
+    
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        let start = match engine::decode_cursor(cursor, count)? {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded
+        };
+        let mut keys = Vec::new();
+        for entry in self.tree.range::<Vec<u8>, _>((start, Bound::Unbounded)).take(count + 1) {
+            keys.push(entry?.0.to_vec());
+        }
+        let is_last = keys.len() <= count;
+        keys.truncate(count);
+        let next_cursor = if is_last { "0".to_owned() } else { engine::encode_cursor(keys.last().unwrap()) };
+        Ok((next_cursor, keys.into_iter().map(String::from_utf8).collect::<std::result::Result<_, _>>()?))
+    }
+    
+    fn len(&self) -> Result<usize> {
+        Ok(self.tree.len())
+    }
+    
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.tree.insert(key, value)?;
+        self.written()?;
+        Ok(())
+    }
+    
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.map(|result| result.to_vec()))
+    }
+    
+    /// Sled keeps the values it reads in its page cache, so the value is shared with the cache instead of copied
+    fn get_reader(&self, key: Vec<u8>) -> Result<Option<ValueReader>> {
+        Ok(self.tree.get(key)?.map(|value| ValueReader::new(value.len() as u64, io::Cursor::new(value))))
+    }
+    
+    fn compact(&self) -> Result<()> {
+        // Sled manages its own space reclamation
+        self.db.flush()?;
+        Ok(())
+    }
+    
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+    
+    fn clear(&self) -> Result<()> {
+        for name in self.db.tree_names().into_iter() {
+            self.db.open_tree(&name)?.clear()?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+    
+    fn backup(&self, dest: &Path) -> Result<()> {
+        // Sled offers no point-in-time view, pairs updated during the copy may be either version
+        let backup = sled::open(dest)?;
+        for name in self.db.tree_names().into_iter() {
+            let tree = backup.open_tree(&name)?;
+            for entry in self.db.open_tree(&name)?.iter() {
+                let (key, value) = entry?;
+                tree.insert(key, value)?;
+            }
+        }
+        backup.flush()?;
+        Ok(())
+    }
+    
+    fn namespace(&self, name: &str) -> Result<Box<dyn KvsEngine + Sync>> {
+        Ok(Box::new(self.with_namespace(name)?))
+    }
+    
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        Ok(SledKvsEngine {
-            db: sled::open(path.into())?
-        })
+        SledKvsEngine::open_with_options(path, KvStoreOptions::default())
     }
 }
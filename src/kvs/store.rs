@@ -16,96 +16,1037 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::cmp::max;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, RwLock, Weak};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
-use super::{KvsEngine, KvsError, Result};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::bloom::BloomFilter;
+use super::cache::LruCache;
+use super::index::{BoundedIndex, PositionMap};
+use super::codec::{Codec, CodecKind};
+use super::util::{SharedQueueThreadPool, ThreadPool};
+use super::{dump, engine, migration, KeyValidation, KvsEngine, KvsError, Result, Utf8Mode, ValueReader, ValueType};
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 struct KvStoreInt {
     header: KvHeader,
-    index: HashMap<String, u64>,
+    index: BoundedIndex<KvsEntryPos>,
+    tombstones: HashMap<Vec<u8>, KvsEntryPos>, // Removal entries of the active segment, shadowing older in-flight entries
+    segments: BTreeMap<u64, u64>, // Size of immutable segments
     modified: bool, // Trigger index update when drop
     db_path: PathBuf,
-    index_path: PathBuf
+    index_path: PathBuf,
+    index_mode: IndexMode,
+    read_only: bool, // Leave all files untouched on drop
+    file_checksum: bool, // Save the checksum of the segment files on drop
+    bloom: Arc<RwLock<BloomFilter>>, // Shared with KvStore, saved along with the index
+    wal: Option<Arc<Mutex<File>>>, // Shared with KvStore, emptied once the segment files are synced
+    namespaced: bool, // Whether the index may hold keys of a namespace, then counting the keys needs a scan
+    reindexed: bool, // Test hook for index reuse
+    fail_compaction_after: Option<usize>, // Test hook for interrupted compaction
+    compaction_delay: Option<Duration>, // Test hook for slow compaction
+    lock: Option<File> // Exclusive lock of the database while opened for writing, released after the header is written
 }
 
 #[derive(Clone, Debug)]
 pub struct KvStore {
     store: Arc<RwLock<KvStoreInt>>,
     compaction_guard: Arc<RwLock<()>>,
+    compaction_lock: Arc<Mutex<()>>, // Only one compaction may run at a time
+    update_lock: Arc<Mutex<()>>, // Serialize read-modify-write operations such as incr
     db_path: Box<PathBuf>,
-    db_offset: Arc<AtomicU64> // Next writable database file offset
+    active_segment: Arc<AtomicU64>, // Segment receiving new entries
+    db_offset: Arc<AtomicU64>, // Next writable offset of the active segment
+    handles: Arc<Mutex<HashMap<u64, Vec<File>>>>, // Cached segment file handles, only valid until next compaction
+    maps: Arc<Mutex<HashMap<u64, Arc<Mmap>>>>, // Memory-mapped segment files, only valid until next compaction
+    options: Arc<KvStoreOptions>,
+    cache: Option<Arc<Mutex<LruCache<KvsEntryPos>>>>, // Recently read values
+    disk_reads: Arc<AtomicU64>, // Number of values read from the segment files
+    injected_interrupts: Arc<AtomicU64>, // Test hook for writes interrupted by signals
+    counters: Arc<KvStoreCounters>, // Shared by all handles and namespaces of the store
+    bloom: Arc<RwLock<BloomFilter>>, // Answer reads of missing keys without locking the index
+    wal: Option<Arc<Mutex<File>>>, // Write-ahead log receiving every entry before the segment
+    group_commit: Option<Arc<GroupCommit>>, // Sync of the write-ahead log shared by concurrent writers
+    namespace: Vec<u8>, // Prefix of the stored keys of the selected namespace, empty for the default namespace
+    compactor: Option<Arc<Compactor>>,
+    observer: Arc<CompactionObserver>,
+    origin: Option<Arc<KvStore>> // Store returned by the first open, registered while any of its handles is alive
 }
 
+// Stores opened for writing in the process by the path of their database file, so they are shared by later opens
+static OPENED_STORES: LazyLock<Mutex<HashMap<PathBuf, Weak<KvStore>>>> = LazyLock::new(Default::default);
+
+/// Options for opening KvStore
+#[derive(Clone, Debug, PartialEq)]
+pub struct KvStoreOptions {
+    /// Run compaction on a dedicated thread instead of blocking the writer which reaches the threshold
+    pub background_compaction: bool,
+    /// Size in byte after which the active segment is frozen and a new one is started
+    pub segment_size: u64,
+    /// Compression applied to the values of new entries
+    pub compression: Compression,
+    /// Total length in byte of the recently read values kept in memory, zero disables the cache
+    pub cache_capacity: u64,
+    /// Target false positive rate of the bloom filter over the keys, must be between 0 and 1
+    pub bloom_false_positive_rate: f64,
+    /// Total size in byte of the segments before the first automatic compaction, the following ones are run
+    /// according to `compaction_policy`. `None` disables automatic compaction.
+    pub compaction_threshold: Option<u64>,
+    /// How the size triggering the next automatic compaction is computed after a compaction
+    pub compaction_policy: CompactionPolicy,
+    /// Automatic compaction is only run once the dead entries make up more than this fraction of the segments
+    pub compaction_dead_ratio: f64,
+    /// Defer automatic compaction until no entry has been written for this long, so it does not compete with bursts
+    /// of writes. Setting it runs the compaction on a dedicated thread as `background_compaction` does.
+    pub compaction_idle_window: Option<Duration>,
+    /// Maximum length in byte of a key
+    pub max_key_size: u64,
+    /// Keys accepted when a value is written, also checked by the server for the sled engine
+    pub key_validation: KeyValidation,
+    /// Maximum length in byte of a value before compression
+    pub max_value_size: u64,
+    /// Append every entry to the write-ahead log `kvs.wal` and sync it before writing the segment
+    pub wal: bool,
+    /// Whether the index is saved to the index file or rebuilt from the segment files on every open
+    pub index_mode: IndexMode,
+    /// Number of threads scanning the segment files in parallel when the index is rebuilt
+    pub reindex_threads: u32,
+    /// Open the database files without write access, all modifications are rejected with `KvsError::ReadOnly`
+    pub read_only: bool,
+    /// Codec of the entries of a new database, an existing database keeps the codec it was created with
+    pub codec: CodecKind,
+    /// Read the values from memory-mapped segment files instead of seeking and reading a file handle
+    pub mmap: bool,
+    /// Handling of values which are not valid UTF-8 in `get`, also applied by `SledKvsEngine::open_with_options`
+    pub utf8: Utf8Mode,
+    /// Save a checksum of the segment files in the header on graceful exit, verified on next open
+    ///
+    /// Computing and verifying the checksum reads all segment files, the checksum of a database is verified on open
+    /// regardless of this option.
+    pub file_checksum: bool,
+    /// Skip writing a string value equal to the current value of the key, which costs a read before every write
+    pub skip_noop_writes: bool,
+    /// Estimated memory usage in byte of the keys kept in the index, `None` keeps every key in memory
+    ///
+    /// The least recently used keys beyond it are spilled to a sorted file, and looked up from there when read.
+    /// The whole index is still loaded in memory while the database is opened.
+    pub index_memory_limit: Option<u64>
+}
+
+/// Persistence of the index of KvStore
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Save the index and the bloom filter on exit, and reuse them on next open when they are up to date
+    Persisted,
+    /// Never write the index file, the index is rebuilt by scanning the segment files on every open
+    AlwaysRebuild
+}
+
+/// Policy computing the total size of the segments triggering the next automatic compaction
+///
+/// The result is never lower than the compaction threshold. `live` is the size of the live entries and `total` is
+/// the size of all segments, both measured when the compaction completes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    /// Twice the total size when the compaction started
+    Doubling,
+    /// `live / ratio`, so compaction is run once the live entries make up less than `ratio` of the segments
+    LiveDataRatio(f64),
+    /// `total + interval`, so compaction is run after every `interval` bytes written
+    FixedInterval(u64),
+    /// `live * (1 + fraction)`, so compaction is run once the dead entries exceed `fraction` of the live entries
+    DeadByteThreshold(f64)
+}
+
+/// Compression algorithm for values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Zstandard with the given compression level
+    Zstd(i32)
+}
+
+/// Iterator over the key/value pairs of KvStore, created by `KvStore::iter`
+///
+/// The keys are taken at creation, keys removed afterward are skipped and keys added afterward are not visited
+pub struct KvStoreIter {
+    store: KvStore,
+    keys: std::vec::IntoIter<Vec<u8>> // Stored keys including the namespace prefix
+}
+
+/// Result of `KvStore::repair`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of valid entries kept
+    pub recovered: usize,
+    /// Number of corrupt regions skipped, each of them held at least one entry
+    pub dropped: usize
+}
+
+/// Result of `KvStore::validate`, the database is consistent if `issues` is empty
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Number of readable entries in the segment files
+    pub entries: usize,
+    /// Number of keys in the index file
+    pub indexed_keys: usize,
+    /// Inconsistencies in the order they were found
+    pub issues: Vec<ValidationIssue>
+}
+
+/// Inconsistency found by `KvStore::validate`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// The database was written by another build, the segment files are not checked
+    VersionMismatch { found: u64, expected: u64 },
+    /// Unreadable bytes starting at `offset` of the segment, the following entries are lost until the next valid one
+    CorruptEntry { segment: u64, offset: u64 },
+    /// The index file has no footer, it is truncated or unreadable after `indexed_keys` entries
+    IncompleteIndex,
+    /// The index file was saved at an older generation, so it would be rebuilt on open
+    StaleIndex { found: Option<u64>, expected: u64 },
+    /// The index file refers to a position which is not an entry setting `key`
+    DanglingIndexEntry { key: Vec<u8>, segment: u64, offset: u64 },
+    /// The index file refers to an entry of `key` shadowed by a later entry
+    OutdatedIndexEntry { key: Vec<u8>, segment: u64, offset: u64 },
+    /// `key` is live in the segment files but missing from the index file
+    MissingIndexEntry { key: Vec<u8> }
+}
+
+/// Snapshot of the counters of KvStore, created by `KvStore::metrics`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KvStoreMetrics {
+    /// Number of entries written, including removals
+    pub writes: u64,
+    /// Number of lookups, including missing keys
+    pub reads: u64,
+    /// Number of compactions completed
+    pub compactions: u64,
+    /// Size in byte freed by compactions
+    pub reclaimed_bytes: u64,
+    /// Number of keys currently stored in every namespace
+    pub live_keys: u64
+}
+
+/// Sizes deciding the automatic compaction of KvStore, created by `KvsEngine::compaction_info`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionInfo {
+    /// Total size in byte of the segment files
+    pub current_size: u64,
+    /// Total size of the segments triggering the next automatic compaction, `None` if it is disabled
+    pub next_compaction_size: Option<u64>,
+    /// Estimated size in byte of the shadowed entries, which are reclaimed by compaction
+    pub dead_bytes: u64
+}
+
+/// Progress of a running compaction, passed to the observer set by `KvStore::set_compaction_observer`
+///
+/// The number of entries in the merged segments is only known once they are read, so the total is given in byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Number of entries read from the merged segments so far
+    pub entries_processed: u64,
+    /// Number of entries copied into the compacted segment so far
+    pub entries_copied: u64,
+    /// Size in byte of the entries read from the merged segments so far
+    pub bytes_processed: u64,
+    /// Total size in byte of the merged segments
+    pub bytes_total: u64
+}
+
+type ProgressCallback = Box<dyn Fn(CompactionProgress) + Send + Sync>;
+
+// Callback receiving the progress of compactions, shared by all handles of the store
+#[derive(Default)]
+struct CompactionObserver(RwLock<Option<ProgressCallback>>);
+
+impl fmt::Debug for CompactionObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.read().unwrap().is_some() { "CompactionObserver(Some)" } else { "CompactionObserver(None)" })
+    }
+}
+
+// Counters behind KvStoreMetrics
+#[derive(Debug, Default)]
+struct KvStoreCounters {
+    writes: AtomicU64,
+    reads: AtomicU64,
+    compactions: AtomicU64,
+    reclaimed_bytes: AtomicU64,
+    last_write: AtomicU64 // Milliseconds since UNIX epoch when the latest entry was written
+}
+
+// Background compaction thread, stopped when the last KvStore handle is dropped
+#[derive(Debug)]
+struct Compactor {
+    signal: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<()>>
+}
+
+// Writers waiting for their records of the write-ahead log to be durable, one of them syncs the log for all
+#[derive(Debug)]
+struct GroupCommit {
+    file: File, // Handle of the log used for syncing, so other writers can append meanwhile
+    state: Mutex<CommitState>,
+    synced: Condvar
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    appended: u64, // Sequence number of the last record appended to the log
+    synced: u64, // Sequence number of the last record known to be durable
+    syncing: bool, // Whether a writer is syncing the log on behalf of the others
+    commits: u64 // Number of completed syncs
+}
+
+// Location of an entry in the segment files
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct KvsEntryPos {
+    segment: u64,
+    offset: u64,
+    len: u64
+}
+
+// In-disk data format for KvStore write-ahead log records, the raw entry and its location in the segment files
+#[derive(Serialize, Deserialize, Debug)]
+struct KvsWalRecords {
+    segment: u64,
+    offset: u64,
+    #[serde(with = "serde_bytes")]
+    entry: Vec<u8>
+}
+
+// Location of the latest entry of every live key
+type KvsIndex = HashMap<Vec<u8>, KvsEntryPos>;
+
 // In-disk data format for KvStore database file entries
+// Variant names are part of the on-disk format
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, Debug)]
-enum KvsEntries {
-    SET(String, String),
-    DELETE(String)
+pub(super) enum KvsEntries {
+    // Key, value and flag of the value, the low four bits of the flag are the compression and the high four bits are
+    // the type tag, entries written before build 1500 have no flag and untagged entries hold strings
+    SET(#[serde(with = "serde_bytes")] Vec<u8>, #[serde(with = "serde_bytes")] Vec<u8>, #[serde(default)] u8),
+    DELETE(#[serde(with = "serde_bytes")] Vec<u8>)
 }
 
 // In-disk data format for KvStore index file entries
 #[derive(Serialize, Deserialize, Debug)]
 struct KvsIndexEntries {
-    key: String,
-    offset: u64
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    segment: u64,
+    offset: u64,
+    len: u64
 }
 
-// In-disk data format for KvStore database file header
+// In-disk data format for KvStore index file footer, written after all entries
 #[derive(Serialize, Deserialize, Debug)]
-struct KvHeader {
-    build_number: u64,
-    last_open: u64,
-    next_compaction_size: u64,
+struct KvsIndexFooter {
+    generation: u64
+}
+
+// Records of KvStore index file, index files written before the footer was introduced have no footer
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum KvsIndexRecords {
+    Entry(KvsIndexEntries),
+    Footer(KvsIndexFooter)
+}
+
+// In-disk data format for KvStore database file header
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(super) struct KvHeader {
+    pub(super) build_number: u64,
+    pub(super) last_open: u64,
+    pub(super) next_compaction_size: u64,
+    // Total size of the entries shadowed by later entries, headers before build 1500 have no counter
+    #[serde(default)]
+    pub(super) dead_bytes: u64,
+    // Total size of the entries written since the last compaction, headers before build 1600 have no counter
+    #[serde(default)]
+    pub(super) total_written: u64,
+    // Increased before the index file becomes outdated, the index file is valid if its footer holds the same number
+    #[serde(default)]
+    pub(super) generation: u64,
+    // Id of the codec of the entries, headers before the codec was selectable have BSON entries
+    #[serde(default)]
+    pub(super) codec: u8,
+    // Checksum of the segment files saved on graceful exit if `file_checksum` is set
+    #[serde(default)]
+    pub(super) checksum: Option<u64>,
     // in byte
     // 0x1: is_last_graceful_exit
-    flags: u64
+    pub(super) flags: u64
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            background_compaction: false,
+            segment_size: KvStore::DEFAULT_SEGMENT_SIZE,
+            compression: Compression::None,
+            cache_capacity: 0,
+            bloom_false_positive_rate: 0.01,
+            compaction_threshold: Some(KvStore::MIN_COMPACTION_THRESHOLD),
+            compaction_policy: CompactionPolicy::Doubling,
+            compaction_dead_ratio: 0.3,
+            compaction_idle_window: None,
+            max_key_size: 1 << 20,
+            key_validation: KeyValidation::Permissive,
+            max_value_size: 64 << 20,
+            wal: false,
+            index_mode: IndexMode::Persisted,
+            reindex_threads: thread::available_parallelism().map_or(1, |threads| threads.get() as u32),
+            read_only: false,
+            codec: CodecKind::Bson,
+            mmap: false,
+            utf8: Utf8Mode::Strict,
+            file_checksum: false,
+            skip_noop_writes: false,
+            index_memory_limit: None
+        }
+    }
+}
+
+/// Chainable configuration of KvStore, see `KvStoreOptions` for the meaning of each option
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    options: KvStoreOptions
+}
+
+impl KvStoreBuilder {
+    /// Builder starting from the default options
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+    
+    /// Run compaction on a dedicated thread
+    pub fn background_compaction(mut self, background_compaction: bool) -> Self {
+        self.options.background_compaction = background_compaction;
+        self
+    }
+    
+    /// Size in byte after which a new segment is started
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.options.segment_size = segment_size;
+        self
+    }
+    
+    /// Compression of the values of new entries
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+    
+    /// Total length in byte of the cached values, zero disables the cache
+    pub fn cache_capacity(mut self, cache_capacity: u64) -> Self {
+        self.options.cache_capacity = cache_capacity;
+        self
+    }
+    
+    /// Target false positive rate of the bloom filter
+    pub fn bloom_false_positive_rate(mut self, bloom_false_positive_rate: f64) -> Self {
+        self.options.bloom_false_positive_rate = bloom_false_positive_rate;
+        self
+    }
+    
+    /// Size in byte before the first automatic compaction, `None` disables it
+    pub fn compaction_threshold(mut self, compaction_threshold: Option<u64>) -> Self {
+        self.options.compaction_threshold = compaction_threshold;
+        self
+    }
+    
+    /// Policy computing the size of the next automatic compaction
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.options.compaction_policy = compaction_policy;
+        self
+    }
+    
+    /// Fraction of dead entries required by automatic compaction
+    pub fn compaction_dead_ratio(mut self, compaction_dead_ratio: f64) -> Self {
+        self.options.compaction_dead_ratio = compaction_dead_ratio;
+        self
+    }
+    
+    /// Time without writes before automatic compaction may run
+    pub fn compaction_idle_window(mut self, compaction_idle_window: Option<Duration>) -> Self {
+        self.options.compaction_idle_window = compaction_idle_window;
+        self
+    }
+    
+    /// Maximum length in byte of a key
+    pub fn max_key_size(mut self, max_key_size: u64) -> Self {
+        self.options.max_key_size = max_key_size;
+        self
+    }
+    
+    /// Keys accepted when a value is written
+    pub fn key_validation(mut self, key_validation: KeyValidation) -> Self {
+        self.options.key_validation = key_validation;
+        self
+    }
+    
+    /// Maximum length in byte of a value
+    pub fn max_value_size(mut self, max_value_size: u64) -> Self {
+        self.options.max_value_size = max_value_size;
+        self
+    }
+    
+    /// Sync every entry to the write-ahead log before it is written
+    pub fn wal(mut self, wal: bool) -> Self {
+        self.options.wal = wal;
+        self
+    }
+    
+    /// Persistence of the index
+    pub fn index_mode(mut self, index_mode: IndexMode) -> Self {
+        self.options.index_mode = index_mode;
+        self
+    }
+    
+    /// Number of threads rebuilding the index
+    pub fn reindex_threads(mut self, reindex_threads: u32) -> Self {
+        self.options.reindex_threads = reindex_threads;
+        self
+    }
+    
+    /// Open without modifying any file
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+    
+    /// Codec of the entries of a new database
+    pub fn codec(mut self, codec: CodecKind) -> Self {
+        self.options.codec = codec;
+        self
+    }
+    
+    /// Read the values from memory-mapped segment files
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.mmap = mmap;
+        self
+    }
+    
+    /// Handling of values which are not valid UTF-8
+    pub fn utf8(mut self, utf8: Utf8Mode) -> Self {
+        self.options.utf8 = utf8;
+        self
+    }
+    
+    /// Save a checksum of the segment files on graceful exit
+    pub fn file_checksum(mut self, file_checksum: bool) -> Self {
+        self.options.file_checksum = file_checksum;
+        self
+    }
+    
+    /// Skip writing a string value equal to the current value of the key
+    pub fn skip_noop_writes(mut self, skip_noop_writes: bool) -> Self {
+        self.options.skip_noop_writes = skip_noop_writes;
+        self
+    }
+    
+    /// Estimated memory usage of the keys kept in the index
+    pub fn index_memory_limit(mut self, index_memory_limit: Option<u64>) -> Self {
+        self.options.index_memory_limit = index_memory_limit;
+        self
+    }
+    
+    /// Options collected so far
+    pub fn options(&self) -> &KvStoreOptions {
+        &self.options
+    }
+    
+    /// Create or open KvStore instance at `path` with the collected options, same as `KvStore::open_with_options`
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.options)
+    }
+}
+
+impl From<KvStoreOptions> for KvStoreBuilder {
+    fn from(options: KvStoreOptions) -> Self {
+        KvStoreBuilder { options }
+    }
 }
 
 impl KvsEngine for KvStore {
     /// Set the value of a string key to a string
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writeback(KvsEntries::SET(key, value))?;
-        // Check if compaction condition meet
-        self.check_compaction()?;
-        Ok(())
+        self.set_bytes(key.into_bytes(), value.into_bytes())
     }
     
     /// Get the string value of a given string key
     fn get(&self, key: String) -> Result<Option<String>> {
-        self.fetch(key)
+        match self.fetch(self.scoped(key.into_bytes()))? {
+            Some(value) => Ok(Some(self.options.utf8.decode(value)?)),
+            None => Ok(None)
+        }
+    }
+    
+    /// Set the value of a string key only if the key does not exist
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, false)
+    }
+    
+    /// Set the value of a string key only if the key already exists
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, true)
     }
     
     /// Remove a given key `key`
     fn remove(&self, key: String) -> Result<()> {
-        if !self.store.read().unwrap().index.contains_key(&key) { return Err(KvsError::KeyNotExist(key)) }
-        self.writeback(KvsEntries::DELETE(key))?;
+        let scoped_key = self.scoped(key.as_bytes().to_vec());
+        if !self.store.read().unwrap().index.contains_key(&scoped_key) { return Err(KvsError::KeyNotExist(key)) }
+        self.writeback(KvsEntries::DELETE(scoped_key))?;
+        self.check_compaction()?;
+        Ok(())
+    }
+    
+    /// Remove a given key if it exists, concurrent removals of the same key only remove it once
+    fn remove_optional(&self, key: String) -> Result<bool> {
+        let scoped_key = self.scoped(key.into_bytes());
+        let removed = self.writeback_if(KvsEntries::DELETE(scoped_key.clone()), |index| index.contains_key(&scoped_key))?;
+        if removed {
+            self.check_compaction()?;
+        }
+        Ok(removed)
+    }
+    
+    /// Remove all keys starting with `prefix`
+    ///
+    /// The matching keys are collected first, then removed one by one, so concurrent readers may see some of them
+    /// removed until it returns.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let scoped_prefix = self.scoped(prefix.as_bytes().to_vec());
+        let keys = self.store.read().unwrap().index.keys()
+            .filter(|key| key.starts_with(&scoped_prefix) && self.in_namespace(key))
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+        let count = keys.len();
+        for key in keys.into_iter() {
+            self.writeback(KvsEntries::DELETE(key))?;
+        }
+        self.check_compaction()?;
+        Ok(count)
+    }
+    
+    /// Add `delta` to the integer value of `key`, values of other types are rejected with `KvsError::WrongType`
+    ///
+    /// Concurrent increments of the same key are serialized, but a concurrent `set` may be overwritten.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        let _update = self.update_lock.lock().unwrap();
+        let scoped_key = self.scoped(key.into_bytes());
+        let current = match self.fetch_typed(&scoped_key)? {
+            Some((value, ValueType::Int)) => String::from_utf8(value)?.parse::<i64>().map_err(|_| KvsError::InvalidDataEntry)?,
+            Some(_) => return Err(KvsError::WrongType),
+            None => 0
+        };
+        let value = current.checked_add(delta).ok_or_else(|| KvsError::InvalidArguments("Increment would overflow".to_owned()))?;
+        let (stored, flag) = self.compress(value.to_string().into_bytes())?;
+        self.writeback(KvsEntries::SET(scoped_key, stored, flag | ValueType::Int.id() << KvStore::FLAG_TYPE_SHIFT))?;
+        self.check_compaction()?;
+        Ok(value)
+    }
+    
+    /// Get the type tag of the value of a given string key, always read from the segment files
+    fn type_of(&self, key: String) -> Result<Option<ValueType>> {
+        Ok(self.fetch_typed(&self.scoped(key.into_bytes()))?.map(|(_, value_type)| value_type))
+    }
+    
+    /// Return the next page of keys of the namespace, sorted from a snapshot of the index on every call
+    fn scan(&self, cursor: &str, count: usize) -> Result<(String, Vec<String>)> {
+        let after = engine::decode_cursor(cursor, count)?;
+        let mut keys = self.store.read().unwrap().index.keys()
+            .filter(|key| self.in_namespace(key))
+            .map(|key| &key[self.prefix_len(key)..])
+            .filter(|key| after.as_ref().is_none_or(|after| *key > after.as_slice()))
+            .map(|key| key.to_vec())
+            .collect::<Vec<_>>();
+        // Only the smallest keys of the page need to be sorted
+        let is_last = keys.len() <= count;
+        if !is_last {
+            keys.select_nth_unstable(count);
+            keys.truncate(count);
+        }
+        keys.sort_unstable();
+        let next_cursor = if is_last { "0".to_owned() } else { engine::encode_cursor(keys.last().unwrap()) };
+        Ok((next_cursor, keys.into_iter().map(String::from_utf8).collect::<std::result::Result<_, _>>()?))
+    }
+    
+    /// Number of keys in the namespace, only the default namespace of a database without namespaces is O(1)
+    fn len(&self) -> Result<usize> {
+        let store = self.store.read().unwrap();
+        if self.namespace.is_empty() && !store.namespaced {
+            return Ok(store.index.len())
+        }
+        Ok(store.index.keys().filter(|key| self.in_namespace(key)).count())
+    }
+    
+    /// Set the value of a binary key to a binary value
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let key = self.scoped(key);
+        if self.options.skip_noop_writes && !self.options.read_only {
+            // A concurrent write of another value is ordered after the skipped one
+            if let Some((current, ValueType::String)) = self.fetch_typed(&key)? {
+                if current == value { return Ok(()) }
+            }
+        }
+        let (value, flag) = self.compress(value)?;
+        self.writeback(KvsEntries::SET(key, value, flag | ValueType::String.id() << KvStore::FLAG_TYPE_SHIFT))?;
+        // Check if compaction condition meet
         self.check_compaction()?;
         Ok(())
     }
     
+    /// Get the binary value of a given binary key
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.fetch(self.scoped(key))
+    }
+    
+    /// Reader of the binary value of a given binary key, read from the segment file as it is consumed
+    ///
+    /// A compressed value is decompressed twice in pieces, once to learn its length.
+    fn get_reader(&self, key: Vec<u8>) -> Result<Option<ValueReader>> {
+        let key = self.scoped(key);
+        let may_contain = self.bloom.read().unwrap().contains(&key);
+        if !may_contain { return Ok(None) }
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        let _lock = self.compaction_guard.read().unwrap(); // Block segment switching until the segment is opened
+        let pos = match self.lookup(&key)? {
+            Some(pos) => pos,
+            None => return Ok(None)
+        };
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        // Handle of its own, the segment stays readable through it even if compaction removes it meanwhile
+        let mut handle = File::open(KvStore::segment_path(&self.db_path, pos.segment))?;
+        let (offset, len, flag) = match self.locate_value(&mut handle, &key, pos)? {
+            Some(location) => location,
+            None => {
+                let (value, _) = self.read_value(&key, pos)?;
+                return Ok(Some(ValueReader::new(value.len() as u64, io::Cursor::new(value))))
+            }
+        };
+        handle.seek(SeekFrom::Start(offset))?;
+        match flag & KvStore::FLAG_COMPRESSION_MASK {
+            KvStore::FLAG_UNCOMPRESSED => Ok(Some(ValueReader::new(len, BufReader::new(handle)))),
+            KvStore::FLAG_ZSTD => {
+                let mut decoder = zstd::stream::read::Decoder::new((&handle).take(len))?;
+                let decoded_len = io::copy(&mut decoder, &mut io::sink())?;
+                handle.seek(SeekFrom::Start(offset))?;
+                Ok(Some(ValueReader::new(decoded_len, zstd::stream::read::Decoder::new(handle.take(len))?)))
+            },
+            _ => Err(KvsError::InvalidDataEntry)
+        }
+    }
+    
+    /// Append to the string or integer value of a given string key, the result is a string
+    fn append(&self, key: String, value: String) -> Result<usize> {
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        let _update = self.update_lock.lock().unwrap();
+        let scoped_key = self.scoped(key.into_bytes());
+        let mut current = match self.fetch_typed(&scoped_key)? {
+            Some((_, ValueType::List)) => return Err(KvsError::WrongType),
+            Some((current, _)) => current,
+            None => Vec::new()
+        };
+        current.extend_from_slice(value.as_bytes());
+        let len = current.len();
+        let (stored, flag) = self.compress(current)?;
+        self.writeback(KvsEntries::SET(scoped_key, stored, flag | ValueType::String.id() << KvStore::FLAG_TYPE_SHIFT))?;
+        self.check_compaction()?;
+        Ok(len)
+    }
+    
+    /// Compact the database file immediately
+    fn compact(&self) -> Result<()> {
+        self.force_compaction()
+    }
+    
+    /// Number of compactions completed since opened, including the automatic ones
+    fn compactions(&self) -> u64 {
+        self.counters.compactions.load(Ordering::Relaxed)
+    }
+    
+    /// Current size of the segment files and the size triggering the next automatic compaction
+    fn compaction_info(&self) -> Option<CompactionInfo> {
+        let store = self.store.read().unwrap();
+        Some(CompactionInfo {
+            current_size: self.total_size(&store),
+            next_compaction_size: self.options.compaction_threshold.map(|_| store.header.next_compaction_size),
+            dead_bytes: store.header.dead_bytes
+        })
+    }
+    
+    /// Sync the active segment and rewrite the index file if modified
+    fn flush(&self) -> Result<()> {
+        // Nothing is ever written
+        if self.options.read_only { return Ok(()) }
+        let _lock = self.compaction_guard.write().unwrap(); // Block writes and segment switching until completed
+        let segment = self.active_segment.load(Ordering::Relaxed);
+        let handle = self.acquire_handle(segment)?;
+        handle.sync_all()?;
+        self.release_handle(segment, handle);
+        
+        let mut store = self.store.write().unwrap();
+        if store.modified && store.index_mode == IndexMode::Persisted {
+            KvStore::write_index(store.index.iter(), store.header.generation, &store.index_path)?;
+            store.modified = false;
+        }
+        store.checkpoint()?;
+        Ok(())
+    }
+    
+    /// Remove all keys of every namespace, the segment files are replaced by a single empty segment
+    fn clear(&self) -> Result<()> {
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let _lock = self.compaction_guard.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        store.mark_modified()?;
+        
+        // Remove from the oldest segment, so the remaining segments always replay to the same result
+        self.handles.lock().unwrap().clear();
+        self.maps.lock().unwrap().clear();
+        let active_segment = self.active_segment.load(Ordering::Relaxed);
+        for segment in store.segments.keys().cloned().chain([active_segment]).collect::<Vec<_>>().into_iter() {
+            fs::remove_file(KvStore::segment_path(&self.db_path, segment))?;
+        }
+        OpenOptions::new().write(true).create_new(true).open(KvStore::segment_path(&self.db_path, 0))?;
+        store.segments.clear();
+        self.active_segment.store(0, Ordering::Relaxed);
+        self.db_offset.store(0, Ordering::Relaxed);
+        
+        store.index.clear();
+        store.tombstones.clear();
+        store.namespaced = false;
+        if let Some(cache) = &self.cache {
+            *cache.lock().unwrap() = LruCache::new(self.options.cache_capacity);
+        }
+        *self.bloom.write().unwrap() = BloomFilter::build(store.index.keys(), self.options.bloom_false_positive_rate);
+        store.header.dead_bytes = 0;
+        store.header.total_written = 0;
+        store.header.next_compaction_size = self.compaction_threshold();
+        KvStore::write_header(&store.header, OpenOptions::new().write(true).open(&*self.db_path)?)?;
+        // Records of the write-ahead log refer to the removed segments
+        store.checkpoint()?;
+        Ok(())
+    }
+    
+    /// Copy a point-in-time snapshot of the live entries into a new database in the directory `dest`
+    ///
+    /// The index is snapshotted under the exclusive guard, then the entries are streamed from the segment files while
+    /// writes continue. The backup is directly openable by `KvStore::open`.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        // Segments referred by the snapshot must not be replaced by compaction until copied
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let mut entries = {
+            let _lock = self.compaction_guard.write().unwrap();
+            self.store.read().unwrap().index.values().collect::<Vec<_>>()
+        };
+        // Keep the original order of the entries
+        entries.sort_unstable();
+        
+        fs::create_dir_all(dest)?;
+        let db_path = dest.join(format!("{}.db", KvStore::DEFAULT_NAME));
+        if db_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Backup destination already contains a database").into())
+        }
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(KvStore::segment_path(&db_path, 0))?);
+        let mut reader: Option<(u64, File)> = None;
+        let mut buf = Vec::new();
+        for pos in entries.into_iter() {
+            if reader.as_ref().map(|(segment, _)| *segment) != Some(pos.segment) {
+                reader = Some((pos.segment, File::open(KvStore::segment_path(&self.db_path, pos.segment))?));
+            }
+            let (_, handle) = reader.as_mut().unwrap();
+            handle.seek(SeekFrom::Start(pos.offset))?;
+            buf.resize(pos.len as usize, 0);
+            handle.read_exact(&mut buf)?;
+            writer.write_all(buf.as_slice())?;
+        }
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        
+        // The header is written last and without the graceful exit bit, so the index is rebuilt on first open
+        let header = KvHeader {
+            build_number: KvStore::BUILD_NUMBER,
+            last_open: 0,
+            next_compaction_size: KvStore::MIN_COMPACTION_THRESHOLD,
+            dead_bytes: 0,
+            total_written: 0,
+            generation: 0,
+            codec: self.options.codec.id(),
+            checksum: None,
+            flags: 0x1
+        };
+        let mut handle = OpenOptions::new().write(true).create_new(true).open(&db_path)?;
+        KvStore::write_header(&header, &mut handle)?;
+        handle.sync_all()?;
+        Ok(())
+    }
+    
+    fn namespace(&self, name: &str) -> Result<Box<dyn KvsEngine + Sync>> {
+        Ok(Box::new(self.with_namespace(name)))
+    }
+    
     /// Create or open KvStore instance
     fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        // Resolve actual database and index path
-        let mut db_path = path.into();
-        let mut index_path = db_path.clone();
-        if db_path.is_dir() {
-            db_path = db_path.join("kvs.db");
-            index_path = index_path.join("kvs.dir");
-        } else {
-            index_path = index_path.with_extension("dir");
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+}
+
+impl KvStore {
+    pub(super) const BUILD_NUMBER: u64 = 1600;
+    /// Name of the database files used when opening a directory without a name
+    pub const DEFAULT_NAME: &'static str = "kvs";
+    pub(super) const MIN_COMPACTION_THRESHOLD: u64 = 32768;
+    const MAX_CACHED_HANDLES: usize = 32;
+    const DEFAULT_SEGMENT_SIZE: u64 = 4 << 20;
+    // Segments with at least this fraction of dead data are merged by automatic compaction
+    const MERGE_DEAD_RATIO: f64 = 0.5;
+    // Values shorter than this are always stored uncompressed
+    const MIN_COMPRESSION_SIZE: usize = 256;
+    const FLAG_UNCOMPRESSED: u8 = 0;
+    const FLAG_ZSTD: u8 = 1;
+    const FLAG_COMPRESSION_MASK: u8 = 0x0f;
+    const FLAG_TYPE_SHIFT: u8 = 4;
+    // Size in byte of the write-ahead log after which the segment files are synced and the log is emptied
+    const WAL_CHECKPOINT_SIZE: u64 = 4 << 20;
+    // Number of entries read by compaction between two progress reports
+    const COMPACTION_PROGRESS_INTERVAL: u64 = 1024;
+    // Leading byte of the keys stored in a namespace, keys of the default namespace starting with it are stored with
+    // the byte doubled, as a namespace name never starts with it
+    const NAMESPACE_MARK: u8 = 0xff;
+    
+    /// Create or open KvStore instance named `name` in the directory `dir`
+    ///
+    /// The database files are `{name}.db`, `{name}.dir` and the segment files `{name}.0.db`, `{name}.1.db`, ...,
+    /// so stores with different names can share the same directory.
+    pub fn open_named(dir: impl Into<PathBuf>, name: &str) -> Result<KvStore> {
+        let dir = dir.into().canonicalize()?;
+        KvStore::open_shared(dir.join(format!("{}.db", name)), dir.join(format!("{}.dir", name)), KvStoreOptions::default())
+    }
+    
+    /// Builder of the options to create or open KvStore instance with
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+    
+    /// Create or open KvStore instance with the given options
+    ///
+    /// The database consists of a header file `kvs.db`, segment files `kvs.0.db`, `kvs.1.db`, ... holding the
+    /// entries, and an index file `kvs.dir`. New entries are only appended to the segment with the largest id.
+    ///
+    /// `path` is either a directory holding the database, or the database file itself, which is created if it does
+    /// not exist yet but its directory must exist. Relative paths and symlinks of the directory are resolved once
+    /// on open, so changing the current directory afterwards does not affect the opened store.
+    ///
+    /// Opening a database already opened for writing in the process returns a handle of the same store, which fails
+    /// with `KvsError::InvalidArguments` if `options` differ from the ones it was opened with. Other processes fail
+    /// with `KvsError::AlreadyLocked` until every handle is dropped.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let db_path = KvStore::resolve_path(path.into())?;
+        let index_path = db_path.with_extension("dir");
+        KvStore::open_shared(db_path, index_path, options)
+    }
+    
+    /// Open the database at `db_path`, or share the store already opened for writing at the same path
+    ///
+    /// Read-only instances are never shared, since they neither write nor take the lock.
+    fn open_shared(db_path: PathBuf, index_path: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        if options.read_only { return KvStore::open_files(db_path, index_path, options) }
+        // Held while opening, so concurrent opens of the same path cannot both miss the opened store
+        let mut opened = OPENED_STORES.lock().unwrap();
+        let origin = match opened.get(&db_path).and_then(Weak::upgrade) {
+            // The codec of an existing database is kept whichever is requested
+            Some(origin) if KvStoreOptions { codec: origin.options.codec, ..options } != *origin.options => {
+                return Err(KvsError::InvalidArguments("Database already opened with different options".to_owned()))
+            },
+            Some(origin) => origin,
+            None => {
+                let origin = Arc::new(KvStore::open_files(db_path.clone(), index_path, options)?);
+                opened.retain(|_, store| store.strong_count() > 0);
+                opened.insert(db_path, Arc::downgrade(&origin));
+                origin
+            }
+        };
+        Ok(KvStore { origin: Some(origin.clone()), ..(*origin).clone() })
+    }
+    
+    /// Absolute path of the database file named by `path`, which is either its directory or the file itself
+    fn resolve_path(path: PathBuf) -> Result<PathBuf> {
+        if path.is_dir() {
+            return Ok(path.canonicalize()?.join(format!("{}.db", KvStore::DEFAULT_NAME)))
+        }
+        // The file may not exist yet, so only its directory is resolved
+        let name = path.file_name()
+            .ok_or_else(|| KvsError::InvalidArguments(format!("Invalid database path {}", path.display())))?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+            _ => Path::new(".").canonicalize()?
+        };
+        Ok(dir.join(name))
+    }
+    
+    /// Create or open KvStore instance keeping up to `capacity_bytes` of recently read values in memory
+    #[deprecated(note = "use `KvStore::builder().cache_capacity(capacity_bytes).open(path)` instead")]
+    pub fn open_with_cache(path: impl Into<PathBuf>, capacity_bytes: u64) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions { cache_capacity: capacity_bytes, ..Default::default() })
+    }
+    
+    /// Create or open KvStore instance syncing every entry to the write-ahead log before it is written
+    ///
+    /// Entries lost from the segment files by a crash are restored from the log on next open.
+    #[deprecated(note = "use `KvStore::builder().wal(true).open(path)` instead")]
+    pub fn open_with_wal(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions { wal: true, ..Default::default() })
+    }
+    
+    /// Open the existing KvStore instance without modifying any of its files
+    ///
+    /// Entries only present in the write-ahead log are not visible, and the index is rebuilt in memory if the index
+    /// file is outdated. Database created by older build must be opened for writing once to be upgraded.
+    #[deprecated(note = "use `KvStore::builder().read_only(true).open(path)` instead")]
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions { read_only: true, ..Default::default() })
+    }
+    
+    /// Create or open KvStore instance reading the values from memory-mapped segment files
+    ///
+    /// Each segment is mapped on first read and mapped again once it has grown past the mapped length.
+    #[deprecated(note = "use `KvStore::builder().mmap(true).open(path)` instead")]
+    pub fn open_mmap(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions { mmap: true, ..Default::default() })
+    }
+    
+    /// Create or open KvStore instance with the database file `db_path` and the index file `index_path`
+    fn open_files(db_path: PathBuf, index_path: PathBuf, mut options: KvStoreOptions) -> Result<KvStore> {
+        // Taken before any file is modified, read-only instances do not coordinate with the writer
+        let lock = if options.read_only { None } else { Some(KvStore::lock(&db_path)?) };
+        if !options.read_only {
+            // Discard the output of an interrupted compaction, the segment files are left untouched in that case
+            let tmp_path = db_path.with_extension("db.tmp");
+            if tmp_path.exists() {
+                fs::remove_file(&tmp_path)?;
+            }
+            
+            // Bring database file created by older build up to date
+            migration::upgrade(&db_path)?;
         }
         
         // Open and create the database file if not exist
-        let mut db_reader = BufReader::new(OpenOptions::new().read(true).write(true).create(true).open(&db_path)?);
-        let mut db_writer = BufWriter::new(OpenOptions::new().read(true).write(true).create(true).open(&db_path)?);
+        let writable = !options.read_only;
+        let mut db_reader = BufReader::new(OpenOptions::new().read(true).write(writable).create(writable).truncate(false).open(&db_path)?);
         
         // Check the present of the database header
         let mut header = if db_path.metadata()?.len() != 0 {
@@ -118,191 +1059,1570 @@ impl KvsEngine for KvStore {
             KvHeader {
                 build_number: KvStore::BUILD_NUMBER,
                 last_open: 0,
-                next_compaction_size: KvStore::MIN_COMPACTION_THRESHOLD,
+                next_compaction_size: options.compaction_threshold.unwrap_or(KvStore::MIN_COMPACTION_THRESHOLD),
+                dead_bytes: 0,
+                total_written: 0,
+                generation: 0,
+                codec: options.codec.id(),
+                checksum: None,
                 flags: 0x1
             }
         };
         
-        header.last_open = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-        header.flags = 0x1;
-        // Update header
-        KvStore::write_header(&header, &mut db_writer)?;
+        // Entries are always decoded with the codec the database was created with
+        options.codec = CodecKind::from_id(header.codec)?;
+        // The threshold is the lower bound of the next compaction size
+        if let Some(threshold) = options.compaction_threshold {
+            header.next_compaction_size = max(header.next_compaction_size, threshold);
+        }
+        // The bit must be checked before it is set again for this session
+        let is_last_graceful_exit = header.flags & 0x1 == 0;
+        // The checksum is only up to date after graceful exit, verified before the session is marked in the header
+        if let (true, Some(checksum)) = (is_last_graceful_exit, header.checksum) {
+            if KvStore::file_checksum(&db_path)? != checksum { return Err(KvsError::FileChecksumMismatch) }
+        }
+        if !options.read_only {
+            header.last_open = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+            header.flags = 0x1;
+            // Update header
+            KvStore::write_header(&header, OpenOptions::new().write(true).open(&db_path)?)?;
+        }
+        
+        // Restore the entries which did not reach the segment files from the write-ahead log
+        let wal_path = db_path.with_extension("wal");
+        let replayed = !options.read_only && wal_path.exists() && KvStore::replay_wal(&db_path, &wal_path)?;
+        let wal = if options.read_only {
+            None
+        } else if options.wal {
+            let handle = OpenOptions::new().create(true).append(true).open(&wal_path)?;
+            handle.set_len(0)?;
+            Some(Arc::new(Mutex::new(handle)))
+        } else {
+            if wal_path.exists() {
+                fs::remove_file(&wal_path)?;
+            }
+            None
+        };
+        
+        // Locate segment files, the last one is the active segment
+        let mut segment_ids = KvStore::list_segments(&db_path)?;
+        if segment_ids.is_empty() && !options.read_only {
+            OpenOptions::new().write(true).create(true).truncate(false).open(KvStore::segment_path(&db_path, 0))?;
+            segment_ids.push(0);
+        }
+        let active_segment = segment_ids.last().cloned().unwrap_or(0);
+        let mut segments = BTreeMap::new();
+        for segment in segment_ids.iter() {
+            segments.insert(*segment, KvStore::segment_path(&db_path, *segment).metadata()?.len());
+        }
+        let mut db_offset = segments.remove(&active_segment).unwrap_or(0);
         
-        let mut index = HashMap::new();
+        let index;
+        let mut bloom = None;
+        let bloom_path = index_path.with_extension("bloom");
         // Build index from index file
-        // Use existing index only if index file has non zero length and is_last_graceful_exit bit is clear
-        if index_path.exists() && index_path.metadata()?.len() != 0 && header.flags & 0x1 == 0 {
-            let mut reader = BufReader::new(OpenOptions::new().read(true).open(&index_path)?);
-            while let Ok(entry) = bson::from_reader::<_, KvsIndexEntries>(&mut reader) {
-                index.insert(entry.key, entry.offset);
+        // Use existing index only if is_last_graceful_exit bit is clear or it was written at the current generation
+        let saved_index = match options.index_mode {
+            IndexMode::Persisted if index_path.exists() => Some(KvStore::read_index(&index_path)?),
+            _ => None
+        };
+        let mut reindexed = replayed || !saved_index.as_ref().is_some_and(|(_, generation)| {
+            is_last_graceful_exit || *generation == Some(header.generation)
+        });
+        // The index file may be stale even though its generation matches, such as when restored from another backup
+        if !reindexed {
+            let mut sizes = segments.clone();
+            sizes.insert(active_segment, db_offset);
+            if !KvStore::index_matches_segments(&db_path, &saved_index.as_ref().unwrap().0, &sizes, options.codec)? {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(path = %index_path.display(), "Index file does not match the segment files, rebuilding the index");
+                reindexed = true;
+            }
+        }
+        if !reindexed {
+            index = saved_index.unwrap().0;
+            if is_last_graceful_exit {
+                // Saved bloom filter matches the index written at the same graceful exit
+                bloom = BloomFilter::load(&bloom_path, options.bloom_false_positive_rate);
+            } else {
+                // The counter is only saved in the header when the generation is increased
+                let total_size = segments.values().sum::<u64>() + db_offset;
+                header.dead_bytes = total_size - index.values().map(|pos| pos.len).sum::<u64>();
             }
         } else {
             // Reindex the database
-            let mut offset = db_reader.seek(SeekFrom::Current(0))?;
-            while let Ok(entry) = bson::from_reader::<_, KvsEntries>(&mut db_reader) {
-                match entry {
-                    KvsEntries::SET(key, _) => { index.insert(key, offset); },
-                    KvsEntries::DELETE(key) => { index.remove(&key); }
+            let valid_end;
+            (index, valid_end) = KvStore::reindex(&db_path, &segment_ids, options.reindex_threads, options.codec)?;
+            // Drop the partially written entry left by an interrupted write, so new entries start after valid data
+            if valid_end < db_offset && !options.read_only {
+                OpenOptions::new().write(true).open(KvStore::segment_path(&db_path, active_segment))?.set_len(valid_end)?;
+                db_offset = valid_end;
+            }
+            // Everything not referred by the rebuilt index is dead
+            let total_size = segments.values().sum::<u64>() + db_offset;
+            header.dead_bytes = total_size - index.values().map(|pos| pos.len).sum::<u64>();
+            match options.index_mode {
+                // The rebuilt index only lives in memory
+                _ if options.read_only => {},
+                // Rewrite index file
+                IndexMode::Persisted => {
+                    let entries = index.iter().map(|(key, pos)| (key.as_slice(), *pos));
+                    KvStore::write_index(entries, header.generation, &index_path)?
+                },
+                // Files left by a previous session in persisted mode would be outdated by the next write
+                IndexMode::AlwaysRebuild => {
+                    for path in [&index_path, &bloom_path] {
+                        if path.exists() {
+                            fs::remove_file(path)?;
+                        }
+                    }
                 }
-                // Store the start offset of next entry
-                offset = db_reader.seek(SeekFrom::Current(0))?;
             }
-            // Rewrite index file
-            KvStore::write_index(&index, &index_path)?;
         }
         
+        let bloom = Arc::new(RwLock::new(
+            bloom.unwrap_or_else(|| BloomFilter::build(index.keys().map(Vec::as_slice), options.bloom_false_positive_rate))));
+        let namespaced = index.keys().any(|key| key.first() == Some(&KvStore::NAMESPACE_MARK));
+        let index = BoundedIndex::new(index, options.index_memory_limit, &index_path)?;
         let store = KvStoreInt {
             header,
             index,
+            tombstones: HashMap::new(),
+            segments,
             modified: false,
             db_path: db_path.clone(),
-            index_path
+            index_path,
+            index_mode: options.index_mode,
+            read_only: options.read_only,
+            file_checksum: options.file_checksum,
+            bloom: bloom.clone(),
+            wal: wal.clone(),
+            namespaced,
+            reindexed,
+            fail_compaction_after: None,
+            compaction_delay: None,
+            lock
         };
         
-        Ok(KvStore {
+        let mut kv_store = KvStore {
             store: Arc::new(RwLock::new(store)),
             compaction_guard: Arc::new(RwLock::new(())),
+            compaction_lock: Arc::new(Mutex::new(())),
+            update_lock: Arc::new(Mutex::new(())),
             db_path: Box::new(db_path),
-            db_offset: Arc::new(AtomicU64::new(db_reader.seek(SeekFrom::End(0))?))
-        })
+            active_segment: Arc::new(AtomicU64::new(active_segment)),
+            db_offset: Arc::new(AtomicU64::new(db_offset)),
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            maps: Arc::new(Mutex::new(HashMap::new())),
+            cache: (options.cache_capacity > 0).then(|| Arc::new(Mutex::new(LruCache::new(options.cache_capacity)))),
+            disk_reads: Arc::new(AtomicU64::new(0)),
+            injected_interrupts: Arc::new(AtomicU64::new(0)),
+            counters: Arc::new(KvStoreCounters::default()),
+            bloom,
+            group_commit: wal.as_ref().map(|wal| -> Result<_> {
+                Ok(Arc::new(GroupCommit::new(wal.lock().unwrap().try_clone()?)))
+            }).transpose()?,
+            wal,
+            namespace: Vec::new(),
+            options: Arc::new(options),
+            compactor: None,
+            observer: Arc::new(CompactionObserver::default()),
+            origin: None
+        };
+        // Deferred compaction is retried by the compaction thread once the store is idle
+        let background = kv_store.options.background_compaction || kv_store.options.compaction_idle_window.is_some();
+        if background && !kv_store.options.read_only {
+            kv_store.compactor = Some(Arc::new(Compactor::spawn(kv_store.clone())));
+        }
+        Ok(kv_store)
     }
-}
-
-impl KvStore {
-    const BUILD_NUMBER: u64 = 1200;
-    const MIN_COMPACTION_THRESHOLD: u64 = 32768;
     
-    fn check_compaction(&self) -> Result<bool> {
-        // Block any read/write operation until compaction completed
-        // Also, wait for other read/write operation to complete
-        if self.db_offset.load(Ordering::Relaxed) >= self.store.read().unwrap().header.next_compaction_size {
-            self.compaction()?;
-            Ok(true)
-        } else { Ok(false) }
+    /// Take the exclusive lock file `{name}.lock` of the database at `db_path`, failing if another instance holds it
+    ///
+    /// The lock is advisory and released by the system when the process exits, so a crash never leaves it behind.
+    fn lock(db_path: &Path) -> Result<File> {
+        let lock_path = db_path.with_extension("lock");
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(&lock_path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(KvsError::AlreadyLocked(lock_path)),
+            Err(TryLockError::Error(err)) => Err(err.into())
+        }
     }
     
-    /// Do compaction if the database file size reaches threshold
-    fn compaction(&self) -> Result<()> {
-        let _lock = self.compaction_guard.write().unwrap();
-        let mut store = self.store.write().unwrap();
-        // Avoid negative indication
-        if self.db_offset.load(Ordering::Relaxed) < store.header.next_compaction_size {
-            return Ok(())
+    /// Salvage the database at `path` whose files are partially corrupt, the store must not be opened
+    ///
+    /// Every segment is scanned entry by entry, unreadable bytes are skipped until the next valid entry and the
+    /// segment is rewritten with the valid entries only. The header is replaced and the index is rebuilt on next open.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        let db_path = KvStore::resolve_path(path.into())?;
+        let tmp_path = db_path.with_extension("db.tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
         }
         
-        let mut entries = HashMap::new();
-        let mut reader: BufReader<File> = BufReader::new(OpenOptions::new().read(true).open(&store.db_path)?);
+        // Bring database file created by older build up to date if its header is still readable
+        let header = File::open(&db_path).ok().and_then(|file| bson::from_reader::<_, KvHeader>(BufReader::new(file)).ok());
+        if header.as_ref().is_some_and(|header| header.build_number < KvStore::BUILD_NUMBER) {
+            migration::upgrade(&db_path)?;
+        }
+        // Entries of a database with unreadable header are assumed to be BSON
+        let codec = header.map_or(Ok(CodecKind::Bson), |header| CodecKind::from_id(header.codec))?;
         
-        for (key, offset) in store.index.iter() {
-            reader.seek(SeekFrom::Start(*offset))?;
-            if let Ok(KvsEntries::SET(key_, value)) = bson::from_reader::<_, KvsEntries>(&mut reader) {
-                if key_ == *key { entries.insert(key_, value); }
-                else { return Err(KvsError::InvalidDataEntry) }
+        let mut report = RepairReport::default();
+        let mut buf = Vec::new();
+        for segment in KvStore::list_segments(&db_path)?.into_iter() {
+            let segment_path = KvStore::segment_path(&db_path, segment);
+            let data = fs::read(&segment_path)?;
+            let mut valid = Vec::with_capacity(data.len());
+            let mut offset = 0;
+            let mut corrupt = false;
+            while offset < data.len() {
+                let is_valid = KvStore::read_raw_entry(&mut &data[offset..], &mut buf).is_ok()
+                    && codec.decode::<KvsEntries, _>(buf.as_slice()).is_ok();
+                if is_valid {
+                    valid.extend_from_slice(buf.as_slice());
+                    offset += buf.len();
+                    report.recovered += 1;
+                    corrupt = false;
+                } else {
+                    // Count each run of unreadable bytes once
+                    if !corrupt {
+                        report.dropped += 1;
+                        corrupt = true;
+                    }
+                    offset += 1;
+                }
+            }
+            if valid.len() != data.len() {
+                let mut handle = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+                handle.write_all(valid.as_slice())?;
+                handle.sync_all()?;
+                fs::rename(&tmp_path, &segment_path)?;
             }
         }
         
-        drop(reader);
-        // Clear file content
-        let mut writer: BufWriter<File> = BufWriter::new(OpenOptions::new().write(true).truncate(true).open(&*store.db_path)?);
-        
-        // Build header
-        let mut offset = KvStore::write_header(&store.header, &mut writer)?;
-        // Reset old index
-        store.index.clear();
-        for (key, value) in entries {
-            store.index.insert(key.to_owned(), offset);
-            let entry = KvsEntries::SET(key, value);
-            writer.write_all(bson::to_vec(&entry)?.as_slice())?;
-            offset = writer.seek(SeekFrom::Current(0))?;
+        // Without the graceful exit bit and the index file, the index is rebuilt on next open
+        // Records of the write-ahead log refer to the offsets before the repair
+        for path in [db_path.with_extension("dir"), db_path.with_extension("wal")] {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
         }
+        let header = KvHeader {
+            build_number: KvStore::BUILD_NUMBER,
+            last_open: 0,
+            next_compaction_size: KvStore::MIN_COMPACTION_THRESHOLD,
+            dead_bytes: 0,
+            total_written: 0,
+            generation: 0,
+            codec: codec.id(),
+            checksum: None,
+            flags: 0x1
+        };
+        let mut handle = OpenOptions::new().write(true).create(true).truncate(true).open(&db_path)?;
+        KvStore::write_header(&header, &mut handle)?;
+        handle.sync_all()?;
+        Ok(report)
+    }
+    
+    /// Check the database at `path` without modifying any of its files, the store should not be opened for writing
+    ///
+    /// Every entry of the segment files is parsed, and the index file is cross-checked against the index rebuilt from
+    /// the segments if it would be reused on open. Unreadable database header is reported as an error.
+    pub fn validate(path: impl Into<PathBuf>) -> Result<ValidationReport> {
+        let db_path = KvStore::resolve_path(path.into())?;
+        let header = bson::from_reader::<_, KvHeader>(BufReader::new(File::open(&db_path)?))
+            .map_err(|_| KvsError::InvalidDatabaseFormat)?;
+        let mut report = ValidationReport::default();
+        // Entries of other builds are in another format
+        if header.build_number != KvStore::BUILD_NUMBER {
+            report.issues.push(ValidationIssue::VersionMismatch { found: header.build_number, expected: KvStore::BUILD_NUMBER });
+            return Ok(report)
+        }
+        let codec = CodecKind::from_id(header.codec)?;
         
-        // Estimate next compaction size: Double the current size
-        // Update header
-        store.header.next_compaction_size = max(self.db_offset.load(Ordering::Relaxed) * 2, KvStore::MIN_COMPACTION_THRESHOLD);
-        KvStore::write_header(&store.header, &mut writer)?;
+        // Rebuild the index from the segment files, remembering the key set by every entry
+        let mut index = HashMap::new();
+        let mut tombstones = HashMap::new();
+        let mut entries = HashMap::new();
+        let mut buf = Vec::new();
+        for segment in KvStore::list_segments(&db_path)?.into_iter() {
+            let data = fs::read(KvStore::segment_path(&db_path, segment))?;
+            let mut offset = 0;
+            let mut corrupt = false;
+            while offset < data.len() {
+                let entry = KvStore::read_raw_entry(&mut &data[offset..], &mut buf)
+                    .and_then(|_| codec.decode::<KvsEntries, _>(buf.as_slice()));
+                match entry {
+                    Ok(entry) => {
+                        let pos = KvsEntryPos { segment, offset: offset as u64, len: buf.len() as u64 };
+                        KvStore::apply_entry(&mut index, &mut tombstones, &entry, pos);
+                        if let KvsEntries::SET(key, ..) = entry {
+                            entries.insert((segment, pos.offset), (key, pos.len));
+                        }
+                        offset += buf.len();
+                        report.entries += 1;
+                        corrupt = false;
+                    },
+                    Err(_) => {
+                        // Report each run of unreadable bytes once
+                        if !corrupt {
+                            report.issues.push(ValidationIssue::CorruptEntry { segment, offset: offset as u64 });
+                            corrupt = true;
+                        }
+                        offset += 1;
+                    }
+                }
+            }
+        }
         
-        // Reset db_offset
-        self.db_offset.store(writer.seek(SeekFrom::End(0))?, Ordering::Relaxed);
+        let index_path = db_path.with_extension("dir");
+        if !index_path.exists() {
+            return Ok(report)
+        }
+        let (saved_index, generation) = KvStore::read_index(&index_path)?;
+        report.indexed_keys = saved_index.len();
+        if generation.is_none() {
+            report.issues.push(ValidationIssue::IncompleteIndex);
+        }
+        // Same rule as open, an outdated index file is harmless as it is never used
+        let is_last_graceful_exit = header.flags & 0x1 == 0;
+        if !is_last_graceful_exit && generation != Some(header.generation) {
+            report.issues.push(ValidationIssue::StaleIndex { found: generation, expected: header.generation });
+            return Ok(report)
+        }
+        let mut saved_index = saved_index.into_iter().collect::<Vec<_>>();
+        saved_index.sort_unstable();
+        for (key, pos) in saved_index.iter() {
+            let is_entry = entries.get(&(pos.segment, pos.offset)) == Some(&(key.clone(), pos.len));
+            if !is_entry {
+                report.issues.push(ValidationIssue::DanglingIndexEntry { key: key.clone(), segment: pos.segment, offset: pos.offset });
+            } else if index.get(key) != Some(pos) {
+                report.issues.push(ValidationIssue::OutdatedIndexEntry { key: key.clone(), segment: pos.segment, offset: pos.offset });
+            }
+        }
+        let saved_keys = saved_index.into_iter().map(|(key, _)| key).collect::<HashSet<_>>();
+        let mut missing = index.into_keys().filter(|key| !saved_keys.contains(key)).collect::<Vec<_>>();
+        missing.sort_unstable();
+        report.issues.extend(missing.into_iter().map(|key| ValidationIssue::MissingIndexEntry { key }));
+        Ok(report)
+    }
+    
+    /// Handle of the same database whose operations only see the keys of namespace `name`
+    ///
+    /// The keys are stored with the prefix `0xff {name} 0xff`. The empty name refers to the default namespace
+    /// holding the keys stored without namespace.
+    pub fn with_namespace(&self, name: &str) -> KvStore {
+        let mut store = self.clone();
+        store.namespace = if name.is_empty() {
+            Vec::new()
+        } else {
+            [&[KvStore::NAMESPACE_MARK], name.as_bytes(), &[KvStore::NAMESPACE_MARK]].concat()
+        };
+        store
+    }
+    
+    /// Stored key of `key` in the selected namespace
+    fn scoped(&self, key: Vec<u8>) -> Vec<u8> {
+        if !self.namespace.is_empty() {
+            [self.namespace.as_slice(), key.as_slice()].concat()
+        } else if key.first() == Some(&KvStore::NAMESPACE_MARK) {
+            [&[KvStore::NAMESPACE_MARK], key.as_slice()].concat()
+        } else {
+            key
+        }
+    }
+    
+    /// Length of the prefix added by `scoped` to the stored key `key` of the selected namespace
+    fn prefix_len(&self, key: &[u8]) -> usize {
+        if self.namespace.is_empty() && key.starts_with(&[KvStore::NAMESPACE_MARK; 2]) { 1 } else { self.namespace.len() }
+    }
+    
+    /// Check if the stored key `key` belongs to the selected namespace
+    fn in_namespace(&self, key: &[u8]) -> bool {
+        if self.namespace.is_empty() {
+            key.first() != Some(&KvStore::NAMESPACE_MARK) || key.starts_with(&[KvStore::NAMESPACE_MARK; 2])
+        } else {
+            key.starts_with(&self.namespace)
+        }
+    }
+    
+    /// Iterate over all key/value pairs of the namespace in unspecified order
+    pub fn iter(&self) -> Result<KvStoreIter> {
+        let keys = self.store.read().unwrap().index.keys()
+            .filter(|key| self.in_namespace(key))
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+        Ok(KvStoreIter {
+            store: self.clone(),
+            keys: keys.into_iter()
+        })
+    }
+    
+    /// Write all live key/value pairs into `writer` in the engine independent dump format
+    pub fn export<W: Write>(&self, writer: W) -> Result<()> {
+        dump::export(self.iter()?, writer)
+    }
+    
+    /// Load key/value pairs from a dump created by `export`, returns the number of imported pairs
+    pub fn import<R: Read>(&self, reader: R) -> Result<usize> {
+        dump::import(self, reader)
+    }
+    
+    /// Call `observer` with the progress of every following compaction, replacing the previous observer
+    ///
+    /// The observer is called from the thread running the compaction, every 1024 entries and after each merged
+    /// segment, while no lock blocking reads or writes is held.
+    pub fn set_compaction_observer(&self, observer: Box<dyn Fn(CompactionProgress) + Send + Sync>) {
+        *self.observer.0.write().unwrap() = Some(observer);
+    }
+    
+    /// Run compaction immediately regardless of the current database file size
+    pub fn force_compaction(&self) -> Result<()> {
+        self.compaction(true)
+    }
+    
+    fn check_compaction(&self) -> Result<bool> {
+        // Only manual compaction is run if disabled
+        if self.options.compaction_threshold.is_none() { return Ok(false) }
+        let store = self.store.read().unwrap();
+        if self.should_compact(&store) {
+            drop(store);
+            match &self.compactor {
+                // The compaction thread waits for the store to be idle if the window is set
+                Some(compactor) => compactor.notify(),
+                None => self.compaction(false)?
+            }
+            Ok(true)
+        } else { Ok(false) }
+    }
+    
+    /// Time left until the store is idle for `compaction_idle_window`, `None` if it is idle already
+    fn idle_remaining(&self) -> Option<Duration> {
+        let window = self.options.compaction_idle_window?;
+        let elapsed = KvStore::now_millis().saturating_sub(self.counters.last_write.load(Ordering::Relaxed));
+        window.checked_sub(Duration::from_millis(elapsed)).filter(|remaining| !remaining.is_zero())
+    }
+    
+    fn now_millis() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+    }
+    
+    /// Check if the segments or the bytes written since the last compaction reach the next compaction size with
+    /// enough dead entries to reclaim
+    fn should_compact(&self, store: &KvStoreInt) -> bool {
+        let total_size = self.total_size(store);
+        max(total_size, store.header.total_written) >= store.header.next_compaction_size
+            && store.header.dead_bytes as f64 > total_size as f64 * self.options.compaction_dead_ratio
+    }
+    
+    /// Total size of all segment files
+    fn total_size(&self, store: &KvStoreInt) -> u64 {
+        store.segments.values().sum::<u64>() + self.db_offset.load(Ordering::Relaxed)
+    }
+    
+    /// Do compaction if the database file size reaches threshold, or unconditionally if `force` is set
+    ///
+    /// The active segment is frozen, then live entries of a contiguous range of immutable segments are merged into
+    /// a single segment. Automatic compaction only merges the range of segments with most dead data, while forced
+    /// compaction merges all of them. Reads and writes are only blocked when switching the segments.
+    ///
+    /// Surviving entries keep their relative order, so recently written keys stay clustered at the tail of the file.
+    fn compaction(&self, force: bool) -> Result<()> {
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("compaction", force).entered();
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let (merged, keep_tombstones, start_size) = {
+            let _lock = self.compaction_guard.write().unwrap();
+            let mut store = self.store.write().unwrap();
+            let total_size = self.total_size(&store);
+            // Avoid negative indication
+            if !force && !self.should_compact(&store) {
+                return Ok(())
+            }
+            
+            // Freeze the active segment, new entries go to a fresh segment from now on
+            self.roll_segment(&mut store)?;
+            // Avoid triggering again until the compaction completes
+            store.header.next_compaction_size = max(total_size * 2, self.compaction_threshold());
+            // Entries written from now on go to the fresh segment and count towards the next compaction
+            store.header.total_written = 0;
+            store.mark_modified()?;
+            
+            let merged = if force {
+                store.segments.keys().cloned().collect::<Vec<_>>()
+            } else {
+                KvStore::select_segments(&store)
+            };
+            // Removal must be kept if older segments may still hold the removed keys
+            let keep_tombstones = merged.first().is_some_and(|first| store.segments.keys().next() != Some(first));
+            (merged, keep_tombstones, total_size)
+        };
+        // Live entries are merged into the newest merged segment
+        let target = match merged.last() {
+            Some(segment) => *segment,
+            None => return Ok(())
+        };
+        let (fail_after, delay) = {
+            let mut store = self.store.write().unwrap();
+            (store.fail_compaction_after.take(), store.compaction_delay)
+        };
+        
+        // Copy live entries one by one in the order they were written, so only a single entry is held in memory
+        // Iterating the index instead would scatter the recently written keys over the compacted segment
+        // Immutable segments are never modified, so no lock is required for reading them
+        let tmp_path = self.db_path.with_extension("db.tmp");
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+        let mut live = Vec::new();
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        let mut progress = CompactionProgress { entries_processed: 0, entries_copied: 0, bytes_processed: 0, bytes_total: 0 };
+        for segment in merged.iter() {
+            progress.bytes_total += KvStore::segment_path(&self.db_path, *segment).metadata()?.len();
+        }
+        for segment in merged.iter() {
+            let segment_path = KvStore::segment_path(&self.db_path, *segment);
+            let segment_size = segment_path.metadata()?.len();
+            let mut reader = BufReader::new(OpenOptions::new().read(true).open(segment_path)?);
+            let mut entry_offset = 0;
+            while entry_offset < segment_size {
+                // The first entry of a segment was just reported at the end of the previous segment
+                if entry_offset > 0 && progress.entries_processed.is_multiple_of(KvStore::COMPACTION_PROGRESS_INTERVAL) {
+                    self.report_progress(progress);
+                }
+                KvStore::read_raw_entry(&mut reader, &mut buf)?;
+                let pos = KvsEntryPos { segment: *segment, offset: entry_offset, len: buf.len() as u64 };
+                entry_offset += pos.len;
+                progress.entries_processed += 1;
+                progress.bytes_processed += pos.len;
+                let copy = match self.options.codec.decode::<KvsEntries, _>(buf.as_slice())? {
+                    KvsEntries::SET(key, ..) => {
+                        let is_live = self.store.read().unwrap().index.get(&key) == Some(pos);
+                        is_live.then_some(Some(key))
+                    },
+                    KvsEntries::DELETE(key) => {
+                        let is_removed = !self.store.read().unwrap().index.contains_key(&key);
+                        (keep_tombstones && is_removed).then_some(None)
+                    }
+                };
+                let key = match copy {
+                    Some(key) => key,
+                    None => continue
+                };
+                
+                if fail_after == Some(live.len()) {
+                    writer.flush()?;
+                    return Err(io::Error::other("Injected compaction failure").into())
+                }
+                if let Some(delay) = delay {
+                    thread::sleep(delay);
+                }
+                writer.write_all(buf.as_slice())?;
+                if let Some(key) = key {
+                    live.push((pos, key, offset));
+                }
+                offset += pos.len;
+                progress.entries_copied += 1;
+            }
+            if segment_size > 0 {
+                self.report_progress(progress);
+            }
+        }
+        // Make sure the compacted segment reaches the disk before it replaces the original
+        let compacted = writer.into_inner().map_err(|err| err.into_error())?;
+        KvStore::retry_interrupted(|| compacted.sync_all())?;
+        
+        // Switch to the compacted segment
+        // The original segments stay intact until this point, so any failure above leaves the store usable
+        let _lock = self.compaction_guard.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        // The index may have been saved by flush since the compaction started
+        store.mark_modified()?;
+        let merged_size = merged.iter().filter_map(|segment| store.segments.get(segment)).sum::<u64>();
+        fs::rename(&tmp_path, KvStore::segment_path(&self.db_path, target))?;
+        // Remove from the oldest segment, so the remaining segments always replay to the same result
+        for segment in merged.iter().filter(|segment| **segment != target) {
+            fs::remove_file(KvStore::segment_path(&self.db_path, *segment))?;
+            store.segments.remove(segment);
+        }
+        store.segments.insert(target, offset);
+        // Cached handles still refer to the replaced segments
+        let mut handles = self.handles.lock().unwrap();
+        let mut maps = self.maps.lock().unwrap();
+        for segment in merged.iter() {
+            handles.remove(segment);
+            maps.remove(segment);
+        }
+        // Records of the write-ahead log refer to the replaced segments
+        store.checkpoint()?;
+        // Entries updated during the compaction are already in the active segment
+        for (pos, key, new_offset) in live.into_iter() {
+            store.index.replace(&key, pos, KvsEntryPos { segment: target, offset: new_offset, len: pos.len });
+        }
+        // Estimate next compaction size with the policy
+        let live_size = store.index.values().map(|pos| pos.len).sum::<u64>();
+        let total_size = self.total_size(&store);
+        let next_size = match self.options.compaction_policy {
+            CompactionPolicy::Doubling => start_size * 2,
+            CompactionPolicy::LiveDataRatio(ratio) => (live_size as f64 / ratio) as u64,
+            CompactionPolicy::FixedInterval(interval) => total_size + interval,
+            CompactionPolicy::DeadByteThreshold(fraction) => (live_size as f64 * (1.0 + fraction)) as u64
+        };
+        store.header.next_compaction_size = max(next_size, self.compaction_threshold());
+        store.header.dead_bytes = total_size - live_size;
+        // Drop the removed keys from the bloom filter
+        *self.bloom.write().unwrap() = BloomFilter::build(store.index.keys(), self.options.bloom_false_positive_rate);
+        self.counters.compactions.fetch_add(1, Ordering::Relaxed);
+        self.counters.reclaimed_bytes.fetch_add(merged_size.saturating_sub(offset), Ordering::Relaxed);
         
         Ok(())
     }
     
-    /// Insert entry to the database file
+    /// Pass the progress of the running compaction to the observer if set
+    fn report_progress(&self, progress: CompactionProgress) {
+        if let Some(observer) = self.observer.0.read().unwrap().as_ref() {
+            observer(progress);
+        }
+    }
+    
+    /// Reject key or value longer than `limit`
+    pub(super) fn check_size(size: usize, limit: u64) -> Result<()> {
+        if size as u64 > limit {
+            return Err(KvsError::ValueTooLarge { size: size as u64, limit })
+        }
+        Ok(())
+    }
+    
+    /// Lower bound of the next compaction size
+    fn compaction_threshold(&self) -> u64 {
+        self.options.compaction_threshold.unwrap_or(KvStore::MIN_COMPACTION_THRESHOLD)
+    }
+    
+    /// Total size of the segments triggering the next automatic compaction, for testing only
+    #[doc(hidden)]
+    pub fn next_compaction_size(&self) -> u64 {
+        self.store.read().unwrap().header.next_compaction_size
+    }
+    
+    /// Total size of the shadowed entries in the segments, for testing only
+    #[doc(hidden)]
+    pub fn dead_bytes(&self) -> u64 {
+        self.store.read().unwrap().header.dead_bytes
+    }
+    
+    /// Select the contiguous range of immutable segments between the first and the last segment
+    /// with at least `MERGE_DEAD_RATIO` of dead data
+    fn select_segments(store: &KvStoreInt) -> Vec<u64> {
+        let mut live_size: HashMap<u64, u64> = HashMap::new();
+        for pos in store.index.values() {
+            *live_size.entry(pos.segment).or_default() += pos.len;
+        }
+        let is_dead = |segment: &u64, size: &u64| {
+            let live = live_size.get(segment).cloned().unwrap_or_default();
+            *size > 0 && (size - live) as f64 >= *size as f64 * KvStore::MERGE_DEAD_RATIO
+        };
+        let first = store.segments.iter().find(|(segment, size)| is_dead(segment, size)).map(|(segment, _)| *segment);
+        let last = store.segments.iter().rev().find(|(segment, size)| is_dead(segment, size)).map(|(segment, _)| *segment);
+        match (first, last) {
+            (Some(first), Some(last)) => store.segments.range(first..=last).map(|(segment, _)| *segment).collect(),
+            _ => Vec::new()
+        }
+    }
+    
+    /// Freeze the active segment if it is not empty and start a new one
+    /// Caller must hold `compaction_guard` exclusively
+    fn roll_segment(&self, store: &mut KvStoreInt) -> Result<()> {
+        let db_offset = self.db_offset.load(Ordering::Relaxed);
+        if db_offset > 0 {
+            let active_segment = self.active_segment.load(Ordering::Relaxed);
+            OpenOptions::new().write(true).create_new(true).open(KvStore::segment_path(&self.db_path, active_segment + 1))?;
+            store.segments.insert(active_segment, db_offset);
+            self.active_segment.store(active_segment + 1, Ordering::Relaxed);
+            self.db_offset.store(0, Ordering::Relaxed);
+            // No entry is being written, so nothing older than the removal entries can be applied anymore
+            store.tombstones.clear();
+        }
+        Ok(())
+    }
+    
+    /// Make the next compaction fail after copying `entries` entries, for testing only
+    #[doc(hidden)]
+    pub fn inject_compaction_failure(&self, entries: usize) {
+        self.store.write().unwrap().fail_compaction_after = Some(entries);
+    }
+    
+    /// Interrupt the next `count` attempts to write an entry to the segment, for testing only
+    #[doc(hidden)]
+    pub fn inject_interrupts(&self, count: u64) {
+        self.injected_interrupts.store(count, Ordering::Relaxed);
+    }
+    
+    /// Handle syncing the write-ahead log for every entry while holding its lock, for benchmarking only
+    #[doc(hidden)]
+    pub fn without_group_commit(mut self) -> KvStore {
+        self.group_commit = None;
+        self
+    }
+    
+    /// Number of syncs of the write-ahead log shared by concurrent writers so far, for testing only
+    #[doc(hidden)]
+    pub fn group_commits(&self) -> u64 {
+        self.group_commit.as_ref().map_or(0, |commit| commit.state.lock().unwrap().commits)
+    }
+    
+    /// Fail with `Interrupted` if an interrupt is injected by `inject_interrupts`
+    fn take_injected_interrupt(&self) -> io::Result<()> {
+        match self.injected_interrupts.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_sub(1)) {
+            Ok(_) => Err(io::ErrorKind::Interrupted.into()),
+            Err(_) => Ok(())
+        }
+    }
+    
+    /// Slow down compaction by sleeping `delay` for every copied entry, for testing only
+    #[doc(hidden)]
+    pub fn inject_compaction_delay(&self, delay: Duration) {
+        self.store.write().unwrap().compaction_delay = Some(delay);
+    }
+    
+    /// Take a snapshot of the counters since the store is opened
+    pub fn metrics(&self) -> KvStoreMetrics {
+        KvStoreMetrics {
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            reads: self.counters.reads.load(Ordering::Relaxed),
+            compactions: self.counters.compactions.load(Ordering::Relaxed),
+            reclaimed_bytes: self.counters.reclaimed_bytes.load(Ordering::Relaxed),
+            live_keys: self.store.read().unwrap().index.len() as u64
+        }
+    }
+    
+    /// Number of values read from the segment files so far, for testing only
+    #[doc(hidden)]
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
+    
+    /// Leave the store without graceful exit like a crashed process, which only releases the lock, for testing only
+    #[doc(hidden)]
+    pub fn abandon(self) {
+        // The next open reads the files left behind instead of sharing the abandoned store
+        if self.origin.is_some() {
+            OPENED_STORES.lock().unwrap().remove(&*self.db_path);
+        }
+        self.store.write().unwrap().lock.take();
+        std::mem::forget(self);
+    }
+    
+    /// Check if the index was rebuilt from the segment files when opened, for testing only
+    #[doc(hidden)]
+    pub fn reindexed(&self) -> bool {
+        self.store.read().unwrap().reindexed
+    }
+    
+    /// Check if `key` passes the bloom filter, for testing only
+    #[doc(hidden)]
+    pub fn bloom_may_contain(&self, key: &[u8]) -> bool {
+        self.bloom.read().unwrap().contains(key)
+    }
+    
+    /// Total size of the entries written since the last compaction, for testing only
+    #[doc(hidden)]
+    pub fn total_written(&self) -> u64 {
+        self.store.read().unwrap().header.total_written
+    }
+    
+    /// Number of keys only found in the spill file of the index, for testing only
+    #[doc(hidden)]
+    pub fn spilled_keys(&self) -> usize {
+        self.store.read().unwrap().index.spilled()
+    }
+    
+    /// Segment and offset of the live entry of `key`, for testing only
+    #[doc(hidden)]
+    pub fn entry_location(&self, key: &str) -> Option<(u64, u64)> {
+        self.store.read().unwrap().index.get(key.as_bytes()).map(|pos| (pos.segment, pos.offset))
+    }
+    
+    /// Insert entry to the active segment
     fn writeback(&self, entry: KvsEntries) -> Result<()> {
-        let mut handle = OpenOptions::new().write(true).open(&*self.db_path)?;
-        let ent_bytes = bson::to_vec(&entry)?;
-        let _lock = self.compaction_guard.read().unwrap(); // Block compaction until completed
-        let offset = self.db_offset.fetch_add(ent_bytes.len() as u64, Ordering::Relaxed);
-        // Write the entry with the specified offset
-        handle.seek(SeekFrom::Start(offset))?;
-        handle.write_all(ent_bytes.as_slice())?;
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        #[cfg(feature = "tracing")]
+        let span = self.writeback_span(&entry);
+        self.check_key(&entry)?;
+        let ent_bytes = self.options.codec.encode(&entry)?;
+        let _lock = self.compaction_guard.read().unwrap(); // Block segment switching until completed
+        self.append_entry(entry, ent_bytes)?;
+        drop(_lock);
+        self.after_writeback()?;
+        #[cfg(feature = "tracing")]
+        span.record("compaction", self.compaction_due());
+        Ok(())
+    }
+    
+    /// Append `entry` only if `condition` holds for the current index, returns whether the entry was written
+    ///
+    /// All other writers are blocked from the check until the entry is applied, so the condition cannot be changed
+    /// in between.
+    fn writeback_if(&self, entry: KvsEntries, condition: impl FnOnce(&BoundedIndex<KvsEntryPos>) -> bool) -> Result<bool> {
+        if self.options.read_only { return Err(KvsError::ReadOnly) }
+        #[cfg(feature = "tracing")]
+        let span = self.writeback_span(&entry);
+        self.check_key(&entry)?;
+        let ent_bytes = self.options.codec.encode(&entry)?;
+        let _lock = self.compaction_guard.write().unwrap();
+        if !condition(&self.store.read().unwrap().index) { return Ok(false) }
+        self.append_entry(entry, ent_bytes)?;
+        drop(_lock);
+        self.after_writeback()?;
+        #[cfg(feature = "tracing")]
+        span.record("compaction", self.compaction_due());
+        Ok(true)
+    }
+    
+    /// Span of writing `entry`, the lengths exclude the prefix of the namespace and the value is the stored one
+    #[cfg(feature = "tracing")]
+    fn writeback_span(&self, entry: &KvsEntries) -> tracing::span::EnteredSpan {
+        let (key_len, value_len) = match entry {
+            KvsEntries::SET(key, value, _) => (key.len() - self.prefix_len(key), value.len()),
+            KvsEntries::DELETE(key) => (key.len() - self.prefix_len(key), 0)
+        };
+        tracing::debug_span!("writeback", key_len, value_len, compaction = tracing::field::Empty).entered()
+    }
+    
+    /// Whether the automatic compaction is triggered by the entries written so far
+    #[cfg(feature = "tracing")]
+    fn compaction_due(&self) -> bool {
+        self.options.compaction_threshold.is_some() && self.should_compact(&self.store.read().unwrap())
+    }
+    
+    /// Validate the size and the content of the key of a value being written, without the prefix of the namespace
+    fn check_key(&self, entry: &KvsEntries) -> Result<()> {
+        match entry {
+            KvsEntries::SET(key, ..) => {
+                let key = &key[self.prefix_len(key)..];
+                KvStore::check_size(key.len(), self.options.max_key_size)?;
+                self.options.key_validation.check(key)
+            },
+            KvsEntries::DELETE(_) => Ok(())
+        }
+    }
+    
+    /// Write the encoded entry at the end of the active segment and apply it to the index
+    /// Caller must hold `compaction_guard` until it returns
+    fn append_entry(&self, entry: KvsEntries, ent_bytes: Vec<u8>) -> Result<()> {
+        // Outdate the saved index before the entry is written
+        if !self.store.read().unwrap().modified {
+            self.store.write().unwrap().mark_modified()?;
+        }
+        let segment = self.active_segment.load(Ordering::Relaxed);
+        let mut handle = self.acquire_handle(segment)?;
+        let offset = match &self.wal {
+            Some(wal) => {
+                // Offsets are taken in the order of the log, so the replay restores the same order
+                let mut wal = wal.lock().unwrap();
+                let offset = self.db_offset.fetch_add(ent_bytes.len() as u64, Ordering::Relaxed);
+                let record = KvsWalRecords { segment, offset, entry: ent_bytes.clone() };
+                wal.write_all(bson::to_vec(&record)?.as_slice())?;
+                match &self.group_commit {
+                    // Other writers append to the log while waiting, so a single sync makes all of them durable
+                    Some(commit) => {
+                        let seq = commit.appended();
+                        drop(wal);
+                        commit.wait_durable(seq)?;
+                    },
+                    None => KvStore::retry_interrupted(|| wal.sync_data())?
+                }
+                offset
+            },
+            None => self.db_offset.fetch_add(ent_bytes.len() as u64, Ordering::Relaxed)
+        };
+        // Write the entry with the specified offset, writing it again from the start is harmless
+        KvStore::retry_interrupted(|| {
+            self.take_injected_interrupt()?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.write_all(ent_bytes.as_slice())
+        })?;
+        self.release_handle(segment, handle);
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.counters.last_write.store(KvStore::now_millis(), Ordering::Relaxed);
         
+        let pos = KvsEntryPos { segment, offset, len: ent_bytes.len() as u64 };
+        if let Some(cache) = &self.cache {
+            match &entry {
+                KvsEntries::SET(key, ..) | KvsEntries::DELETE(key) => cache.lock().unwrap().remove(key)
+            }
+        }
         let mut store = self.store.write().unwrap();
+        // Entries may be applied out of order by concurrent writers, so the same rule as reindex is used
+        let shadowed = {
+            let store = &mut *store;
+            KvStore::apply_entry(&mut store.index, &mut store.tombstones, &entry, pos)
+        };
+        store.index.evict()?;
+        store.header.dead_bytes += shadowed.map_or(0, |shadowed| shadowed.len);
+        store.header.total_written += pos.len;
         match entry {
-            KvsEntries::SET(key, _) => 'blk1: {
-                if let Some(offset_) = store.index.get(&key) {
-                    if *offset_ > offset { break 'blk1; }
+            KvsEntries::SET(key, ..) => {
+                if key.first() == Some(&KvStore::NAMESPACE_MARK) {
+                    store.namespaced = true;
                 }
-                store.index.insert(key, offset);
-            },
-            KvsEntries::DELETE(key) => 'blk2: {
-                if let Some(offset_) = store.index.get(&key) {
-                    if *offset_ > offset { break 'blk2; }
+                if shadowed != Some(pos) {
+                    // Added under the index lock, so a rebuild of the filter never misses a key in the index
+                    self.bloom.read().unwrap().insert(&key);
+                    if self.bloom.read().unwrap().is_full() {
+                        *self.bloom.write().unwrap() = BloomFilter::build(store.index.keys(), self.options.bloom_false_positive_rate);
+                    }
                 }
-                store.index.remove(&key);
+            },
+            KvsEntries::DELETE(_) => {
+                // The removal entry itself holds no live data
+                store.header.dead_bytes += pos.len;
             }
         }
-        store.modified = true;
         Ok(())
     }
     
+    /// Roll the active segment and checkpoint the write-ahead log once they are full
+    fn after_writeback(&self) -> Result<()> {
+        // Start a new segment once the active segment is full
+        if self.db_offset.load(Ordering::Relaxed) >= self.options.segment_size {
+            let _lock = self.compaction_guard.write().unwrap();
+            if self.db_offset.load(Ordering::Relaxed) >= self.options.segment_size {
+                self.roll_segment(&mut self.store.write().unwrap())?;
+            }
+        }
+        // Keep the write-ahead log small, no entry is being written while the segment files are synced
+        if let Some(wal) = &self.wal {
+            if wal.lock().unwrap().metadata()?.len() >= KvStore::WAL_CHECKPOINT_SIZE {
+                let _lock = self.compaction_guard.write().unwrap();
+                self.store.read().unwrap().checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Set the value of a string key only if the presence of the key is `exists`
+    fn set_if(&self, key: String, value: String, exists: bool) -> Result<bool> {
+        let (value, flag) = self.compress(value.into_bytes())?;
+        let key = self.scoped(key.into_bytes());
+        let entry = KvsEntries::SET(key.clone(), value, flag | ValueType::String.id() << KvStore::FLAG_TYPE_SHIFT);
+        let written = self.writeback_if(entry, |index| index.contains_key(&key) == exists)?;
+        self.check_compaction()?;
+        Ok(written)
+    }
+    
     /// Fetch entry with the given `key`
-    fn fetch(&self, key: String) -> Result<Option<String>> {
-        let _lock = self.compaction_guard.read().unwrap(); // Block compaction until completed
-        let result = self.store.read().unwrap().index.get(&key).cloned();
-        if let Some(offset) = result {
-            let mut handle = OpenOptions::new().read(true).open(&*self.db_path)?;
-            handle.seek(SeekFrom::Start(offset))?;
-            if let Ok(KvsEntries::SET(key_, value)) = bson::from_reader::<_, KvsEntries>(handle.by_ref()) {
-                if key == key_ {
-                    return Ok(Some(value))
+    fn fetch(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("fetch", key_len = key.len() - self.prefix_len(&key),
+                                        value_len = tracing::field::Empty).entered();
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        let may_contain = self.bloom.read().unwrap().contains(&key);
+        if !may_contain { return Ok(None) }
+        let _lock = self.compaction_guard.read().unwrap(); // Block segment switching until completed
+        if let Some(pos) = self.lookup(&key)? {
+            // Cached value is only used if the key has not been updated or moved since it was read
+            let value = match self.cache.as_ref().and_then(|cache| cache.lock().unwrap().get(&key, pos)) {
+                Some(value) => value,
+                None => {
+                    let (value, _) = self.read_value(&key, pos)?;
+                    if let Some(cache) = &self.cache {
+                        cache.lock().unwrap().insert(key, pos, value.clone());
+                    }
+                    value
                 }
-            }
-            Err(KvsError::InvalidDataEntry)
+            };
+            #[cfg(feature = "tracing")]
+            span.record("value_len", value.len());
+            Ok(Some(value))
         } else { Ok(None) }
     }
     
-    /// Rewrite the current index file
-    fn write_index(index: &HashMap<String, u64>, db_path: &PathBuf) -> Result<()> {
+    /// Fetch entry with the given `key` along with its type, bypassing the cache which only holds the values
+    fn fetch_typed(&self, key: &[u8]) -> Result<Option<(Vec<u8>, ValueType)>> {
+        let may_contain = self.bloom.read().unwrap().contains(key);
+        if !may_contain { return Ok(None) }
+        let _lock = self.compaction_guard.read().unwrap(); // Block segment switching until completed
+        match self.lookup(key)? {
+            Some(pos) => Ok(Some(self.read_value(key, pos)?)),
+            None => Ok(None)
+        }
+    }
+    
+    /// Position of the entry of `key`, a key only found in the spill file of the index is moved back to memory
+    fn lookup(&self, key: &[u8]) -> Result<Option<KvsEntryPos>> {
+        let store = self.store.read().unwrap();
+        if let Some(pos) = store.index.get_in_memory(key) { return Ok(Some(pos)) }
+        if !store.index.is_spilled() { return Ok(None) }
+        drop(store);
+        let mut store = self.store.write().unwrap();
+        let pos = store.index.promote(key);
+        store.index.evict()?;
+        Ok(pos)
+    }
+    
+    /// Read and decompress the value of `key` in the entry at `pos`
+    /// Caller must hold `compaction_guard`
+    fn read_value(&self, key: &[u8], pos: KvsEntryPos) -> Result<(Vec<u8>, ValueType)> {
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        let entry = if self.options.mmap {
+            let map = self.acquire_map(pos.segment, pos.offset + pos.len)?;
+            self.options.codec.decode::<KvsEntries, _>(&map[pos.offset as usize..(pos.offset + pos.len) as usize])
+        } else {
+            let mut handle = self.acquire_handle(pos.segment)?;
+            handle.seek(SeekFrom::Start(pos.offset))?;
+            let entry = self.options.codec.decode::<KvsEntries, _>(BufReader::new(&mut handle));
+            self.release_handle(pos.segment, handle);
+            entry
+        };
+        match entry {
+            Ok(KvsEntries::SET(key_, value, flag)) if key == key_ => {
+                let value_type = ValueType::from_id(flag >> KvStore::FLAG_TYPE_SHIFT)?;
+                Ok((KvStore::decompress(value, flag & KvStore::FLAG_COMPRESSION_MASK)?, value_type))
+            },
+            _ => Err(KvsError::InvalidDataEntry)
+        }
+    }
+    
+    /// Offset of the stored value of `key` in the segment file, its stored length and its flag, reading only the bytes
+    /// around the value of the entry at `pos`, `None` if the entry is not laid out as written by this build
+    fn locate_value(&self, handle: &mut File, key: &[u8], pos: KvsEntryPos) -> Result<Option<(u64, u64, u8)>> {
+        let mut read_at = |offset: u64, len: u64| -> Result<Vec<u8>> {
+            let mut buf = vec![0; len as usize];
+            handle.seek(SeekFrom::Start(pos.offset + offset))?;
+            handle.read_exact(&mut buf)?;
+            Ok(buf)
+        };
+        let key_len = key.len() as u64;
+        match self.options.codec {
+            // Length of the entry and index of the variant, followed by the key and the value prefixed with their
+            // lengths, then the flag
+            CodecKind::Bincode => {
+                let header_len = 24 + key_len;
+                if pos.len < header_len + 1 { return Ok(None) }
+                let header = read_at(0, header_len)?;
+                let value_len = u64::from_le_bytes(header[header.len() - 8..].try_into().unwrap());
+                let expected = [&(pos.len as i32).to_le_bytes()[..], &0u32.to_le_bytes(), &key_len.to_le_bytes(), key,
+                    &value_len.to_le_bytes()].concat();
+                if header != expected || pos.len.checked_sub(header_len + 1) != Some(value_len) { return Ok(None) }
+                let flag = read_at(header_len + value_len, 1)?[0];
+                Ok(Some((pos.offset + header_len, value_len, flag)))
+            },
+            // Document holding the array of the key, the value and the flag under the name of the variant
+            CodecKind::Bson => {
+                let header_len = 29 + key_len;
+                if pos.len < header_len + 2 { return Ok(None) }
+                let header = read_at(0, header_len)?;
+                let value_len = i32::from_le_bytes(header[header.len() - 5..header.len() - 1].try_into().unwrap());
+                let expected = [&(pos.len as i32).to_le_bytes()[..], b"\x04SET\0", &(pos.len as i32 - 10).to_le_bytes(),
+                    b"\x050\0", &(key_len as i32).to_le_bytes(), b"\0", key,
+                    b"\x051\0", &value_len.to_le_bytes(), b"\0"].concat();
+                if header != expected || value_len < 0 { return Ok(None) }
+                let value_len = value_len as u64;
+                // Entries written before build 1500 have no flag
+                match pos.len.checked_sub(header_len + value_len) {
+                    Some(2) => Ok(Some((pos.offset + header_len, value_len, 0))),
+                    Some(9) => {
+                        let trailer = read_at(header_len + value_len, 9)?;
+                        if trailer[..3] != *b"\x102\0" || trailer[4..] != [0; 5] { return Ok(None) }
+                        Ok(Some((pos.offset + header_len, value_len, trailer[3])))
+                    },
+                    _ => Ok(None)
+                }
+            }
+        }
+    }
+    
+    /// Compress `value` according to the options, returning the stored bytes and the compression flag
+    ///
+    /// Every written value passes through here, so the size limit is checked on the value before compression.
+    fn compress(&self, value: Vec<u8>) -> Result<(Vec<u8>, u8)> {
+        KvStore::check_size(value.len(), self.options.max_value_size)?;
+        match self.options.compression {
+            Compression::Zstd(level) if value.len() >= KvStore::MIN_COMPRESSION_SIZE => {
+                let compressed = zstd::encode_all(value.as_slice(), level)?;
+                // Keep incompressible value as is
+                if compressed.len() < value.len() {
+                    return Ok((compressed, KvStore::FLAG_ZSTD))
+                }
+                Ok((value, KvStore::FLAG_UNCOMPRESSED))
+            },
+            _ => Ok((value, KvStore::FLAG_UNCOMPRESSED))
+        }
+    }
+    
+    /// Restore the original value from the stored bytes
+    fn decompress(value: Vec<u8>, flag: u8) -> Result<Vec<u8>> {
+        match flag {
+            KvStore::FLAG_UNCOMPRESSED => Ok(value),
+            KvStore::FLAG_ZSTD => Ok(zstd::decode_all(value.as_slice())?),
+            _ => Err(KvsError::InvalidDataEntry)
+        }
+    }
+    
+    /// Take a cached segment file handle, or open a new one if none is available
+    /// Caller must hold `compaction_guard` until the handle is released
+    fn acquire_handle(&self, segment: u64) -> Result<File> {
+        if let Some(handle) = self.handles.lock().unwrap().get_mut(&segment).and_then(|handles| handles.pop()) {
+            return Ok(handle)
+        }
+        Ok(OpenOptions::new().read(true).write(!self.options.read_only).open(KvStore::segment_path(&self.db_path, segment))?)
+    }
+    
+    /// Return the segment file handle to the cache
+    fn release_handle(&self, segment: u64, handle: File) {
+        let mut handles = self.handles.lock().unwrap();
+        let handles = handles.entry(segment).or_default();
+        if handles.len() < KvStore::MAX_CACHED_HANDLES {
+            handles.push(handle);
+        }
+    }
+    
+    /// Take the memory map of the segment, mapping the file again if the map is shorter than `len`
+    /// Caller must hold `compaction_guard` until the map is released
+    fn acquire_map(&self, segment: u64, len: u64) -> Result<Arc<Mmap>> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(map) = maps.get(&segment).filter(|map| map.len() as u64 >= len) {
+            return Ok(map.clone())
+        }
+        let handle = OpenOptions::new().read(true).open(KvStore::segment_path(&self.db_path, segment))?;
+        // Segment files are never truncated while the store is open, only appended, renamed or removed,
+        // so the mapped range stays valid until the map is dropped
+        let map = Arc::new(unsafe { Mmap::map(&handle)? });
+        if (map.len() as u64) < len {
+            return Err(KvsError::InvalidDataEntry)
+        }
+        maps.insert(segment, map.clone());
+        Ok(map)
+    }
+    
+    /// Path of the segment file with id `segment`
+    pub(super) fn segment_path(db_path: &Path, segment: u64) -> PathBuf {
+        db_path.with_extension(format!("{}.db", segment))
+    }
+    
+    /// Find the ids of all existing segment files in ascending order
+    pub(super) fn list_segments(db_path: &Path) -> Result<Vec<u64>> {
+        let prefix = format!("{}.", db_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default());
+        let dir = match db_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if let Some(segment) = name.to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".db"))
+                .and_then(|id| id.parse::<u64>().ok()) {
+                segments.push(segment);
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+    
+    /// Rebuild the index by replaying the entries of all segments in order
+    ///
+    /// Segments are scanned by up to `threads` threads, as every segment file starts with an entry. The results are
+    /// merged in the order of the segments, so the index is the same as the one of a serial scan.
+    /// Returns the index and the end offset of the last complete entry in the last segment
+    fn reindex(db_path: &Path, segments: &[u64], threads: u32, codec: CodecKind) -> Result<(HashMap<Vec<u8>, KvsEntryPos>, u64)> {
+        let mut index = HashMap::new();
+        let mut valid_end = 0;
+        if threads <= 1 || segments.len() <= 1 {
+            for segment in segments.iter() {
+                let (segment_index, tombstones, end) = KvStore::scan_segment(db_path, *segment, codec)?;
+                KvStore::merge_segment_index(&mut index, segment_index, tombstones);
+                valid_end = end;
+            }
+            return Ok((index, valid_end))
+        }
+        
+        let pool = SharedQueueThreadPool::new(min(threads, segments.len() as u32))?;
+        let (sender, receiver) = mpsc::channel();
+        for (i, segment) in segments.iter().cloned().enumerate() {
+            let sender = sender.clone();
+            let db_path = db_path.to_path_buf();
+            pool.spawn(move || {
+                let _ = sender.send((i, KvStore::scan_segment(&db_path, segment, codec)));
+            });
+        }
+        drop(sender);
+        // Hold the results completed ahead of an older segment
+        let mut pending = BTreeMap::new();
+        let mut merged = 0;
+        for (i, scan) in receiver.iter() {
+            pending.insert(i, scan?);
+            while let Some((segment_index, tombstones, end)) = pending.remove(&merged) {
+                KvStore::merge_segment_index(&mut index, segment_index, tombstones);
+                valid_end = end;
+                merged += 1;
+            }
+        }
+        // A panicking scan never sends its result
+        if merged != segments.len() {
+            return Err(io::Error::other("Failed to scan a segment file").into())
+        }
+        Ok((index, valid_end))
+    }
+    
+    /// Replay the entries of a single segment
+    ///
+    /// Returns the index of the keys set and the removal entries of the keys removed in the segment, and the end
+    /// offset of the last complete entry
+    fn scan_segment(db_path: &Path, segment: u64, codec: CodecKind) -> Result<(KvsIndex, KvsIndex, u64)> {
+        let mut index = HashMap::new();
+        let mut tombstones = HashMap::new();
+        let mut reader = BufReader::new(OpenOptions::new().read(true).open(KvStore::segment_path(db_path, segment))?);
+        let mut offset = 0;
+        while let Ok(entry) = codec.decode::<KvsEntries, _>(&mut reader) {
+            // Store the start offset of next entry
+            let next_offset = reader.stream_position()?;
+            let pos = KvsEntryPos { segment, offset, len: next_offset - offset };
+            KvStore::apply_entry(&mut index, &mut tombstones, &entry, pos);
+            offset = next_offset;
+        }
+        Ok((index, tombstones, offset))
+    }
+    
+    /// Apply the result of `scan_segment` on top of the index of the older segments
+    fn merge_segment_index(index: &mut KvsIndex, segment_index: KvsIndex, tombstones: KvsIndex) {
+        // A key is either set or removed at the end of the segment
+        for key in tombstones.into_keys() {
+            index.remove(&key);
+        }
+        index.extend(segment_index);
+    }
+    
+    /// Apply the entry at `pos` to `index`, returns the position of the entry shadowed by the change if any
+    ///
+    /// The entry with the highest position always wins whatever the order of application, so the index built by
+    /// writers appending concurrently is the same as the one rebuilt from the segment files. `tombstones` keeps the
+    /// position of the removal entries, so an older entry applied afterward does not bring the key back.
+    fn apply_entry(index: &mut impl PositionMap<KvsEntryPos>, tombstones: &mut KvsIndex, entry: &KvsEntries,
+                   pos: KvsEntryPos) -> Option<KvsEntryPos> {
+        let key = match entry {
+            KvsEntries::SET(key, ..) | KvsEntries::DELETE(key) => key
+        };
+        let current = index.position(key);
+        if max(current, tombstones.get(key).cloned()) > Some(pos) {
+            // The new entry is shadowed by a newer one, a removal entry shadows nothing in that case
+            return match entry {
+                KvsEntries::SET(..) => Some(pos),
+                KvsEntries::DELETE(_) => None
+            }
+        }
+        match entry {
+            KvsEntries::SET(..) => {
+                tombstones.remove(key);
+                index.set_position(key.clone(), pos);
+            },
+            KvsEntries::DELETE(_) => {
+                index.remove_position(key);
+                tombstones.insert(key.clone(), pos);
+            }
+        }
+        current
+    }
+    
+    /// Write the entries of the write-ahead log missing from the segment files, returns whether any is written
+    ///
+    /// Every record since the last checkpoint is kept in the log, so the missing entries are restored in order.
+    /// The segment files are synced afterward, so the log can be emptied.
+    fn replay_wal(db_path: &Path, wal_path: &Path) -> Result<bool> {
+        let mut reader = BufReader::new(File::open(wal_path)?);
+        let mut replayed = false;
+        let mut buf = Vec::new();
+        // The last record may be partially written, its entry was never written to the segment
+        while let Ok(record) = bson::from_reader::<_, KvsWalRecords>(&mut reader) {
+            let mut handle = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                .open(KvStore::segment_path(db_path, record.segment))?;
+            buf.resize(record.entry.len(), 0);
+            let is_present = handle.metadata()?.len() >= record.offset + record.entry.len() as u64
+                && handle.seek(SeekFrom::Start(record.offset)).and_then(|_| handle.read_exact(&mut buf)).is_ok()
+                && buf == record.entry;
+            if !is_present {
+                handle.seek(SeekFrom::Start(record.offset))?;
+                handle.write_all(record.entry.as_slice())?;
+                replayed = true;
+            }
+        }
+        KvStore::sync_segments(db_path)?;
+        Ok(replayed)
+    }
+    
+    /// Run `op` again as long as it is interrupted by a signal before completing
+    ///
+    /// Only for operations which have no effect when interrupted or can be repeated from the start
+    fn retry_interrupted<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match op() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result
+            }
+        }
+    }
+    
+    /// Make all segment files durable on the disk
+    fn sync_segments(db_path: &Path) -> Result<()> {
+        for segment in KvStore::list_segments(db_path)?.into_iter() {
+            let handle = File::open(KvStore::segment_path(db_path, segment))?;
+            KvStore::retry_interrupted(|| handle.sync_all())?;
+        }
+        Ok(())
+    }
+    
+    /// FNV-1a checksum of the segment files of the database at `db_path` along with their ids, so swapped or missing
+    /// segments are detected as well
+    fn file_checksum(db_path: &Path) -> Result<u64> {
+        let mut hash = 0xcbf29ce484222325_u64;
+        let mut update = |data: &[u8]| {
+            for byte in data {
+                hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+            }
+        };
+        let mut buf = vec![0; 64 * 1024];
+        for segment in KvStore::list_segments(db_path)? {
+            update(&segment.to_le_bytes());
+            let mut reader = File::open(KvStore::segment_path(db_path, segment))?;
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => update(&buf[..len]),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into())
+                }
+            }
+        }
+        Ok(hash)
+    }
+    
+    /// Read the raw bytes of the entry at the current position of `reader` into `buf`
+    pub(super) fn read_raw_entry<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<()> {
+        // Each entry is a BSON document prefixed with its total length in little-endian
+        let mut len_bytes = [0; 4];
+        reader.read_exact(&mut len_bytes)?;
+        let len = i32::from_le_bytes(len_bytes);
+        if len < 5 { return Err(KvsError::InvalidDataEntry) }
+        buf.clear();
+        buf.extend_from_slice(&len_bytes);
+        if reader.take(len as u64 - 4).read_to_end(buf)? != len as usize - 4 {
+            return Err(KvsError::InvalidDataEntry)
+        }
+        Ok(())
+    }
+    
+    /// Check that every position in `index` lies within the segment of the given size and holds a `SET` entry of its key
+    fn index_matches_segments(db_path: &Path, index: &KvsIndex, sizes: &BTreeMap<u64, u64>, codec: CodecKind) -> Result<bool> {
+        // Visit the entries in file order, so each segment is read from start to end once
+        let mut positions = index.iter().collect::<Vec<_>>();
+        positions.sort_unstable_by_key(|(_, pos)| (pos.segment, pos.offset));
+        let mut reader: Option<(u64, BufReader<File>)> = None;
+        for (key, pos) in positions.into_iter() {
+            match sizes.get(&pos.segment) {
+                Some(size) if pos.offset + pos.len <= *size => {},
+                _ => return Ok(false)
+            }
+            if reader.as_ref().is_none_or(|(segment, _)| *segment != pos.segment) {
+                let file = File::open(KvStore::segment_path(db_path, pos.segment))?;
+                reader = Some((pos.segment, BufReader::new(file)));
+            }
+            let (_, reader) = reader.as_mut().unwrap();
+            reader.seek(SeekFrom::Start(pos.offset))?;
+            match codec.decode::<KvsEntries, _>(reader.by_ref().take(pos.len)) {
+                Ok(KvsEntries::SET(key_, ..)) if key_ == *key => {},
+                _ => return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+    
+    /// Read the index file and the generation in its footer
+    fn read_index(index_path: &Path) -> Result<(KvsIndex, Option<u64>)> {
+        let mut index = HashMap::new();
+        let mut generation = None;
+        let mut reader = BufReader::new(OpenOptions::new().read(true).open(index_path)?);
+        while let Ok(record) = bson::from_reader::<_, KvsIndexRecords>(&mut reader) {
+            match record {
+                KvsIndexRecords::Entry(entry) => {
+                    index.insert(entry.key, KvsEntryPos { segment: entry.segment, offset: entry.offset, len: entry.len });
+                },
+                KvsIndexRecords::Footer(footer) => {
+                    generation = Some(footer.generation);
+                    break
+                }
+            }
+        }
+        Ok((index, generation))
+    }
+    
+    /// Rewrite the current index file, the footer is written last so a partially written index has no generation
+    fn write_index<'a>(index: impl Iterator<Item = (&'a [u8], KvsEntryPos)>, generation: u64, db_path: &PathBuf) -> Result<()> {
         let mut handle = OpenOptions::new().write(true).truncate(true).create(true).open(db_path)?;
-        let mut writer = BufWriter::new(handle.by_ref());
-        for (key, offset) in index.iter() {
+        let mut writer = BufWriter::new(&mut handle);
+        for (key, pos) in index {
             let entry = KvsIndexEntries {
-                key: key.clone(),
-                offset: *offset
+                key: key.to_vec(),
+                segment: pos.segment,
+                offset: pos.offset,
+                len: pos.len
             };
             writer.write_all(bson::to_vec(&entry)?.as_slice())?;
         }
+        writer.write_all(bson::to_vec(&KvsIndexFooter { generation })?.as_slice())?;
+        writer.flush()?;
         Ok(())
     }
     
     /// Update database file header
-    fn write_header<W: Write + Seek>(header: &KvHeader, mut writer: W) -> Result<u64> {
+    pub(super) fn write_header<W: Write + Seek>(header: &KvHeader, mut writer: W) -> Result<u64> {
         let header_byte = bson::to_vec(header)?;
         writer.seek(SeekFrom::Start(0))?;
         writer.write_all(header_byte.as_slice())?;
         // Remark: The current cursor is now just just after the header region
-        Ok(writer.seek(SeekFrom::Current(0))?)
+        Ok(writer.stream_position()?)
+    }
+}
+
+impl Iterator for KvStoreIter {
+    type Item = Result<(String, String)>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            let value = match self.store.fetch(key.clone()) {
+                Ok(Some(value)) => value,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err))
+            };
+            let key = key[self.store.prefix_len(&key)..].to_vec();
+            let pair = String::from_utf8(key).and_then(|key| String::from_utf8(value).map(|value| (key, value)));
+            return Some(pair.map_err(KvsError::from))
+        }
+        None
+    }
+}
+
+impl Compactor {
+    /// Start the compaction thread working on `store`
+    fn spawn(store: KvStore) -> Compactor {
+        // At most one pending request, further requests are merged into it
+        let (signal, receiver) = mpsc::sync_channel::<()>(1);
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                // Deferred while the store is being written, new requests are merged into this one meanwhile
+                while let Some(remaining) = store.idle_remaining() {
+                    thread::sleep(remaining);
+                }
+                // Failed compaction is retried on next request
+                let _ = store.compaction(false);
+            }
+        });
+        Compactor {
+            signal: Some(signal),
+            handle: Some(handle)
+        }
+    }
+    
+    /// Request a compaction without waiting for it
+    fn notify(&self) {
+        if let Some(signal) = &self.signal {
+            let _ = signal.try_send(());
+        }
+    }
+}
+
+impl GroupCommit {
+    fn new(file: File) -> GroupCommit {
+        GroupCommit {
+            file,
+            state: Mutex::new(CommitState::default()),
+            synced: Condvar::new()
+        }
+    }
+    
+    /// Number the record just appended to the log, must be called under the lock of the log to follow its order
+    fn appended(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.appended += 1;
+        state.appended
+    }
+    
+    /// Wait until the record numbered `seq` is durable, syncing the log if no other writer is doing so
+    fn wait_durable(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced < seq {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // Every record appended so far is covered by the sync
+            let target = state.appended;
+            state.syncing = true;
+            drop(state);
+            let result = KvStore::retry_interrupted(|| self.file.sync_data());
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = max(state.synced, target);
+                state.commits += 1;
+            }
+            // Waiters retry the sync themselves if it failed
+            self.synced.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Stop the thread and wait for the running compaction
+        drop(self.signal.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl KvStoreInt {
+    /// Increase the generation in the header before the saved index file becomes outdated
+    fn mark_modified(&mut self) -> Result<()> {
+        if !self.modified {
+            self.header.generation += 1;
+            KvStore::write_header(&self.header, OpenOptions::new().write(true).open(&self.db_path)?)?;
+            self.modified = true;
+        }
+        Ok(())
+    }
+    
+    /// Make the segment files durable, then empty the write-ahead log, no entry must be being written
+    fn checkpoint(&self) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            let wal = wal.lock().unwrap();
+            KvStore::sync_segments(&self.db_path)?;
+            KvStore::retry_interrupted(|| wal.set_len(0))?;
+            KvStore::retry_interrupted(|| wal.sync_all())?;
+        }
+        Ok(())
     }
 }
 
 impl Drop for KvStoreInt {
     fn drop(&mut self) {
-        // Rewrite index if modified
-        if self.modified {
-            // Rewrite index file
-            KvStore::write_index(&self.index, &self.index_path).unwrap();
+        if self.read_only { return }
+        self.checkpoint().unwrap();
+        if self.index_mode == IndexMode::Persisted {
+            // Rewrite index if modified
+            if self.modified {
+                // Rewrite index file
+                KvStore::write_index(self.index.iter(), self.header.generation, &self.index_path).unwrap();
+            }
+            // The filter is only loaded with the index after graceful exit, so it is always saved here
+            self.bloom.read().unwrap().save(&self.index_path.with_extension("bloom")).unwrap();
         }
+        self.header.checksum = self.file_checksum.then(|| KvStore::file_checksum(&self.db_path).unwrap());
         // Set last_graceful_exit bit
         self.header.flags = 0x0;
         KvStore::write_header(&self.header, OpenOptions::new().write(true).open(&*self.db_path).unwrap()).unwrap();
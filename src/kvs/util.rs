@@ -16,8 +16,37 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use super::Result;
-use std::thread;
+use super::{KvsError, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Thread pool implementation selected at runtime
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThreadPoolKind {
+    /// `NaiveThreadPool`, a new thread for every job
+    Naive,
+    /// `SharedQueueThreadPool`
+    #[default]
+    SharedQueue,
+    /// `RayonThreadPool`
+    Rayon
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = KvsError;
+    
+    fn from_str(name: &str) -> Result<ThreadPoolKind> {
+        match name {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared" => Ok(ThreadPoolKind::SharedQueue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(KvsError::InvalidArguments(format!("Unknown thread pool {}", name)))
+        }
+    }
+}
 
 pub trait ThreadPool {
     /// Creates a new thread pool, immediately spawning the specified number of threads
@@ -38,26 +67,73 @@ impl ThreadPool for NaiveThreadPool {
     }
 }
 
-pub struct SharedQueueThreadPool;
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of worker threads taking jobs from a shared queue
+///
+/// Dropping the pool waits for all queued jobs to finish
+pub struct SharedQueueThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>
+}
 
 impl ThreadPool for SharedQueueThreadPool {
-    fn new(_thread: u32) -> Result<Self> where Self: Sized {
-        todo!()
+    fn new(thread: u32) -> Result<Self> where Self: Sized {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(thread as usize);
+        for _ in 0..thread {
+            let receiver = receiver.clone();
+            workers.push(thread::Builder::new().spawn(move || SharedQueueThreadPool::run(receiver))?);
+        }
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers
+        })
     }
     
-    fn spawn<F>(&self, _job: F) where F: FnOnce() + Send + 'static {
-        todo!()
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        self.sender.as_ref().unwrap().send(Box::new(job)).unwrap();
     }
 }
 
-pub struct RayonThreadPool;
+impl SharedQueueThreadPool {
+    /// Take jobs until the pool is dropped
+    fn run(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break
+            };
+            // A panicking job should not take down the worker
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // Close the queue, the workers exit after draining the remaining jobs
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Thread pool backed by rayon
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool
+}
 
 impl ThreadPool for RayonThreadPool {
-    fn new(_thread: u32) -> Result<Self> where Self: Sized {
-        todo!()
+    fn new(thread: u32) -> Result<Self> where Self: Sized {
+        Ok(RayonThreadPool {
+            pool: rayon::ThreadPoolBuilder::new().num_threads(thread as usize).build()?
+        })
     }
     
-    fn spawn<F>(&self, _job: F) where F: FnOnce() + Send + 'static {
-        todo!()
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        self.pool.spawn(job);
     }
 }
@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsCommand, KvsEngine, KvsError, SledKvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -164,6 +166,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    let _ = child.wait();
     
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,6 +174,55 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-server --log-format json` should write one JSON object per record, filtered by `--log-level`
+#[test]
+fn cli_json_log() {
+    let records = |level: &str| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4082", "--log-format", "json", "--log-level", level])
+            .current_dir(&temp_dir)
+            .stderr(File::create(temp_dir.path().join("term")).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+        
+        // The terminal and the stderr file receive the same records, each with its own timestamp
+        let parse = |name: &str| fs::read_to_string(temp_dir.path().join(name)).expect("unable to read from log file")
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("log record is not JSON"))
+            .collect::<Vec<_>>();
+        let records = parse("stderr");
+        let messages = |records: &[serde_json::Value]| records.iter().map(|record| record["msg"].clone()).collect::<Vec<_>>();
+        assert_eq!(messages(&records), messages(&parse("term")));
+        records
+    };
+    
+    let info = records("info");
+    let started = info.iter().find(|record| record["msg"] == "kvs-server").expect("missing start record");
+    assert_eq!(started["level"], "INFO");
+    assert_eq!(started["addr"], "127.0.0.1:4082");
+    assert_eq!(started["version"], env!("CARGO_PKG_VERSION"));
+    assert!(started["ts"].is_string());
+    
+    assert!(records("warn").iter().all(|record| record["level"] != "INFO"));
+    
+    // Unknown level or format is rejected
+    for args in [["--log-level", "verbose"], ["--log-format", "xml"]] {
+        let temp_dir = TempDir::new().unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4082"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -184,6 +236,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
         
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +256,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
         
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -224,6 +278,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
     
@@ -305,6 +360,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
     
@@ -335,3 +391,266 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Failures should be reported on stderr with an exit code distinguishing their kind
+#[test]
+fn client_cli_exit_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    
+    // Dead server
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(contains("Connection error"));
+    
+    // Live server
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("Key not found"));
+    
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stderr(is_empty());
+    
+    child.kill().expect("server exited before killed");
+    let _ = child.wait();
+}
+
+// `kvs-client selftest` should pass against a live server without leaving its key behind
+#[test]
+fn client_cli_selftest() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["selftest", "--addr", "127.0.0.1:4083"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(contains("FAIL"))
+        .stderr(contains("Connection error"));
+    
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4083"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["selftest", "--addr", "127.0.0.1:4083"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("SET").and(contains("GET")).and(contains("RM")).and(contains("PASS")))
+            .stderr(is_empty());
+    }
+    let client = KvsClient::open("127.0.0.1:4083").unwrap();
+    assert_eq!(client.len().unwrap(), 0);
+    
+    child.kill().expect("server exited before killed");
+    let _ = child.wait();
+}
+
+// `kvs` should persist the state in the base directory across invocations with both engines
+#[test]
+fn cli_embedded_store() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let base_dir = temp_dir.path().to_str().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let kvs = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs").unwrap();
+            cmd.args(args).args(["--base-dir", base_dir, "--engine", engine]).current_dir(&work_dir);
+            cmd
+        };
+        
+        kvs(&["set", "key1", "value1"]).assert().success().stdout(is_empty());
+        kvs(&["set", "key2", "value2"]).assert().success().stdout(is_empty());
+        kvs(&["get", "key1"]).assert().success().stdout("value1\n");
+        kvs(&["rm", "key1"]).assert().success().stdout(is_empty());
+        kvs(&["get", "key1"]).assert().success().stdout("Key not found\n");
+        kvs(&["rm", "key1"]).assert().failure().stdout("Key not found\n");
+        kvs(&["get", "key2"]).assert().success().stdout("value2\n");
+        
+        // Nothing is written outside the base directory
+        assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+    }
+}
+
+// `kvs` should keep the database in the directory given by `KVS_DATA_DIR`, creating it if missing
+#[test]
+fn cli_data_dir_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let work_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).env("KVS_DATA_DIR", &data_dir).current_dir(&work_dir);
+        cmd
+    };
+    
+    kvs(&["set", "key1", "value1"]).assert().success().stdout(is_empty());
+    assert!(data_dir.join("kvs.db").exists());
+    assert!(!work_dir.path().join("kvs.db").exists());
+    kvs(&["get", "key1"]).assert().success().stdout("value1
+");
+    
+    // `--base-dir` takes precedence over the environment variable
+    let other_dir = TempDir::new().unwrap();
+    kvs(&["get", "key1", "--base-dir", other_dir.path().to_str().unwrap()]).assert().success().stdout("Key not found
+");
+    assert!(other_dir.path().join("kvs.db").exists());
+    kvs(&["get", "key1"]).assert().success().stdout("value1
+");
+}
+
+// `kvs-server` should serve requests with every thread pool selected by `--pool`
+#[test]
+fn cli_thread_pool() {
+    for (pool, addr) in [("naive", "127.0.0.1:4051"), ("shared", "127.0.0.1:4052"), ("rayon", "127.0.0.1:4053")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", "kvs", "--addr", addr, "--pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", pool, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", pool));
+        
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    }
+    
+    // At least one thread is required
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4054", "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4054", "--pool", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Writes acknowledged by a server with the sled engine should survive killing the server
+#[test]
+fn cli_sled_acknowledged_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4086"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    
+    let client = KvsClient::open("127.0.0.1:4086").unwrap();
+    let commands = (0..200).map(|i| KvsCommand::Set(format!("key{}", i), format!("value{}", i))).collect();
+    assert!(client.pipeline(commands).unwrap().into_iter().all(|result| result.is_ok()));
+    client.set("key200".to_owned(), "value200".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+    let _ = child.wait();
+    
+    let store = SledKvsEngine::open(temp_dir.path()).unwrap();
+    for i in 0..201 {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+}
+
+// `kvs-server` should serve clients over TLS with the certificate and key given by `--tls-cert` and `--tls-key`
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert_path = temp_dir.path().join("server.crt");
+    let key_path = temp_dir.path().join("server.key");
+    fs::write(&cert_path, certified.cert.pem()).unwrap();
+    fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+    let (cert, key) = (cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+    
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4090", "--tls-cert", cert, "--tls-key", key])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    
+    let client = KvsClient::open_tls("127.0.0.1:4090", &cert_path).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert!(KvsClient::open("127.0.0.1:4090").unwrap().get("key1".to_owned()).is_err());
+    child.kill().expect("server exited before killed");
+    let _ = child.wait();
+    
+    // The certificate is useless without its key
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4090", "--tls-cert", cert])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` should only serve clients authenticated with the token given by `--token`
+#[test]
+fn cli_token() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4092", "--token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    
+    let mut client = KvsClient::open("127.0.0.1:4092").unwrap();
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Unauthorized)));
+    client.authenticate("secret").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    child.kill().expect("server exited before killed");
+    let _ = child.wait();
+}
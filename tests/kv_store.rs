@@ -1,6 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use assert_cmd::prelude::*;
+use bson::{doc, Bson, Document};
+use kvs::{open_engine, open_engine_with_options, Codec, CodecKind, CompactionPolicy, CompactionProgress, Compression, IndexMode, KeyValidation, KvStore, KvStoreBuilder, KvStoreMetrics, KvStoreOptions, KvsEngine, KvsError, Result, SledKvsEngine, Utf8Mode, ValidationIssue, ValidationReport, ValueType};
+use predicates::str::contains;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Read;
+use std::process::Command;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -25,6 +33,284 @@ fn get_stored_value() -> Result<()> {
     Ok(())
 }
 
+// Should reuse the index file after graceful exit
+#[test]
+fn reuse_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    drop(store);
+    
+    // The index of an empty database is reused as well
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.reindexed());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    
+    // Read-only sessions leave the index valid
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(!store.reindexed());
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+    }
+    
+    // Without graceful exit the index is rebuilt
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.abandon();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    
+    Ok(())
+}
+
+// Stale index file should be detected on open and replaced by rebuilding the index
+#[test]
+fn stale_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stale_path = temp_dir.path().join("stale.dir");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    fs::copy(temp_dir.path().join("kvs.dir"), &stale_path)?;
+    
+    // Offsets past the end of the segment
+    let store = KvStore::open(temp_dir.path())?;
+    store.clear()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::copy(&stale_path, temp_dir.path().join("kvs.dir"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.len()?, 1);
+    drop(store);
+    
+    // Offsets within the segment but at the entries of other keys
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.reindexed());
+    store.clear()?;
+    for i in 0..100 {
+        store.set(format!("key{}", 99 - i), format!("value{}", 99 - i))?;
+    }
+    drop(store);
+    fs::copy(&stale_path, temp_dir.path().join("kvs.dir"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    
+    Ok(())
+}
+
+// Should never write the index file in AlwaysRebuild mode
+#[test]
+fn always_rebuild_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { index_mode: IndexMode::AlwaysRebuild, ..KvStoreOptions::default() };
+    for i in 0..3 {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert!(store.reindexed());
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.flush()?;
+        for j in 0..=i {
+            assert_eq!(store.get(format!("key{}", j))?, Some(format!("value{}", j)));
+        }
+        drop(store);
+        assert!(!temp_dir.path().join("kvs.dir").exists());
+        assert!(!temp_dir.path().join("kvs.bloom").exists());
+    }
+    
+    // The index file written in persisted mode is removed
+    drop(KvStore::open(temp_dir.path())?);
+    assert!(temp_dir.path().join("kvs.dir").exists());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!temp_dir.path().join("kvs.dir").exists());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    
+    Ok(())
+}
+
+// Read-only store should serve reads and leave the database files untouched
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    
+    let mtimes = || ["kvs.db", "kvs.dir", "kvs.0.db"].map(|name| {
+        fs::metadata(temp_dir.path().join(name)).and_then(|metadata| metadata.modified()).expect("unable to read the file time")
+    });
+    let before = mtimes();
+    thread::sleep(Duration::from_millis(20));
+    
+    let store = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(store.set("key2".to_owned(), "value2".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    store.flush()?;
+    drop(store);
+    assert_eq!(mtimes(), before);
+    
+    // Missing database is not created
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::builder().read_only(true).open(empty_dir.path()).is_err());
+    assert!(!empty_dir.path().join("kvs.db").exists());
+    
+    Ok(())
+}
+
+// Entries missing from the segment files after a crash are restored from the write-ahead log
+#[test]
+fn write_ahead_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().wal(true).open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.abandon();
+    assert!(temp_dir.path().join("kvs.wal").exists());
+    
+    // Simulate the segment file lagging behind the log
+    OpenOptions::new().write(true).open(temp_dir.path().join("kvs.0.db"))
+        .and_then(|file| file.set_len(0))
+        .expect("unable to truncate the segment file");
+    
+    let store = KvStore::builder().wal(true).open(temp_dir.path())?;
+    assert!(store.reindexed());
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+    
+    // The log is emptied once the segment files are synced
+    assert_eq!(fs::metadata(temp_dir.path().join("kvs.wal")).expect("unable to read the log file").len(), 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join("kvs.wal").exists());
+    
+    Ok(())
+}
+
+// Entries of concurrent writers sharing a sync of the write-ahead log should all be restored after a crash
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().wal(true).open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles = (0..8).map(|i| {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            barrier.wait();
+            for j in 0..50 {
+                store.set(format!("key{}-{}", i, j), format!("value{}", j)).unwrap();
+            }
+        })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // Every write returned after a sync covering its entry
+    let commits = store.group_commits();
+    assert!(commits > 0 && commits <= 400);
+    store.abandon();
+    
+    // Simulate the segment file lagging behind the log
+    OpenOptions::new().write(true).open(temp_dir.path().join("kvs.0.db"))
+        .and_then(|file| file.set_len(0))
+        .expect("unable to truncate the segment file");
+    
+    let store = KvStore::builder().wal(true).open(temp_dir.path())?;
+    assert_eq!(store.len()?, 400);
+    for i in 0..8 {
+        for j in 0..50 {
+            assert_eq!(store.get(format!("key{}-{}", i, j))?, Some(format!("value{}", j)));
+        }
+    }
+    
+    Ok(())
+}
+
+// Should reuse the index file saved by flush after ungraceful exit if nothing was written since then
+#[test]
+fn reuse_flushed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.flush()?;
+    store.abandon();
+    
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.reindexed());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    // Written after flush, the generation no longer matches
+    store.flush()?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    store.abandon();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    for key_id in 0..101 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    Ok(())
+}
+
+// Flush should persist the entries written before it and the index of the kvs engine
+#[test]
+fn flush_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.flush()?;
+    assert!(temp_dir.path().join("kvs.dir").exists());
+    // Nothing is saved on exit after a crash
+    store.abandon();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.reindexed());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    // Sled can be opened again as soon as the flushed handles are dropped
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..10 {
+        let store = SledKvsEngine::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        store.flush()?;
+        let namespace = store.namespace("first")?;
+        namespace.set("key".to_owned(), format!("value{}", round))?;
+        let iter = store.iter()?;
+        drop((store, namespace));
+        drop(iter);
+        let store = SledKvsEngine::open(temp_dir.path())?;
+        assert_eq!(store.get("key99".to_owned())?, Some(format!("value{}", round)));
+        assert_eq!(store.namespace("first")?.get("key".to_owned())?, Some(format!("value{}", round)));
+    }
+    
+    Ok(())
+}
+
 // Should overwrite existent value
 #[test]
 fn overwrite_value() -> Result<()> {
@@ -72,59 +358,1462 @@ fn remove_non_existent_key() -> Result<()> {
 }
 
 #[test]
-fn remove_key() -> Result<()> {
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Stores with different names in the same directory should not clobber each other
+#[test]
+fn named_stores() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store1 = KvStore::open_named(temp_dir.path(), "store1")?;
+    let store2 = KvStore::open_named(temp_dir.path(), "store2")?;
+    for i in 0..100 {
+        store1.set(format!("key{}", i), format!("value{}", i))?;
+        store2.set(format!("key{}", i), format!("other{}", i))?;
+    }
+    store2.remove("key0".to_owned())?;
+    store1.compact()?;
+    assert!(temp_dir.path().join("store1.db").exists());
+    assert!(temp_dir.path().join("store2.db").exists());
+    
+    // Open from disk again and check persistent data
+    drop(store1);
+    drop(store2);
+    let store1 = KvStore::open_named(temp_dir.path(), "store1")?;
+    let store2 = KvStore::open_named(temp_dir.path(), "store2")?;
+    assert_eq!(store1.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store2.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store1.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store2.get(format!("key{}", i))?, Some(format!("other{}", i)));
+    }
+    
+    Ok(())
+}
+
+// Iterator should yield every key exactly once with its value
+#[test]
+fn iterate_all_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(sled_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        sled.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    
+    for pairs in [store.iter()?.collect::<Result<Vec<_>>>()?, sled.iter()?.collect::<Result<Vec<_>>>()?] {
+        assert_eq!(pairs.len(), 500);
+        let keys = pairs.iter().map(|(key, _)| key.clone()).collect::<HashSet<_>>();
+        assert_eq!(keys.len(), 500);
+        for (key, value) in pairs {
+            assert_eq!(key.replace("key", "value"), value);
+        }
+    }
+    
+    // Keys removed after creating the iterator are skipped
+    let iter = store.iter()?;
+    for i in 0..250 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(iter.count(), 250);
+    
+    Ok(())
+}
+
+// Partially written entry at the end of the segment should be discarded on open
+#[test]
+fn truncated_final_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    
+    // Simulate a crash in the middle of writing an entry
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let valid_size = fs::metadata(&segment_path).expect("unable to read the segment file").len();
+    let entry = bson::to_vec(&doc! { "SET": [Bson::String("key10".to_owned()), Bson::String("value10".to_owned()), 0] }).unwrap();
+    let mut data = fs::read(&segment_path).expect("unable to read the segment file");
+    data.extend_from_slice(&entry[..entry.len() / 2]);
+    fs::write(&segment_path, data).expect("unable to write the segment file");
+    // The index is rebuilt from the segments without index file
+    fs::remove_file(temp_dir.path().join("kvs.dir")).expect("unable to remove the index file");
+    
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&segment_path).expect("unable to read the segment file").len(), valid_size);
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+    
+    // Reindexing from scratch should see every entry
+    fs::remove_file(temp_dir.path().join("kvs.dir")).expect("unable to remove the index file");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..11 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    
+    Ok(())
+}
+
+// Repair should recover the entries around a corrupt region and make the store openable again
+#[test]
+fn repair_corrupt_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    
+    // Overwrite a run in the middle of the segment, the header and the index with garbage
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let mut data = fs::read(&segment_path).expect("unable to read the segment file");
+    let middle = data.len() / 2;
+    data[middle..middle + 100].fill(0xff);
+    fs::write(&segment_path, data).expect("unable to write the segment file");
+    fs::write(temp_dir.path().join("kvs.db"), [0xff; 16]).expect("unable to write the database file");
+    fs::write(temp_dir.path().join("kvs.dir"), [0xff; 16]).expect("unable to write the index file");
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::InvalidDatabaseFormat)));
+    
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.dropped, 1);
+    assert!(report.recovered > 80 && report.recovered < 100);
+    
+    let store = KvStore::open(temp_dir.path())?;
+    let recovered = (0..100).filter(|i| store.get(format!("key{}", i)).unwrap() == Some(format!("value{}", i))).count();
+    assert_eq!(recovered, report.recovered);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    
+    Ok(())
+}
+
+// Validation should report the inconsistencies of a corrupted index file without modifying any file
+#[test]
+fn validate_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key5".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::validate(temp_dir.path())?, ValidationReport { entries: 101, indexed_keys: 100, issues: Vec::new() });
+    
+    // Move the entry of key1 and drop the entry of key2 from the index file
+    let index_path = temp_dir.path().join("kvs.dir");
+    let data = fs::read(&index_path).expect("unable to read the index file");
+    let mut reader = data.as_slice();
+    let mut index = Vec::new();
+    while let Ok(mut record) = Document::from_reader(&mut reader) {
+        match record.get_binary_generic("key").ok().map(|key| key.as_slice()) {
+            Some(b"key1") => {
+                let offset = record.get_i64("offset").unwrap();
+                record.insert("offset", offset + 1);
+            },
+            Some(b"key2") => continue,
+            _ => {}
+        }
+        record.to_writer(&mut index).unwrap();
+    }
+    fs::write(&index_path, &index).expect("unable to write the index file");
+    let mtime = fs::metadata(&index_path).expect("unable to read the index file").modified().unwrap();
+    
+    let report = KvStore::validate(temp_dir.path())?;
+    assert_eq!(report.indexed_keys, 99);
+    assert_eq!(report.issues.len(), 2);
+    assert!(matches!(&report.issues[0], ValidationIssue::DanglingIndexEntry { key, segment: 0, .. } if key == b"key1"));
+    assert_eq!(report.issues[1], ValidationIssue::MissingIndexEntry { key: b"key2".to_vec() });
+    assert_eq!(fs::read(&index_path).expect("unable to read the index file"), index);
+    assert_eq!(fs::metadata(&index_path).expect("unable to read the index file").modified().unwrap(), mtime);
+    
+    // A truncated index file has no footer
+    fs::write(&index_path, &index[..index.len() / 2]).expect("unable to write the index file");
+    assert!(KvStore::validate(temp_dir.path())?.issues.contains(&ValidationIssue::IncompleteIndex));
+    
+    // Corrupt entries are reported by position
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let mut data = fs::read(&segment_path).expect("unable to read the segment file");
+    data[100..110].fill(0xff);
+    fs::write(&segment_path, data).expect("unable to write the segment file");
+    fs::remove_file(&index_path).expect("unable to remove the index file");
+    let report = KvStore::validate(temp_dir.path())?;
+    assert!(matches!(report.issues.as_slice(), [ValidationIssue::CorruptEntry { segment: 0, offset }] if *offset <= 100));
+    
+    Ok(())
+}
+
+// The same key in different namespaces should hold independent values with both engines
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let check = |default: &dyn KvsEngine, first: &dyn KvsEngine, second: &dyn KvsEngine| -> Result<()> {
+        default.set("key1".to_owned(), "default".to_owned())?;
+        first.set("key1".to_owned(), "first".to_owned())?;
+        second.set("key1".to_owned(), "second".to_owned())?;
+        first.set("key2".to_owned(), "first".to_owned())?;
+        assert_eq!(default.get("key1".to_owned())?, Some("default".to_owned()));
+        assert_eq!(first.get("key1".to_owned())?, Some("first".to_owned()));
+        assert_eq!(second.get("key1".to_owned())?, Some("second".to_owned()));
+        assert_eq!(second.get("key2".to_owned())?, None);
+        assert!(matches!(second.remove("key2".to_owned()), Err(KvsError::KeyNotExist(_))));
+        second.remove("key1".to_owned())?;
+        assert_eq!(first.get("key1".to_owned())?, Some("first".to_owned()));
+        Ok(())
+    };
+    check(&store, &store.with_namespace("first"), &store.with_namespace("second"))?;
+    
+    // Iteration only sees the keys of the namespace
+    let keys = |store: KvStore| store.iter().unwrap().map(|pair| pair.unwrap().0).collect::<HashSet<_>>();
+    assert_eq!(keys(store.clone()), HashSet::from(["key1".to_owned()]));
+    assert_eq!(keys(store.with_namespace("first")), HashSet::from(["key1".to_owned(), "key2".to_owned()]));
+    assert!(keys(store.with_namespace("second")).is_empty());
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.with_namespace("first").get("key2".to_owned())?, Some("first".to_owned()));
+    
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(sled_dir.path())?;
+    check(&sled, &sled.with_namespace("first")?, &sled.with_namespace("second")?)?;
+    // The trees of sled itself are not namespaces
+    assert!(matches!(sled.with_namespace("__sled__default"), Err(KvsError::InvalidArguments(_))));
+    assert!(matches!(sled.with_namespace("__sled__"), Err(KvsError::InvalidArguments(_))));
+    
+    Ok(())
+}
+
+// Dump exported from KvStore should be importable into sled
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+    
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(sled.import(dump.as_slice())?, 150);
+    for i in 0..50 {
+        assert_eq!(sled.get(format!("key{}", i))?, None);
+    }
+    for i in 50..200 {
+        assert_eq!(sled.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    
+    // Truncated dump is rejected
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    assert!(matches!(other.import(&dump[..dump.len() - 1]), Err(KvsError::InvalidDatabaseFormat)));
+    
+    Ok(())
+}
+
+// Engines should be usable from async code through AsyncKvsEngine
+// The trait is called by path since its methods share the names of KvsEngine
+#[tokio::test(flavor = "multi_thread")]
+async fn async_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let tasks = (0..100).map(|i| {
+        let store = store.clone();
+        tokio::spawn(async move { kvs::AsyncKvsEngine::set(&store, format!("key{}", i), format!("value{}", i)).await })
+    }).collect::<Vec<_>>();
+    for task in tasks.into_iter() {
+        task.await.unwrap()?;
+    }
+    
+    for i in 0..100 {
+        assert_eq!(kvs::AsyncKvsEngine::get(&store, format!("key{}", i)).await?, Some(format!("value{}", i)));
+    }
+    kvs::AsyncKvsEngine::remove(&store, "key0".to_owned()).await?;
+    assert_eq!(kvs::AsyncKvsEngine::get(&store, "key0".to_owned()).await?, None);
+    assert!(matches!(kvs::AsyncKvsEngine::remove(&store, "key0".to_owned()).await, Err(KvsError::KeyNotExist(_))));
+    
+    Ok(())
+}
+
+// Binary keys and values should round-trip byte-identical with both engines
+#[test]
+fn binary_key_value() -> Result<()> {
+    fn check<E: KvsEngine>(temp_dir: &TempDir) -> Result<()> {
+        let key = vec![0xFF, 0x00, b'k', 0xFE];
+        let value = vec![b'v', 0x00, 0xFF, 0x80, 0x00];
+        let store = E::open(temp_dir.path())?;
+        store.set_bytes(key.clone(), value.clone())?;
+        assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+        assert_eq!(store.get_bytes(vec![0xFF])?, None);
+        
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(key)?, Some(value));
+        Ok(())
+    }
+    
+    check::<KvStore>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    check::<SledKvsEngine>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    Ok(())
+}
+
+// Values read through `get_reader` should be identical to `get_bytes` with every codec and compression
+#[test]
+fn get_reader() -> Result<()> {
+    fn read(store: &dyn KvsEngine, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match store.get_reader(key.to_vec())? {
+            Some(mut reader) => {
+                let mut value = Vec::new();
+                reader.read_to_end(&mut value)?;
+                assert_eq!(reader.len(), value.len() as u64);
+                Some(value)
+            },
+            None => None
+        })
+    }
+    
+    // Not valid UTF-8, and compressible
+    let large = (0..(1 << 20) + 7).map(|i| [0xFF, 0x00, b'a' + (i % 26) as u8][i % 3]).collect::<Vec<_>>();
+    for codec in [CodecKind::Bson, CodecKind::Bincode] {
+        for compression in [Compression::None, Compression::Zstd(3)] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = KvStoreOptions { codec, compression, ..Default::default() };
+            let store = KvStore::open_with_options(temp_dir.path(), options)?;
+            store.set_bytes(b"large".to_vec(), large.clone())?;
+            store.set_bytes(vec![0xFF, b'k'], b"small".to_vec())?;
+            store.set_bytes(b"empty".to_vec(), Vec::new())?;
+            store.with_namespace("first").set_bytes(b"large".to_vec(), b"first".to_vec())?;
+            
+            for key in [&b"large"[..], &[0xFF, b'k'], b"empty"] {
+                assert_eq!(read(&store, key)?, store.get_bytes(key.to_vec())?);
+            }
+            assert_eq!(read(&store, b"large")?, Some(large.clone()));
+            assert_eq!(read(&store.with_namespace("first"), b"large")?, Some(b"first".to_vec()));
+            assert_eq!(read(&store, b"missing")?, None);
+        }
+    }
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    sled.set_bytes(b"large".to_vec(), large.clone())?;
+    assert_eq!(read(&sled, b"large")?, Some(large));
+    assert_eq!(read(&sled, b"missing")?, None);
+    
+    Ok(())
+}
+
+// Binary keys of the default namespace starting with the namespace mark should not alias keys of a namespace
+#[test]
+fn binary_key_namespace_mark() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0xFF, b'a', 0xFF, b'k'];
+    store.with_namespace("a").set("k".to_owned(), "namespaced".to_owned())?;
+    store.set_bytes(key.clone(), b"default".to_vec())?;
+    
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.with_namespace("a").get("k".to_owned())?, Some("namespaced".to_owned()));
+        assert_eq!(store.with_namespace("a").len()?, 1);
+        assert_eq!(store.get_bytes(key.clone())?, Some(b"default".to_vec()));
+        assert_eq!(store.len()?, 1);
+        assert!(store.scan("0", 10).is_err()); // Listed in the default namespace, but not a UTF-8 string key
+        Ok(())
+    };
+    check(&store)?;
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.remove_prefix("")?, 1);
+    assert_eq!(store.get_bytes(key)?, None);
+    assert_eq!(store.with_namespace("a").get("k".to_owned())?, Some("namespaced".to_owned()));
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                   .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+    
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+        
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+        
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+    
+    panic!("No compaction detected");
+}
+
+// Automatic compaction should follow the configured threshold and can be disabled
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let segments_size = |path: &std::path::Path| {
+        WalkDir::new(path).into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_str().unwrap().ends_with(".db"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let write = |threshold: Option<u64>| -> Result<(TempDir, KvStore)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { compaction_threshold: threshold, ..Default::default() };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..200 {
+            store.set("key1".to_owned(), format!("value{}", iter))?;
+        }
+        Ok((temp_dir, store))
+    };
+    
+    // About 8 KiB is written for a single key
+    let (temp_dir, _store) = write(Some(1024))?;
+    assert!(segments_size(temp_dir.path()) < 4096);
+    let (temp_dir, _store) = write(Some(1 << 20))?;
+    assert!(segments_size(temp_dir.path()) > 8192);
+    
+    // Only manual compaction is run if disabled
+    let (temp_dir, store) = write(None)?;
+    assert!(segments_size(temp_dir.path()) > 8192);
+    store.compact()?;
+    assert!(segments_size(temp_dir.path()) < 4096);
+    assert_eq!(store.get("key1".to_owned())?, Some("value199".to_owned()));
+    
+    Ok(())
+}
+
+// Should only run automatic compaction once there are enough dead entries
+#[test]
+fn compaction_dead_bytes() -> Result<()> {
+    let open = |path: &std::path::Path| {
+        KvStore::open_with_options(path, KvStoreOptions { compaction_threshold: Some(1024), ..Default::default() })
+    };
+    
+    // Every key is written once, so nothing can be reclaimed
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.dead_bytes(), 0);
+    assert_eq!(store.next_compaction_size(), 1024);
+    
+    // Overwritten entries are dead
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.next_compaction_size() > 1024);
+    assert!(store.dead_bytes() < store.next_compaction_size());
+    
+    // The counter survives restarts
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let dead_bytes = store.dead_bytes();
+    assert!(dead_bytes > 0);
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.dead_bytes(), dead_bytes);
+    
+    Ok(())
+}
+
+// Keys should only be validated when enabled, removal accepts any key
+#[test]
+fn key_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(String::new(), "value1".to_owned())?;
+    store.set("key\u{0}1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    
+    let open = |key_validation| KvStore::open_with_options(temp_dir.path(), KvStoreOptions { key_validation, ..Default::default() });
+    let store = open(KeyValidation::NonEmpty)?;
+    assert!(matches!(store.set(String::new(), "value3".to_owned()), Err(KvsError::InvalidKey(_))));
+    assert!(matches!(store.append(String::new(), "value3".to_owned()), Err(KvsError::InvalidKey(_))));
+    store.set("key\u{0}1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    
+    let store = open(KeyValidation::Strict)?;
+    assert!(matches!(store.set(String::new(), "value4".to_owned()), Err(KvsError::InvalidKey(_))));
+    assert!(matches!(store.set("key\u{0}1".to_owned(), "value4".to_owned()), Err(KvsError::InvalidKey(_))));
+    assert!(matches!(store.set_nx("key\r\n".to_owned(), "value4".to_owned()), Err(KvsError::InvalidKey(_))));
+    assert!(matches!(store.namespace("ns1")?.set(String::new(), "value4".to_owned()), Err(KvsError::InvalidKey(_))));
+    assert_eq!(store.get(String::new())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key\u{0}1".to_owned())?, Some("value3".to_owned()));
+    // Existing invalid keys can still be removed
+    store.remove(String::new())?;
+    store.remove("key\u{0}1".to_owned())?;
+    
+    // Unicode keys are valid
+    store.set("键1".to_owned(), "值1".to_owned())?;
+    store.namespace("ns1")?.set("键2".to_owned(), "值2".to_owned())?;
+    assert_eq!(store.get("键1".to_owned())?, Some("值1".to_owned()));
+    assert!(matches!("unknown".parse::<KeyValidation>(), Err(KvsError::InvalidArguments(_))));
+    
+    Ok(())
+}
+
+// Bytes written before restarts should count towards the next automatic compaction
+#[test]
+fn compaction_churn_across_restarts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions { compaction_threshold: Some(4096), ..Default::default() })
+    };
+    
+    // Each session writes far less than the threshold
+    let mut total_written = 0;
+    let mut compacted = false;
+    for session in 0..20 {
+        let store = open()?;
+        assert_eq!(store.total_written(), total_written);
+        for iter in 0..10 {
+            store.set("key1".to_owned(), format!("value{}-{}", session, iter))?;
+        }
+        if store.total_written() < total_written {
+            compacted = true;
+            assert!(total_written < 4096);
+            assert!(store.next_compaction_size() > 4096);
+            break
+        }
+        assert!(store.total_written() > total_written);
+        total_written = store.total_written();
+        assert!(total_written < 4096);
+    }
+    assert!(compacted);
+    assert!(open()?.get("key1".to_owned())?.is_some());
+    
+    Ok(())
+}
+
+// Should remove every key and leave only an empty segment
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { segment_size: 1024, cache_capacity: 1 << 20, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.with_namespace("first").set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.clear()?;
+    
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+        assert_eq!(store.with_namespace("first").get(format!("key{}", key_id))?, None);
+    }
+    let segments = fs::read_dir(temp_dir.path()).expect("unable to list the directory")
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".db") && name != "kvs.db")
+        .collect::<Vec<_>>();
+    assert_eq!(segments, vec!["kvs.0.db".to_owned()]);
+    assert_eq!(fs::metadata(temp_dir.path().join("kvs.0.db")).expect("unable to read the segment file").len(), 0);
+    
+    // The first entry after clearing takes the position of a cleared entry
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    
+    // Sled drops the keys of every tree
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.with_namespace("first")?.set("key1".to_owned(), "value1".to_owned())?;
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.with_namespace("first")?.get("key1".to_owned())?, None);
+    
+    Ok(())
+}
+
+// Counters should follow the performed operations
+#[test]
+fn metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics(), KvStoreMetrics::default());
+    
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.metrics(), KvStoreMetrics { writes: 5, reads: 3, compactions: 0, reclaimed_bytes: 0, live_keys: 2 });
+    
+    // All shadowed entries are dropped by compaction
+    let dead_bytes = store.dead_bytes();
+    assert!(dead_bytes > 0);
+    store.force_compaction()?;
+    assert_eq!(store.metrics(), KvStoreMetrics { writes: 5, reads: 3, compactions: 1, reclaimed_bytes: dead_bytes, live_keys: 2 });
+    
+    Ok(())
+}
+
+// Entries should round-trip with every codec, and the codec of an existing database is kept
+#[test]
+fn codecs() -> Result<()> {
+    for codec in [CodecKind::Bson, CodecKind::Bincode] {
+        // Records start with their length like BSON documents
+        let record = HashMap::from([("key1".to_owned(), "value1".to_owned()), ("key2".to_owned(), "".to_owned())]);
+        let encoded = codec.encode(&record)?;
+        assert_eq!(i32::from_le_bytes(encoded[..4].try_into().unwrap()) as usize, encoded.len());
+        assert_eq!(codec.decode::<HashMap<String, String>, _>(encoded.as_slice())?, record);
+        
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { codec, compression: Compression::Zstd(3), ..Default::default() };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i).repeat(i))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.compact()?;
+        store.set("key1".to_owned(), "value".to_owned())?;
+        store.abandon();
+        
+        // Rebuilt from the segment files with the codec in the header
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.reindexed());
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+        for i in 2..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i).repeat(i)));
+        }
+    }
+    
+    Ok(())
+}
+
+// Values should be read from the mapped segments, including entries written after the segment was mapped
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().mmap(true).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    // The active segment has grown past the mapped length
+    store.set("key2".to_owned(), "value2".repeat(1000))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".repeat(1000)));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    
+    // The compacted segment replaces the mapped one
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+    store.compact()?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    
+    Ok(())
+}
+
+// Values should keep the type they were written with, and untagged entries should hold strings
+#[test]
+fn value_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 1)?, 1);
+    assert_eq!(store.incr("counter".to_owned(), 41)?, 42);
+    assert_eq!(store.incr("counter".to_owned(), -50)?, -8);
+    assert_eq!(store.type_of("counter".to_owned())?, Some(ValueType::Int));
+    assert_eq!(store.get("counter".to_owned())?, Some("-8".to_owned()));
+    assert_eq!(store.type_of("missing".to_owned())?, None);
+    
+    // Strings are never incremented, even if they hold a number
+    store.set("key1".to_owned(), "10".to_owned())?;
+    assert_eq!(store.type_of("key1".to_owned())?, Some(ValueType::String));
+    assert!(matches!(store.incr("key1".to_owned(), 1), Err(KvsError::WrongType)));
+    assert!(matches!(store.incr("counter".to_owned(), i64::MIN), Err(KvsError::InvalidArguments(_))));
+    // Setting the key replaces the type
+    store.set("counter".to_owned(), "value".to_owned())?;
+    assert_eq!(store.type_of("counter".to_owned())?, Some(ValueType::String));
+    store.incr("counter2".to_owned(), 5)?;
+    drop(store);
+    
+    // Entries written before the type tag existed
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let entry = bson::to_vec(&doc! { "SET": [Bson::String("key2".to_owned()), Bson::String("value2".to_owned()), 0] }).unwrap();
+    let mut data = fs::read(&segment_path).expect("unable to read the segment file");
+    data.extend_from_slice(&entry);
+    fs::write(&segment_path, data).expect("unable to write the segment file");
+    fs::remove_file(temp_dir.path().join("kvs.dir")).expect("unable to remove the index file");
+    
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.type_of("key2".to_owned())?, Some(ValueType::String));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.type_of("counter2".to_owned())?, Some(ValueType::Int));
+    assert_eq!(store.incr("counter2".to_owned(), 1)?, 6);
+    
+    // Engines without type tags report strings
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 3)?, 3);
+    assert_eq!(store.type_of("counter".to_owned())?, Some(ValueType::String));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(store.incr("key1".to_owned(), 1), Err(KvsError::WrongType)));
+    
+    Ok(())
+}
+
+// Invalid UTF-8 values should fail the string API unless lossy decoding is selected
+#[test]
+fn utf8_modes() -> Result<()> {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+        store.set_bytes(b"key1".to_vec(), b"value\xff1".to_vec())?;
+        assert!(matches!(store.get("key1".to_owned()), Err(KvsError::InvalidUtf8(_))));
+        assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(b"value\xff1".to_vec()));
+        drop(store);
+        
+        let options = KvStoreOptions { utf8: Utf8Mode::Lossy, ..Default::default() };
+        let store = open_engine_with_options(engine, temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value\u{fffd}1".to_owned()));
+        assert_eq!(store.namespace("")?.get("key1".to_owned())?, Some("value\u{fffd}1".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// Conditional set should only write if the presence of the key matches, with concurrent writers too
+#[test]
+fn conditional_set() -> Result<()> {
+    fn check<E: KvsEngine + Clone + Sync>(temp_dir: &TempDir) -> Result<()> {
+        let store = E::open(temp_dir.path())?;
+        assert!(!store.set_xx("key1".to_owned(), "value1".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+        assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(store.set_xx("key1".to_owned(), "value3".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        store.remove("key1".to_owned())?;
+        assert!(!store.set_xx("key1".to_owned(), "value4".to_owned())?);
+        
+        // Only one of the racing writers sets the key
+        let barrier = Arc::new(Barrier::new(8));
+        let handles = (0..8).map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.set_nx("key2".to_owned(), format!("value{}", i)).unwrap()
+            })
+        }).collect::<Vec<_>>();
+        let winners = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|written| *written).count();
+        assert_eq!(winners, 1);
+        
+        Ok(())
+    }
+    
+    check::<KvStore>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    check::<SledKvsEngine>(&TempDir::new().expect("unable to create temporary working directory"))
+}
+
+// Should page through the keys of the namespace in ascending order
+#[test]
+fn scan() -> Result<()> {
+    fn check<E: KvsEngine>(temp_dir: &TempDir) -> Result<()> {
+        let store = E::open(temp_dir.path())?;
+        for i in 0..250 {
+            store.set(format!("key{:03}", i), format!("value{}", i))?;
+        }
+        store.namespace("first")?.set("key000".to_owned(), "value".to_owned())?;
+        
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            let (next_cursor, page) = store.scan(&cursor, 100)?;
+            // Keys added behind the cursor are not visited
+            store.set("key".to_owned(), "value".to_owned())?;
+            keys.extend(page);
+            if next_cursor == "0" { break }
+            cursor = next_cursor;
+        }
+        assert_eq!(keys, (0..250).map(|i| format!("key{:03}", i)).collect::<Vec<_>>());
+        assert_eq!(store.namespace("first")?.scan("0", 100)?, ("0".to_owned(), vec!["key000".to_owned()]));
+        assert!(matches!(store.scan("abc", 100), Err(KvsError::InvalidArguments(_))));
+        assert!(matches!(store.scan("0", 0), Err(KvsError::InvalidArguments(_))));
+        
+        Ok(())
+    }
+    
+    check::<KvStore>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    check::<SledKvsEngine>(&TempDir::new().expect("unable to create temporary working directory"))
+}
+
+// Should remove the keys with the prefix only
+#[test]
+fn remove_prefix() -> Result<()> {
+    fn check<E: KvsEngine>(temp_dir: &TempDir) -> Result<()> {
+        let store = E::open(temp_dir.path())?;
+        for prefix in ["user.root.", "user.rooted.", "user.guest.", "group.root."] {
+            for i in 0..50 {
+                store.set(format!("{}{}", prefix, i), format!("value{}", i))?;
+            }
+        }
+        store.namespace("first")?.set("user.root.1".to_owned(), "value1".to_owned())?;
+        
+        assert_eq!(store.remove_prefix("user.root.")?, 50);
+        assert_eq!(store.remove_prefix("user.root.")?, 0);
+        for i in 0..50 {
+            assert_eq!(store.get(format!("user.root.{}", i))?, None);
+            for prefix in ["user.rooted.", "user.guest.", "group.root."] {
+                assert_eq!(store.get(format!("{}{}", prefix, i))?, Some(format!("value{}", i)));
+            }
+        }
+        // Other namespaces are not affected
+        assert_eq!(store.namespace("first")?.get("user.root.1".to_owned())?, Some("value1".to_owned()));
+        
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.get("user.root.1".to_owned())?, None);
+        assert_eq!(store.get("user.guest.1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+    
+    check::<KvStore>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    check::<SledKvsEngine>(&TempDir::new().expect("unable to create temporary working directory"))?;
+    Ok(())
+}
+
+// Should reject keys and values longer than the limits
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { max_key_size: 8, max_value_size: 1024, compression: Compression::Zstd(3), ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    
+    store.set("k".repeat(8), "v".repeat(1024))?;
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(1024)));
+    assert!(matches!(store.set("k".repeat(9), "v".to_owned()), Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    // The limit applies before compression
+    assert!(matches!(store.set("key1".to_owned(), "v".repeat(1025)),
+        Err(KvsError::ValueTooLarge { size: 1025, limit: 1024 })));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    // Every writer is limited, not only `set`
+    assert!(matches!(store.incr("k".repeat(9), 1), Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    assert!(matches!(store.append("k".repeat(9), "v".to_owned()), Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    assert!(matches!(store.set_nx("k".repeat(9), "v".to_owned()), Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    store.append("key2".to_owned(), "v".repeat(1024))?;
+    assert!(matches!(store.append("key2".to_owned(), "v".to_owned()),
+        Err(KvsError::ValueTooLarge { size: 1025, limit: 1024 })));
+    assert_eq!(store.get("k".repeat(9))?, None);
+    
+    Ok(())
+}
+
+// Should recompute the next compaction size with the compaction policy
+#[test]
+fn compaction_policy() -> Result<()> {
+    let segments_size = |path: &std::path::Path| {
+        WalkDir::new(path).into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| {
+                let name = entry.file_name().to_str().unwrap();
+                name.ends_with(".db") && name != "kvs.db"
+            })
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let check = |policy: CompactionPolicy, expected: &dyn Fn(u64) -> u64| -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { compaction_threshold: Some(1), compaction_policy: policy, ..Default::default() };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..20 {
+            for key_id in 0..50 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        // Only the live entries are left after forced compaction
+        store.force_compaction()?;
+        let live_size = segments_size(temp_dir.path());
+        assert_eq!(store.next_compaction_size(), expected(live_size));
+        Ok(())
+    };
+    
+    check(CompactionPolicy::LiveDataRatio(0.5), &|live| live * 2)?;
+    check(CompactionPolicy::FixedInterval(4096), &|live| live + 4096)?;
+    check(CompactionPolicy::DeadByteThreshold(0.25), &|live| (live as f64 * 1.25) as u64)?;
+    
+    // Doubling depends on the size when the compaction started
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { compaction_threshold: Some(1), ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.force_compaction()?;
+    let live_size = segments_size(temp_dir.path());
+    store.force_compaction()?;
+    assert_eq!(store.next_compaction_size(), live_size * 2);
+    
+    // Never lower than the compaction threshold
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_policy: CompactionPolicy::FixedInterval(1), ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.force_compaction()?;
+    assert_eq!(store.next_compaction_size(), 32768);
+    
+    Ok(())
+}
+
+// Compaction should keep every key correct with values much larger than the entry header
+#[test]
+fn compaction_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |key_id: usize, iter: usize| format!("{}{}", key_id, iter).repeat(16384);
+    
+    for iter in 0..4 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+        }
+    }
+    store.force_compaction()?;
+    
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 3)));
+    }
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 3)));
+    }
+    assert!(!temp_dir.path().join("kvs.db.tmp").exists());
+    
+    Ok(())
+}
+
+// Reads and writes should work right after compaction replaced the database file
+#[test]
+fn access_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.clone();
+    
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert_eq!(other.get("key0".to_owned())?, Some("value2".to_owned()));
+    store.force_compaction()?;
+    
+    for key_id in 0..100 {
+        assert_eq!(other.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    other.set("key0".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value3".to_owned()));
+    
+    Ok(())
+}
+
+// An interrupted compaction should leave the original database readable
+#[test]
+fn compaction_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.inject_compaction_failure(50);
+    assert!(store.force_compaction().is_err());
+    
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("kvs.db.tmp").exists());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// Background compaction should not block writes until it completes
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { background_compaction: true, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    // Every live entry takes 10 ms to copy, the compaction takes at least 2 seconds
+    store.inject_compaction_delay(Duration::from_millis(10));
+    
+    let start = Instant::now();
+    for iter in 0..3 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    // Compaction is triggered in the middle, the new keys are not touching the entries being compacted
+    for key_id in 0..1000 {
+        store.set(format!("new_key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(temp_dir.path().join("kvs.db.tmp").exists());
+    assert!(start.elapsed() < Duration::from_secs(2));
+    
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// Compaction should be deferred while the store is written continuously, and run once the writes pause
+#[test]
+fn idle_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: Some(1),
+        compaction_idle_window: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    
+    // Overwriting the same key meets the compaction condition after a few writes
+    let start = Instant::now();
+    let mut iter = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+        iter += 1;
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.compactions(), 0);
+    
+    let paused = Instant::now();
+    while store.compactions() == 0 && paused.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(store.compactions(), 1);
+    assert!(paused.elapsed() >= Duration::from_millis(300));
+    assert_eq!(store.get("key".to_owned())?, Some(format!("value{}", iter - 1)));
+    
+    Ok(())
+}
+
+// Reads should stay fast and correct while a slow compaction is copying the segments
+#[test]
+fn read_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    // Every live entry takes 5 ms to copy, the compaction takes at least 1 second
+    store.inject_compaction_delay(Duration::from_millis(5));
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compact())
+    };
+    thread::sleep(Duration::from_millis(100));
+    
+    let readers = (0..4).map(|_| {
+        let store = store.clone();
+        thread::spawn(move || -> Result<Duration> {
+            let mut max_latency = Duration::ZERO;
+            for key_id in 0..200 {
+                let start = Instant::now();
+                assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+                max_latency = max_latency.max(start.elapsed());
+            }
+            Ok(max_latency)
+        })
+    }).collect::<Vec<_>>();
+    for reader in readers.into_iter() {
+        assert!(reader.join().unwrap()? < Duration::from_millis(200));
+    }
+    assert!(!compaction.is_finished());
+    compaction.join().unwrap()?;
+    
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// Cached values should be served without reading the segment files and be invalidated by writes
+// Every option set on the builder should take effect on the opened store
+#[test]
+fn store_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder()
+        .wal(true)
+        .cache_capacity(1024)
+        .index_mode(IndexMode::AlwaysRebuild)
+        .codec(CodecKind::Bincode)
+        .max_value_size(16)
+        .key_validation(KeyValidation::Strict);
+    assert_eq!(builder.options().max_value_size, 16);
+    let store = builder.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Write-ahead log
+    assert!(temp_dir.path().join("kvs.wal").exists());
+    // Value cache
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert_eq!(store.disk_reads(), 1);
+    // Limits and validation
+    assert!(matches!(store.set("key2".to_owned(), "v".repeat(17)), Err(KvsError::ValueTooLarge { size: 17, limit: 16 })));
+    assert!(matches!(store.set("key\n".to_owned(), "value".to_owned()), Err(KvsError::InvalidKey(_))));
+    drop(store);
+    // No index file is kept
+    assert!(!temp_dir.path().join("kvs.dir").exists());
+    
+    // Read-only store built from the existing options
+    let options = KvStoreOptions { index_mode: IndexMode::AlwaysRebuild, ..Default::default() };
+    let store = KvStoreBuilder::from(options).read_only(true).open(temp_dir.path())?;
+    assert!(store.reindexed());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(store.set("key1".to_owned(), "value2".to_owned()), Err(KvsError::ReadOnly)));
+    
+    // The codec of a new database is kept in its header, the entries are not readable as BSON
+    let segment = fs::read(temp_dir.path().join("kvs.0.db"))?;
+    assert!(bson::from_slice::<Document>(&segment).is_err());
+    
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().cache_capacity(64).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.disk_reads(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.disk_reads(), 1);
+    
+    // Updated value must be read again
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.disk_reads(), 2);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    
+    // Least recently used values are evicted once the capacity is exceeded
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{:09}", i))?;
+        store.get(format!("key{}", i))?;
+    }
+    let disk_reads = store.disk_reads();
+    for i in 6..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{:09}", i)));
+    }
+    assert_eq!(store.disk_reads(), disk_reads);
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("value{:09}", 0)));
+    assert_eq!(store.disk_reads(), disk_reads + 1);
+    
+    Ok(())
+}
+
+// Bloom filter should never reject a stored key and keep false positives near the target rate
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { bloom_false_positive_rate: 0.01, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..5000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let false_positive_rate = |store: &KvStore| {
+        (0..10000).filter(|i| store.bloom_may_contain(format!("missing{}", i).as_bytes())).count() as f64 / 10000.0
+    };
+    
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..5000 {
+            assert!(store.bloom_may_contain(format!("key{}", i).as_bytes()));
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        assert!(false_positive_rate(store) < 0.02);
+        Ok(())
+    };
+    check(&store)?;
+    
+    // Filter is rebuilt by compaction and saved on exit
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    assert!(temp_dir.path().join("kvs.bloom").exists());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    
+    Ok(())
+}
+
+// Writes should roll over to a new segment once the active segment reaches the size cap
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { segment_size: 1024, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(temp_dir.path().join("kvs.1.db").exists());
+    assert!(temp_dir.path().join("kvs.2.db").exists());
+    
+    // Values should be resolved from any segment
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    Ok(())
+}
+
+// Scanning the segments in parallel should rebuild the same index as a serial scan
+#[test]
+fn parallel_reindex() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { segment_size: 1024, compaction_threshold: Some(1 << 30), ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..2000 {
+        let key = format!("key{}", iter % 150);
+        if iter % 7 == 0 {
+            let _ = store.remove(key);
+        } else {
+            store.set(key, format!("value{}", iter))?;
+        }
+    }
+    let expected = store.iter()?.collect::<Result<HashSet<_>>>()?;
+    drop(store);
+    assert!(temp_dir.path().join("kvs.10.db").exists());
+    
+    for reindex_threads in [1, 4] {
+        let options = KvStoreOptions { index_mode: IndexMode::AlwaysRebuild, reindex_threads, ..options.clone() };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert!(store.reindexed());
+        assert_eq!(store.iter()?.collect::<Result<HashSet<_>>>()?, expected);
+    }
+    
+    Ok(())
+}
+
+// Automatic compaction should only merge segments with mostly dead data and keep the removal of older entries
+#[test]
+fn selective_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { segment_size: 1024, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let first_segment = temp_dir.path().join("kvs.0.db");
+    let first_segment_size = first_segment.metadata()?.len();
+    
+    for iter in 0..500 {
+        store.set("hot".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    for iter in 500..1000 {
+        store.set("hot".to_owned(), format!("value{}", iter))?;
+    }
+    
+    // Segments without dead data are left untouched
+    assert_eq!(first_segment.metadata()?.len(), first_segment_size);
+    let db_size = WalkDir::new(temp_dir.path()).into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_str().unwrap().ends_with(".db"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum::<u64>();
+    assert!(db_size < 32768);
+    
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    
+    Ok(())
+}
+
+// Compressible values should take less space on disk with compression enabled
+#[test]
+fn value_compression() -> Result<()> {
+    let value = "0123456789".repeat(1000);
+    let db_size = |compression: Compression| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { compression, ..Default::default() };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), value.clone())?;
+        store.set("key2".to_owned(), "short".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        assert_eq!(store.get("key2".to_owned())?, Some("short".to_owned()));
+        Ok(temp_dir.path().join("kvs.0.db").metadata()?.len())
+    };
+    
+    let uncompressed = db_size(Compression::None)?;
+    let compressed = db_size(Compression::Zstd(3))?;
+    assert!(compressed * 10 < uncompressed);
+    
+    Ok(())
+}
+
+// Entries written with and without compression should be readable from the same database
+#[test]
+fn mixed_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "0123456789".repeat(1000);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), value.clone())?;
+    drop(store);
+    
+    let options = KvStoreOptions { compression: Compression::Zstd(3), ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+    
+    Ok(())
+}
+
+// Database file written by build 1001 should be migrated on open
+#[test]
+fn migrate_from_build_1001() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut fixture = Vec::new();
+    doc! { "build_number": 1001_i64, "last_open": 0_i64, "flags": 0_i64 }.to_writer(&mut fixture).unwrap();
+    doc! { "key": "key1", "value": "value1" }.to_writer(&mut fixture).unwrap();
+    doc! { "key": "key2", "value": "value2" }.to_writer(&mut fixture).unwrap();
+    doc! { "key": "key1", "value": Bson::Null }.to_writer(&mut fixture).unwrap();
+    doc! { "key": "key3", "value": "value3" }.to_writer(&mut fixture).unwrap();
+    fs::write(temp_dir.path().join("kvs.db"), fixture).expect("unable to write the fixture");
+    
     let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Database file written by newer build should be rejected
 #[test]
-fn compaction() -> Result<()> {
+fn open_future_build() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                   .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
-    
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
-        
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
-        
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
-    }
+    let mut fixture = Vec::new();
+    doc! { "build_number": 9999_i64, "last_open": 0_i64, "next_compaction_size": 0_i64, "flags": 0_i64 }
+        .to_writer(&mut fixture).unwrap();
+    fs::write(temp_dir.path().join("kvs.db"), fixture).expect("unable to write the fixture");
     
-    panic!("No compaction detected");
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::IncompatibleDatabaseVersion(9999, _))));
 }
 
 #[test]
@@ -158,6 +1847,103 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Concurrent optional removals of the same key should remove it only once
+#[test]
+fn concurrent_remove_optional() -> Result<()> {
+    fn check(store: impl KvsEngine + Clone) -> Result<()> {
+        for round in 0..20 {
+            let key = format!("key{}", round);
+            store.set(key.clone(), "value".to_owned())?;
+            let barrier = Arc::new(Barrier::new(4));
+            let handles = (0..4).map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let key = key.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.remove_optional(key).unwrap()
+                })
+            }).collect::<Vec<_>>();
+            let removed = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|removed| *removed).count();
+            assert_eq!(removed, 1);
+            assert_eq!(store.get(key.clone())?, None);
+            assert!(!store.remove_optional(key)?);
+        }
+        Ok(())
+    }
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_append() -> Result<()> {
+    fn check(store: impl KvsEngine + Clone) -> Result<()> {
+        let barrier = Arc::new(Barrier::new(9));
+        for i in 0..8 {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.append("key1".to_owned(), i.to_string()).unwrap();
+                }
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        
+        // Every appended byte is kept
+        let value = store.get("key1".to_owned())?.unwrap();
+        assert_eq!(value.len(), 800);
+        for i in 0..8 {
+            assert_eq!(value.matches(&i.to_string()).count(), 100);
+        }
+        Ok(())
+    }
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Concurrent writers may apply their entries out of the order of their offsets, reindex should agree with them
+#[test]
+fn concurrent_set_remove_reindex() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(9));
+    for i in 0..8 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            for j in 0..500 {
+                let key = format!("key{}", j % 8);
+                if (i + j) % 3 == 0 {
+                    let _ = store.remove(key);
+                } else {
+                    store.set(key, format!("value{}-{}", i, j)).unwrap();
+                }
+            }
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+    
+    let values = (0..8).map(|i| store.get(format!("key{}", i))).collect::<Result<Vec<_>>>()?;
+    // Rebuild the index from the segment files without graceful exit
+    store.abandon();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.reindexed());
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, value);
+    }
+    
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +1995,472 @@ fn concurrent_get() -> Result<()> {
     
     Ok(())
 }
+
+// Modified segment file should be rejected on open after graceful exit if the checksum is saved
+#[test]
+fn file_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions { file_checksum: true, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    // Intact files pass the verification
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let mut data = fs::read(&segment_path)?;
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&segment_path, data)?;
+    assert!(matches!(KvStore::open_with_options(temp_dir.path(), options()), Err(KvsError::FileChecksumMismatch)));
+    // Rejected open leaves the header untouched, so the mismatch is reported again
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::FileChecksumMismatch)));
+    
+    // No checksum is saved without the option, the same modification is not detected on open
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let mut data = fs::read(&segment_path)?;
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&segment_path, data)?;
+    KvStore::open(temp_dir.path())?;
+    
+    Ok(())
+}
+
+// Writes interrupted by a signal should be retried instead of failing
+#[test]
+fn interrupted_write() -> Result<()> {
+    for wal in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder().wal(wal).open(temp_dir.path())?;
+        store.inject_interrupts(1);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.inject_interrupts(3);
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        
+        // Retried entries are written at the same offset again
+        assert_eq!(store.metrics().writes, 3);
+        assert_eq!(fs::metadata(temp_dir.path().join("kvs.0.db"))?.len(), store.total_written());
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 1..=3 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    
+    Ok(())
+}
+
+// Only one instance should open the database for writing at a time
+#[test]
+fn lock_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    
+    // Opening again in the process shares the store, other processes are blocked
+    let shared = KvStore::open(temp_dir.path())?;
+    assert_eq!(shared.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(open_engine("kvs", temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    let other_process = || {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(["get", "key1", "--base-dir", temp_dir.path().to_str().unwrap()]);
+        cmd
+    };
+    other_process().assert().failure().stderr(contains("AlreadyLocked"));
+    // Readers and databases of other names are not blocked
+    assert_eq!(KvStore::builder().read_only(true).open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    KvStore::open_named(temp_dir.path(), "other")?.set("key1".to_owned(), "value2".to_owned())?;
+    
+    // Released once every handle is dropped
+    let namespace = store.with_namespace("ns1");
+    drop(store);
+    drop(shared);
+    other_process().assert().failure().stderr(contains("AlreadyLocked"));
+    drop(namespace);
+    other_process().assert().success().stdout("value1\n");
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Symlinked directories and relative paths should be resolved once on open
+#[cfg(unix)]
+#[test]
+fn open_resolved_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+    let link = temp_dir.path().join("link");
+    std::os::unix::fs::symlink(&data_dir, &link)?;
+    
+    let store = KvStore::open(&link)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(link.join("other.db"))?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::open(&data_dir)?.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(KvStore::open(data_dir.join("other.db"))?.get("key1".to_owned())?, Some("value2".to_owned()));
+    
+    // Changing the current directory after open does not move the database files
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(&data_dir)?;
+    let store = KvStore::open("relative.db");
+    std::env::set_current_dir(temp_dir.path())?;
+    let written = store.and_then(|store| store.set("key2".to_owned(), "value2".to_owned()));
+    std::env::set_current_dir(cwd)?;
+    written?;
+    assert!(!temp_dir.path().join("relative.db").exists());
+    assert_eq!(KvStore::open(data_dir.join("relative.db"))?.get("key2".to_owned())?, Some("value2".to_owned()));
+    
+    // The directory of a new database file must exist
+    assert!(KvStore::open(temp_dir.path().join("missing").join("kvs.db")).is_err());
+    
+    Ok(())
+}
+
+// Compaction observer should receive increasing progress until every merged entry is read
+#[test]
+fn compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions { compaction_threshold: None, ..Default::default() })?;
+    for key_id in 0..5000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..2500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id + 1))?;
+    }
+    
+    let reports = Arc::new(Mutex::new(Vec::<CompactionProgress>::new()));
+    let observed = reports.clone();
+    store.set_compaction_observer(Box::new(move |progress| observed.lock().unwrap().push(progress)));
+    store.force_compaction()?;
+    
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() >= 7);
+    for pair in reports.windows(2) {
+        assert!(pair[0].entries_processed < pair[1].entries_processed);
+        assert!(pair[0].bytes_processed < pair[1].bytes_processed);
+        assert!(pair[0].entries_copied <= pair[1].entries_copied);
+        assert_eq!(pair[0].bytes_total, pair[1].bytes_total);
+    }
+    let last = reports.last().unwrap();
+    assert_eq!(last.entries_processed, 7500);
+    assert_eq!(last.entries_copied, 5000);
+    assert_eq!(last.bytes_processed, last.bytes_total);
+    
+    Ok(())
+}
+
+// Compaction should keep the surviving entries in the order they were written
+#[test]
+fn compaction_preserves_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in [10, 3, 40, 10] {
+        store.set(format!("key{}", key_id), "updated".to_owned())?;
+    }
+    store.remove("key20".to_owned())?;
+    
+    let keys_by_location = |store: &KvStore| {
+        let mut keys = (0..50).map(|key_id| format!("key{}", key_id))
+            .filter_map(|key| store.entry_location(&key).map(|location| (location, key)))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+    let before = keys_by_location(&store);
+    store.compact()?;
+    let after = keys_by_location(&store);
+    
+    // Shadowed entries are dropped, so the surviving entries are moved
+    assert_ne!(after, before);
+    assert_eq!(after.len(), 49);
+    assert!(after.iter().all(|((segment, _), _)| *segment == after[0].0.0));
+    let order = |keys: &[((u64, u64), String)]| keys.iter().map(|(_, key)| key.clone()).collect::<Vec<_>>();
+    assert_eq!(order(&after), order(&before));
+    assert_eq!(order(&after)[46..], ["key3", "key40", "key10"]);
+    
+    Ok(())
+}
+
+// Name and fields of a span
+#[cfg(feature = "tracing")]
+type SpanRecord = (String, HashMap<String, String>);
+
+// Layer collecting the name and the fields of every span in memory
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct SpanCollector(Arc<Mutex<Vec<SpanRecord>>>);
+
+#[cfg(feature = "tracing")]
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_owned(), format!("{:?}", value));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<S> tracing_subscriber::Layer<S> for SpanCollector
+    where S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> {
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id,
+                   ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let mut spans = self.0.lock().unwrap();
+        // The position of the span is kept to add the fields recorded later
+        ctx.span(id).unwrap().extensions_mut().insert(spans.len());
+        spans.push((attrs.metadata().name().to_owned(), fields));
+    }
+    
+    fn on_record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>,
+                 ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let index = *ctx.span(id).unwrap().extensions().get::<usize>().unwrap();
+        values.record(&mut FieldVisitor(&mut self.0.lock().unwrap()[index].1));
+    }
+}
+
+// Every write should emit a writeback span with the lengths and whether compaction is triggered
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() -> Result<()> {
+    use tracing_subscriber::layer::SubscriberExt;
+    
+    let collector = SpanCollector::default();
+    let subscriber = tracing_subscriber::registry().with(collector.clone());
+    tracing::subscriber::with_default(subscriber, || -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.with_namespace("ns").set("key22".to_owned(), "value22".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        
+        let spans = collector.0.lock().unwrap();
+        let writebacks = spans.iter().filter(|(name, _)| name == "writeback").map(|(_, fields)| fields).collect::<Vec<_>>();
+        assert_eq!(writebacks.len(), 2);
+        assert_eq!((writebacks[0]["key_len"].as_str(), writebacks[0]["value_len"].as_str()), ("4", "6"));
+        assert_eq!((writebacks[1]["key_len"].as_str(), writebacks[1]["value_len"].as_str()), ("5", "7"));
+        assert!(writebacks.iter().all(|fields| fields["compaction"] == "false"));
+        let fetch = spans.iter().find(|(name, _)| name == "fetch").map(|(_, fields)| fields).unwrap();
+        assert_eq!((fetch["key_len"].as_str(), fetch["value_len"].as_str()), ("4", "6"));
+        Ok(())
+    })?;
+    
+    // Overwriting the same key soon triggers compaction with the smallest threshold
+    let collector = SpanCollector::default();
+    let subscriber = tracing_subscriber::registry().with(collector.clone());
+    tracing::subscriber::with_default(subscriber, || -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions { compaction_threshold: Some(1), ..Default::default() })?;
+        for i in 0..100 {
+            store.set("key".to_owned(), format!("value{}", i))?;
+        }
+        
+        let spans = collector.0.lock().unwrap();
+        assert!(spans.iter().any(|(name, fields)| name == "writeback" && fields["compaction"] == "true"));
+        assert!(spans.iter().any(|(name, fields)| name == "compaction" && fields["force"] == "false"));
+        Ok(())
+    })
+}
+
+// Number of keys should follow sets and removes, counting an overwritten key once
+#[test]
+fn len() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        assert_eq!(store.len()?, 0);
+        assert!(store.is_empty()?);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.len()?, 2);
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.len()?, 2);
+        store.remove("key2".to_owned())?;
+        assert!(!store.remove_optional("key2".to_owned())?);
+        assert_eq!(store.len()?, 1);
+        assert!(!store.is_empty()?);
+        
+        // Keys of other namespaces are not counted
+        let other = store.namespace("other")?;
+        assert_eq!(other.len()?, 0);
+        other.set("key1".to_owned(), "value1".to_owned())?;
+        other.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(other.len()?, 2);
+        assert_eq!(store.len()?, 1);
+        
+        store.clear()?;
+        assert_eq!(store.len()?, 0);
+        assert_eq!(other.len()?, 0);
+        Ok(())
+    }
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?)?;
+    
+    // Keys of a namespace are still told apart after reopening
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.with_namespace("other").set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.with_namespace("other").len()?, 1);
+    
+    Ok(())
+}
+
+// Setting the value a key already holds should not append an entry when no-op writes are skipped
+#[test]
+fn skip_noop_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_len = || fs::metadata(temp_dir.path().join("kvs.0.db")).unwrap().len();
+    let store = KvStore::builder().skip_noop_writes(true).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = segment_len();
+    for _ in 0..100 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(segment_len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    // Other values and other types are still written
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(segment_len() > len);
+    store.incr("key2".to_owned(), 1)?;
+    let len = segment_len();
+    store.set("key2".to_owned(), "1".to_owned())?;
+    assert!(segment_len() > len);
+    assert_eq!(store.type_of("key2".to_owned())?, Some(ValueType::String));
+    drop(store);
+    
+    // Every write is appended by default
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let len = segment_len();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(segment_len() > len);
+    
+    Ok(())
+}
+
+// Opening the same database from several threads should share one store, so no write is lost
+#[test]
+fn shared_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let barrier = Arc::new(Barrier::new(4));
+    let handles = (0..4).map(|i| {
+        let path = temp_dir.path().to_owned();
+        let barrier = barrier.clone();
+        thread::spawn(move || -> Result<KvStore> {
+            barrier.wait();
+            let store = KvStore::open(path)?;
+            for j in 0..100 {
+                store.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+            }
+            Ok(store)
+        })
+    }).collect::<Vec<_>>();
+    let stores = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Result<Vec<_>>>()?;
+    
+    // Every open observes the writes of the others
+    for store in stores.iter() {
+        assert_eq!(store.len()?, 400);
+        assert_eq!(store.get("key3-99".to_owned())?, Some("value99".to_owned()));
+    }
+    // The same path spelt differently refers to the same store
+    let other = KvStore::open(temp_dir.path().join("kvs.db"))?;
+    other.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(stores[0].get("key".to_owned())?, Some("value".to_owned()));
+    
+    drop(stores);
+    drop(other);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 401);
+    
+    Ok(())
+}
+
+// Opening an already opened database with different options should fail instead of ignoring the options
+#[test]
+fn shared_open_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { max_value_size: 16, codec: CodecKind::Bincode, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    
+    assert!(matches!(KvStore::open_with_options(temp_dir.path(), KvStoreOptions { max_value_size: 32, ..options.clone() }),
+        Err(KvsError::InvalidArguments(_))));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::InvalidArguments(_))));
+    // Same options, and the codec of the existing database is kept regardless of the requested one
+    let other = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    KvStore::open_with_options(temp_dir.path(), KvStoreOptions { codec: CodecKind::Bson, ..options })?;
+    // Read-only instances are not shared
+    KvStore::open_with_options(temp_dir.path(), KvStoreOptions { read_only: true, ..Default::default() })?;
+    
+    drop(store);
+    drop(other);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Keys spilled from the index beyond its memory limit should stay readable, writable and removable
+#[test]
+fn index_memory_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().index_memory_limit(Some(32 << 10)).open(temp_dir.path());
+    let store = open()?;
+    for i in 0..10000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(store.spilled_keys() > 9000);
+    assert_eq!(store.len()?, 10000);
+    for i in (0..10000).rev() {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    
+    // Overwrite and remove keys whether they are in memory or spilled
+    for i in (0..10000).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in (1..10000).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.len()?, 10000 - 3333);
+    assert_eq!(store.iter()?.count(), 10000 - 3333);
+    
+    // Entries moved by compaction are updated in the spill file
+    store.compact()?;
+    drop(store);
+    let store = open()?;
+    for i in 0..10000 {
+        let expected = match i % 3 {
+            0 => Some(format!("new{}", i)),
+            1 => None,
+            _ => Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    
+    Ok(())
+}
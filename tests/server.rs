@@ -0,0 +1,1560 @@
+use bson::{doc, Document};
+use kvs::{detect_engine, open_engine, Acceptor, AsyncKvsClient, ClientConfig, ConnectionLimitPolicy, IpNetwork, KvStore, KvStoreOptions, KeyValidation, KvsApi, KvsClient, KvsCommand, KvsEngine, KvsError, KvsHandle, KvsServer, KvsServerOptions, Peer, Result, ServerStats, ServerTiming, ValueType};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Start a server in background and wait until it is ready to accept connections
+fn spawn_server(engine: &str, path: &Path, addr: &str) -> KvsClient {
+    let server = KvsServer::open(engine, path).expect("unable to open the server");
+    let addr_ = addr.to_owned();
+    thread::spawn(move || {
+        server.start(addr_).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    KvsClient::open(addr).expect("unable to connect to the server")
+}
+
+// Write a self-signed certificate for 127.0.0.1 and its private key into `dir`
+fn self_signed_cert(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).expect("unable to generate certificate");
+    let cert_path = dir.join(format!("{}.crt", name));
+    let key_path = dir.join(format!("{}.key", name));
+    fs::write(&cert_path, certified.cert.pem()).expect("unable to write certificate");
+    fs::write(&key_path, certified.key_pair.serialize_pem()).expect("unable to write private key");
+    (cert_path, key_path)
+}
+
+// COMPACT command should shrink the database file with redundant entries
+#[test]
+fn compact_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4010");
+    
+    for i in 0..100 {
+        client.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let db_size = || {
+        WalkDir::new(temp_dir.path()).into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_str().unwrap().ends_with(".db"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let size_before = db_size();
+    
+    client.compact()?;
+    assert!(db_size() < size_before);
+    assert_eq!(client.get("key1".to_owned())?, Some("value99".to_owned()));
+    
+    Ok(())
+}
+
+// KILL should wait for the requests in progress before shutting down the server
+#[test]
+fn graceful_kill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    let handle = thread::spawn(move || server.start("127.0.0.1:4011"));
+    thread::sleep(Duration::from_millis(500));
+    
+    // Send only the first half of a SET request, so it is still in progress when KILL arrives
+    let request = bson::to_vec(&doc! { "cmd": "SET", "argument": ["key1", "value1"] }).unwrap();
+    let mut stream = TcpStream::connect("127.0.0.1:4011")?;
+    stream.write_all(&request[..request.len() / 2])?;
+    thread::sleep(Duration::from_millis(200));
+    KvsClient::open("127.0.0.1:4011")?.send_terminate_signal()?;
+    thread::sleep(Duration::from_millis(200));
+    stream.write_all(&request[request.len() / 2..])?;
+    let reply = Document::from_reader(&mut stream).expect("unable to read the reply");
+    assert_eq!(reply.get_str("status"), Ok("Success"));
+    
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect("127.0.0.1:4011").is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Idle connections should be dropped after the read timeout, so other clients are still served
+#[test]
+fn idle_connection_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsServerOptions {
+        read_timeout: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4012").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    // Occupy every worker thread with connections sending nothing
+    let idle = (0..16).map(|_| TcpStream::connect("127.0.0.1:4012")).collect::<std::io::Result<Vec<_>>>()?;
+    let start = Instant::now();
+    let client = KvsClient::open("127.0.0.1:4012")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(start.elapsed() < Duration::from_secs(3));
+    drop(idle);
+    
+    Ok(())
+}
+
+// Client should retry connecting until the server becomes available
+#[test]
+fn client_connect_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        server.start("127.0.0.1:4013").unwrap();
+    });
+    
+    let config = ClientConfig {
+        retries: 5,
+        backoff: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let client = KvsClient::open_with_config("127.0.0.1:4013", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    // Without retry the first attempt fails immediately
+    let config = ClientConfig { retries: 0, ..Default::default() };
+    let client = KvsClient::open_with_config("127.0.0.1:4014", config)?;
+    assert!(client.get("key1".to_owned()).is_err());
+    
+    Ok(())
+}
+
+// Client should tell refused, reset and timed out connections apart
+#[test]
+fn client_connection_errors() -> Result<()> {
+    let config = ClientConfig {
+        retries: 0,
+        request_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    
+    // Nothing is listening on the port
+    let client = KvsClient::open_with_config("127.0.0.1:4064", config.clone())?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::ConnectionRefused)));
+    
+    // The server closes the connection in the middle of the reply
+    let listener = TcpListener::bind("127.0.0.1:4065")?;
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            let mut buf = [0; 64];
+            let _ = conn.read(&mut buf);
+            let _ = conn.write_all(&[0x40, 0x00]);
+        }
+    });
+    let client = KvsClient::open_with_config("127.0.0.1:4065", config.clone())?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::ConnectionReset)));
+    
+    // The server never replies
+    let listener = TcpListener::bind("127.0.0.1:4066")?;
+    thread::spawn(move || {
+        let mut held = Vec::new();
+        for conn in listener.incoming() {
+            held.push(conn.unwrap());
+        }
+    });
+    let client = KvsClient::open_with_config("127.0.0.1:4066", config)?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Timeout)));
+    
+    Ok(())
+}
+
+// Server should report the address it failed to listen on
+#[test]
+fn bind_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4067");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", other_dir.path())?;
+    let err = server.start("127.0.0.1:4067").unwrap_err();
+    assert!(err.to_string().contains("127.0.0.1:4067"));
+    match err {
+        KvsError::BindFailed { addr, source } => {
+            assert_eq!(addr, "127.0.0.1:4067");
+            assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+        },
+        err => panic!("unexpected error {:?}", err)
+    }
+    // The running server is not affected
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// SET and GET should work over TLS with a trusted certificate
+#[test]
+fn tls_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed_cert(cert_dir.path(), "server");
+    let server = KvsServer::open_with_tls("kvs", temp_dir.path(), &cert, &key)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4015").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let client = KvsClient::open_tls("127.0.0.1:4015", &cert)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    // Plaintext client cannot talk to the TLS server
+    let client = KvsClient::open("127.0.0.1:4015")?;
+    assert!(client.get("key1".to_owned()).is_err());
+    
+    // The certificate is useless without its key
+    let options = KvsServerOptions { tls_cert: Some(cert), ..Default::default() };
+    assert!(matches!(KvsServer::open_with_options("kvs", cert_dir.path(), options), Err(KvsError::InvalidArguments(_))));
+    
+    Ok(())
+}
+
+// Client should refuse a server presenting an untrusted certificate
+#[test]
+fn tls_untrusted_cert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed_cert(cert_dir.path(), "server");
+    let (other_cert, _) = self_signed_cert(cert_dir.path(), "other");
+    let server = KvsServer::open_with_tls("kvs", temp_dir.path(), &cert, &key)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4016").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let client = KvsClient::open_tls("127.0.0.1:4016", &other_cert)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    
+    Ok(())
+}
+
+// Start a server requiring `token` in background
+fn spawn_auth_server(path: &Path, addr: &str, token: &str) -> KvsClient {
+    let server = KvsServer::open_with_auth("kvs", path, token).expect("unable to open the server");
+    let addr_ = addr.to_owned();
+    thread::spawn(move || {
+        server.start(addr_).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    KvsClient::open(addr).expect("unable to connect to the server")
+}
+
+// Authenticated client should be able to access the store
+#[test]
+fn auth_correct_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = spawn_auth_server(temp_dir.path(), "127.0.0.1:4017", "secret");
+    client.authenticate("secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Wrong token should be rejected
+#[test]
+fn auth_wrong_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = spawn_auth_server(temp_dir.path(), "127.0.0.1:4018", "secret");
+    assert!(matches!(client.authenticate("wrong"), Err(KvsError::Unauthorized)));
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Unauthorized)));
+    
+    Ok(())
+}
+
+// Requests without authentication should be rejected, including KILL
+#[test]
+fn auth_skipped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = spawn_auth_server(temp_dir.path(), "127.0.0.1:4019", "secret");
+    assert!(matches!(client.set("key1".to_owned(), "value1".to_owned()), Err(KvsError::Unauthorized)));
+    assert!(matches!(client.send_terminate_signal(), Err(KvsError::Unauthorized)));
+    assert!(matches!(client.backup("backup".to_owned()), Err(KvsError::Unauthorized)));
+    assert!(!temp_dir.path().join(KvsServer::BACKUP_DIR).exists());
+    
+    // The server is still running
+    client.authenticate("secret")?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    
+    Ok(())
+}
+
+// Token and TLS should be combinable with each other and with the other server options
+#[test]
+fn auth_with_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed_cert(cert_dir.path(), "server");
+    let options = KvsServerOptions {
+        tls_cert: Some(cert.clone()),
+        tls_key: Some(key),
+        auth_token: Some("secret".to_owned()),
+        max_connections: Some(4),
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4091").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let mut client = KvsClient::open_tls("127.0.0.1:4091", &cert)?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Unauthorized)));
+    client.authenticate("secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Send raw bytes to the server and assert on the raw reply
+fn assert_reply(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+    stream.write_all(request).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expected));
+}
+
+// Server in RESP mode should understand multi-bulk and inline commands
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    thread::spawn(move || {
+        server.start_resp("127.0.0.1:4020").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4020")?;
+    assert_reply(&mut stream, b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", b"$6\r\nvalue1\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n", b"$-1\r\n");
+    assert_reply(&mut stream, b"*3\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n", b":1\r\n");
+    
+    // Inline commands
+    assert_reply(&mut stream, b"set key2 value2\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"GET key2\r\n", b"$6\r\nvalue2\r\n");
+    assert_reply(&mut stream, b"DEL key1 key2 key3\r\n", b":2\r\n");
+    assert_reply(&mut stream, b"EXISTS key1\r\n", b":0\r\n");
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+    
+    // Errors
+    assert_reply(&mut stream, b"GET\r\n", b"-ERR wrong number of arguments for 'get' command\r\n");
+    assert_reply(&mut stream, b"FLUSHALL\r\n", b"-ERR unknown command 'flushall'\r\n");
+    assert_reply(&mut stream, b"*1\r\n$abc\r\n", b"-ERR Protocol error\r\n");
+    
+    Ok(())
+}
+
+// Server in RESP mode should reject oversized commands before allocating for them, and keep running
+#[test]
+fn resp_oversized_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    thread::spawn(move || {
+        server.start_resp("127.0.0.1:4089").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4089")?;
+    assert_reply(&mut stream, b"*1\r\n$4611686018427387904\r\n", b"-ERR Protocol error\r\n");
+    let mut stream = TcpStream::connect("127.0.0.1:4089")?;
+    assert_reply(&mut stream, b"*4611686018427387904\r\n", b"-ERR Protocol error\r\n");
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4089")?;
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+    
+    Ok(())
+}
+
+// Server in RESP mode should require the token and close the connection after a rejected one
+#[test]
+fn resp_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open_with_auth("kvs", temp_dir.path(), "secret")?;
+    thread::spawn(move || {
+        server.start_resp("127.0.0.1:4088").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4088")?;
+    assert_reply(&mut stream, b"AUTH wrong\r\n", b"-ERR invalid password\r\n");
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4088")?;
+    assert_reply(&mut stream, b"AUTH secre\r\n", b"-ERR invalid password\r\n");
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4088")?;
+    assert_reply(&mut stream, b"GET key1\r\n", b"-NOAUTH Authentication required.\r\n");
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    
+    let mut stream = TcpStream::connect("127.0.0.1:4088")?;
+    assert_reply(&mut stream, b"AUTH secret\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"SET key1 value1\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"GET key1\r\n", b"$6\r\nvalue1\r\n");
+    
+    Ok(())
+}
+
+// Send a raw HTTP request and return the status line and the JSON body
+fn http_request(addr: &str, request: &str) -> (String, serde_json::Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status_line = head.lines().next().unwrap().to_owned();
+    (status_line, serde_json::from_str(body).unwrap())
+}
+
+// HTTP gateway should map GET, PUT and DELETE on /kv/{key} to the store
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    thread::spawn(move || {
+        server.start_http("127.0.0.1:4021").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    let addr = "127.0.0.1:4021";
+    
+    let (status, body) = http_request(addr, "PUT /kv/key1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\n\r\nvalue1");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body["key"], "key1");
+    
+    let (status, body) = http_request(addr, "GET /kv/key1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body["value"], "value1");
+    
+    // Escaped key
+    let (status, _) = http_request(addr, "PUT /kv/key%202 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue2");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let (_, body) = http_request(addr, "GET /kv/key%202 HTTP/1.1\r\n\r\n");
+    assert_eq!(body["value"], "value2");
+    
+    let (status, _) = http_request(addr, "DELETE /kv/key1 HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let (status, body) = http_request(addr, "GET /kv/key1 HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    assert_eq!(body["error"], "Key not found");
+    let (status, _) = http_request(addr, "DELETE /kv/key1 HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    
+    // Malformed requests
+    let (status, _) = http_request(addr, "GET /kv/%zz HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    let (status, _) = http_request(addr, "PUT /kv/key1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    
+    Ok(())
+}
+
+// Drain collecting the key-value pairs of every record in memory
+#[derive(Clone, Default)]
+struct MemoryDrain(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+struct MapSerializer(HashMap<String, String>);
+
+impl slog::Serializer for MapSerializer {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+impl slog::Drain for MemoryDrain {
+    type Ok = ();
+    type Err = slog::Never;
+    
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> std::result::Result<(), slog::Never> {
+        let mut serializer = MapSerializer(HashMap::new());
+        slog::KV::serialize(&record.kv(), record, &mut serializer).unwrap();
+        self.0.lock().unwrap().push(serializer.0);
+        Ok(())
+    }
+}
+
+// Every request should produce a log record with its status, without the value itself
+#[test]
+fn request_logging() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = MemoryDrain::default();
+    let options = KvsServerOptions {
+        logger: slog::Logger::root(drain.clone(), slog::o!()),
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4022").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let client = KvsClient::open("127.0.0.1:4022")?;
+    client.set("key1".to_owned(), "secret".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+    
+    let records = drain.0.lock().unwrap();
+    assert_eq!(records.len(), 3);
+    let fields = records.iter()
+        .map(|record| (record["cmd"].as_str(), record["status"].as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(fields, vec![("SET", "Success"), ("GET", "Success"), ("REMOVE", "KeyNotFound")]);
+    assert_eq!(records[0]["value_len"], "6");
+    assert_eq!(records[0]["peer"].split(':').next(), Some("127.0.0.1"));
+    assert!(records[0].contains_key("latency_us"));
+    assert!(records.iter().all(|record| record.values().all(|value| !value.contains("secret"))));
+    
+    Ok(())
+}
+
+// Metrics should count exactly the requests performed
+#[test]
+fn metrics_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4023");
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        client.get(format!("key{}", i))?;
+    }
+    for i in 15..25 {
+        let _ = client.remove(format!("key{}", i));
+    }
+    
+    let metrics = client.metrics()?;
+    assert!(metrics.contains("kvs_requests_total{cmd=\"set\"} 20\n"));
+    assert!(metrics.contains("kvs_requests_total{cmd=\"get\"} 10\n"));
+    assert!(metrics.contains("kvs_requests_total{cmd=\"remove\"} 10\n"));
+    assert!(metrics.contains("kvs_errors_total 5\n"));
+    assert!(metrics.contains("kvs_request_duration_seconds_bucket{le=\"+Inf\"} 40\n"));
+    assert!(metrics.contains("kvs_request_duration_seconds_count 40\n"));
+    
+    Ok(())
+}
+
+// BACKUP should produce a consistent snapshot while another client keeps writing
+#[test]
+fn backup_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4024");
+    for i in 0..500 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    
+    // Keys are written in order, so any consistent snapshot holds a prefix of them
+    let writer = thread::spawn(move || -> Result<()> {
+        let client = KvsClient::open("127.0.0.1:4024")?;
+        for i in 500..2000 {
+            client.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        Ok(())
+    });
+    thread::sleep(Duration::from_millis(50));
+    client.backup("backup".to_owned())?;
+    writer.join().unwrap()?;
+    
+    let backup = KvStore::open(temp_dir.path().join(KvsServer::BACKUP_DIR).join("backup"))?;
+    let count = backup.iter()?.count();
+    assert!(count >= 500);
+    for i in 0..2000 {
+        let expected = (i < count).then(|| format!("value{}", i));
+        assert_eq!(backup.get(format!("key{}", i))?, expected);
+    }
+    
+    // The destination must not be overwritten
+    assert!(client.backup("backup".to_owned()).is_err());
+    
+    // The destination must stay in the backup directory
+    assert!(matches!(client.backup("/tmp/..".to_owned()), Err(KvsError::InvalidArguments(_))));
+    assert!(matches!(client.backup("../x".to_owned()), Err(KvsError::InvalidArguments(_))));
+    assert!(matches!(client.backup("backup/../x".to_owned()), Err(KvsError::InvalidArguments(_))));
+    assert!(!temp_dir.path().join("x").exists());
+    
+    Ok(())
+}
+
+// Async server should serve far more simultaneous connections than the threads of the blocking server
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    thread::spawn(move || server.start_async("127.0.0.1:4025").unwrap());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    // Every client keeps its connection open until all clients are connected
+    let barrier = Arc::new(tokio::sync::Barrier::new(2000));
+    let tasks = (0..2000).map(|i| {
+        let barrier = barrier.clone();
+        tokio::spawn(async move {
+            let mut client = AsyncKvsClient::connect("127.0.0.1:4025").await?;
+            client.set(format!("key{}", i), format!("value{}", i)).await?;
+            barrier.wait().await;
+            assert_eq!(client.get(format!("key{}", i)).await?, Some(format!("value{}", i)));
+            client.remove(format!("key{}", i)).await?;
+            assert_eq!(client.get(format!("key{}", i)).await?, None);
+            Ok::<_, KvsError>(())
+        })
+    }).collect::<Vec<_>>();
+    for task in tasks.into_iter() {
+        tokio::time::timeout(Duration::from_secs(30), task).await.expect("request timed out").unwrap()?;
+    }
+    
+    Ok(())
+}
+
+// Pipelined commands should be executed and replied in order
+#[test]
+fn pipeline_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4026");
+    
+    let mut commands = (0..100).map(|i| KvsCommand::Set(format!("key{}", i), format!("value{}", i))).collect::<Vec<_>>();
+    commands.push(KvsCommand::Remove("missing".to_owned()));
+    commands.extend((0..100).map(|i| KvsCommand::Get(format!("key{}", i))));
+    let results = client.pipeline(commands)?;
+    
+    assert_eq!(results.len(), 201);
+    for result in results[..100].iter() {
+        assert!(matches!(result, Ok(None)));
+    }
+    assert!(matches!(&results[100], Err(KvsError::KeyNotExist(key)) if key == "missing"));
+    for (i, result) in results[101..].iter().enumerate() {
+        assert_eq!(result.as_ref().unwrap(), &Some(format!("value{}", i)));
+    }
+    assert!(client.pipeline(Vec::new())?.is_empty());
+    
+    Ok(())
+}
+
+// Pipelines whose requests and replies both exceed the socket buffers should not deadlock
+#[test]
+fn pipeline_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4087");
+    
+    let value = "v".repeat(256 << 10);
+    let commands = (0..64)
+        .flat_map(|i| [KvsCommand::Set(format!("key{}", i), value.clone()), KvsCommand::Get(format!("key{}", i))])
+        .collect::<Vec<_>>();
+    let results = client.pipeline(commands)?;
+    
+    assert_eq!(results.len(), 128);
+    for pair in results.chunks(2) {
+        assert!(matches!(pair[0], Ok(None)));
+        assert_eq!(pair[1].as_ref().unwrap().as_deref(), Some(value.as_str()));
+    }
+    
+    Ok(())
+}
+
+// Clients in different namespaces should not see the keys of each other
+#[test]
+fn namespace_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4027");
+    let first = client.with_namespace("first");
+    let second = client.with_namespace("second");
+    
+    client.set("key1".to_owned(), "default".to_owned())?;
+    first.set("key1".to_owned(), "first".to_owned())?;
+    second.set("key1".to_owned(), "second".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(first.get("key1".to_owned())?, Some("first".to_owned()));
+    assert_eq!(second.get("key1".to_owned())?, Some("second".to_owned()));
+    first.remove("key1".to_owned())?;
+    assert_eq!(first.get("key1".to_owned())?, None);
+    assert_eq!(client.with_namespace("").get("key1".to_owned())?, Some("default".to_owned()));
+    
+    Ok(())
+}
+
+// Namespace rejected by the engine should be answered with the error instead of dropping the connection
+#[test]
+fn namespace_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("sled", temp_dir.path(), "127.0.0.1:4093");
+    client.set("key1".to_owned(), "default".to_owned())?;
+    
+    let reserved = client.with_namespace("__sled__default");
+    assert!(matches!(reserved.get("key1".to_owned()), Err(KvsError::InvalidArguments(_))));
+    assert_eq!(client.get("key1".to_owned())?, Some("default".to_owned()));
+    
+    Ok(())
+}
+
+// In-process shutdown should stop both servers without any connection to them
+#[test]
+fn shutdown_without_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    let handle = {
+        let server = server.clone();
+        thread::spawn(move || server.start("127.0.0.1:4028"))
+    };
+    thread::sleep(Duration::from_millis(500));
+    let start = Instant::now();
+    server.shutdown();
+    handle.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // The listener is closed once the server returned
+    assert!(TcpStream::connect("127.0.0.1:4028").is_err());
+    
+    let async_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", async_dir.path())?;
+    let handle = {
+        let server = server.clone();
+        thread::spawn(move || server.start_async("127.0.0.1:4029"))
+    };
+    thread::sleep(Duration::from_millis(500));
+    server.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect("127.0.0.1:4029").is_err());
+    
+    Ok(())
+}
+
+// FLUSH should remove the keys of every namespace on both engines
+#[test]
+fn flush_command() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4030"), ("sled", "127.0.0.1:4031")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        let scoped = client.with_namespace("first");
+        for i in 0..100 {
+            client.set(format!("key{}", i), format!("value{}", i))?;
+            scoped.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        client.clear()?;
+        for i in 0..100 {
+            assert_eq!(client.get(format!("key{}", i))?, None);
+            assert_eq!(scoped.get(format!("key{}", i))?, None);
+        }
+        
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// Oversized keys and values should be rejected by the server with the sled engine as well
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsServerOptions {
+        store: KvStoreOptions { max_key_size: 8, max_value_size: 16, ..Default::default() },
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options("sled", temp_dir.path(), options)?;
+    thread::spawn(move || {
+        server.start("127.0.0.1:4032").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    
+    let client = KvsClient::open("127.0.0.1:4032")?;
+    client.set("k".repeat(8), "v".repeat(16))?;
+    assert_eq!(client.get("k".repeat(8))?, Some("v".repeat(16)));
+    assert!(matches!(client.set("k".repeat(9), "v".to_owned()),
+        Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    assert!(matches!(client.set("key1".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge { size: 17, limit: 16 })));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(client.incr("k".repeat(9), 1), Err(KvsError::ValueTooLarge { size: 9, limit: 8 })));
+    assert_eq!(client.get("k".repeat(9))?, None);
+    
+    Ok(())
+}
+
+// Empty value should be told apart from a missing key
+#[test]
+fn get_empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4033");
+    client.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    
+    let results = client.pipeline(vec![KvsCommand::Get("key1".to_owned()), KvsCommand::Get("key2".to_owned())])?;
+    assert!(matches!(results.as_slice(), [Ok(Some(value)), Ok(None)] if value.is_empty()));
+    
+    Ok(())
+}
+
+// Undecodable request should be answered with an error reply instead of leaving the client waiting
+#[test]
+fn malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4034");
+    let async_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", async_dir.path())?;
+    thread::spawn(move || server.start_async("127.0.0.1:4035").unwrap());
+    thread::sleep(Duration::from_millis(500));
+    
+    for addr in ["127.0.0.1:4034", "127.0.0.1:4035"] {
+        // The length is valid, so the connection keeps serving the following requests
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.write_all(&[12, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef])?;
+        let reply = Document::from_reader(&mut stream).expect("unable to read the reply");
+        assert_eq!(reply.get_str("status"), Ok("MalformedRequest"));
+        assert!(reply.get_str("result").unwrap().starts_with("Malformed request"));
+        stream.write_all(bson::to_vec(&doc! { "cmd": "GET", "argument": ["key1"] }).unwrap().as_slice())?;
+        let reply = Document::from_reader(&mut stream).expect("unable to read the reply");
+        assert_eq!(reply.get_str("status"), Ok("KeyNotFound"));
+        
+        // Garbage without a valid length is answered, then the connection is closed
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.write_all(b"junk")?;
+        let reply = Document::from_reader(&mut stream).expect("unable to read the reply");
+        assert_eq!(reply.get_str("status"), Ok("MalformedRequest"));
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest)?, 0);
+    }
+    
+    Ok(())
+}
+
+// Engine errors should reach the client with their kind
+#[test]
+fn error_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4036");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    
+    // Overwrite the stored entry in place, so the next read fails to decode it
+    let segment_path = temp_dir.path().join("kvs.0.db");
+    let len = fs::metadata(&segment_path).expect("unable to read the segment file").len();
+    fs::write(&segment_path, vec![0; len as usize]).expect("unable to write the segment file");
+    
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.kind(), "InvalidDataEntry");
+    assert!(matches!(err, KvsError::InvalidDataEntry));
+    // Coarse errors are unchanged
+    assert!(matches!(client.remove("key2".to_owned()), Err(KvsError::KeyNotExist(_))));
+    
+    Ok(())
+}
+
+// PING should keep a persistent connection usable for the following requests
+#[tokio::test(flavor = "multi_thread")]
+async fn ping_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = tokio::task::spawn_blocking({
+        let path = temp_dir.path().to_owned();
+        move || spawn_server("kvs", &path, "127.0.0.1:4037")
+    }).await.unwrap();
+    client.ping()?;
+    
+    let mut client = AsyncKvsClient::connect("127.0.0.1:4037").await?;
+    for _ in 0..10 {
+        client.ping().await?;
+    }
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.ping().await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    
+    Ok(())
+}
+
+// Connections over the limit should be rejected or queued according to the policy
+#[tokio::test(flavor = "multi_thread")]
+async fn max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (addr, policy, is_async) in [("127.0.0.1:4038", ConnectionLimitPolicy::Reject, false),
+                                     ("127.0.0.1:4039", ConnectionLimitPolicy::Reject, true),
+                                     ("127.0.0.1:4040", ConnectionLimitPolicy::Queue, false),
+                                     ("127.0.0.1:4041", ConnectionLimitPolicy::Queue, true)] {
+        let options = KvsServerOptions { max_connections: Some(2), connection_limit_policy: policy, ..Default::default() };
+        let server = KvsServer::open_with_options("kvs", temp_dir.path().join(&addr[10..]), options)?;
+        thread::spawn(move || if is_async { server.start_async(addr).unwrap() } else { server.start(addr).unwrap() });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        
+        // Hold all connection slots
+        let mut first = AsyncKvsClient::connect(addr).await?;
+        first.ping().await?;
+        let mut second = AsyncKvsClient::connect(addr).await?;
+        second.set("key1".to_owned(), "value1".to_owned()).await?;
+        
+        let client = KvsClient::open(addr)?;
+        let request = tokio::task::spawn_blocking(move || client.get("key1".to_owned()));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        match policy {
+            ConnectionLimitPolicy::Reject => {
+                assert!(matches!(request.await.unwrap(), Err(KvsError::ServerBusy)));
+                drop(first);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert_eq!(KvsClient::open(addr)?.get("key1".to_owned())?, Some("value1".to_owned()));
+            },
+            ConnectionLimitPolicy::Queue => {
+                // Served once a slot is released
+                assert!(!request.is_finished());
+                drop(first);
+                assert_eq!(request.await.unwrap()?, Some("value1".to_owned()));
+            }
+        }
+        second.ping().await?;
+    }
+    
+    Ok(())
+}
+
+// Repeated scans should visit every key exactly once
+#[test]
+fn scan_command() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4042"), ("sled", "127.0.0.1:4043")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        let commands = (0..1000).map(|i| KvsCommand::Set(format!("key{}", i), format!("value{}", i))).collect();
+        assert!(client.pipeline(commands)?.into_iter().all(|result| result.is_ok()));
+        
+        let mut seen = HashMap::new();
+        let mut cursor = "0".to_owned();
+        let mut pages = 0;
+        loop {
+            let (next_cursor, keys) = client.scan(&cursor, 100)?;
+            assert!(keys.len() <= 100);
+            for key in keys.into_iter() {
+                *seen.entry(key).or_insert(0) += 1;
+            }
+            pages += 1;
+            if next_cursor == "0" { break }
+            cursor = next_cursor;
+        }
+        assert!(pages >= 10);
+        assert_eq!(seen.len(), 1000);
+        assert!(seen.values().all(|count| *count == 1));
+        assert!(client.scan("xyz", 100).is_err());
+        assert!(client.scan("0", 0).is_err());
+    }
+    
+    Ok(())
+}
+
+// Values set by INCR should be reported as integers
+#[test]
+fn incr_and_type_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4044");
+    assert_eq!(client.incr("counter".to_owned(), 1)?, 1);
+    assert_eq!(client.incr("counter".to_owned(), 9)?, 10);
+    assert_eq!(client.type_of("counter".to_owned())?, Some(ValueType::Int));
+    assert_eq!(client.get("counter".to_owned())?, Some("10".to_owned()));
+    
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.type_of("key1".to_owned())?, Some(ValueType::String));
+    assert_eq!(client.type_of("key2".to_owned())?, None);
+    assert!(matches!(client.incr("key1".to_owned(), 1), Err(KvsError::WrongType)));
+    
+    Ok(())
+}
+
+// INFO should describe the engine serving the requests
+#[test]
+fn info_command() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4045"), ("sled", "127.0.0.1:4046")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        let info = client.info()?;
+        assert_eq!(info.engine, engine);
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.build_number.is_some(), engine == "kvs");
+        assert!(info.uptime_secs < 60);
+    }
+    
+    Ok(())
+}
+
+// INFO should report the sizes deciding the automatic compaction of the kvs engine
+#[test]
+fn compaction_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4084");
+    // Total length of the segment files kvs.N.db, excluding the header file kvs.db
+    let segments_len = || fs::read_dir(temp_dir.path()).unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| {
+            let name = entry.file_name().into_string().unwrap();
+            name.starts_with("kvs.") && name.ends_with(".db") && name != "kvs.db"
+        })
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum::<u64>();
+    
+    let initial = client.info()?.compaction.expect("missing compaction info");
+    assert_eq!(initial.current_size, segments_len());
+    assert_eq!(initial.next_compaction_size, Some(32768));
+    for i in 0..10 {
+        client.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let info = client.info()?.compaction.expect("missing compaction info");
+    assert!(info.current_size > initial.current_size);
+    assert_eq!(info.current_size, segments_len());
+    // Every overwritten entry is dead
+    assert!(info.dead_bytes > 0 && info.dead_bytes < info.current_size);
+    
+    client.compact()?;
+    let info = client.info()?.compaction.expect("missing compaction info");
+    assert_eq!(info.current_size, segments_len());
+    assert_eq!(info.dead_bytes, 0);
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("sled", temp_dir.path(), "127.0.0.1:4085");
+    assert_eq!(client.info()?.compaction, None);
+    
+    Ok(())
+}
+
+// SET with NX or XX should report whether the value was written
+#[test]
+fn conditional_set_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4047");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!client.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!client.set_xx("key2".to_owned(), "value2".to_owned())?);
+    assert_eq!(client.get("key2".to_owned())?, None);
+    
+    assert!(client.set_nx("key2".to_owned(), "value2".to_owned())?);
+    assert!(client.set_xx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    
+    Ok(())
+}
+
+// Peers outside the allowlist or inside the denylist should be disconnected before any request is read
+#[test]
+fn access_control() -> Result<()> {
+    let network: IpNetwork = "192.168.1.0/24".parse()?;
+    assert!(network.contains("192.168.1.42".parse().unwrap()));
+    assert!(!network.contains("192.168.2.1".parse().unwrap()));
+    assert!(network.contains("::ffff:192.168.1.1".parse().unwrap()));
+    assert!("::1".parse::<IpNetwork>()?.contains("::1".parse().unwrap()));
+    assert!(matches!("10.0.0.0/33".parse::<IpNetwork>(), Err(KvsError::InvalidArguments(_))));
+    assert!(matches!("localhost/8".parse::<IpNetwork>(), Err(KvsError::InvalidAddress(_))));
+    
+    let start = |addr: &'static str, allowlist: Option<Vec<&str>>, denylist: Vec<&str>| -> Result<TempDir> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let parse = |networks: Vec<&str>| networks.into_iter().map(str::parse).collect::<Result<Vec<IpNetwork>>>();
+        let options = KvsServerOptions {
+            allowlist: allowlist.map(parse).transpose()?,
+            denylist: parse(denylist)?,
+            ..Default::default()
+        };
+        let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+        thread::spawn(move || {
+            server.start(addr).unwrap();
+        });
+        Ok(temp_dir)
+    };
+    let _allowed = start("127.0.0.1:4048", Some(vec!["127.0.0.0/8"]), vec![])?;
+    let _mismatched = start("127.0.0.1:4049", Some(vec!["10.0.0.0/8", "::1"]), vec![])?;
+    let _denied = start("127.0.0.1:4050", Some(vec!["127.0.0.0/8"]), vec!["127.0.0.1"])?;
+    thread::sleep(Duration::from_millis(500));
+    
+    let client = KvsClient::open("127.0.0.1:4048")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    for addr in ["127.0.0.1:4049", "127.0.0.1:4050"] {
+        let rejected = KvsClient::open(addr).and_then(|client| client.get("key1".to_owned()));
+        assert!(rejected.is_err());
+    }
+    
+    Ok(())
+}
+
+// Statistics of every session should be accumulated into the stats file on KILL
+#[test]
+fn persisted_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let run_session = |commands: &dyn Fn(&KvsClient) -> Result<()>| -> Result<()> {
+        let options = KvsServerOptions { persist_stats: true, ..Default::default() };
+        let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+        let handle = thread::spawn(move || server.start("127.0.0.1:4055"));
+        thread::sleep(Duration::from_millis(500));
+        let mut client = KvsClient::open("127.0.0.1:4055")?;
+        commands(&client)?;
+        client.send_terminate_signal()?;
+        handle.join().unwrap()
+    };
+    assert_eq!(ServerStats::load(temp_dir.path())?, ServerStats::default());
+    
+    run_session(&|client| {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key2".to_owned(), "value2".to_owned())?;
+        client.get("key1".to_owned())?;
+        client.compact()
+    })?;
+    let stats = ServerStats::load(temp_dir.path())?;
+    assert_eq!(stats.sessions, 1);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.requests.get("SET"), Some(&2));
+    assert_eq!(stats.requests.get("GET"), Some(&1));
+    assert_eq!(stats.requests.get("COMPACT"), Some(&1));
+    assert_eq!(stats.requests.get("KILL"), Some(&1));
+    
+    run_session(&|client| {
+        client.remove("key1".to_owned())?;
+        client.get("key2".to_owned()).map(|_| ())
+    })?;
+    let stats = ServerStats::load(temp_dir.path())?;
+    assert_eq!(stats.sessions, 2);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.requests.get("SET"), Some(&2));
+    assert_eq!(stats.requests.get("GET"), Some(&2));
+    assert_eq!(stats.requests.get("REMOVE"), Some(&1));
+    assert_eq!(stats.requests.get("KILL"), Some(&2));
+    
+    Ok(())
+}
+
+// Requests should be served over Unix domain socket with the same protocol, the socket file is removed on KILL
+#[cfg(unix)]
+#[test]
+fn unix_domain_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    let socket_path_ = socket_path.clone();
+    let handle = thread::spawn(move || server.start_uds(socket_path_));
+    thread::sleep(Duration::from_millis(500));
+    
+    let mut client = KvsClient::open_uds(&socket_path)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(matches!(client.remove("key2".to_owned()), Err(KvsError::KeyNotExist(_))));
+    assert_eq!(client.info()?.engine, "kvs");
+    
+    client.send_terminate_signal()?;
+    handle.join().unwrap()?;
+    assert!(!socket_path.exists());
+    
+    Ok(())
+}
+
+// Requests with the wrong number of arguments should be replied with the command and the counts as JSON
+#[test]
+fn wrong_argument_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4056");
+    let mut stream = TcpStream::connect("127.0.0.1:4056")?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    
+    let cases: [(&str, usize, usize, usize); 19] = [
+        ("PING", 1, 0, 0), ("INFO", 1, 0, 0), ("GET", 0, 1, 1), ("GET", 2, 1, 1), ("SET", 1, 2, 3), ("SET", 4, 2, 3),
+        ("RM", 0, 1, 1), ("REMOVE", 2, 1, 1), ("DELETE", 0, 1, 1), ("INCR", 3, 1, 2), ("TYPE", 0, 1, 1),
+        ("SCAN", 1, 2, 2), ("NAMESPACE", 0, 1, 1), ("COMPACT", 1, 0, 0), ("FLUSH", 1, 0, 0), ("BACKUP", 0, 1, 1),
+        ("METRICS", 1, 0, 0), ("AUTH", 0, 1, 1), ("KILL", 1, 0, 0)
+    ];
+    for (cmd, provided, min_expected, max_expected) in cases {
+        let argument = vec!["arg"; provided];
+        stream.write_all(bson::to_vec(&doc! { "cmd": cmd, "argument": argument }).unwrap().as_slice())?;
+        let reply = Document::from_reader(&mut stream).expect("unable to read the reply");
+        assert_eq!(reply.get_str("status"), Ok("InvalidArguments"), "{}", cmd);
+        assert_eq!(reply.get_str("error_kind"), Ok("WrongArgumentCount"), "{}", cmd);
+        let fields: serde_json::Value = serde_json::from_str(reply.get_str("result").unwrap()).unwrap();
+        assert_eq!(fields, serde_json::json!({
+            "command": cmd, "min_expected": min_expected, "max_expected": max_expected, "provided": provided
+        }));
+    }
+    
+    let err = KvsError::WrongArgumentCount { command: "INCR".to_owned(), expected: 1..=2, provided: 3 };
+    assert_eq!(err.to_string(), "`INCR` command required 1 or 2 argument, provided 3");
+    let err = KvsError::WrongArgumentCount { command: "GET".to_owned(), expected: 1..=1, provided: 0 };
+    assert_eq!(err.to_string(), "`GET` command required 1 argument, provided 0");
+    
+    Ok(())
+}
+
+// Client should fail over to the next server only if the connection cannot be established
+#[test]
+fn client_failover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let standby_dir = TempDir::new().expect("unable to create temporary working directory");
+    let live = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4058");
+    let standby = spawn_server("kvs", standby_dir.path(), "127.0.0.1:4059");
+    
+    // Nothing is listening on the first address
+    let client = KvsClient::open_many(&["127.0.0.1:4057", "127.0.0.1:4058", "127.0.0.1:4059"])?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Error replies are returned as is
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(matches!(client.remove("key2".to_owned()), Err(KvsError::KeyNotExist(_))));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    
+    assert_eq!(live.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(standby.get("key1".to_owned())?, None);
+    assert_eq!(standby.get("key2".to_owned())?, None);
+    assert!(matches!(KvsClient::open_many(&[]), Err(KvsError::InvalidArguments(_))));
+    
+    Ok(())
+}
+
+// GETRANGE should reply the substring between the inclusive indices, clamped to the value
+#[test]
+fn get_range_command() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4060"), ("sled", "127.0.0.1:4061")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        client.set("key1".to_owned(), "This is a string".to_owned())?;
+        let get_range = |start: i64, end: i64| client.get_range("key1".to_owned(), start, end);
+        
+        assert_eq!(get_range(0, 3)?, Some("This".to_owned()));
+        assert_eq!(get_range(5, 6)?, Some("is".to_owned()));
+        assert_eq!(get_range(-3, -1)?, Some("ing".to_owned()));
+        assert_eq!(get_range(0, -1)?, Some("This is a string".to_owned()));
+        // Out of bounds indices are clamped
+        assert_eq!(get_range(10, 100)?, Some("string".to_owned()));
+        assert_eq!(get_range(-100, 3)?, Some("This".to_owned()));
+        assert_eq!(get_range(i64::MIN, i64::MAX)?, Some("This is a string".to_owned()));
+        assert_eq!(get_range(3, 1)?, Some(String::new()));
+        assert_eq!(get_range(100, 200)?, Some(String::new()));
+        
+        assert_eq!(client.get_range("key2".to_owned(), 0, -1)?, None);
+        client.set("key2".to_owned(), String::new())?;
+        assert_eq!(client.get_range("key2".to_owned(), 0, -1)?, Some(String::new()));
+        client.incr("key3".to_owned(), 12345)?;
+        assert_eq!(client.get_range("key3".to_owned(), 1, 2)?, Some("23".to_owned()));
+    }
+    
+    Ok(())
+}
+
+#[test]
+fn append_command() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4062"), ("sled", "127.0.0.1:4063")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        
+        // Missing key is treated as empty
+        assert_eq!(client.append("key1".to_owned(), "Hello".to_owned())?, 5);
+        assert_eq!(client.get("key1".to_owned())?, Some("Hello".to_owned()));
+        assert_eq!(client.append("key1".to_owned(), ", World".to_owned())?, 12);
+        assert_eq!(client.get("key1".to_owned())?, Some("Hello, World".to_owned()));
+        assert_eq!(client.append("key1".to_owned(), String::new())?, 12);
+        
+        client.set("key2".to_owned(), "value".to_owned())?;
+        assert_eq!(client.append("key2".to_owned(), "2".to_owned())?, 6);
+        assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+        
+        client.incr("key3".to_owned(), 12)?;
+        assert_eq!(client.append("key3".to_owned(), "34".to_owned())?, 4);
+        assert_eq!(client.get("key3".to_owned())?, Some("1234".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// The same code should run against the server and against the engine in process
+#[test]
+fn in_process_handle() -> Result<()> {
+    fn exercise(api: &impl KvsApi) -> Result<()> {
+        api.set("key1".to_owned(), "value1".to_owned())?;
+        api.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(api.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(api.get("key3".to_owned())?, None);
+        api.remove("key2".to_owned())?;
+        assert!(matches!(api.remove("key2".to_owned()), Err(KvsError::KeyNotExist(_))));
+        assert_eq!(api.scan("0", 10)?, ("0".to_owned(), vec!["key1".to_owned()]));
+        Ok(())
+    }
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(&spawn_server("kvs", temp_dir.path(), "127.0.0.1:4068"))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = KvsHandle::new(KvStore::open(temp_dir.path())?);
+    exercise(&handle)?;
+    assert_eq!(handle.clone().get("key1".to_owned())?, Some("value1".to_owned()));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(&KvsHandle::from_boxed(open_engine("sled", temp_dir.path())?))?;
+    
+    Ok(())
+}
+
+// Invalid keys should be rejected by the server before reaching either engine
+#[test]
+fn key_validation() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4069"), ("sled", "127.0.0.1:4070")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvsServerOptions {
+            store: KvStoreOptions { key_validation: KeyValidation::Strict, ..Default::default() },
+            ..Default::default()
+        };
+        let server = KvsServer::open_with_options(engine, temp_dir.path(), options)?;
+        thread::spawn(move || server.start(addr).unwrap());
+        thread::sleep(Duration::from_millis(500));
+        let client = KvsClient::open(addr)?;
+        
+        assert!(matches!(client.set(String::new(), "value1".to_owned()), Err(KvsError::InvalidKey(_))));
+        assert!(matches!(client.set("key\u{0}1".to_owned(), "value1".to_owned()), Err(KvsError::InvalidKey(_))));
+        assert!(matches!(client.set_nx("key\n1".to_owned(), "value1".to_owned()), Err(KvsError::InvalidKey(_))));
+        assert!(matches!(client.append(String::new(), "value1".to_owned()), Err(KvsError::InvalidKey(_))));
+        assert!(matches!(client.incr("key\t1".to_owned(), 1), Err(KvsError::InvalidKey(_))));
+        assert_eq!(client.get(String::new())?, None);
+        
+        client.set("键1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("键1".to_owned())?, Some("value1".to_owned()));
+    }
+    
+    Ok(())
+}
+
+// REMOVEIFEXISTS should tell whether the key was removed instead of failing for a missing key
+#[test]
+fn remove_if_exists() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4071"), ("sled", "127.0.0.1:4072")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        
+        assert!(client.remove_if_exists("key1".to_owned())?);
+        assert_eq!(client.get("key1".to_owned())?, None);
+        assert!(!client.remove_if_exists("key1".to_owned())?);
+        assert!(!client.remove_if_exists("key2".to_owned())?);
+        // The plain removal still fails for a missing key
+        assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::KeyNotExist(_))));
+    }
+    
+    Ok(())
+}
+
+// DBSIZE should report the number of keys in the namespace of the client
+#[test]
+fn dbsize() -> Result<()> {
+    for (engine, addr) in [("kvs", "127.0.0.1:4080"), ("sled", "127.0.0.1:4081")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let client = spawn_server(engine, temp_dir.path(), addr);
+        assert_eq!(client.len()?, 0);
+        assert!(client.is_empty()?);
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key2".to_owned(), "value2".to_owned())?;
+        client.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(client.len()?, 2);
+        client.remove("key2".to_owned())?;
+        assert_eq!(client.len()?, 1);
+        
+        let other = client.with_namespace("other");
+        assert_eq!(other.len()?, 0);
+        other.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(other.len()?, 1);
+        assert_eq!(client.len()?, 1);
+    }
+    
+    Ok(())
+}
+
+// Large value should be written to the sink as it is streamed, identical to the stored value
+#[test]
+fn get_to_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let async_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Not valid UTF-8, so never readable by GET
+    let binary = vec![0xFF, 0xFE, 0x00, 0x80];
+    for dir in [temp_dir.path(), async_dir.path()] {
+        KvStore::open(dir)?.set_bytes(b"binary".to_vec(), binary.clone())?;
+    }
+    let _client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4073");
+    let server = KvsServer::open("kvs", async_dir.path())?;
+    thread::spawn(move || server.start_async("127.0.0.1:4074").unwrap());
+    thread::sleep(Duration::from_millis(500));
+    
+    // Not a multiple of the chunk size, so the last chunk is partial
+    let value = (0..(8 << 20) + 123).map(|i| (b'a' + (i % 26) as u8) as char).collect::<String>();
+    for addr in ["127.0.0.1:4073", "127.0.0.1:4074"] {
+        let client = KvsClient::open(addr)?;
+        client.set("large".to_owned(), value.clone())?;
+        
+        let path = temp_dir.path().join("large.out");
+        let mut file = fs::File::create(&path)?;
+        assert_eq!(client.get_to_writer("large".to_owned(), &mut file)?, Some(value.len() as u64));
+        drop(file);
+        assert!(fs::read(&path)? == value.as_bytes());
+        assert_eq!(client.get_to_writer("missing".to_owned(), &mut Vec::new())?, None);
+        // Plain GET still returns the whole value
+        assert_eq!(client.get("large".to_owned())?.map(|value| value.len()), Some(value.len()));
+        let mut received = Vec::new();
+        assert_eq!(client.get_to_writer("binary".to_owned(), &mut received)?, Some(binary.len() as u64));
+        assert_eq!(received, binary);
+    }
+    
+    Ok(())
+}
+
+// Listener failing the accepts with the injected errors before accepting from the real listener
+struct FaultyListener {
+    listener: mio::net::TcpListener,
+    errors: Mutex<Vec<io::Error>>
+}
+
+impl mio::event::Source for FaultyListener {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> io::Result<()> {
+        self.listener.register(registry, token, interests)
+    }
+    
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> io::Result<()> {
+        self.listener.reregister(registry, token, interests)
+    }
+    
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.listener.deregister(registry)
+    }
+}
+
+impl Acceptor for FaultyListener {
+    type Stream = TcpStream;
+    
+    fn accept_stream(&self) -> io::Result<(TcpStream, Peer)> {
+        if let Some(err) = self.errors.lock().unwrap().pop() { return Err(err) }
+        let (stream, peer_addr) = self.listener.accept()?;
+        let stream = TcpStream::from(stream);
+        stream.set_nonblocking(false)?;
+        Ok((stream, Peer::Tcp(peer_addr)))
+    }
+}
+
+// Running out of file descriptors should be logged and retried, while a broken listener stops the server
+#[test]
+fn accept_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = MemoryDrain::default();
+    let options = KvsServerOptions {
+        logger: slog::Logger::root(drain.clone(), slog::o!()),
+        ..Default::default()
+    };
+    let server = KvsServer::open_with_options("kvs", temp_dir.path(), options)?;
+    let listener = FaultyListener {
+        listener: mio::net::TcpListener::bind("127.0.0.1:4075".parse().unwrap())?,
+        // Too many open files
+        errors: Mutex::new((0..3).map(|_| io::Error::from_raw_os_error(24)).collect())
+    };
+    thread::spawn(move || server.start_with_listener(listener).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    
+    // The connection is accepted once the errors are gone, instead of waiting for another one
+    let client = KvsClient::open_with_config("127.0.0.1:4075", ClientConfig {
+        request_timeout: Some(Duration::from_secs(2)),
+        ..Default::default()
+    })?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let failures = drain.0.lock().unwrap().iter().filter(|record| record.contains_key("backoff_ms")).count();
+    assert_eq!(failures, 3);
+    
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open("kvs", temp_dir.path())?;
+    let listener = FaultyListener {
+        listener: mio::net::TcpListener::bind("127.0.0.1:4076".parse().unwrap())?,
+        errors: Mutex::new(vec![io::Error::from(io::ErrorKind::InvalidInput)])
+    };
+    let handle = thread::spawn(move || server.start_with_listener(listener));
+    thread::sleep(Duration::from_millis(500));
+    let _stream = TcpStream::connect("127.0.0.1:4076")?;
+    assert!(matches!(handle.join().unwrap(), Err(KvsError::IOError(err)) if err.kind() == io::ErrorKind::InvalidInput));
+    
+    Ok(())
+}
+
+// Server latency should cover the engine operation only, measured apart from the whole round trip
+#[test]
+fn server_latency() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn_server("kvs", temp_dir.path(), "127.0.0.1:4077");
+    
+    // Reading and checking a large value takes a while on the server
+    let value = "a".repeat(8 << 20);
+    client.set("large".to_owned(), value.clone())?;
+    let (result, timing) = client.get_timed("large".to_owned())?;
+    assert_eq!(result.map(|result| result.len()), Some(value.len()));
+    let ServerTiming { server_latency, round_trip } = timing;
+    let server_latency = server_latency.expect("server latency is reported");
+    assert!(server_latency > Duration::ZERO);
+    // The round trip also includes connecting and transferring the value
+    assert!(server_latency < round_trip);
+    
+    let (result, timing) = client.get_timed("missing".to_owned())?;
+    assert_eq!(result, None);
+    assert!(timing.server_latency.is_some());
+    
+    Ok(())
+}
+
+// Client should detect the reply of another request instead of returning it as its own
+#[tokio::test]
+async fn protocol_desync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = tokio::task::spawn_blocking({
+        let path = temp_dir.path().to_owned();
+        move || spawn_server("kvs", &path, "127.0.0.1:4078")
+    }).await.unwrap();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    
+    // The server replies to every request with the id of the following one
+    let listener = TcpListener::bind("127.0.0.1:4079")?;
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            while let Ok(request) = Document::from_reader(&mut conn) {
+                let id = request.get_i64("id").unwrap_or_default();
+                let reply = doc! { "result": "value1", "status": "Success", "request_id": id + 1 };
+                let mut buf = Vec::new();
+                reply.to_writer(&mut buf).unwrap();
+                if conn.write_all(&buf).is_err() { break }
+            }
+        }
+    });
+    let client = KvsClient::open("127.0.0.1:4079")?;
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::ProtocolDesync)));
+    assert!(matches!(client.set("key1".to_owned(), "value1".to_owned()), Err(KvsError::ProtocolDesync)));
+    
+    let mut client = AsyncKvsClient::connect("127.0.0.1:4079").await?;
+    assert!(matches!(client.get("key1".to_owned()).await, Err(KvsError::ProtocolDesync)));
+    
+    Ok(())
+}
+
+// Opening a database with the other engine should fail in the library, not only in kvs-server
+#[test]
+fn conflicting_engine() -> Result<()> {
+    for (created, requested) in [("kvs", "sled"), ("sled", "kvs")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        assert_eq!(detect_engine(temp_dir.path()), None);
+        drop(open_engine(created, temp_dir.path())?);
+        assert_eq!(detect_engine(temp_dir.path()), Some(created));
+        
+        let conflict = |result: Result<()>| matches!(result,
+            Err(KvsError::ConflictingEngine { found, requested: engine }) if found == created && engine == requested);
+        assert!(conflict(open_engine(requested, temp_dir.path()).map(drop)));
+        assert!(conflict(KvsServer::open(requested, temp_dir.path()).map(drop)));
+        // The engine name is not case-sensitive
+        assert!(KvsServer::open(&created.to_uppercase(), temp_dir.path()).is_ok());
+    }
+    
+    Ok(())
+}